@@ -0,0 +1,32 @@
+//! records which commit the binary was built from and when, for `/version`.
+//! builds outside a git checkout report the commit as `unknown`.
+
+use std::{
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+fn main() {
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|commit| commit.trim().to_string())
+        .filter(|commit| !commit.is_empty())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    let built_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis())
+        .unwrap_or_default();
+
+    println!("cargo:rustc-env=CHAT_GIT_COMMIT={}", commit);
+    println!("cargo:rustc-env=CHAT_BUILD_TIMESTAMP={}", built_at);
+
+    // rebuilt on a new commit or checkout, not on every build
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
@@ -0,0 +1,198 @@
+//! files sent along with messages, either over the socket or uploaded ahead
+//! of the message. like the history they're only kept in memory, up to a
+//! total size past which the oldest are dropped to make room. anyone holding
+//! an attachment's id can download it, ids are as hard to guess as tokens.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+use warp::hyper::body::Bytes;
+
+/// what recipients get to see of an attachment, the file itself is fetched
+/// from `url`
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct AttachmentInfo {
+    pub id: String,
+    pub filename: String,
+    pub mime: String,
+    pub size: usize,
+    pub url: String,
+}
+
+impl AttachmentInfo {
+    fn new(id: &str, file: &File) -> Self {
+        AttachmentInfo {
+            id: id.to_string(),
+            filename: file.filename.clone(),
+            mime: file.mime.clone(),
+            size: file.data.len(),
+            url: format!("/attachments/{}", id),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct File {
+    pub filename: String,
+    pub mime: String,
+    pub data: Bytes,
+
+    /// token of the client that sent the file, the only one who may attach
+    /// it to a message
+    pub owner: String,
+}
+
+/// the file is larger than everything that may be kept at once
+#[derive(Debug)]
+pub struct TooLarge;
+
+#[derive(Debug, Default)]
+struct Files {
+    by_id: HashMap<String, File>,
+
+    /// ids, oldest first
+    order: VecDeque<String>,
+
+    total_bytes: usize,
+}
+
+#[derive(Debug)]
+pub struct Attachments {
+    files: Mutex<Files>,
+    max_total_bytes: usize,
+}
+
+impl Attachments {
+    pub fn new(max_total_bytes: usize) -> Self {
+        Attachments {
+            files: Mutex::new(Files::default()),
+            max_total_bytes,
+        }
+    }
+
+    /// keeps a file, dropping the oldest ones if there's no room for it
+    pub fn insert(&self, file: File) -> Result<AttachmentInfo, TooLarge> {
+        let size = file.data.len();
+
+        if size > self.max_total_bytes {
+            return Err(TooLarge);
+        }
+
+        let mut files = self.files.lock().unwrap();
+
+        while files.total_bytes + size > self.max_total_bytes {
+            let Some(oldest) = files.order.pop_front() else {
+                break;
+            };
+
+            if let Some(oldest) = files.by_id.remove(&oldest) {
+                files.total_bytes -= oldest.data.len();
+            }
+        }
+
+        let id = uuid::Uuid::new_v4().as_simple().to_string();
+        let info = AttachmentInfo::new(&id, &file);
+
+        files.total_bytes += size;
+        files.order.push_back(id.clone());
+        files.by_id.insert(id, file);
+
+        Ok(info)
+    }
+
+    pub fn get(&self, id: &str) -> Option<File> {
+        self.files.lock().unwrap().by_id.get(id).cloned()
+    }
+
+    /// what a message shows of a file uploaded by `owner`, `None` if there's
+    /// no such file or it's someone else's
+    pub fn info_for(&self, id: &str, owner: &str) -> Option<AttachmentInfo> {
+        let files = self.files.lock().unwrap();
+        let file = files.by_id.get(id).filter(|file| file.owner == owner)?;
+
+        Some(AttachmentInfo::new(id, file))
+    }
+
+    pub fn remove(&self, id: &str) {
+        let mut files = self.files.lock().unwrap();
+
+        if let Some(file) = files.by_id.remove(id) {
+            files.total_bytes -= file.data.len();
+            files.order.retain(|other| other != id);
+        }
+    }
+}
+
+/// longest accepted file name, in characters
+const MAX_FILENAME_LENGTH: usize = 255;
+
+/// whether a file name can be shown and offered for download as is: no
+/// paths, no control characters
+pub fn is_valid_filename(filename: &str) -> bool {
+    !filename.trim().is_empty()
+        && filename.chars().count() <= MAX_FILENAME_LENGTH
+        && !filename
+            .chars()
+            .any(|c| c.is_control() || c == '/' || c == '\\')
+}
+
+/// whether `mime` looks like a media type, `type/subtype` without parameters
+pub fn is_valid_mime(mime: &str) -> bool {
+    let is_token = |part: &str| {
+        !part.is_empty()
+            && part
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"!#$&-^_.+".contains(&b))
+    };
+
+    match mime.split_once('/') {
+        Some((kind, subtype)) => is_token(kind) && is_token(subtype),
+        None => false,
+    }
+}
+
+/// whether `mime` matches one of `allowed`, which may hold wildcards like
+/// `image/*` or `*` for anything
+pub fn is_allowed_mime(mime: &str, allowed: &[String]) -> bool {
+    let kind = mime.split('/').next().unwrap_or_default();
+
+    allowed.iter().any(|allowed| {
+        allowed == "*"
+            || allowed.eq_ignore_ascii_case(mime)
+            || allowed
+                .strip_suffix("/*")
+                .is_some_and(|allowed| allowed.eq_ignore_ascii_case(kind))
+    })
+}
+
+/// a `Content-Disposition` value offering the file as a download. the plain
+/// `filename` is an ascii stand-in, clients that know better use the
+/// percent-encoded `filename*`
+pub fn content_disposition(filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| match c {
+            ' ' => ' ',
+            '"' | '\\' | '%' => '_',
+            c if c.is_ascii_graphic() => c,
+            _ => '_',
+        })
+        .collect();
+
+    let mut encoded = String::with_capacity(filename.len());
+
+    for b in filename.bytes() {
+        if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback, encoded
+    )
+}
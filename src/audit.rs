@@ -0,0 +1,85 @@
+//! what admins did and to whom. entries can only be appended, and each gets
+//! the next sequence number, so a gap in the numbers shows entries went
+//! missing. like the reports only so many are kept in memory, the oldest
+//! make room first.
+
+use std::{collections::VecDeque, sync::Mutex};
+
+/// maximum number of entries kept
+const AUDIT_LIMIT: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Kick,
+    Ban,
+    Unban,
+    Announce,
+    Maintenance,
+    ResolveReport,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Entry {
+    /// position in the log, counting from 1 since the server started
+    pub seq: u64,
+
+    pub action: Action,
+
+    /// who did it. there's a single admin token, so this is the address the
+    /// request came from
+    pub admin: String,
+
+    /// what it was done to, a name, an address, a report id or a setting
+    pub target: String,
+
+    /// unix timestamp in milliseconds of the action
+    pub timestamp: i64,
+}
+
+#[derive(Debug, Default)]
+struct Log {
+    entries: VecDeque<Entry>,
+    appended: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct AuditLog {
+    log: Mutex<Log>,
+}
+
+impl AuditLog {
+    /// appends an entry stamped with the current time
+    pub fn record(&self, action: Action, admin: Option<std::net::SocketAddr>, target: &str) {
+        let mut log = self.log.lock().unwrap();
+
+        if log.entries.len() == AUDIT_LIMIT {
+            log.entries.pop_front();
+        }
+
+        log.appended += 1;
+
+        let entry = Entry {
+            seq: log.appended,
+            action,
+            admin: admin.map_or_else(|| "unknown".to_string(), |addr| addr.ip().to_string()),
+            target: target.to_string(),
+            timestamp: super::unix_millis(),
+        };
+
+        tracing::info!(
+            seq = entry.seq,
+            action = ?entry.action,
+            admin = %entry.admin,
+            target = %entry.target,
+            "audit"
+        );
+
+        log.entries.push_back(entry);
+    }
+
+    /// every entry kept, oldest first
+    pub fn list(&self) -> Vec<Entry> {
+        self.log.lock().unwrap().entries.iter().cloned().collect()
+    }
+}
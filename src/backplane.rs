@@ -0,0 +1,449 @@
+//! optional redis backplane, so more than one instance can serve the same
+//! clients. built with the `redis` feature and given `CHAT_REDIS_URL`, names
+//! are claimed in a set every instance shares, keeping them unique across
+//! instances, and a direct message for a client that another instance holds
+//! is published on the client's channel, `chat:to:<name key>`. every instance
+//! listens on all of those channels and delivers what's meant for its own
+//! clients. without the feature or the url every call is a no-op and an
+//! instance only knows the clients it holds itself.
+//!
+//! the set is only cleaned up by the instance holding a name, names of an
+//! instance that died stay claimed until they're removed from the set by
+//! hand. publishing is fire and forget, messages relayed while an instance
+//! had lost its subscription don't reach it.
+
+use super::Message;
+
+/// the set of name keys claimed by any instance
+#[cfg(feature = "redis")]
+const NAMES_KEY: &str = "chat:names";
+
+/// prefix of the channel the messages for one client are published on,
+/// followed by its name key
+#[cfg(feature = "redis")]
+const CHANNEL_PREFIX: &str = "chat:to:";
+
+/// what goes over a client's channel
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Relayed {
+    /// token of the sender, so the recipient's blocks still apply
+    pub sender: String,
+
+    pub message: Message,
+}
+
+/// redis couldn't be reached or answered with an error
+#[derive(Debug)]
+pub struct Unavailable;
+
+/// the `host:port` a `redis://host[:port]` url points at
+pub fn address(url: &str) -> Result<String, String> {
+    let rest = url
+        .strip_prefix("redis://")
+        .ok_or_else(|| format!("{} is not a redis:// url", url))?;
+    let host = rest.split('/').next().unwrap_or_default();
+
+    match host {
+        "" => Err(format!("{} has no host", url)),
+        host if host.contains(':') => Ok(host.to_string()),
+        host => Ok(format!("{}:6379", host)),
+    }
+}
+
+#[cfg(feature = "redis")]
+pub struct Backplane {
+    /// `None` without a url, and every call is a no-op
+    inner: Option<Inner>,
+}
+
+#[cfg(feature = "redis")]
+struct Inner {
+    address: String,
+
+    /// commands for the task that owns the connection, sent one at a time
+    commands: tokio::sync::mpsc::UnboundedSender<resp::Command>,
+
+    /// whether `listen` currently holds a subscription, so relayed messages
+    /// reach this instance
+    subscribed: std::sync::atomic::AtomicBool,
+}
+
+#[cfg(feature = "redis")]
+impl Backplane {
+    /// a backplane to the redis at `url`, connected to on first use. must be
+    /// called from within the runtime
+    pub fn new(url: Option<&str>) -> Self {
+        let inner = url.map(|url| {
+            let address = address(url).expect("redis url is checked when loading the config");
+            let (commands, queue) = tokio::sync::mpsc::unbounded_channel();
+
+            tokio::spawn(resp::run_commands(address.clone(), queue));
+
+            Inner {
+                address,
+                commands,
+                subscribed: Default::default(),
+            }
+        });
+
+        Backplane { inner }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.inner.is_some()
+    }
+
+    /// whether messages relayed by other instances get here, true without a
+    /// backplane as there's nothing to miss
+    pub fn is_healthy(&self) -> bool {
+        self.inner
+            .as_ref()
+            .is_none_or(|inner| inner.subscribed.load(std::sync::atomic::Ordering::SeqCst))
+    }
+
+    async fn call(&self, args: Vec<String>) -> Result<resp::Value, Unavailable> {
+        let Some(inner) = &self.inner else {
+            return Err(Unavailable);
+        };
+
+        let (reply, replied) = tokio::sync::oneshot::channel();
+
+        inner
+            .commands
+            .send(resp::Command {
+                args,
+                reply: Some(reply),
+            })
+            .map_err(|_| Unavailable)?;
+
+        match replied.await {
+            Ok(Ok(resp::Value::Error(error))) => {
+                tracing::warn!(%error, "backplane command failed");
+                Err(Unavailable)
+            }
+            Ok(Ok(value)) => Ok(value),
+            Ok(Err(err)) => {
+                tracing::warn!(%err, "backplane unreachable");
+                Err(Unavailable)
+            }
+            Err(_) => Err(Unavailable),
+        }
+    }
+
+    /// sends a command without waiting for the answer, failures are logged
+    fn send(&self, args: Vec<String>) {
+        if let Some(inner) = &self.inner {
+            let _ = inner.commands.send(resp::Command { args, reply: None });
+        }
+    }
+
+    /// claims name key `key` for this instance, false when another instance
+    /// or this one already holds it
+    pub async fn claim_name(&self, key: &str) -> Result<bool, Unavailable> {
+        if !self.is_enabled() {
+            return Ok(true);
+        }
+
+        let claimed = self
+            .call(vec!["SADD".into(), NAMES_KEY.into(), key.into()])
+            .await?;
+
+        Ok(matches!(claimed, resp::Value::Integer(1)))
+    }
+
+    /// claims every one of `keys` without checking who held them, for the
+    /// clients an instance restored on startup
+    pub fn claim_names(&self, keys: impl IntoIterator<Item = String>) {
+        let mut args = vec!["SADD".to_string(), NAMES_KEY.to_string()];
+        args.extend(keys);
+
+        if args.len() > 2 {
+            self.send(args);
+        }
+    }
+
+    pub fn release_name(&self, key: &str) {
+        self.send(vec!["SREM".into(), NAMES_KEY.into(), key.into()]);
+    }
+
+    /// publishes `message` on the channel of each of `to` that another
+    /// instance claimed, and returns the names it went out to
+    pub async fn relay(&self, message: &Message, to: Vec<String>) -> Vec<String> {
+        let mut relayed = Vec::new();
+
+        if !self.is_enabled() || to.is_empty() {
+            return relayed;
+        }
+
+        let payload = serde_json::to_string(&Relayed {
+            sender: message.sender.clone(),
+            message: message.clone(),
+        })
+        .unwrap();
+
+        for name in to {
+            let key = super::name_key(&name);
+
+            let claimed = self
+                .call(vec!["SISMEMBER".into(), NAMES_KEY.into(), key.clone()])
+                .await;
+
+            if !matches!(claimed, Ok(resp::Value::Integer(1))) {
+                continue;
+            }
+
+            let published = self
+                .call(vec![
+                    "PUBLISH".into(),
+                    format!("{}{}", CHANNEL_PREFIX, key),
+                    payload.clone(),
+                ])
+                .await;
+
+            if published.is_ok() {
+                tracing::debug!(to = %name, "message relayed");
+                relayed.push(name);
+            }
+        }
+
+        relayed
+    }
+
+    /// hands every message published for a client to `deliver`, along with
+    /// the name key it's for, subscribing again whenever the connection is
+    /// lost. never returns unless there's no backplane
+    pub async fn listen(&self, deliver: impl Fn(&str, Relayed)) {
+        let Some(inner) = &self.inner else {
+            return;
+        };
+
+        loop {
+            let subscribed =
+                resp::subscribe(&inner.address, CHANNEL_PREFIX, &inner.subscribed, &deliver).await;
+
+            inner
+                .subscribed
+                .store(false, std::sync::atomic::Ordering::SeqCst);
+
+            if let Err(err) = subscribed {
+                tracing::warn!(%err, "backplane subscription lost, retrying");
+            }
+
+            tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+        }
+    }
+}
+
+#[cfg(not(feature = "redis"))]
+pub struct Backplane;
+
+#[cfg(not(feature = "redis"))]
+impl Backplane {
+    pub fn new(url: Option<&str>) -> Self {
+        if url.is_some() {
+            tracing::warn!("built without the redis feature, CHAT_REDIS_URL is ignored");
+        }
+
+        Backplane
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        false
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        true
+    }
+
+    pub async fn claim_name(&self, _key: &str) -> Result<bool, Unavailable> {
+        Ok(true)
+    }
+
+    pub fn claim_names(&self, _keys: impl IntoIterator<Item = String>) {}
+
+    pub fn release_name(&self, _key: &str) {}
+
+    pub async fn relay(&self, _message: &Message, _to: Vec<String>) -> Vec<String> {
+        Vec::new()
+    }
+
+    pub async fn listen(&self, _deliver: impl Fn(&str, Relayed)) {}
+}
+
+/// just enough of the redis protocol for the commands above
+#[cfg(feature = "redis")]
+mod resp {
+    use std::{future::Future, io, pin::Pin};
+
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::TcpStream,
+    };
+
+    #[derive(Debug)]
+    pub enum Value {
+        Status,
+        Error(String),
+        Integer(i64),
+        Bulk(Option<String>),
+        Array(Vec<Value>),
+    }
+
+    pub struct Command {
+        pub args: Vec<String>,
+
+        /// where the answer goes, `None` when nobody waits for it
+        pub reply: Option<tokio::sync::oneshot::Sender<io::Result<Value>>>,
+    }
+
+    struct Connection {
+        stream: BufReader<TcpStream>,
+    }
+
+    impl Connection {
+        async fn open(address: &str) -> io::Result<Self> {
+            Ok(Connection {
+                stream: BufReader::new(TcpStream::connect(address).await?),
+            })
+        }
+
+        async fn send(&mut self, args: &[String]) -> io::Result<()> {
+            let mut frame = format!("*{}\r\n", args.len());
+
+            for arg in args {
+                frame.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+            }
+
+            self.stream.get_mut().write_all(frame.as_bytes()).await
+        }
+
+        fn read(&mut self) -> Pin<Box<dyn Future<Output = io::Result<Value>> + Send + '_>> {
+            Box::pin(async move {
+                let mut line = String::new();
+
+                if self.stream.read_line(&mut line).await? == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+
+                let line = line.trim_end_matches("\r\n");
+                let invalid = || io::Error::new(io::ErrorKind::InvalidData, line.to_string());
+                let length = || line[1..].parse::<i64>().map_err(|_| invalid());
+
+                match line.as_bytes().first() {
+                    Some(b'+') => Ok(Value::Status),
+                    Some(b'-') => Ok(Value::Error(line[1..].to_string())),
+                    Some(b':') => Ok(Value::Integer(length()?)),
+                    Some(b'$') => {
+                        let Ok(length) = usize::try_from(length()?) else {
+                            return Ok(Value::Bulk(None));
+                        };
+
+                        // the data is followed by its own line break
+                        let mut data = vec![0; length + 2];
+                        self.stream.read_exact(&mut data).await?;
+                        data.truncate(length);
+
+                        String::from_utf8(data)
+                            .map(|data| Value::Bulk(Some(data)))
+                            .map_err(|_| io::ErrorKind::InvalidData.into())
+                    }
+                    Some(b'*') => {
+                        let length = usize::try_from(length()?).unwrap_or(0);
+                        let mut values = Vec::with_capacity(length);
+
+                        for _ in 0..length {
+                            values.push(self.read().await?);
+                        }
+
+                        Ok(Value::Array(values))
+                    }
+                    _ => Err(invalid()),
+                }
+            })
+        }
+    }
+
+    /// runs commands one after the other over a single connection, opened
+    /// again after it failed
+    pub async fn run_commands(
+        address: String,
+        mut commands: tokio::sync::mpsc::UnboundedReceiver<Command>,
+    ) {
+        let mut connection = None;
+
+        while let Some(command) = commands.recv().await {
+            let result = async {
+                let connection = match &mut connection {
+                    Some(connection) => connection,
+                    None => connection.insert(Connection::open(&address).await?),
+                };
+
+                connection.send(&command.args).await?;
+                connection.read().await
+            }
+            .await;
+
+            if result.is_err() {
+                connection = None;
+            }
+
+            match (command.reply, result) {
+                (Some(reply), result) => {
+                    let _ = reply.send(result);
+                }
+                (None, Err(err)) => tracing::warn!(%err, "backplane unreachable"),
+                (None, Ok(Value::Error(error))) => {
+                    tracing::warn!(%error, "backplane command failed")
+                }
+                (None, Ok(_)) => {}
+            }
+        }
+    }
+
+    /// subscribes to every channel starting with `prefix` and hands what's
+    /// published on them to `deliver`, with the rest of the channel name.
+    /// `subscribed` is set once redis confirmed the subscription. returns
+    /// only once the connection fails
+    pub async fn subscribe(
+        address: &str,
+        prefix: &str,
+        subscribed: &std::sync::atomic::AtomicBool,
+        deliver: impl Fn(&str, super::Relayed),
+    ) -> io::Result<()> {
+        let mut connection = Connection::open(address).await?;
+
+        connection
+            .send(&["PSUBSCRIBE".to_string(), format!("{}*", prefix)])
+            .await?;
+
+        tracing::info!(%address, "listening on the backplane");
+
+        loop {
+            let Value::Array(parts) = connection.read().await? else {
+                continue;
+            };
+
+            // the answer to PSUBSCRIBE, messages follow from here on
+            if matches!(parts.first(), Some(Value::Bulk(Some(kind))) if kind == "psubscribe") {
+                subscribed.store(true, std::sync::atomic::Ordering::SeqCst);
+                continue;
+            }
+
+            let Ok(
+                [Value::Bulk(Some(kind)), _, Value::Bulk(Some(channel)), Value::Bulk(Some(payload))],
+            ) = <[Value; 4]>::try_from(parts)
+            else {
+                continue;
+            };
+
+            let Some(key) = channel.strip_prefix(prefix).filter(|_| kind == "pmessage") else {
+                continue;
+            };
+
+            match serde_json::from_str(&payload) {
+                Ok(relayed) => deliver(key, relayed),
+                Err(err) => tracing::warn!(%err, "malformed message on the backplane"),
+            }
+        }
+    }
+}
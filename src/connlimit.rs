@@ -0,0 +1,77 @@
+//! caps how many sockets a single address may hold open at once. a slot is
+//! taken before the upgrade and handed back once it's dropped, after the
+//! socket was cleaned up or when the upgrade never happened
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Debug)]
+pub struct ConnectionLimiter {
+    /// address -> sockets it holds open
+    open: Mutex<HashMap<IpAddr, usize>>,
+
+    /// sockets allowed per address, 0 turns the limiter off
+    limit: usize,
+}
+
+/// one open socket of `addr`, counted until dropped
+#[derive(Debug)]
+pub struct Slot {
+    limiter: Arc<ConnectionLimiter>,
+    addr: IpAddr,
+}
+
+impl ConnectionLimiter {
+    pub fn new(limit: usize) -> Self {
+        ConnectionLimiter {
+            open: Mutex::new(HashMap::new()),
+            limit,
+        }
+    }
+
+    /// takes a slot for `addr`, or `None` if it's at the limit already
+    pub fn acquire(self: &Arc<Self>, addr: IpAddr) -> Option<Slot> {
+        if self.limit == 0 {
+            return Some(Slot {
+                limiter: self.clone(),
+                addr,
+            });
+        }
+
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(addr).or_default();
+
+        if *count >= self.limit {
+            return None;
+        }
+
+        *count += 1;
+
+        Some(Slot {
+            limiter: self.clone(),
+            addr,
+        })
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if self.limiter.limit == 0 {
+            return;
+        }
+
+        let mut open = self.limiter.open.lock().unwrap();
+
+        // addresses without sockets are forgotten so the map doesn't grow
+        if let Some(count) = open.get_mut(&self.addr) {
+            *count -= 1;
+
+            if *count == 0 {
+                open.remove(&self.addr);
+            }
+        }
+    }
+}
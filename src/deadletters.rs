@@ -0,0 +1,49 @@
+//! messages that were still queued for a client when it went away for good,
+//! kept so there's a record of what never arrived. like the reports they only
+//! live in memory, and only so many are kept, the oldest make room first.
+
+use std::{collections::VecDeque, sync::Mutex};
+
+/// maximum number of dead letters kept
+const DEAD_LETTER_LIMIT: usize = 1000;
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct DeadLetter {
+    pub message_id: String,
+
+    /// name of the client who sent the message, as it was then
+    pub from: String,
+
+    /// name of the client it never reached
+    pub to: String,
+
+    pub body: String,
+
+    /// why it can't be delivered anymore, like `account_deleted`
+    pub reason: &'static str,
+
+    /// unix timestamp in milliseconds of when the message was given up on
+    pub timestamp: i64,
+}
+
+#[derive(Debug, Default)]
+pub struct DeadLetters {
+    letters: Mutex<VecDeque<DeadLetter>>,
+}
+
+impl DeadLetters {
+    pub fn insert(&self, letter: DeadLetter) {
+        let mut letters = self.letters.lock().unwrap();
+
+        if letters.len() == DEAD_LETTER_LIMIT {
+            letters.pop_front();
+        }
+
+        letters.push_back(letter);
+    }
+
+    /// every dead letter kept, oldest first
+    pub fn list(&self) -> Vec<DeadLetter> {
+        self.letters.lock().unwrap().iter().cloned().collect()
+    }
+}
@@ -0,0 +1,191 @@
+//! passes message bodies go through before they're stored: a word filter
+//! and optional html escaping. words are matched case-insensitively anywhere
+//! in the body, so a blocked word inside a longer one counts too.
+
+/// what to do with a body containing a blocked word
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterMode {
+    /// turn the message down
+    Reject,
+
+    /// replace every character of a blocked word with `*`
+    Mask,
+}
+
+impl std::str::FromStr for FilterMode {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "reject" => Ok(FilterMode::Reject),
+            "mask" => Ok(FilterMode::Mask),
+            _ => Err(()),
+        }
+    }
+}
+
+/// the body contained a blocked word and the filter rejects those
+#[derive(Debug)]
+pub struct Blocked;
+
+#[derive(Debug)]
+pub struct ContentFilter {
+    /// lowercased, as characters so matches line up with the body
+    words: Vec<Vec<char>>,
+    mode: FilterMode,
+}
+
+impl ContentFilter {
+    /// an empty word list lets everything through
+    pub fn new<'a>(words: impl IntoIterator<Item = &'a str>, mode: FilterMode) -> Self {
+        ContentFilter {
+            words: words
+                .into_iter()
+                .map(|word| word.chars().map(lowercase).collect::<Vec<_>>())
+                .filter(|word| !word.is_empty())
+                .collect(),
+            mode,
+        }
+    }
+
+    /// the body as it may be delivered, masked if need be
+    pub fn apply(&self, body: String) -> Result<String, Blocked> {
+        if self.words.is_empty() {
+            return Ok(body);
+        }
+
+        let chars: Vec<char> = body.chars().collect();
+        let lower: Vec<char> = chars.iter().copied().map(lowercase).collect();
+        let mut blocked = vec![false; chars.len()];
+
+        for word in &self.words {
+            for start in 0..lower.len().saturating_sub(word.len() - 1) {
+                if lower[start..].starts_with(word) {
+                    blocked[start..start + word.len()].fill(true);
+                }
+            }
+        }
+
+        if !blocked.contains(&true) {
+            return Ok(body);
+        }
+
+        match self.mode {
+            FilterMode::Reject => Err(Blocked),
+            FilterMode::Mask => Ok(chars
+                .iter()
+                .zip(&blocked)
+                .map(|(&c, &blocked)| if blocked { '*' } else { c })
+                .collect()),
+        }
+    }
+}
+
+/// simple per-character lowercasing, keeping one character per character
+fn lowercase(c: char) -> char {
+    let mut lower = c.to_lowercase();
+
+    match (lower.next(), lower.next()) {
+        (Some(lower), None) => lower,
+        _ => c,
+    }
+}
+
+/// escapes the characters that mean something in html, so clients that
+/// render bodies as markup show them as text
+pub fn escape_html(body: &str) -> String {
+    let mut escaped = String::with_capacity(body.len());
+
+    for c in body.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(words: &[&str], mode: FilterMode, body: &str) -> Result<String, Blocked> {
+        ContentFilter::new(words.iter().copied(), mode).apply(body.to_string())
+    }
+
+    #[test]
+    fn reject_turns_down_a_blocked_word() {
+        assert!(apply(&["darn"], FilterMode::Reject, "well darn it").is_err());
+        assert!(apply(&["darn"], FilterMode::Reject, "DARN").is_err());
+        assert_eq!(
+            apply(&["darn"], FilterMode::Reject, "all good").unwrap(),
+            "all good"
+        );
+    }
+
+    #[test]
+    fn mask_ignores_case_and_keeps_the_original_case_elsewhere() {
+        assert_eq!(
+            apply(&["Darn"], FilterMode::Mask, "Well DARN, darn it").unwrap(),
+            "Well ****, **** it"
+        );
+    }
+
+    #[test]
+    fn mask_stops_at_the_word_boundaries() {
+        // punctuation and spaces around the word stay put
+        assert_eq!(
+            apply(&["darn"], FilterMode::Mask, "darn! (darn) darn").unwrap(),
+            "****! (****) ****"
+        );
+    }
+
+    #[test]
+    fn mask_catches_a_word_inside_a_longer_one() {
+        assert_eq!(
+            apply(&["darn"], FilterMode::Mask, "undarnable").unwrap(),
+            "un****able"
+        );
+    }
+
+    #[test]
+    fn mask_covers_overlapping_words() {
+        assert_eq!(
+            apply(&["abc", "cde"], FilterMode::Mask, "xabcdex").unwrap(),
+            "x*****x"
+        );
+    }
+
+    #[test]
+    fn mask_lines_up_with_multibyte_characters() {
+        assert_eq!(
+            apply(&["über"], FilterMode::Mask, "ÜBER café").unwrap(),
+            "**** café"
+        );
+    }
+
+    #[test]
+    fn empty_word_list_lets_everything_through() {
+        assert_eq!(
+            apply(&[], FilterMode::Reject, "anything").unwrap(),
+            "anything"
+        );
+        assert_eq!(
+            apply(&[""], FilterMode::Mask, "anything").unwrap(),
+            "anything"
+        );
+    }
+
+    #[test]
+    fn html_is_escaped() {
+        assert_eq!(
+            escape_html(r#"<b class="x">Tom & Jerry's</b>"#),
+            "&lt;b class=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/b&gt;"
+        );
+    }
+}
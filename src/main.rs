@@ -1,264 +1,7912 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+mod attachments;
+mod audit;
+mod backplane;
+mod connlimit;
+mod deadletters;
+mod filter;
+mod metrics;
+mod outbox;
+mod password;
+mod ratelimit;
+mod reports;
+mod schedule;
+mod store;
+mod wire;
 
+#[cfg(test)]
+mod tests;
+
+use clap::Parser;
+use dashmap::DashMap;
 use futures::{SinkExt, StreamExt};
 use tokio::sync::Mutex;
+use unicode_normalization::UnicodeNormalization;
 use warp::{
     ws::{WebSocket, Ws},
     Filter, Reply,
 };
 
+/// whether a client can be reached right now
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Presence {
+    Online,
+    Away,
+    #[default]
+    Offline,
+}
+
 #[derive(Debug, Default, serde::Serialize)]
 struct Client {
     name: String,
 
+    /// online while connected, unless the client says it's away or has been
+    /// idle for a while
+    status: Presence,
+
+    /// free text set by the client, like "in a meeting"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status_message: Option<String>,
+
+    /// unix timestamp in milliseconds of the client's last connect,
+    /// disconnect or message
+    last_seen: i64,
+
     #[serde(skip)]
     token: String,
 
+    /// away because it went quiet rather than because it said so, the next
+    /// message brings it back
+    #[serde(skip)]
+    idle: bool,
+
     #[serde(skip)]
     disconnect_timer: Option<tokio::task::JoinHandle<()>>,
 
+    /// live sockets by connection id, one for every device the client is
+    /// connected from
+    #[serde(skip)]
+    connections: HashMap<String, outbox::Sender<ServerEvent>>,
+
+    /// most recent messages addressed to this client, oldest first
+    #[serde(skip)]
+    history: VecDeque<Message>,
+
+    /// messages that arrived while the client had no live socket
+    #[serde(skip)]
+    pending: VecDeque<ServerEvent>,
+
+    /// when the session lapses unless the client does something authenticated
+    #[serde(skip)]
+    expires_at: Option<tokio::time::Instant>,
+
+    /// messages the client may still send right away, refilled over time
+    #[serde(skip)]
+    send_allowance: f64,
+
+    /// when `send_allowance` was last refilled, `None` until the first send
+    #[serde(skip)]
+    send_allowance_at: Option<tokio::time::Instant>,
+
+    /// responses to recent sends that carried an idempotency key, oldest first
+    #[serde(skip)]
+    sent_keys: VecDeque<SentKey>,
+
+    /// sequence number of the last message addressed to the client
+    #[serde(skip)]
+    last_seq: u64,
+
+    /// messages queued since the client was last connected
+    #[serde(skip)]
+    unread: usize,
+
+    /// tokens of the clients whose direct messages this one doesn't want,
+    /// tokens rather than names so a rename doesn't get around it
+    #[serde(skip)]
+    blocked: HashSet<String>,
+
+    /// hash of the password the account was registered with, `None` for
+    /// clients that only have their token. an account with a password stays
+    /// until it's deleted, logging in to it starts a session
+    #[serde(skip)]
+    password_hash: Option<String>,
+
+    /// sessions of an account with a password by their id, which is kept
+    /// across refreshes. the client's own token is never handed out for such
+    /// an account, sessions stand in for it
     #[serde(skip)]
-    tx: Option<tokio::sync::mpsc::UnboundedSender<Message>>,
+    sessions: HashMap<String, Session>,
 }
 
-type Clients = Arc<Mutex<HashMap<String, Client>>>;
+/// one login to an account with a password. its short-lived access token
+/// authenticates requests, its refresh token trades the pair for a new one
+/// until the refresh token runs out, and then the session is over
+#[derive(Debug)]
+struct Session {
+    access_token: String,
+    access_expires_at: tokio::time::Instant,
+    refresh_token: String,
+    refresh_expires_at: tokio::time::Instant,
 
-#[derive(serde::Serialize)]
-struct Message {
-    from: String,
-    body: String,
+    /// what the client said it logs in from, like "laptop"
+    device: Option<String>,
+
+    /// unix timestamp in milliseconds of the login
+    created_at: i64,
+
+    /// unix timestamp in milliseconds of the last socket or event stream
+    /// opened with the session
+    connected_at: Option<i64>,
+
+    /// unix timestamp in milliseconds of the last request made with the
+    /// session, or of its last connect or disconnect
+    last_active: i64,
+
+    /// ids of the client's connections opened with the session, closed
+    /// along with it
+    connections: HashSet<String>,
 }
 
-#[derive(serde::Deserialize)]
-struct RegistrationRequest {
-    name: String,
+impl Session {
+    fn new(config: &Config, device: Option<String>) -> Self {
+        let now = unix_millis();
+
+        let mut session = Session {
+            access_token: String::new(),
+            access_expires_at: tokio::time::Instant::now(),
+            refresh_token: String::new(),
+            refresh_expires_at: tokio::time::Instant::now(),
+            device,
+            created_at: now,
+            connected_at: None,
+            last_active: now,
+            connections: HashSet::new(),
+        };
+
+        session.rotate(config);
+        session
+    }
+
+    /// replaces both tokens with fresh ones, each valid for its full ttl again
+    fn rotate(&mut self, config: &Config) {
+        let now = tokio::time::Instant::now();
+
+        self.access_token = uuid::Uuid::new_v4().as_simple().to_string();
+        self.access_expires_at = now + config.access_token_ttl;
+        self.refresh_token = uuid::Uuid::new_v4().as_simple().to_string();
+        self.refresh_expires_at = now + config.refresh_token_ttl;
+    }
+
+    fn tokens(&self) -> SessionTokens {
+        SessionTokens {
+            token: self.access_token.clone(),
+            refresh_token: self.refresh_token.clone(),
+        }
+    }
 }
 
-#[derive(serde::Serialize, Default)]
-struct RegistrationResponse {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub token: Option<String>,
+/// longest device label kept, in characters, anything past it is cut off
+const MAX_DEVICE_LABEL_LENGTH: usize = 64;
 
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub error: Option<&'static str>,
+/// the device label a client sent, trimmed and cut to length. control
+/// characters are dropped, nothing much left means no label
+fn device_label(label: Option<&str>) -> Option<String> {
+    let label = label?
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect::<String>()
+        .trim()
+        .chars()
+        .take(MAX_DEVICE_LABEL_LENGTH)
+        .collect::<String>();
+
+    Some(label).filter(|label| !label.is_empty())
 }
 
-#[derive(Debug, serde::Deserialize)]
-struct MessageRequest {
+/// what the client holds of a session
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+struct SessionTokens {
+    /// the access token
     pub token: String,
-    pub body: String,
-    pub to: String,
+
+    pub refresh_token: String,
 }
 
-async fn handle_registration(
-    request: RegistrationRequest,
-    clients: Clients,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    if clients
-        .lock()
-        .await
-        .contains_key(&request.name.to_lowercase())
-    {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&RegistrationResponse {
-                error: Some("The name is already taken"),
-                ..Default::default()
-            }),
-            warp::http::StatusCode::NOT_ACCEPTABLE,
-        ));
-    }
+/// the outcome of a send, remembered so a retry with the same key gets it
+/// again instead of delivering the message twice
+#[derive(Debug)]
+struct SentKey {
+    key: String,
+    sent_at: tokio::time::Instant,
 
-    let token = uuid::Uuid::new_v4().as_simple().to_string();
+    /// `None` while the first send with the key is still going
+    response: Option<MessageResponse>,
+}
 
-    let client = Client {
-        token: token.clone(),
-        name: request.name.clone(),
-        ..Default::default()
-    };
+/// what a send with an idempotency key found when it claimed the key
+#[derive(Debug)]
+enum Claim {
+    /// the key is new, the send goes ahead
+    Claimed,
 
-    clients
-        .lock()
-        .await
-        .insert(request.name.to_lowercase(), client);
+    /// an earlier send with the key went through, this is what it got
+    Sent(MessageResponse),
 
-    Ok(warp::reply::with_status(
-        warp::reply::json(&RegistrationResponse {
-            token: Some(token),
-            ..Default::default()
-        }),
-        warp::http::StatusCode::OK,
-    ))
+    /// an earlier send with the key hasn't finished yet
+    InFlight,
 }
 
-async fn client_connected(token: String, ws: WebSocket, clients: Clients) {
-    let (mut ws_tx, mut ws_rx) = ws.split();
-    let (client_tx, mut client_rx) = tokio::sync::mpsc::unbounded_channel();
+/// what came of handing an event to a client
+#[derive(Debug, PartialEq, Eq)]
+enum Delivery {
+    Sent,
 
-    if let Some((_, client)) = clients
-        .lock()
-        .await
-        .iter_mut()
-        .find(|(_, client)| client.token == token)
-    {
-        if let Some(disconnect_timer) = client.disconnect_timer.take() {
-            disconnect_timer.abort();
+    /// the client is offline, it gets the event once it reconnects
+    Queued,
+
+    /// the outbox was full and the overflow policy discarded the event
+    Dropped,
+}
+
+/// maximum number of messages kept in a client's history
+const HISTORY_LIMIT: usize = 100;
+
+/// how much of its history a client keeps, the oldest messages go first
+#[derive(Debug, Clone, Copy)]
+struct HistoryCaps {
+    max_messages: usize,
+
+    /// total size of the message bodies, 0 for no limit. the newest message
+    /// is always kept, even when it's larger on its own
+    max_bytes: usize,
+}
+
+/// maximum number of messages queued for an offline client, the oldest are
+/// dropped first
+const PENDING_LIMIT: usize = 100;
+
+/// maximum number of idempotency keys remembered per client
+const IDEMPOTENCY_LIMIT: usize = 100;
+
+/// how long an idempotency key is remembered, retries come much sooner
+const IDEMPOTENCY_WINDOW: tokio::time::Duration = tokio::time::Duration::from_secs(10 * 60);
+
+impl Client {
+    /// every stored copy of message `id` addressed to this client, in the
+    /// history and in the queue of messages it hasn't received yet
+    fn message_copies_mut<'a>(&'a mut self, id: &'a str) -> impl Iterator<Item = &'a mut Message> {
+        let pending = self.pending.iter_mut().filter_map(|event| match event {
+            ServerEvent::Message(message) => Some(message),
+            _ => None,
+        });
+
+        self.history
+            .iter_mut()
+            .chain(pending)
+            .filter(move |message| message.id == id)
+    }
+
+    /// shows the reactions the client holding `token` left on this client's
+    /// messages under its new name
+    fn rename_reactor(&mut self, token: &str, name: &str) {
+        let pending = self.pending.iter_mut().filter_map(|event| match event {
+            ServerEvent::Message(message) => Some(message),
+            _ => None,
+        });
+
+        for message in self.history.iter_mut().chain(pending) {
+            for reactors in message.reactions.values_mut() {
+                if let Some(reactor) = reactors.get_mut(token) {
+                    *reactor = name.to_string();
+                }
+            }
         }
+    }
 
-        client.tx = Some(client_tx.clone());
+    /// whether the client still has a copy of message `id`
+    fn holds_message(&self, id: &str) -> bool {
+        self.history.iter().any(|message| message.id == id)
+            || self
+                .pending
+                .iter()
+                .any(|event| matches!(event, ServerEvent::Message(message) if message.id == id))
     }
 
-    tokio::spawn(async move {
-        while let Some(message) = client_rx.recv().await {
-            let _ = ws_tx
-                .send(warp::ws::Message::text(
-                    serde_json::to_string(&message).unwrap(),
-                ))
-                .await;
+    /// drops every stored copy of message `id`, returns whether there was one
+    fn remove_message(&mut self, id: &str) -> bool {
+        let pending = self.pending.len();
+
+        self.pending
+            .retain(|event| !matches!(event, ServerEvent::Message(message) if message.id == id));
+
+        // it was counted as unread while it was queued
+        let unqueued = pending - self.pending.len();
+        self.unread = self.unread.saturating_sub(unqueued);
+
+        let history = self.history.len();
+        self.history.retain(|message| message.id != id);
+
+        unqueued > 0 || history != self.history.len()
+    }
+
+    /// what a client that saw every message up to `last_seq` missed, in
+    /// order: queued messages too old to still be in the history, then the
+    /// newer history, then the other queued events. empties the queue, the
+    /// history replay stands in for the queued copies of messages
+    fn missed_since(&mut self, last_seq: u64) -> Vec<ServerEvent> {
+        let in_history: HashSet<&str> = self
+            .history
+            .iter()
+            .map(|message| message.id.as_str())
+            .collect();
+
+        let mut queued = HashSet::new();
+        let mut missed = Vec::new();
+        let mut others = Vec::new();
+
+        for event in self.pending.drain(..) {
+            match event {
+                ServerEvent::Message(message) if in_history.contains(message.id.as_str()) => {
+                    queued.insert(message.id);
+                }
+                ServerEvent::Message(message) if message.seq <= last_seq => {}
+                ServerEvent::Message(message) => missed.push(ServerEvent::Message(message)),
+                event => others.push(event),
+            }
         }
-    });
 
-    // keep loop busy while socket is connected
-    while ws_rx.next().await.is_some() {}
+        // only messages that never made it out are acknowledged to the sender
+        for message in self.history.iter().filter(|message| message.seq > last_seq) {
+            missed.push(ServerEvent::Message(Message {
+                acknowledge: message.acknowledge && queued.contains(&message.id),
+                ..message.clone()
+            }));
+        }
 
-    // disconnected, clean up
+        missed.extend(others);
+        missed
+    }
 
-    if let Some((_, client)) = clients
-        .lock()
-        .await
-        .iter_mut()
-        .find(|(_, client)| client.token == token)
-    {
-        let clients = clients.clone();
+    /// whether `message` repeats the last message queued for the client,
+    /// from the same sender with the same body and no more than `window`
+    /// apart. a zero window never matches
+    fn is_queued_duplicate(&self, message: &Message, window: tokio::time::Duration) -> bool {
+        if window.is_zero() {
+            return false;
+        }
 
-        client.disconnect_timer = Some(tokio::spawn(async move {
-            tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
+        let last = self.pending.iter().rev().find_map(|event| match event {
+            ServerEvent::Message(message) => Some(message),
+            _ => None,
+        });
 
-            clients
-                .lock()
-                .await
-                .retain(|_, client| client.token != token);
-        }));
+        last.is_some_and(|last| {
+            last.sender == message.sender
+                && last.body == message.body
+                && last.attachment.is_none()
+                && message.attachment.is_none()
+                && message.timestamp - last.timestamp <= window.as_millis() as i64
+        })
     }
-}
 
-async fn ws_handler(
-    token: String,
-    ws: Ws,
-    clients: Clients,
-) -> Result<impl Reply, warp::Rejection> {
-    let client = clients
-        .lock()
-        .await
-        .iter()
-        .any(|(_, client)| client.token == token);
+    /// a copy of `message` stamped with the client's next sequence number
+    fn sequenced(&mut self, message: &Message) -> Message {
+        self.last_seq += 1;
 
-    match client {
-        true => Ok(ws.on_upgrade(|socket| client_connected(token, socket, clients))),
-        false => Err(warp::reject::reject()),
+        Message {
+            seq: self.last_seq,
+            ..message.clone()
+        }
     }
-}
 
-async fn handle_send_message(
-    request: MessageRequest,
-    clients: Clients,
-) -> Result<impl Reply, warp::Rejection> {
-    let clients = clients.lock().await;
+    /// moves the history copy of message `id` on to `status`, never back
+    fn advance_status(&mut self, id: &str, status: DeliveryStatus) {
+        if let Some(message) = self.history.iter_mut().find(|message| message.id == id) {
+            message.status = message.status.max(status);
+        }
+    }
 
-    let client = clients
-        .get(&request.to.to_lowercase())
-        .ok_or_else(warp::reject::not_found)?;
+    fn push_history(&mut self, message: Message, caps: &HistoryCaps) {
+        self.history.push_back(message);
+        self.prune_history(caps);
+    }
 
-    let (_, sender_client) = clients
-        .iter()
-        .find(|(_, client)| client.token == request.token)
-        .ok_or_else(warp::reject)?;
-
-    let _ = client
-        .tx
-        .as_ref()
-        .ok_or_else(warp::reject::not_found)?
-        .send(Message {
-            from: sender_client.name.clone(),
-            body: request.body,
+    /// drops the oldest messages until the history fits within `caps`
+    fn prune_history(&mut self, caps: &HistoryCaps) {
+        let overflow = self.history.len().saturating_sub(caps.max_messages);
+        self.history.drain(..overflow);
+
+        if caps.max_bytes == 0 {
+            return;
+        }
+
+        let mut bytes = self.history_bytes();
+
+        while bytes > caps.max_bytes && self.history.len() > 1 {
+            if let Some(message) = self.history.pop_front() {
+                bytes -= message.body.len();
+            }
+        }
+    }
+
+    /// total size of the message bodies in the history
+    fn history_bytes(&self) -> usize {
+        self.history.iter().map(|message| message.body.len()).sum()
+    }
+
+    fn push_pending(&mut self, event: ServerEvent) {
+        if self.pending.len() == PENDING_LIMIT {
+            self.pending.pop_front();
+        }
+
+        self.pending.push_back(event);
+    }
+
+    /// claims idempotency key `key` for a send, unless an earlier send with
+    /// it is still remembered. checking and claiming happen under the same
+    /// guard, so of two sends racing with one key only one goes ahead
+    fn claim_key(&mut self, key: &str) -> Claim {
+        let now = tokio::time::Instant::now();
+
+        while self
+            .sent_keys
+            .front()
+            .is_some_and(|sent| now.duration_since(sent.sent_at) >= IDEMPOTENCY_WINDOW)
+        {
+            self.sent_keys.pop_front();
+        }
+
+        if let Some(sent) = self.sent_keys.iter().find(|sent| sent.key == key) {
+            return match &sent.response {
+                Some(response) => Claim::Sent(response.clone()),
+                None => Claim::InFlight,
+            };
+        }
+
+        if self.sent_keys.len() == IDEMPOTENCY_LIMIT {
+            self.sent_keys.pop_front();
+        }
+
+        self.sent_keys.push_back(SentKey {
+            key: key.to_string(),
+            sent_at: now,
+            response: None,
         });
 
-    Ok(warp::reply())
+        Claim::Claimed
+    }
+
+    /// settles the claim on `key`. only successful sends are remembered, a
+    /// failed one gives the key up as it may well succeed when retried
+    fn settle_key(&mut self, key: &str, response: Option<MessageResponse>) {
+        let Some(at) = self.sent_keys.iter().position(|sent| sent.key == key) else {
+            return;
+        };
+
+        match response {
+            Some(response) => self.sent_keys[at].response = Some(response),
+            None => {
+                self.sent_keys.remove(at);
+            }
+        }
+    }
+
+    /// notes that the client did something, true if that brings it back
+    /// from being idle
+    fn touch(&mut self) -> bool {
+        self.last_seen = unix_millis();
+
+        std::mem::take(&mut self.idle)
+    }
+
+    fn is_connected(&self) -> bool {
+        !self.connections.is_empty()
+    }
+
+    /// hands an event to every live socket of the client. it only counts as
+    /// dropped if no socket took it and one of them was full. a message asks
+    /// for an ack on the first socket only, so its sender hears back once.
+    fn send(&self, event: &ServerEvent) -> Result<(), outbox::SendError<()>> {
+        let mut event = event.clone();
+        let mut sent = false;
+        let mut full = false;
+
+        for tx in self.connections.values() {
+            match tx.send(event.clone()) {
+                Ok(()) => {
+                    sent = true;
+
+                    if let ServerEvent::Message(message) = &mut event {
+                        message.acknowledge = false;
+                    }
+                }
+                Err(outbox::SendError::Full(_)) => full = true,
+                Err(outbox::SendError::Closed(_)) => {}
+            }
+        }
+
+        match (sent, full) {
+            (true, _) => Ok(()),
+            (false, true) => Err(outbox::SendError::Full(())),
+            (false, false) => Err(outbox::SendError::Closed(())),
+        }
+    }
+
+    /// hands an event to the client's sockets, queueing it while the client
+    /// is offline
+    fn send_or_queue(&mut self, event: ServerEvent) -> Delivery {
+        match self.send(&event) {
+            Ok(()) => Delivery::Sent,
+            Err(outbox::SendError::Full(())) => Delivery::Dropped,
+            Err(outbox::SendError::Closed(())) => {
+                self.push_pending(event);
+                Delivery::Queued
+            }
+        }
+    }
+
+    /// a client with a live socket is active by definition and never expires,
+    /// neither does an account with a password, only its sessions do
+    fn is_expired(&self, now: tokio::time::Instant) -> bool {
+        !self.is_connected()
+            && self.password_hash.is_none()
+            && self
+                .expires_at
+                .map(|expires_at| expires_at <= now)
+                .unwrap_or(false)
+    }
+
+    /// the id of the session `token` is the access token of, with the
+    /// session
+    fn session_for(&self, token: &str) -> Option<(&String, &Session)> {
+        self.sessions
+            .iter()
+            .find(|(_, session)| session.access_token == token)
+    }
+
+    /// the client's own token followed by both tokens of each session,
+    /// everything that leads to it
+    fn credentials(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.token).chain(
+            self.sessions
+                .values()
+                .flat_map(|session| [&session.access_token, &session.refresh_token]),
+        )
+    }
+
+    /// spends one message from the client's allowance, a token bucket holding
+    /// up to `send_burst` messages and refilling at `send_rate` per second
+    fn throttle_send(&mut self, config: &Config) -> Result<(), ChatError> {
+        if config.send_rate <= 0.0 {
+            return Ok(());
+        }
+
+        let now = tokio::time::Instant::now();
+        let burst = f64::from(config.send_burst);
+
+        self.send_allowance = match self.send_allowance_at {
+            Some(at) => (self.send_allowance
+                + now.duration_since(at).as_secs_f64() * config.send_rate)
+                .min(burst),
+            None => burst,
+        };
+        self.send_allowance_at = Some(now);
+
+        if self.send_allowance < 1.0 {
+            let retry_after = tokio::time::Duration::from_secs_f64(
+                (1.0 - self.send_allowance) / config.send_rate,
+            );
+
+            return Err(ChatError::RateLimited { retry_after });
+        }
+
+        self.send_allowance -= 1.0;
+        Ok(())
+    }
 }
 
-async fn handle_status(
-    token: String,
-    clients: Clients,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    match clients
-        .lock()
-        .await
-        .iter()
-        .find(|(_, client)| client.token == token)
+/// the client owning `token`, its own or the access token of one of its
+/// sessions, if it's still around. refresh tokens don't count
+fn client_for_token<'a>(clients: &'a ClientMap, token: &str) -> Option<ClientRef<'a>> {
+    // the index may briefly point at a name that was just taken over
+    clients
+        .get_mut(&clients.key_for_token(token)?)
+        .filter(|client| client.token == token || client.session_for(token).is_some())
+}
+
+/// finds the client owning `token` and slides its session window forward.
+/// sessions that already lapsed are rejected even if not swept yet. the
+/// returned guard locks part of the map, drop it before touching other
+/// clients.
+///
+/// `token` may be a session's access token, which doesn't slide, it's good
+/// until it runs out. the client's own token is what identifies it
+/// everywhere else, so use the returned client's from here on.
+fn authenticate<'a>(
+    clients: &'a ClientMap,
+    token: &str,
+    config: &Config,
+) -> Result<ClientRef<'a>, ChatError> {
+    let now = tokio::time::Instant::now();
+
+    let mut client = client_for_token(clients, token)
+        .filter(|client| {
+            !client.is_expired(now)
+                && client
+                    .session_for(token)
+                    .is_none_or(|(_, session)| session.access_expires_at > now)
+        })
+        .ok_or(ChatError::InvalidToken)?;
+
+    client.expires_at = Some(now + config.token_ttl);
+
+    if let Some(session) = client
+        .sessions
+        .values_mut()
+        .find(|session| session.access_token == token)
     {
-        Some((_, client)) => Ok(warp::reply::with_status(
-            warp::reply::json(&client),
-            warp::http::StatusCode::OK,
-        )),
-        None => Err(warp::reject()),
+        session.last_active = unix_millis();
     }
+
+    Ok(client)
 }
 
-async fn handle_list_clients(clients: Clients) -> Result<impl Reply, warp::Rejection> {
-    let clients = clients.lock().await;
-    let clients: Vec<&Client> = clients.iter().map(|(_, client)| client).collect();
+/// sets the presence of the client owning `token`, letting everyone else know
+/// if it changed
+fn set_presence(clients: &ClientMap, token: &str, status: Presence) {
+    let Some(mut client) = client_for_token(clients, token) else {
+        return;
+    };
 
-    Ok(warp::reply::json(&clients))
-}
+    if client.status == status {
+        return;
+    }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() {
-    let clients: Clients = Arc::new(Mutex::new(HashMap::<String, Client>::new()));
-    let clients = warp::any().map(move || clients.clone());
+    client.status = status;
 
-    let serve_static = warp::get().and(warp::fs::dir("static/"));
+    let name = client.name.clone();
+    let status_message = client.status_message.clone();
+    drop(client);
 
-    let registration_handler = warp::path("register")
-        .and(warp::post())
-        .and(warp::body::json())
-        .and(clients.clone())
-        .and_then(handle_registration);
+    broadcast_presence(clients, &name, status, status_message.as_deref());
+}
 
-    let send_message_handler = warp::path("send_message")
-        .and(warp::post())
-        .and(warp::body::json())
-        .and(clients.clone())
-        .and_then(handle_send_message);
+/// tells every other connected client that `name` changed its presence,
+/// offline clients just see the current status once they ask
+fn broadcast_presence(
+    clients: &ClientMap,
+    name: &str,
+    status: Presence,
+    status_message: Option<&str>,
+) {
+    tracing::debug!(%name, ?status, "broadcasting presence");
 
-    let status_handler = warp::path("status")
-        .and(warp::get())
-        .and(warp::path::param())
-        .and(clients.clone())
-        .and_then(handle_status);
+    for client in clients.iter().filter(|client| client.name != name) {
+        let _ = client.send(&ServerEvent::Presence {
+            name: name.to_string(),
+            status,
+            status_message: status_message.map(str::to_string),
+        });
+    }
+}
 
-    let messages_handler = warp::path("messages")
-        .and(warp::path::param())
-        .and(warp::ws())
-        .and(clients.clone())
-        .and_then(ws_handler);
+/// clients by name key, with an index from token to name key so requests
+/// don't have to scan every client to authenticate. operations on different
+/// clients don't block each other, but a guard returned by the map locks its
+/// whole shard, so never hold one while looking up another client.
+///
+/// clients are only added and removed through this type so the index can't
+/// drift from the map.
+///
+/// a second index leads from a message id to the tokens of the clients
+/// holding a copy, so changing a message only visits its recipients. it may
+/// still list clients whose copy has since gone, `prune_message_index`
+/// clears those.
+#[derive(Default)]
+struct ClientMap {
+    by_name: DashMap<String, Client>,
+    by_token: DashMap<String, String>,
+    by_message: DashMap<String, HashSet<String>>,
+}
 
-    let list_clients_handler = warp::path("clients")
-        .and(warp::get())
-        .and(clients.clone())
-        .and_then(handle_list_clients);
+impl ClientMap {
+    /// name key of the client owning `token`
+    fn key_for_token(&self, token: &str) -> Option<String> {
+        self.by_token.get(token).map(|key| key.clone())
+    }
 
-    let routes = registration_handler
-        .or(messages_handler)
+    fn get(&self, key: &str) -> Option<dashmap::mapref::one::Ref<'_, String, Client>> {
+        self.by_name.get(key)
+    }
+
+    fn get_mut(&self, key: &str) -> Option<ClientRef<'_>> {
+        self.by_name.get_mut(key)
+    }
+
+    fn iter(&self) -> dashmap::iter::Iter<'_, String, Client> {
+        self.by_name.iter()
+    }
+
+    fn iter_mut(&self) -> dashmap::iter::IterMut<'_, String, Client> {
+        self.by_name.iter_mut()
+    }
+
+    fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    /// adds a client under `key`, replacing whatever was there
+    fn insert(&self, key: String, client: Client) {
+        let credentials = client.credentials().cloned().collect::<Vec<_>>();
+
+        for message in &client.history {
+            self.index_message(&message.id, &client.token);
+        }
+
+        if let Some(replaced) = self.by_name.insert(key.clone(), client) {
+            for token in replaced.credentials() {
+                self.by_token.remove(token);
+            }
+        }
+
+        for token in credentials {
+            self.by_token.insert(token, key.clone());
+        }
+    }
+
+    /// adds a client under `key` unless the key is taken, in which case the
+    /// client is handed back. checking and inserting happen under one lock so
+    /// two callers can't both claim a key.
+    fn try_insert(&self, key: String, client: Client) -> Result<ClientRef<'_>, Box<Client>> {
+        match self.by_name.entry(key) {
+            dashmap::mapref::entry::Entry::Occupied(_) => Err(Box::new(client)),
+            dashmap::mapref::entry::Entry::Vacant(entry) => {
+                for token in client.credentials() {
+                    self.by_token.insert(token.clone(), entry.key().clone());
+                }
+
+                Ok(entry.insert(client))
+            }
+        }
+    }
+
+    fn remove(&self, key: &str) -> Option<(String, Client)> {
+        let (key, client) = self.by_name.remove(key)?;
+
+        for token in client.credentials() {
+            self.by_token.remove_if(token, |_, indexed| *indexed == key);
+        }
+
+        Some((key, client))
+    }
+
+    /// notes that the client owning `token` holds a copy of message `id`
+    fn index_message(&self, id: &str, token: &str) {
+        self.by_message
+            .entry(id.to_string())
+            .or_default()
+            .insert(token.to_string());
+    }
+
+    /// tokens of the clients holding a copy of message `id`, as far as the
+    /// index knows. look them up with `client_for_token`
+    fn message_holders(&self, id: &str) -> Vec<String> {
+        self.by_message
+            .get(id)
+            .map(|holders| holders.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// drops the index entries of clients that no longer hold a copy of the
+    /// message, returns how many went. the index is never locked while a
+    /// client is looked up, delivering locks them the other way round
+    fn prune_message_index(&self) -> usize {
+        let ids = self
+            .by_message
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect::<Vec<_>>();
+
+        let mut pruned = 0;
+
+        for id in ids {
+            let gone = self
+                .message_holders(&id)
+                .into_iter()
+                .filter(|token| {
+                    client_for_token(self, token).is_none_or(|client| !client.holds_message(&id))
+                })
+                .collect::<HashSet<_>>();
+
+            if gone.is_empty() {
+                continue;
+            }
+
+            pruned += gone.len();
+
+            if let Some(mut holders) = self.by_message.get_mut(&id) {
+                holders.retain(|token| !gone.contains(token));
+            }
+
+            self.by_message
+                .remove_if(&id, |_, holders| holders.is_empty());
+        }
+
+        pruned
+    }
+
+    /// starts a session for the client under `key` and returns its tokens,
+    /// `None` if the client is gone
+    fn start_session(
+        &self,
+        key: &str,
+        device: Option<String>,
+        config: &Config,
+    ) -> Option<SessionTokens> {
+        let session = Session::new(config, device);
+        let tokens = session.tokens();
+        let mut client = self.by_name.get_mut(key)?;
+
+        // indexed while the client is locked, so it can't be removed without
+        // its session being unindexed too
+        self.by_token.insert(tokens.token.clone(), key.to_string());
+        self.by_token
+            .insert(tokens.refresh_token.clone(), key.to_string());
+
+        client
+            .sessions
+            .insert(uuid::Uuid::new_v4().as_simple().to_string(), session);
+
+        Some(tokens)
+    }
+
+    /// trades the refresh token of a session for a fresh pair of tokens, the
+    /// old ones stop working. `None` if it isn't a refresh token or it ran out
+    fn refresh_session(&self, refresh_token: &str, config: &Config) -> Option<SessionTokens> {
+        let key = self.key_for_token(refresh_token)?;
+        let mut client = self.by_name.get_mut(&key)?;
+        let now = tokio::time::Instant::now();
+
+        let session = client
+            .sessions
+            .values_mut()
+            .find(|session| session.refresh_token == refresh_token)
+            .filter(|session| session.refresh_expires_at > now)?;
+
+        self.by_token.remove(&session.access_token);
+        self.by_token.remove(&session.refresh_token);
+
+        session.rotate(config);
+
+        self.by_token
+            .insert(session.access_token.clone(), key.clone());
+        self.by_token.insert(session.refresh_token.clone(), key);
+
+        Some(session.tokens())
+    }
+
+    /// ends the session `token` is the access token of, false if it isn't
+    /// one
+    fn end_session(&self, token: &str) -> bool {
+        let Some(mut client) = self
+            .key_for_token(token)
+            .and_then(|key| self.by_name.get_mut(&key))
+        else {
+            return false;
+        };
+
+        let Some(id) = client.session_for(token).map(|(id, _)| id.clone()) else {
+            return false;
+        };
+
+        self.drop_session(&mut client, &id)
+    }
+
+    /// ends session `id` of the client under `key`, false if it has none by
+    /// that id
+    fn revoke_session(&self, key: &str, id: &str) -> bool {
+        match self.by_name.get_mut(key) {
+            Some(mut client) => self.drop_session(&mut client, id),
+            None => false,
+        }
+    }
+
+    /// ends every session whose refresh token ran out, returns how many did
+    fn end_lapsed_sessions(&self, now: tokio::time::Instant) -> usize {
+        let mut ended = 0;
+
+        for mut client in self.by_name.iter_mut() {
+            let lapsed = client
+                .sessions
+                .iter()
+                .filter(|(_, session)| session.refresh_expires_at <= now)
+                .map(|(id, _)| id.clone())
+                .collect::<Vec<_>>();
+
+            for id in lapsed {
+                if self.drop_session(&mut client, &id) {
+                    ended += 1;
+                }
+            }
+        }
+
+        ended
+    }
+
+    /// forgets session `id` of `client` along with its tokens, and closes
+    /// the connections opened with it by dropping their senders
+    fn drop_session(&self, client: &mut Client, id: &str) -> bool {
+        let Some(session) = client.sessions.remove(id) else {
+            return false;
+        };
+
+        self.by_token.remove(&session.access_token);
+        self.by_token.remove(&session.refresh_token);
+
+        for connection_id in &session.connections {
+            client.connections.remove(connection_id);
+        }
+
+        true
+    }
+}
+
+type Clients = Arc<ClientMap>;
+
+type ClientRef<'a> = dashmap::mapref::one::RefMut<'a, String, Client>;
+
+#[derive(Debug, Default)]
+struct Room {
+    /// tokens of the room's members
+    members: HashSet<String>,
+
+    /// only invited clients may join a private room, and only members see it
+    /// listed
+    private: bool,
+
+    /// only moderators and the owner may post, everyone else just reads
+    read_only: bool,
+
+    /// tokens of the clients invited but not joined yet
+    invited: HashSet<String>,
+
+    /// members above a plain member, by token. every room with members has
+    /// exactly one owner
+    roles: HashMap<String, RoomRole>,
+
+    /// most recent messages posted to the room, oldest first, pruned by
+    /// `retention`
+    history: VecDeque<Message>,
+
+    retention: Retention,
+}
+
+/// how much of its history a room keeps
+#[derive(Debug, Clone, Copy)]
+struct Retention {
+    max_messages: usize,
+
+    /// older messages are dropped, `None` keeps them as long as there's room
+    max_age: Option<tokio::time::Duration>,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Retention {
+            max_messages: HISTORY_LIMIT,
+            max_age: None,
+        }
+    }
+}
+
+/// what a member may do in a room, each role may do everything the ones
+/// before it may
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+enum RoomRole {
+    Member,
+
+    /// may kick members and delete their messages
+    Moderator,
+
+    /// may also hand out roles
+    Owner,
+}
+
+impl Room {
+    fn role(&self, token: &str) -> RoomRole {
+        self.roles.get(token).copied().unwrap_or(RoomRole::Member)
+    }
+
+    /// drops a member along with its role. an owner leaving hands the room
+    /// to a moderator, or to any member if there's none
+    fn remove_member(&mut self, token: &str) -> bool {
+        self.invited.remove(token);
+
+        if !self.members.remove(token) {
+            return false;
+        }
+
+        if self.roles.remove(token) == Some(RoomRole::Owner) {
+            let heir = self
+                .roles
+                .iter()
+                .find(|(_, role)| **role == RoomRole::Moderator)
+                .map(|(token, _)| token)
+                .or_else(|| self.members.iter().next())
+                .cloned();
+
+            if let Some(heir) = heir {
+                self.roles.insert(heir, RoomRole::Owner);
+            }
+        }
+
+        true
+    }
+
+    fn push_history(&mut self, message: Message) {
+        self.history.push_back(message);
+        self.prune_history();
+    }
+
+    /// drops whatever the retention policy no longer covers. done on every
+    /// write and read rather than on a timer, a quiet room is only pruned
+    /// once someone looks at it
+    fn prune_history(&mut self) {
+        let overflow = self
+            .history
+            .len()
+            .saturating_sub(self.retention.max_messages);
+        self.history.drain(..overflow);
+
+        if let Some(max_age) = self.retention.max_age {
+            let cutoff = unix_millis() - max_age.as_millis() as i64;
+
+            while self
+                .history
+                .front()
+                .is_some_and(|message| message.timestamp < cutoff)
+            {
+                self.history.pop_front();
+            }
+        }
+    }
+}
+
+/// room name -> room. a room goes away with its last member
+type Rooms = Arc<Mutex<HashMap<String, Room>>>;
+
+/// names and addresses turned away at registration
+#[derive(Debug, Default)]
+struct Bans {
+    /// stored as name keys
+    names: HashSet<String>,
+    addrs: HashSet<std::net::IpAddr>,
+}
+
+type BanList = Arc<Mutex<Bans>>;
+
+/// conversation -> ids of the messages pinned in it, oldest pin first. a
+/// conversation is the tokens of its two participants, see `conversation`
+type Pins = Arc<Mutex<HashMap<(String, String), Vec<String>>>>;
+
+/// registrations or logins seen per remote address
+type AddressLimiter = Arc<Mutex<ratelimit::RateLimiter<std::net::IpAddr>>>;
+
+#[derive(Debug)]
+struct Config {
+    /// address the http server listens on
+    bind_addr: std::net::SocketAddr,
+
+    /// serve https/wss instead of plaintext. tokens travel in an
+    /// `Authorization: Bearer` header or the `bearer.` subprotocol of a
+    /// socket, so this is what keeps them from being readable on the wire.
+    tls: Option<TlsConfig>,
+
+    /// how long a client whose connection dropped keeps its name and stays
+    /// online, so it can reconnect, before it's removed. 0 removes it at
+    /// once. an account only goes offline, it keeps its name for the next
+    /// login
+    disconnect_grace: tokio::time::Duration,
+
+    /// how often connected sockets are pinged
+    ping_interval: tokio::time::Duration,
+
+    /// how long past a ping a silent socket is considered dead
+    pong_timeout: tokio::time::Duration,
+
+    /// how many outgoing messages can be buffered per socket
+    outbox_capacity: usize,
+
+    /// what happens when a socket's outbox is full
+    overflow_policy: outbox::OverflowPolicy,
+
+    /// how long a token stays valid without any authenticated activity
+    token_ttl: tokio::time::Duration,
+
+    /// how long the access token of an account's session is valid, it
+    /// doesn't slide
+    access_token_ttl: tokio::time::Duration,
+
+    /// how long a session's refresh token is valid, each refresh starts it
+    /// over
+    refresh_token_ttl: tokio::time::Duration,
+
+    /// how often expired sessions are swept out
+    session_sweep_interval: tokio::time::Duration,
+
+    /// how long sockets get to close cleanly when the server shuts down
+    shutdown_drain: tokio::time::Duration,
+
+    /// how long a connected client may go without sending anything before it
+    /// shows as away, zero disables
+    idle_away: tokio::time::Duration,
+
+    /// what rooms keep of their history unless they ask for less when
+    /// they're created
+    room_retention: Retention,
+
+    /// what each client keeps of the messages addressed to it
+    history_caps: HistoryCaps,
+
+    /// identical messages queued for an offline client this close together
+    /// are only kept once, zero disables
+    dedup_window: tokio::time::Duration,
+
+    /// longest accepted message body, in bytes
+    max_message_bytes: usize,
+
+    /// largest file that can be attached to a message, 0 turns attachments off
+    max_attachment_bytes: usize,
+
+    /// how much attached files may take up in memory, the oldest are dropped
+    /// past it
+    attachment_storage_bytes: usize,
+
+    /// media types files may be attached as, `image/*` style wildcards allowed
+    attachment_mime_types: Vec<String>,
+
+    /// largest request accepted on routes without a message body, in bytes
+    max_request_bytes: u64,
+
+    /// how long a client may take to send a request body, so one trickling
+    /// it in can't hold on to a task. `None` waits as long as it takes.
+    /// uploads aren't covered, warp reads multipart bodies on its own
+    request_timeout: Option<tokio::time::Duration>,
+
+    /// names nobody may register, stored as name keys
+    reserved_names: HashSet<String>,
+
+    /// clients that may be registered at once, 0 disables the cap
+    max_clients: usize,
+
+    /// sockets a single address may have open at once, 0 disables the cap
+    max_connections_per_ip: usize,
+
+    /// words messages may not contain
+    content_filter: filter::ContentFilter,
+
+    /// html-escape message bodies before they're stored and delivered, for
+    /// clients that render them as markup
+    escape_html: bool,
+
+    /// registrations allowed per remote address within one window, 0 disables
+    register_rate_limit: u32,
+
+    register_rate_window: tokio::time::Duration,
+
+    /// logins allowed per remote address within one window, 0 disables
+    login_rate_limit: u32,
+
+    login_rate_window: tokio::time::Duration,
+
+    /// pbkdf2 rounds a new password is hashed with, hashes made with a
+    /// different number still verify
+    password_hash_iterations: std::num::NonZeroU32,
+
+    /// messages per second each client may send once its burst is spent, 0
+    /// disables the limit
+    send_rate: f64,
+
+    /// messages a client may send back to back
+    send_burst: u32,
+
+    /// sqlite database clients and history are kept in, only used when built
+    /// with the `sqlite` feature
+    database_path: std::path::PathBuf,
+
+    /// token moderators authenticate admin endpoints with, they're turned off
+    /// without one
+    admin_token: Option<String>,
+
+    /// redis instances share clients through, only used when built with the
+    /// `redis` feature
+    redis_url: Option<String>,
+
+    /// cross-origin access for browsers, `None` leaves only same-origin pages
+    /// able to call the api
+    cors: Option<CorsConfig>,
+}
+
+impl Config {
+    /// largest request accepted on routes carrying a message body, leaving
+    /// room for the json envelope and escaping
+    fn max_message_request_bytes(&self) -> u64 {
+        self.max_message_bytes as u64 * 2 + 16 * 1024
+    }
+}
+
+#[derive(Debug)]
+struct TlsConfig {
+    cert_path: std::path::PathBuf,
+    key_path: std::path::PathBuf,
+}
+
+#[derive(Debug)]
+struct CorsConfig {
+    /// `None` allows any origin, only meant for development
+    origins: Option<Vec<String>>,
+
+    methods: Vec<warp::http::Method>,
+    headers: Vec<warp::http::header::HeaderName>,
+}
+
+impl CorsConfig {
+    fn load(settings: &mut Settings) -> Result<Option<Self>, String> {
+        let origins = settings.list("CHAT_CORS_ORIGINS", "");
+
+        let methods = settings
+            .list("CHAT_CORS_METHODS", "GET,POST")
+            .into_iter()
+            .map(|method| {
+                method
+                    .to_uppercase()
+                    .parse()
+                    .map_err(|_| format!("invalid method in CHAT_CORS_METHODS: {:?}", method))
+            })
+            .collect::<Result<_, _>>()?;
+
+        let headers = settings
+            .list("CHAT_CORS_HEADERS", "content-type,x-request-id")
+            .into_iter()
+            .map(|header| {
+                header
+                    .parse()
+                    .map_err(|_| format!("invalid header in CHAT_CORS_HEADERS: {:?}", header))
+            })
+            .collect::<Result<_, _>>()?;
+
+        if origins.is_empty() {
+            return Ok(None);
+        }
+
+        let origins = if origins == ["*"] {
+            None
+        } else {
+            for origin in &origins {
+                if !is_origin(origin) {
+                    return Err(format!(
+                        "invalid origin in CHAT_CORS_ORIGINS: {:?}, expected e.g. https://example.com",
+                        origin
+                    ));
+                }
+            }
+
+            Some(origins)
+        };
+
+        Ok(Some(CorsConfig {
+            origins,
+            methods,
+            headers,
+        }))
+    }
+
+    fn filter(&self) -> warp::filters::cors::Builder {
+        let cors = warp::cors()
+            .allow_methods(self.methods.clone())
+            .allow_headers(self.headers.clone())
+            .expose_header("x-request-id");
+
+        match &self.origins {
+            Some(origins) => cors.allow_origins(origins.iter().map(String::as_str)),
+            None => cors.allow_any_origin(),
+        }
+    }
+}
+
+/// whether `origin` looks like the value of an `Origin` header: a scheme and
+/// a host, maybe a port, nothing else
+fn is_origin(origin: &str) -> bool {
+    let Ok(uri) = origin.parse::<warp::http::Uri>() else {
+        return false;
+    };
+
+    match (uri.scheme_str(), uri.authority()) {
+        (Some(scheme), Some(authority)) => origin == format!("{}://{}", scheme, authority),
+        _ => false,
+    }
+}
+
+impl Config {
+    /// builds the config from the built-in defaults, overridden by the config
+    /// file if there is one, then by env vars and finally by the command line
+    fn load(args: &Args) -> Result<Self, String> {
+        let mut settings = Settings::load(&args.config_path())?;
+        settings.overrides = args.overrides();
+
+        Self::from_settings(settings)
+    }
+
+    /// the built-in defaults alone, whatever the env or the working
+    /// directory hold
+    #[cfg(test)]
+    fn defaults() -> Self {
+        Self::from_settings(Settings::empty()).expect("the defaults are valid")
+    }
+
+    fn from_settings(mut settings: Settings) -> Result<Self, String> {
+        let tls = match (settings.raw("CHAT_TLS_CERT"), settings.raw("CHAT_TLS_KEY")) {
+            (Some(cert_path), Some(key_path)) => Some(TlsConfig {
+                cert_path: cert_path.into(),
+                key_path: key_path.into(),
+            }),
+            (None, None) => None,
+            _ => {
+                return Err("CHAT_TLS_CERT and CHAT_TLS_KEY must be set together".to_string());
+            }
+        };
+
+        let ping_interval_secs = settings.get("CHAT_PING_INTERVAL_SECS", 30)?;
+
+        if ping_interval_secs == 0 {
+            return Err("CHAT_PING_INTERVAL_SECS must be greater than 0".to_string());
+        }
+
+        let outbox_capacity = settings.get("CHAT_OUTBOX_CAPACITY", 256)?;
+
+        if outbox_capacity == 0 {
+            return Err("CHAT_OUTBOX_CAPACITY must be greater than 0".to_string());
+        }
+
+        let session_sweep_secs = settings.get("CHAT_SESSION_SWEEP_SECS", 60)?;
+
+        if session_sweep_secs == 0 {
+            return Err("CHAT_SESSION_SWEEP_SECS must be greater than 0".to_string());
+        }
+
+        let room_history_limit = settings.get("CHAT_ROOM_HISTORY_LIMIT", HISTORY_LIMIT)?;
+
+        if room_history_limit == 0 {
+            return Err("CHAT_ROOM_HISTORY_LIMIT must be greater than 0".to_string());
+        }
+
+        let history_limit = settings.get("CHAT_HISTORY_LIMIT", HISTORY_LIMIT)?;
+
+        if history_limit == 0 {
+            return Err("CHAT_HISTORY_LIMIT must be greater than 0".to_string());
+        }
+
+        let room_history_max_age_secs =
+            settings.get("CHAT_ROOM_HISTORY_MAX_AGE_SECS", 7 * 24 * 60 * 60)?;
+
+        let register_rate_window_secs = settings.get("CHAT_REGISTER_RATE_WINDOW_SECS", 60)?;
+
+        if register_rate_window_secs == 0 {
+            return Err("CHAT_REGISTER_RATE_WINDOW_SECS must be greater than 0".to_string());
+        }
+
+        let access_token_ttl_secs = settings.get("CHAT_ACCESS_TOKEN_TTL_SECS", 15 * 60)?;
+
+        if access_token_ttl_secs == 0 {
+            return Err("CHAT_ACCESS_TOKEN_TTL_SECS must be greater than 0".to_string());
+        }
+
+        let refresh_token_ttl_secs =
+            settings.get("CHAT_REFRESH_TOKEN_TTL_SECS", 30 * 24 * 60 * 60)?;
+
+        if refresh_token_ttl_secs < access_token_ttl_secs {
+            return Err(
+                "CHAT_REFRESH_TOKEN_TTL_SECS must be at least CHAT_ACCESS_TOKEN_TTL_SECS"
+                    .to_string(),
+            );
+        }
+
+        let login_rate_window_secs = settings.get("CHAT_LOGIN_RATE_WINDOW_SECS", 60)?;
+
+        if login_rate_window_secs == 0 {
+            return Err("CHAT_LOGIN_RATE_WINDOW_SECS must be greater than 0".to_string());
+        }
+
+        let password_hash_iterations =
+            std::num::NonZeroU32::new(settings.get("CHAT_PASSWORD_HASH_ITERATIONS", 600_000)?)
+                .ok_or_else(|| {
+                    "CHAT_PASSWORD_HASH_ITERATIONS must be greater than 0".to_string()
+                })?;
+
+        let send_rate: f64 = settings.get("CHAT_SEND_RATE_PER_SEC", 5.0)?;
+
+        if !(send_rate >= 0.0 && send_rate.is_finite()) {
+            return Err("CHAT_SEND_RATE_PER_SEC must be a non-negative number".to_string());
+        }
+
+        let send_burst = settings.get("CHAT_SEND_BURST", 10)?;
+
+        if send_burst == 0 {
+            return Err("CHAT_SEND_BURST must be greater than 0".to_string());
+        }
+
+        let redis_url = settings.raw("CHAT_REDIS_URL").filter(|url| !url.is_empty());
+
+        if let Some(url) = &redis_url {
+            backplane::address(url).map_err(|err| format!("CHAT_REDIS_URL: {}", err))?;
+        }
+
+        let config = Config {
+            bind_addr: std::net::SocketAddr::new(
+                settings.get("CHAT_BIND_ADDR", std::net::Ipv4Addr::UNSPECIFIED.into())?,
+                settings.get("CHAT_PORT", 8080)?,
+            ),
+            tls,
+            disconnect_grace: tokio::time::Duration::from_secs(
+                settings.get("CHAT_DISCONNECT_GRACE_SECS", 10)?,
+            ),
+            ping_interval: tokio::time::Duration::from_secs(ping_interval_secs),
+            pong_timeout: tokio::time::Duration::from_secs(
+                settings.get("CHAT_PONG_TIMEOUT_SECS", 10)?,
+            ),
+            outbox_capacity,
+            overflow_policy: settings.get(
+                "CHAT_OUTBOX_OVERFLOW_POLICY",
+                outbox::OverflowPolicy::DropOldest,
+            )?,
+            token_ttl: tokio::time::Duration::from_secs(
+                settings.get("CHAT_TOKEN_TTL_SECS", 86400)?,
+            ),
+            access_token_ttl: tokio::time::Duration::from_secs(access_token_ttl_secs),
+            refresh_token_ttl: tokio::time::Duration::from_secs(refresh_token_ttl_secs),
+            session_sweep_interval: tokio::time::Duration::from_secs(session_sweep_secs),
+            shutdown_drain: tokio::time::Duration::from_secs(
+                settings.get("CHAT_SHUTDOWN_DRAIN_SECS", 5)?,
+            ),
+            idle_away: tokio::time::Duration::from_secs(settings.get("CHAT_IDLE_AWAY_SECS", 300)?),
+            dedup_window: tokio::time::Duration::from_secs(
+                settings.get("CHAT_DEDUP_WINDOW_SECS", 5)?,
+            ),
+            room_retention: Retention {
+                max_messages: room_history_limit,
+                max_age: Some(tokio::time::Duration::from_secs(room_history_max_age_secs))
+                    .filter(|max_age| !max_age.is_zero()),
+            },
+            history_caps: HistoryCaps {
+                max_messages: history_limit,
+                max_bytes: settings.get("CHAT_HISTORY_MAX_BYTES", 1024 * 1024)?,
+            },
+            max_message_bytes: settings.get("CHAT_MAX_MESSAGE_BYTES", 4096)?,
+            max_attachment_bytes: settings.get("CHAT_MAX_ATTACHMENT_BYTES", 5 * 1024 * 1024)?,
+            attachment_storage_bytes: settings
+                .get("CHAT_ATTACHMENT_STORAGE_BYTES", 256 * 1024 * 1024)?,
+            attachment_mime_types: settings.list(
+                "CHAT_ATTACHMENT_MIME_TYPES",
+                "image/*,audio/*,video/*,application/pdf,text/plain",
+            ),
+            max_request_bytes: settings.get("CHAT_MAX_REQUEST_BYTES", 16 * 1024)?,
+            request_timeout: Some(tokio::time::Duration::from_secs(
+                settings.get("CHAT_REQUEST_TIMEOUT_SECS", 30)?,
+            ))
+            .filter(|timeout| !timeout.is_zero()),
+            reserved_names: settings
+                .list("CHAT_RESERVED_NAMES", "admin,system,server")
+                .iter()
+                .map(|name| name_key(name))
+                .collect(),
+            max_clients: settings.get("CHAT_MAX_CLIENTS", 5000)?,
+            max_connections_per_ip: settings.get("CHAT_MAX_CONNECTIONS_PER_IP", 20)?,
+            content_filter: filter::ContentFilter::new(
+                settings
+                    .list("CHAT_BLOCKED_WORDS", "")
+                    .iter()
+                    .map(String::as_str),
+                settings.get("CHAT_FILTER_MODE", filter::FilterMode::Mask)?,
+            ),
+            escape_html: settings.get("CHAT_ESCAPE_HTML", false)?,
+            register_rate_limit: settings.get("CHAT_REGISTER_RATE_LIMIT", 10)?,
+            register_rate_window: tokio::time::Duration::from_secs(register_rate_window_secs),
+            login_rate_limit: settings.get("CHAT_LOGIN_RATE_LIMIT", 10)?,
+            login_rate_window: tokio::time::Duration::from_secs(login_rate_window_secs),
+            password_hash_iterations,
+            send_rate,
+            send_burst,
+            database_path: settings.get("CHAT_DATABASE", "chat.db".into())?,
+            admin_token: settings
+                .raw("CHAT_ADMIN_TOKEN")
+                .filter(|token| !token.is_empty()),
+            redis_url,
+            cors: CorsConfig::load(&mut settings)?,
+        };
+
+        settings.finish()?;
+
+        Ok(config)
+    }
+}
+
+/// command line arguments, anything left out falls back to env vars, the
+/// config file and the built-in defaults, in that order
+#[derive(Debug, clap::Parser)]
+#[command(version, about)]
+struct Args {
+    /// address to listen on
+    #[arg(long)]
+    bind: Option<std::net::IpAddr>,
+
+    /// port to listen on
+    #[arg(long)]
+    port: Option<u16>,
+
+    /// seconds a dropped client has to reconnect before it's removed
+    #[arg(long)]
+    grace_secs: Option<u64>,
+
+    /// longest accepted message body, in bytes
+    #[arg(long)]
+    max_message_bytes: Option<usize>,
+
+    /// config file, defaults to chat.toml when it exists
+    #[arg(long, value_name = "PATH")]
+    config: Option<std::path::PathBuf>,
+}
+
+impl Args {
+    fn config_path(&self) -> Option<std::path::PathBuf> {
+        let default = std::path::Path::new("chat.toml");
+
+        self.config
+            .clone()
+            .or_else(|| default.exists().then(|| default.to_path_buf()))
+    }
+
+    /// the arguments that were given, keyed like the settings they override
+    fn overrides(&self) -> HashMap<String, String> {
+        [
+            ("CHAT_BIND_ADDR", self.bind.map(|bind| bind.to_string())),
+            ("CHAT_PORT", self.port.map(|port| port.to_string())),
+            (
+                "CHAT_DISCONNECT_GRACE_SECS",
+                self.grace_secs.map(|secs| secs.to_string()),
+            ),
+            (
+                "CHAT_MAX_MESSAGE_BYTES",
+                self.max_message_bytes.map(|bytes| bytes.to_string()),
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| Some((key.to_string(), value?)))
+        .collect()
+    }
+}
+
+/// raw setting values, from the command line, env vars or the config file.
+/// settings are named after their env var, the file uses the same name in
+/// lowercase without the `CHAT_` prefix, e.g. `port = 8080` for `CHAT_PORT`.
+struct Settings {
+    overrides: HashMap<String, String>,
+    file: HashMap<String, String>,
+    path: Option<std::path::PathBuf>,
+
+    /// whether env vars are looked at, off for settings that must not
+    /// depend on the environment
+    read_env: bool,
+}
+
+impl Settings {
+    /// no settings at all, not even from env vars
+    fn empty() -> Self {
+        Settings {
+            overrides: HashMap::new(),
+            file: HashMap::new(),
+            path: None,
+            read_env: false,
+        }
+    }
+
+    fn load(path: &Option<std::path::PathBuf>) -> Result<Self, String> {
+        let Some(path) = path else {
+            return Ok(Settings {
+                read_env: true,
+                ..Settings::empty()
+            });
+        };
+
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| format!("could not read {}: {}", path.display(), err))?;
+        let table: toml::Table = contents
+            .parse()
+            .map_err(|err| format!("could not parse {}: {}", path.display(), err))?;
+
+        // values are kept as strings so the file and env vars parse alike,
+        // arrays become the comma separated lists env vars use
+        let file = table
+            .into_iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    toml::Value::String(value) => value,
+                    toml::Value::Array(values) => values
+                        .into_iter()
+                        .map(|value| match value {
+                            toml::Value::String(value) => value,
+                            value => value.to_string(),
+                        })
+                        .collect::<Vec<_>>()
+                        .join(","),
+                    toml::Value::Table(_) => {
+                        return Err(format!("{} in {} can't be a table", key, path.display()));
+                    }
+                    value => value.to_string(),
+                };
+
+                Ok((key, value))
+            })
+            .collect::<Result<_, String>>()?;
+
+        Ok(Settings {
+            overrides: HashMap::new(),
+            file,
+            path: Some(path.to_path_buf()),
+            read_env: true,
+        })
+    }
+
+    /// the value of a setting, the command line wins over env vars, which
+    /// win over the file
+    fn raw(&mut self, key: &str) -> Option<String> {
+        let from_file = self
+            .file
+            .remove(&key.trim_start_matches("CHAT_").to_lowercase());
+
+        self.overrides
+            .remove(key)
+            .or_else(|| self.read_env.then(|| std::env::var(key).ok()).flatten())
+            .or(from_file)
+    }
+
+    /// reads and parses a setting, falling back to `default` when unset
+    fn get<T: std::str::FromStr>(&mut self, key: &str, default: T) -> Result<T, String> {
+        match self.raw(key) {
+            Some(value) => value
+                .parse()
+                .map_err(|_| format!("invalid value for {}: {:?}", key, value)),
+            None => Ok(default),
+        }
+    }
+
+    /// reads a comma separated list, falling back to `default` when unset.
+    /// entries are trimmed and empty ones dropped
+    fn list(&mut self, key: &str, default: &str) -> Vec<String> {
+        self.raw(key)
+            .unwrap_or_else(|| default.to_string())
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// fails on file entries nothing asked for, they're most likely typos
+    fn finish(self) -> Result<(), String> {
+        let mut unknown: Vec<String> = self.file.into_keys().collect();
+
+        if unknown.is_empty() {
+            return Ok(());
+        }
+
+        unknown.sort_unstable();
+
+        Err(format!(
+            "unknown settings in {}: {}",
+            self.path.unwrap_or_default().display(),
+            unknown.join(", ")
+        ))
+    }
+}
+
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+struct Message {
+    /// shared by every recipient of the same send
+    id: String,
+
+    /// counts up per recipient, so clients can put messages from different
+    /// senders in order and notice gaps
+    seq: u64,
+
+    from: String,
+    body: String,
+
+    /// unix timestamp in milliseconds, set by the server on receipt
+    timestamp: i64,
+
+    /// the room the message was posted to, `None` for direct messages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    room: Option<String>,
+
+    /// a file sent along with the message, downloaded separately
+    #[serde(skip_serializing_if = "Option::is_none")]
+    attachment: Option<Box<attachments::AttachmentInfo>>,
+
+    /// unix timestamp in milliseconds after which the message is removed
+    /// everywhere, `None` for messages that stay
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<i64>,
+
+    /// token of the sender, so read receipts still find it after a rename
+    #[serde(skip)]
+    sender: String,
+
+    /// whether the sender is told once the message reaches the recipient's
+    /// socket, off for broadcasts and rooms so they don't flood the sender
+    #[serde(skip)]
+    acknowledge: bool,
+
+    /// set once the sender deleted the message, the body is cleared but the
+    /// entry stays so the history keeps its order
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    deleted: bool,
+
+    /// emoji -> token of each client who reacted with it -> its name. kept
+    /// by token so a rename or a new client under an old name doesn't mix
+    /// reactions up, only the names are sent
+    #[serde(
+        skip_serializing_if = "HashMap::is_empty",
+        serialize_with = "serialize_reactions",
+        skip_deserializing
+    )]
+    reactions: HashMap<String, HashMap<String, String>>,
+
+    /// how far this copy got towards its recipient, only tracked on the
+    /// copies in a client's history
+    #[serde(skip)]
+    status: DeliveryStatus,
+}
+
+/// the reactions of a message as emoji -> names of the clients who reacted
+fn serialize_reactions<S: serde::Serializer>(
+    reactions: &HashMap<String, HashMap<String, String>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    use serde::ser::SerializeMap;
+
+    let mut map = serializer.serialize_map(Some(reactions.len()))?;
+
+    for (emoji, reactors) in reactions {
+        let mut names: Vec<&String> = reactors.values().collect();
+        names.sort_unstable();
+
+        map.serialize_entry(emoji, &names)?;
+    }
+
+    map.end()
+}
+
+/// where a message stands with one recipient. it only ever moves forward,
+/// a read message was delivered too even if the write went unnoticed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+enum DeliveryStatus {
+    /// accepted and queued for the recipient
+    #[default]
+    Sent,
+
+    /// written to one of the recipient's live sockets
+    Delivered,
+
+    /// the recipient sent a read receipt for it
+    Read,
+}
+
+/// everything the server pushes over a socket, tagged with a `type` field so
+/// clients can tell chat messages from other events
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum ServerEvent {
+    Message(Message),
+    /// always carries the current status message, `None` when there's none
+    Presence {
+        name: String,
+        status: Presence,
+        status_message: Option<String>,
+    },
+    Typing {
+        from: String,
+    },
+
+    /// a message the client sent was written to the socket of `to`
+    Ack {
+        message_id: String,
+        to: String,
+    },
+
+    /// `by` read a message the client sent
+    Read {
+        message_id: String,
+        by: String,
+    },
+
+    /// the sender changed the body of a message the client received
+    Edit {
+        message_id: String,
+        body: String,
+    },
+
+    /// the sender deleted a message the client received
+    Delete {
+        message_id: String,
+    },
+
+    /// a message with a time to live reached it and is gone, sent to the
+    /// sender and to every recipient still holding it
+    Expire {
+        message_id: String,
+    },
+
+    /// a client changed its name, rosters should follow
+    Rename {
+        from: String,
+        to: String,
+    },
+
+    /// the client was mentioned by name in a message, whether or not it was
+    /// one of the recipients
+    Mention {
+        from: String,
+        message_id: String,
+    },
+
+    /// `by` added or, when `added` is false, took back a reaction
+    Reaction {
+        message_id: String,
+        emoji: String,
+        by: String,
+        added: bool,
+    },
+
+    /// `by` pinned or, when `pinned` is false, unpinned a message in a
+    /// conversation the client is part of
+    Pin {
+        message_id: String,
+        by: String,
+        pinned: bool,
+    },
+
+    /// `by` invited the client to a private room, it may join from now on
+    Invite {
+        room: String,
+        by: String,
+    },
+
+    /// the owner of a room the client is in gave `name` a new role
+    Role {
+        room: String,
+        name: String,
+        role: RoomRole,
+    },
+
+    /// a notice from the server itself rather than from another client
+    System {
+        body: String,
+    },
+
+    /// a message the client sent was still queued when its recipient went
+    /// away for good, it will never arrive
+    Undeliverable {
+        message_id: String,
+        reason: &'static str,
+    },
+
+    /// a file the client was sending over this socket was turned down
+    #[serde(rename = "attach_failed")]
+    AttachFailed {
+        filename: String,
+        error: &'static str,
+    },
+}
+
+/// longest accepted reaction, in characters. enough for emoji built from
+/// several code points, like flags or family sequences
+const MAX_REACTION_LENGTH: usize = 8;
+
+/// longest accepted client name, in characters
+const MAX_NAME_LENGTH: usize = 32;
+
+/// longest accepted status message, in characters
+const MAX_STATUS_MESSAGE_LENGTH: usize = 100;
+
+/// validates a requested name and returns its NFC normalized form, so names
+/// that only differ in how their characters are composed can't coexist
+fn normalize_name(name: &str) -> Result<String, &'static str> {
+    let name: String = name.trim().nfc().collect();
+
+    if name.is_empty() {
+        return Err("The name can't be empty");
+    }
+
+    if name.chars().count() > MAX_NAME_LENGTH {
+        return Err("The name is too long");
+    }
+
+    if name.chars().any(char::is_control) {
+        return Err("The name can't contain control characters");
+    }
+
+    Ok(name)
+}
+
+/// key a client is stored under, names are unique regardless of case and
+/// unicode composition
+fn name_key(name: &str) -> String {
+    name.nfc().collect::<String>().to_lowercase()
+}
+
+/// name keys of everyone mentioned as `@name` in a message body. mentions end
+/// at the first character that can't be part of one, and trailing dots or
+/// dashes are dropped so `@bob.` and `(@bob)` both mention bob. an `@` in the
+/// middle of a word, like an email address, isn't a mention.
+fn mentions(body: &str) -> HashSet<String> {
+    let is_name_char = |c: char| c.is_alphanumeric() || matches!(c, '_' | '-' | '.');
+    let mut mentioned = HashSet::new();
+    let mut previous = None;
+
+    for (i, c) in body.char_indices() {
+        if c == '@' && !previous.map(is_name_char).unwrap_or(false) {
+            let rest = &body[i + 1..];
+            let end = rest.find(|c| !is_name_char(c)).unwrap_or(rest.len());
+            let name = rest[..end].trim_end_matches(['.', '-']);
+
+            if !name.is_empty() {
+                mentioned.insert(name_key(name));
+            }
+        }
+
+        previous = Some(c);
+    }
+
+    mentioned
+}
+
+/// tells everyone mentioned in `message` that they were, queueing the notice
+/// for the ones that are offline. `notify` picks who may be told, so room
+/// messages don't reach outsiders.
+fn notify_mentions(clients: &ClientMap, message: &Message, notify: impl Fn(&Client) -> bool) {
+    for key in mentions(&message.body) {
+        let Some(mut client) = clients.get_mut(&key) else {
+            continue;
+        };
+
+        if client.token == message.sender
+            || client.blocked.contains(&message.sender)
+            || !notify(&client)
+        {
+            continue;
+        }
+
+        let delivery = client.send_or_queue(ServerEvent::Mention {
+            from: message.from.clone(),
+            message_id: message.id.clone(),
+        });
+
+        tracing::debug!(to = %client.name, ?delivery, "mention sent");
+    }
+}
+
+/// enough of a token to correlate log lines without leaking the secret
+fn token_prefix(token: &str) -> String {
+    token.chars().take(8).collect()
+}
+
+/// whether an `Accept-Encoding` header lists gzip, or anything, with a
+/// non-zero quality
+fn accepts_gzip(accept_encoding: Option<&str>) -> bool {
+    accept_encoding.is_some_and(|accept_encoding| {
+        accept_encoding.split(',').any(|encoding| {
+            let mut parts = encoding.split(';').map(str::trim);
+            let name = parts.next().unwrap_or_default();
+
+            let quality = parts
+                .find_map(|param| param.strip_prefix("q="))
+                .and_then(|quality| quality.parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            (name.eq_ignore_ascii_case("gzip") || name == "*") && quality > 0.0
+        })
+    })
+}
+
+/// passes only requests that do, or don't, take gzip responses. warp's gzip
+/// filter compresses whatever it's given, so routes are split on this first
+fn gzip_accepted(wanted: bool) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("accept-encoding")
+        .and_then(move |accept_encoding: Option<String>| async move {
+            if accepts_gzip(accept_encoding.as_deref()) == wanted {
+                Ok(())
+            } else {
+                Err(warp::reject())
+            }
+        })
+        .untuple_one()
+}
+
+/// longest request id taken from a client, longer ones are replaced
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// the id a client sent in `x-request-id`, or a fresh one if it sent none or
+/// one that can't go back in a header as is. recorded on the request span
+fn request_id(header: Option<String>) -> String {
+    let id = header
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LENGTH
+                && id.bytes().all(|b| b.is_ascii_graphic())
+        })
+        .unwrap_or_else(|| uuid::Uuid::new_v4().as_simple().to_string());
+
+    tracing::Span::current().record("request_id", id.as_str());
+
+    id
+}
+
+fn unix_millis() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+#[derive(serde::Deserialize)]
+struct RegistrationRequest {
+    name: String,
+
+    /// makes the name an account to log in to again, rather than one that's
+    /// gone once its token lapses
+    #[serde(default)]
+    password: Option<String>,
+
+    /// label for the account's first session, shown when listing them
+    #[serde(default)]
+    device: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct LoginRequest {
+    name: String,
+    password: String,
+
+    /// label for the session, shown when listing them
+    #[serde(default)]
+    device: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct RefreshRequest {
+    refresh_token: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct RevokeSessionRequest {
+    pub token: String,
+}
+
+/// one of an account's sessions as its owner sees it, without its tokens
+#[derive(Debug, serde::Serialize)]
+struct SessionInfo {
+    pub id: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+
+    /// unix timestamps in milliseconds
+    pub created_at: i64,
+    pub connected_at: Option<i64>,
+    pub last_active: i64,
+
+    /// whether a socket or event stream is open with it right now
+    pub connected: bool,
+
+    /// whether it's the session the list was asked for with
+    pub current: bool,
+}
+
+#[derive(serde::Serialize, Default)]
+struct RegistrationResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+
+    /// only for an account, which gets a session
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<&'static str>,
+}
+
+#[derive(serde::Serialize)]
+struct HealthResponse {
+    pub status: &'static str,
+    pub uptime_secs: u64,
+}
+
+#[derive(serde::Serialize)]
+struct VersionResponse {
+    pub version: &'static str,
+
+    /// short hash of the commit the binary was built from, `unknown` when it
+    /// wasn't built from a git checkout
+    pub commit: &'static str,
+
+    /// unix timestamp in milliseconds of the build
+    pub build_timestamp: u64,
+}
+
+#[derive(serde::Serialize)]
+struct StatsResponse {
+    pub registered: usize,
+    pub connected: usize,
+
+    /// disconnected clients whose grace period hasn't run out yet
+    pub in_grace: usize,
+
+    pub messages_delivered: u64,
+    pub uptime_secs: u64,
+
+    /// messages kept in client histories, and the size of their bodies
+    pub history_messages: usize,
+    pub history_bytes: usize,
+}
+
+#[derive(serde::Serialize)]
+struct MessageStatusResponse {
+    pub message_id: String,
+
+    /// the furthest every recipient got, the lowest of their statuses
+    pub status: DeliveryStatus,
+
+    /// recipient name -> where the message stands with them
+    pub recipients: std::collections::BTreeMap<String, DeliveryStatus>,
+}
+
+#[derive(serde::Serialize)]
+struct ReportResponse {
+    pub report_id: String,
+}
+
+/// clients listed when the request doesn't ask for a page size
+const DEFAULT_PAGE_LIMIT: usize = 50;
+
+/// largest page of clients handed out at once
+const MAX_PAGE_LIMIT: usize = 200;
+
+#[derive(Debug, serde::Deserialize)]
+struct SearchQuery {
+    pub q: String,
+
+    /// only search messages from the client with this name
+    pub from: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ListClientsQuery {
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+
+    /// only list clients whose name starts with this, ignoring case
+    pub prefix: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct ClientsPage {
+    /// clients in the whole listing, not just this page
+    pub total: usize,
+    pub clients: Vec<Box<serde_json::value::RawValue>>,
+}
+
+/// a client's own view of itself, with what only it should see
+#[derive(Debug, serde::Serialize)]
+struct StatusResponse {
+    pub name: String,
+    pub status: Presence,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status_message: Option<String>,
+
+    pub last_seen: i64,
+
+    /// messages that arrived while the client was away
+    pub unread: usize,
+}
+
+#[derive(serde::Serialize)]
+struct ErrorResponse {
+    pub error: &'static str,
+}
+
+/// why the core of a request turned it down. the functions behind the
+/// handlers return it so they can be called without warp, the handlers turn
+/// it into the matching rejection
+#[derive(Debug, PartialEq, Eq)]
+enum ChatError {
+    InvalidToken,
+    ShuttingDown,
+    Maintenance,
+    RateLimited { retry_after: tokio::time::Duration },
+    MessageTooLong,
+    BlockedContent,
+    UnknownRecipient,
+    UnknownAttachment,
+    InvalidTtl,
+    BackplaneUnavailable,
+    InvalidCredentials,
+    SendInProgress,
+}
+
+impl From<ChatError> for warp::Rejection {
+    fn from(err: ChatError) -> Self {
+        match err {
+            ChatError::InvalidToken => warp::reject::custom(InvalidToken),
+            ChatError::ShuttingDown => warp::reject::custom(ShuttingDown),
+            ChatError::Maintenance => warp::reject::custom(Maintenance),
+            ChatError::RateLimited { retry_after } => {
+                warp::reject::custom(RateLimited { retry_after })
+            }
+            ChatError::MessageTooLong => warp::reject::custom(MessageTooLong),
+            ChatError::BlockedContent => warp::reject::custom(BlockedContent),
+            ChatError::UnknownRecipient => warp::reject::custom(UnknownRecipient),
+            ChatError::UnknownAttachment => warp::reject::custom(UnknownAttachment),
+            ChatError::InvalidTtl => warp::reject::custom(InvalidTtl),
+            ChatError::BackplaneUnavailable => warp::reject::custom(BackplaneUnavailable),
+            ChatError::InvalidCredentials => warp::reject::custom(InvalidCredentials),
+            ChatError::SendInProgress => warp::reject::custom(SendInProgress),
+        }
+    }
+}
+
+/// the recipient of a message is not registered
+#[derive(Debug)]
+struct UnknownRecipient;
+
+impl warp::reject::Reject for UnknownRecipient {}
+
+/// the token does not belong to any registered client
+#[derive(Debug)]
+struct InvalidToken;
+
+impl warp::reject::Reject for InvalidToken {}
+
+/// no account with a password goes by the name, or the password is wrong.
+/// which one isn't told
+#[derive(Debug)]
+struct InvalidCredentials;
+
+impl warp::reject::Reject for InvalidCredentials {}
+
+/// the name is banned
+#[derive(Debug)]
+struct BannedName;
+
+impl warp::reject::Reject for BannedName {}
+
+/// a ban request named neither a name nor an address
+#[derive(Debug)]
+struct InvalidBan;
+
+impl warp::reject::Reject for InvalidBan {}
+
+/// the message contains a blocked word and the filter rejects those
+#[derive(Debug)]
+struct BlockedContent;
+
+impl warp::reject::Reject for BlockedContent {}
+
+/// no client is registered under the given name
+#[derive(Debug)]
+struct UnknownClient;
+
+impl warp::reject::Reject for UnknownClient {}
+
+/// the server is shutting down and no longer takes new clients
+#[derive(Debug)]
+struct ShuttingDown;
+
+impl warp::reject::Reject for ShuttingDown {}
+
+/// the address already holds as many sockets as it may
+#[derive(Debug)]
+struct TooManyConnections;
+
+impl warp::reject::Reject for TooManyConnections {}
+
+/// the backplane that keeps names unique across instances can't be reached
+#[derive(Debug)]
+struct BackplaneUnavailable;
+
+impl warp::reject::Reject for BackplaneUnavailable {}
+
+/// the server is in maintenance mode and takes no new registrations or
+/// sockets
+#[derive(Debug)]
+struct Maintenance;
+
+impl warp::reject::Reject for Maintenance {}
+
+/// the message body is longer than the configured limit
+#[derive(Debug)]
+struct MessageTooLong;
+
+impl warp::reject::Reject for MessageTooLong {}
+
+/// the time to live is zero or longer than `MAX_MESSAGE_TTL`
+#[derive(Debug)]
+struct InvalidTtl;
+
+impl warp::reject::Reject for InvalidTtl {}
+
+#[derive(Debug)]
+struct StatusMessageTooLong;
+
+impl warp::reject::Reject for StatusMessageTooLong {}
+
+#[derive(Debug)]
+struct UnknownAttachment;
+
+impl warp::reject::Reject for UnknownAttachment {}
+
+/// a send with the same idempotency key is still under way
+#[derive(Debug)]
+struct SendInProgress;
+
+impl warp::reject::Reject for SendInProgress {}
+
+/// the file's name or media type can't be used
+#[derive(Debug)]
+struct InvalidAttachment;
+
+impl warp::reject::Reject for InvalidAttachment {}
+
+#[derive(Debug)]
+struct AttachmentTooLarge;
+
+impl warp::reject::Reject for AttachmentTooLarge {}
+
+/// files of this media type can't be attached
+#[derive(Debug)]
+struct MimeNotAllowed;
+
+impl warp::reject::Reject for MimeNotAllowed {}
+
+/// the message is not in the client's history, or never existed
+#[derive(Debug)]
+struct UnknownMessage;
+
+impl warp::reject::Reject for UnknownMessage {}
+
+/// the account has no session by that id
+#[derive(Debug)]
+struct UnknownSession;
+
+impl warp::reject::Reject for UnknownSession {}
+
+#[derive(Debug)]
+struct UnknownReport;
+
+impl warp::reject::Reject for UnknownReport {}
+
+/// a report needs a reason, and not an essay
+#[derive(Debug)]
+struct InvalidReason;
+
+impl warp::reject::Reject for InvalidReason {}
+
+/// the conversation already has as many pins as it may
+#[derive(Debug)]
+struct TooManyPins;
+
+impl warp::reject::Reject for TooManyPins {}
+
+/// a search needs something to search for
+#[derive(Debug)]
+struct EmptyQuery;
+
+impl warp::reject::Reject for EmptyQuery {}
+
+/// only the sender of a message may change it
+#[derive(Debug)]
+struct NotMessageSender;
+
+impl warp::reject::Reject for NotMessageSender {}
+
+/// a reaction has to be a short emoji-like string
+#[derive(Debug)]
+struct InvalidReaction;
+
+impl warp::reject::Reject for InvalidReaction {}
+
+/// the requested name breaks the naming rules
+#[derive(Debug)]
+struct InvalidName;
+
+impl warp::reject::Reject for InvalidName {}
+
+/// the requested name is reserved
+#[derive(Debug)]
+struct ReservedName;
+
+impl warp::reject::Reject for ReservedName {}
+
+/// another client already holds the requested name
+#[derive(Debug)]
+struct NameTaken;
+
+impl warp::reject::Reject for NameTaken {}
+
+/// the client is not a member of the room it tried to act on
+#[derive(Debug)]
+struct NotRoomMember;
+
+impl warp::reject::Reject for NotRoomMember {}
+
+/// the room is private and the client wasn't invited
+#[derive(Debug)]
+struct NotInvited;
+
+impl warp::reject::Reject for NotInvited {}
+
+#[derive(Debug)]
+struct RoomExists;
+
+impl warp::reject::Reject for RoomExists {}
+
+/// the client's role in the room doesn't allow what it tried
+#[derive(Debug)]
+struct InsufficientRole;
+
+impl warp::reject::Reject for InsufficientRole {}
+
+/// the socket only offered envelope versions the server doesn't speak
+#[derive(Debug)]
+struct UnsupportedProtocol;
+
+impl warp::reject::Reject for UnsupportedProtocol {}
+
+/// only moderators and the owner may post to the room
+#[derive(Debug)]
+struct ReadOnlyRoom;
+
+impl warp::reject::Reject for ReadOnlyRoom {}
+
+/// the room asked to keep no messages, or more than the server allows
+#[derive(Debug)]
+struct InvalidRetention;
+
+impl warp::reject::Reject for InvalidRetention {}
+
+/// owners can't change their own role, they hand the room over instead
+#[derive(Debug)]
+struct InvalidRole;
+
+impl warp::reject::Reject for InvalidRole {}
+
+/// too many requests, try again once `retry_after` has passed
+#[derive(Debug)]
+struct RateLimited {
+    retry_after: tokio::time::Duration,
+}
+
+impl warp::reject::Reject for RateLimited {}
+
+/// one or more recipient names, a bare string is accepted for compatibility
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+enum Recipients {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Recipients {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            Recipients::One(name) => vec![name],
+            Recipients::Many(names) => names,
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct MessageRequest {
+    pub token: String,
+    pub body: String,
+    pub to: Recipients,
+
+    /// makes the request safe to retry, a repeat with the same key gets the
+    /// original response and the message isn't delivered again
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+
+    /// id of a file uploaded beforehand to send along
+    #[serde(default)]
+    pub attachment: Option<String>,
+
+    /// seconds after which the message is removed from the recipients'
+    /// history, whether or not they read it
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+
+    /// unix timestamp in milliseconds to hold the message back until, one
+    /// that already passed sends it right away
+    #[serde(default)]
+    pub deliver_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize)]
+struct MessageResponse {
+    pub message_id: String,
+
+    /// recipients with a live socket the message was handed to
+    pub delivered: Vec<String>,
+
+    /// offline recipients, they get the message once they reconnect
+    pub queued: Vec<String>,
+
+    pub unknown: Vec<String>,
+
+    /// recipients another instance holds, the message went to them through
+    /// the backplane
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub relayed: Vec<String>,
+
+    /// set when the message was scheduled instead of sent, the recipients
+    /// aren't resolved until then so the lists above stay empty
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deliver_at: Option<i64>,
+}
+
+/// a message pushed over the websocket, the sender is known from the socket
+#[derive(Debug, serde::Deserialize)]
+struct SocketMessageRequest {
+    pub to: Recipients,
+    pub body: String,
+}
+
+/// anything a client pushes over the websocket. control messages carry a
+/// `type`, plain messages don't
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+enum SocketRequest {
+    Control(SocketControl),
+    Message(SocketMessageRequest),
+}
+
+/// sending a file: `attach_begin`, then binary frames with the file's bytes
+/// in order, then `attach_end`. the file goes out as a message once it's
+/// complete
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum SocketControl {
+    AttachBegin {
+        to: Recipients,
+        filename: String,
+        size: usize,
+        mime: String,
+
+        /// sent as the body of the message carrying the file
+        #[serde(default)]
+        body: String,
+    },
+    AttachEnd,
+}
+
+/// an attachment being received over a socket
+struct Upload {
+    to: Vec<String>,
+    filename: String,
+    mime: String,
+    size: usize,
+    body: String,
+    data: Vec<u8>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct LogoutRequest {
+    pub token: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct KickRequest {
+    pub admin_token: String,
+    pub name: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ResolveReportRequest {
+    pub admin_token: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct MaintenanceRequest {
+    pub admin_token: String,
+    pub enabled: bool,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct AnnounceRequest {
+    pub admin_token: String,
+    pub body: String,
+}
+
+/// bans or unbans a name, an address or both at once
+#[derive(Debug, serde::Deserialize)]
+struct BanRequest {
+    pub admin_token: String,
+
+    #[serde(default)]
+    pub name: Option<String>,
+
+    #[serde(default)]
+    pub ip: Option<std::net::IpAddr>,
+}
+
+impl BanRequest {
+    /// the name and address banned, as written to the audit log
+    fn target(&self) -> String {
+        let name = self.name.as_deref().map(name_key);
+        let ip = self.ip.map(|ip| ip.to_string());
+
+        name.into_iter().chain(ip).collect::<Vec<_>>().join(" ")
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct TypingRequest {
+    pub token: String,
+    pub to: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ReadRequest {
+    pub token: String,
+    pub message_id: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct EditMessageRequest {
+    pub token: String,
+    pub body: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct DeleteMessageRequest {
+    pub token: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct PinRequest {
+    pub token: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ReactionRequest {
+    pub token: String,
+    pub emoji: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct RenameRequest {
+    pub token: String,
+    pub name: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ReportRequest {
+    pub token: String,
+    pub message_id: String,
+    pub reason: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct BlockRequest {
+    pub token: String,
+    pub name: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct PresenceRequest {
+    pub token: String,
+    pub status: Presence,
+
+    /// replaces the status message, an empty one clears it and leaving it
+    /// out keeps the current one
+    #[serde(default)]
+    pub status_message: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct CreateRoomRequest {
+    pub token: String,
+    pub name: String,
+
+    #[serde(default)]
+    pub private: bool,
+
+    #[serde(default)]
+    pub read_only: bool,
+
+    /// how many messages the room keeps, at most the configured room
+    /// history limit which is also the default
+    #[serde(default)]
+    pub max_messages: Option<usize>,
+
+    /// how long the room keeps messages, at most the configured maximum age
+    /// if there is one
+    #[serde(default)]
+    pub max_age_secs: Option<u64>,
+}
+
+/// a member acting on another client in a room
+#[derive(Debug, serde::Deserialize)]
+struct RoomMemberRequest {
+    pub token: String,
+    pub name: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct RoomRoleRequest {
+    pub token: String,
+    pub name: String,
+    pub role: RoomRole,
+}
+
+#[derive(serde::Serialize)]
+struct RoomMessageResponse {
+    pub message_id: String,
+    pub delivered: usize,
+}
+
+#[derive(serde::Serialize)]
+struct RoomInfo {
+    pub name: String,
+    pub members: usize,
+    pub private: bool,
+    pub read_only: bool,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct RoomMembershipRequest {
+    pub token: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct RoomMessageRequest {
+    pub token: String,
+    pub body: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct BroadcastRequest {
+    pub token: String,
+    pub body: String,
+}
+
+#[derive(serde::Serialize)]
+struct BroadcastResponse {
+    pub delivered: usize,
+}
+
+#[tracing::instrument(name = "registration", skip_all, fields(name = %request.name, remote = ?remote))]
+async fn handle_registration(
+    request: RegistrationRequest,
+    remote: Option<std::net::SocketAddr>,
+    state: State,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let (response, status) = match register_client(
+        &state,
+        &request.name,
+        request.password.as_deref(),
+        request.device.as_deref(),
+        remote,
+    )
+    .await?
+    {
+        RegistrationOutcome::Registered {
+            token,
+            refresh_token,
+        } => (
+            RegistrationResponse {
+                token: Some(token),
+                refresh_token,
+                ..Default::default()
+            },
+            warp::http::StatusCode::OK,
+        ),
+        RegistrationOutcome::Refused { error, status } => (
+            RegistrationResponse {
+                error: Some(error),
+                ..Default::default()
+            },
+            status,
+        ),
+    };
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&response),
+        status,
+    ))
+}
+
+/// what came of a registration the server was willing to look at
+#[derive(Debug, PartialEq, Eq)]
+enum RegistrationOutcome {
+    Registered {
+        token: String,
+
+        /// set when the client registered an account
+        refresh_token: Option<String>,
+    },
+
+    /// turned down for a reason the client is shown, answered with `status`
+    Refused {
+        error: &'static str,
+        status: warp::http::StatusCode,
+    },
+}
+
+/// registers a client under `name`, coming from `remote`. with a password the
+/// name becomes an account and the tokens handed out are its first session
+async fn register_client(
+    state: &State,
+    name: &str,
+    password: Option<&str>,
+    device: Option<&str>,
+    remote: Option<std::net::SocketAddr>,
+) -> Result<RegistrationOutcome, ChatError> {
+    let State {
+        clients,
+        config,
+        metrics,
+        store,
+        bans,
+        registration_limiter,
+        shutting_down,
+        maintenance,
+        backplane,
+        ..
+    } = state;
+
+    let refuse = |error, status| {
+        metrics.registration_failures.inc();
+        Ok(RegistrationOutcome::Refused { error, status })
+    };
+
+    if shutting_down.load(Ordering::SeqCst) {
+        metrics.registration_failures.inc();
+        return Err(ChatError::ShuttingDown);
+    }
+
+    if maintenance.load(Ordering::SeqCst) {
+        metrics.registration_failures.inc();
+        return Err(ChatError::Maintenance);
+    }
+
+    let banned_addr = match remote {
+        Some(remote) => bans.lock().await.addrs.contains(&remote.ip()),
+        None => false,
+    };
+
+    if banned_addr {
+        tracing::info!("address is banned");
+        return refuse("Your address is banned", warp::http::StatusCode::FORBIDDEN);
+    }
+
+    if let Some(remote) = remote {
+        let checked = registration_limiter
+            .lock()
+            .await
+            .check(remote.ip(), tokio::time::Instant::now());
+
+        if let Err(retry_after) = checked {
+            tracing::info!("registration rate limited");
+            metrics.registration_failures.inc();
+
+            return Err(ChatError::RateLimited { retry_after });
+        }
+    }
+
+    let name = match normalize_name(name) {
+        Ok(name) => name,
+        Err(error) => {
+            tracing::info!(error, "invalid name");
+            return refuse(error, warp::http::StatusCode::BAD_REQUEST);
+        }
+    };
+
+    if bans.lock().await.names.contains(&name_key(&name)) {
+        tracing::info!("name is banned");
+        return refuse("The name is banned", warp::http::StatusCode::FORBIDDEN);
+    }
+
+    if config.reserved_names.contains(&name_key(&name)) {
+        tracing::info!("name is reserved");
+        return refuse("The name is reserved", warp::http::StatusCode::BAD_REQUEST);
+    }
+
+    match password {
+        Some(password) if password.chars().count() < password::MIN_LENGTH => {
+            tracing::info!("password too short");
+            return refuse(
+                "The password must be at least 8 characters",
+                warp::http::StatusCode::BAD_REQUEST,
+            );
+        }
+        Some(password) if password.len() > password::MAX_LENGTH => {
+            tracing::info!("password too long");
+            return refuse(
+                "The password is too long",
+                warp::http::StatusCode::BAD_REQUEST,
+            );
+        }
+        _ => {}
+    }
+
+    // a soft cap, racing registrations may go over it by a few
+    if config.max_clients > 0 && clients.len() >= config.max_clients {
+        tracing::warn!(max_clients = config.max_clients, "server is full");
+        return refuse(
+            "The server is full",
+            warp::http::StatusCode::SERVICE_UNAVAILABLE,
+        );
+    }
+
+    // the map below only knows this instance, the backplane knows them all
+    match backplane.claim_name(&name_key(&name)).await {
+        Ok(true) => {}
+        Ok(false) => {
+            tracing::info!("name already taken on another instance");
+            return refuse(
+                "The name is already taken",
+                warp::http::StatusCode::NOT_ACCEPTABLE,
+            );
+        }
+        Err(backplane::Unavailable) => {
+            metrics.registration_failures.inc();
+            return Err(ChatError::BackplaneUnavailable);
+        }
+    }
+
+    let token = uuid::Uuid::new_v4().as_simple().to_string();
+
+    let password_hash = match password {
+        Some(password) => Some(hash_password(password, config).await),
+        None => None,
+    };
+
+    // an account never hands out its own token, only sessions
+    let session = password_hash
+        .is_some()
+        .then(|| Session::new(config, device_label(device)));
+    let session_tokens = session.as_ref().map(Session::tokens);
+
+    let client = Client {
+        token: token.clone(),
+        name,
+        last_seen: unix_millis(),
+        expires_at: Some(tokio::time::Instant::now() + config.token_ttl),
+        sessions: session
+            .into_iter()
+            .map(|session| (uuid::Uuid::new_v4().as_simple().to_string(), session))
+            .collect(),
+        password_hash,
+        ..Default::default()
+    };
+
+    match clients.try_insert(name_key(&client.name), client) {
+        Err(_) => {
+            tracing::info!("name already taken");
+            return refuse(
+                "The name is already taken",
+                warp::http::StatusCode::NOT_ACCEPTABLE,
+            );
+        }
+        Ok(client) => store.insert_client(&client),
+    }
+
+    tracing::info!(
+        token = %token_prefix(&token),
+        account = session_tokens.is_some(),
+        "client registered"
+    );
+    metrics.registrations.inc();
+
+    Ok(match session_tokens {
+        Some(SessionTokens {
+            token,
+            refresh_token,
+        }) => RegistrationOutcome::Registered {
+            token,
+            refresh_token: Some(refresh_token),
+        },
+        None => RegistrationOutcome::Registered {
+            token,
+            refresh_token: None,
+        },
+    })
+}
+
+/// hashes `password` off the runtime, it takes a while on purpose
+async fn hash_password(password: &str, config: &Config) -> String {
+    let password = password.to_string();
+    let iterations = config.password_hash_iterations;
+
+    tokio::task::spawn_blocking(move || password::hash(&password, iterations))
+        .await
+        .expect("hashing a password panicked")
+}
+
+#[tracing::instrument(name = "login", skip_all, fields(name = %request.name, remote = ?remote))]
+async fn handle_login(
+    request: LoginRequest,
+    remote: Option<std::net::SocketAddr>,
+    state: State,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let tokens = log_in(
+        &state,
+        &request.name,
+        &request.password,
+        request.device.as_deref(),
+        remote,
+    )
+    .await?;
+
+    Ok(warp::reply::json(&tokens))
+}
+
+/// starts a session for the account `name` if `password` is its password
+/// and returns the session's tokens. a name without an account costs as much
+/// time as a wrong password, so the two can't be told apart
+async fn log_in(
+    state: &State,
+    name: &str,
+    password: &str,
+    device: Option<&str>,
+    remote: Option<std::net::SocketAddr>,
+) -> Result<SessionTokens, ChatError> {
+    let State {
+        clients,
+        config,
+        login_limiter,
+        shutting_down,
+        maintenance,
+        ..
+    } = state;
+
+    if shutting_down.load(Ordering::SeqCst) {
+        return Err(ChatError::ShuttingDown);
+    }
+
+    if maintenance.load(Ordering::SeqCst) {
+        return Err(ChatError::Maintenance);
+    }
+
+    if let Some(remote) = remote {
+        login_limiter
+            .lock()
+            .await
+            .check(remote.ip(), tokio::time::Instant::now())
+            .map_err(|retry_after| {
+                tracing::info!("login rate limited");
+                ChatError::RateLimited { retry_after }
+            })?;
+    }
+
+    if password.len() > password::MAX_LENGTH {
+        return Err(ChatError::InvalidCredentials);
+    }
+
+    let key = normalize_name(name)
+        .map(|name| name_key(&name))
+        .map_err(|_| ChatError::InvalidCredentials)?;
+
+    let password_hash = clients
+        .get(&key)
+        .and_then(|client| client.password_hash.clone());
+
+    let password = password.to_string();
+    let iterations = config.password_hash_iterations;
+
+    let verified = tokio::task::spawn_blocking(move || match password_hash {
+        Some(password_hash) => password::verify(&password, &password_hash),
+        None => {
+            password::hash(&password, iterations);
+            false
+        }
+    })
+    .await
+    .expect("verifying a password panicked");
+
+    if !verified {
+        tracing::info!("login failed");
+        return Err(ChatError::InvalidCredentials);
+    }
+
+    // the account may have been deleted while the password was checked
+    let tokens = clients
+        .start_session(&key, device_label(device), config)
+        .ok_or(ChatError::InvalidCredentials)?;
+
+    tracing::info!("client logged in");
+
+    Ok(tokens)
+}
+
+async fn handle_refresh(
+    request: RefreshRequest,
+    state: State,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&refresh_session(
+        &state,
+        &request.refresh_token,
+    )?))
+}
+
+/// trades a session's refresh token for a new access token and a new refresh
+/// token. the old ones stop working, a refresh token is only good once
+fn refresh_session(state: &State, refresh_token: &str) -> Result<SessionTokens, ChatError> {
+    let tokens = state
+        .clients
+        .refresh_session(refresh_token, &state.config)
+        .ok_or(ChatError::InvalidToken)?;
+
+    tracing::debug!(token = %token_prefix(&tokens.token), "session refreshed");
+
+    Ok(tokens)
+}
+
+/// the sessions of the account `token` belongs to, oldest first. a client
+/// without a password has none
+async fn handle_list_sessions(
+    token: String,
+    clients: Clients,
+    config: Arc<Config>,
+) -> Result<impl Reply, warp::Rejection> {
+    let client = authenticate(&clients, &token, &config)?;
+
+    let mut sessions: Vec<SessionInfo> = client
+        .sessions
+        .iter()
+        .map(|(id, session)| SessionInfo {
+            id: id.clone(),
+            device: session.device.clone(),
+            created_at: session.created_at,
+            connected_at: session.connected_at,
+            last_active: session.last_active,
+            connected: !session.connections.is_empty(),
+            current: session.access_token == token,
+        })
+        .collect();
+
+    sessions.sort_by_key(|session| session.created_at);
+
+    Ok(warp::reply::json(&sessions))
+}
+
+/// ends one of the account's sessions, closing whatever was connected with
+/// it. its tokens stop working, the other sessions carry on
+async fn handle_revoke_session(
+    id: String,
+    request: RevokeSessionRequest,
+    clients: Clients,
+    config: Arc<Config>,
+) -> Result<impl Reply, warp::Rejection> {
+    let key = authenticate(&clients, &request.token, &config)?
+        .key()
+        .clone();
+
+    if !clients.revoke_session(&key, &id) {
+        return Err(warp::reject::custom(UnknownSession));
+    }
+
+    tracing::info!(name = %key, session = %id, "session revoked");
+
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+#[tracing::instrument(
+    name = "socket",
+    skip_all,
+    fields(token = %token_prefix(&token), remote = ?remote, ?format, ?last_seq, name)
+)]
+#[allow(clippy::too_many_arguments)]
+async fn client_connected(
+    token: String,
+    session: Option<String>,
+    remote: Option<std::net::SocketAddr>,
+    format: wire::WireFormat,
+    version: wire::ProtocolVersion,
+    last_seq: Option<u64>,
+    ws: WebSocket,
+    clients: Clients,
+    config: Arc<Config>,
+    metrics: Arc<metrics::Metrics>,
+    store: Arc<store::Store>,
+    attachments: Arc<attachments::Attachments>,
+    rooms: Rooms,
+    dead_letters: Arc<deadletters::DeadLetters>,
+    backplane: Arc<backplane::Backplane>,
+) {
+    let (mut ws_tx, mut ws_rx) = ws.split();
+    let (client_tx, mut client_rx) =
+        outbox::channel(config.outbox_capacity, config.overflow_policy);
+
+    let connection_id = uuid::Uuid::new_v4().as_simple().to_string();
+
+    attach_connection(
+        &clients,
+        &token,
+        session.as_deref(),
+        &connection_id,
+        client_tx,
+        last_seq,
+        &config,
+        &store,
+    );
+
+    metrics.connection_opened();
+
+    // no client guard lives past this point. the read loop and the
+    // forwarding task only go through plain functions that drop theirs
+    // before returning, so a long-lived socket never holds up other clients
+    let ping_interval = config.ping_interval;
+    let pong_timeout = config.pong_timeout;
+
+    // the forwarding task ends when the outbox closes or the socket can no
+    // longer be written to, either way the client is gone
+    let mut forwarding_task = tokio::spawn({
+        let clients = clients.clone();
+        let token = token.clone();
+
+        async move {
+            let mut ping_timer = tokio::time::interval_at(
+                tokio::time::Instant::now() + ping_interval,
+                ping_interval,
+            );
+
+            loop {
+                tokio::select! {
+                    message = client_rx.recv() => match message {
+                        Some(message) => {
+                            let frame = format.frame(version, &message);
+
+                            // a client that stopped reading can block the write
+                            // forever, give up on it once the outbox is closed.
+                            // a healthy one still gets a moment to flush, like
+                            // the shutdown notice
+                            let sent = tokio::select! {
+                                result = ws_tx.send(frame) => result.is_ok(),
+                                _ = async {
+                                    client_rx.closed().await;
+                                    tokio::time::sleep(pong_timeout).await;
+                                } => false,
+                            };
+
+                            if !sent {
+                                break;
+                            }
+
+                            // the frame is out, let the sender know
+                            acknowledge(&clients, &token, message);
+                        }
+                        // the outbox was closed, either because the client went
+                        // away or because it fell too far behind. a stuck socket
+                        // may never accept the close frame, so don't wait forever.
+                        None => {
+                            let _ = tokio::time::timeout(pong_timeout, ws_tx.close()).await;
+                            break;
+                        }
+                    },
+                    _ = ping_timer.tick() => {
+                        if ws_tx.send(warp::ws::Message::ping(Vec::new())).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    });
+
+    // keep loop busy while socket is connected, delivering any text frames
+    // the client pushes. pings are answered by warp itself, and any frame
+    // (pongs included) counts as a sign of life. a socket that stays silent
+    // for a full ping interval plus the timeout is treated as dead.
+    let mut left_cleanly = false;
+    let mut upload: Option<Upload> = None;
+
+    // a finished join handle must not be polled again
+    let mut forwarding_done = false;
+
+    loop {
+        let message = tokio::select! {
+            message = ws_rx.next() => message,
+            _ = tokio::time::sleep(config.ping_interval + config.pong_timeout) => {
+                tracing::info!("socket stopped responding to pings");
+                None
+            }
+            _ = &mut forwarding_task, if !forwarding_done => {
+                forwarding_done = true;
+                None
+            }
+        };
+
+        let Some(Ok(message)) = message else {
+            break;
+        };
+
+        // a normal close means the client left on purpose and won't be back
+        // in a moment, anything else may be a flaky network
+        if message.is_close() {
+            left_cleanly = matches!(message.close_frame(), Some((1000, _)));
+            break;
+        }
+
+        // while a file is coming in, binary frames are its bytes
+        if message.is_binary() {
+            if let Some(mut current) = upload.take() {
+                if current.data.len() + message.as_bytes().len() > current.size {
+                    reject_upload(&clients, &token, &connection_id, current, "size_mismatch");
+                } else {
+                    current.data.extend_from_slice(message.as_bytes());
+                    upload = Some(current);
+                }
+
+                continue;
+            }
+        }
+
+        let Some(request) = wire::decode_frame::<SocketRequest>(&message) else {
+            continue;
+        };
+
+        match request {
+            Ok(SocketRequest::Message(request)) => {
+                let to = request.to.into_vec();
+
+                if deliver_message(
+                    &clients,
+                    &token,
+                    to.clone(),
+                    request.body,
+                    None,
+                    &config,
+                    &metrics,
+                    &store,
+                    &attachments,
+                    &backplane,
+                )
+                .await
+                .is_err()
+                {
+                    tracing::debug!(?to, "could not deliver socket message");
+                }
+            }
+            Ok(SocketRequest::Control(SocketControl::AttachBegin {
+                to,
+                filename,
+                size,
+                mime,
+                body,
+            })) => {
+                if let Some(previous) = upload.take() {
+                    reject_upload(&clients, &token, &connection_id, previous, "interrupted");
+                }
+
+                let begun = Upload {
+                    to: to.into_vec(),
+                    filename,
+                    mime,
+                    size,
+                    body,
+                    data: Vec::new(),
+                };
+
+                let error = if config.max_attachment_bytes == 0 {
+                    Some("attachments_disabled")
+                } else if size > config.max_attachment_bytes {
+                    Some("too_large")
+                } else if !attachments::is_valid_filename(&begun.filename) {
+                    Some("invalid_filename")
+                } else if !attachments::is_valid_mime(&begun.mime) {
+                    Some("invalid_mime")
+                } else if !attachments::is_allowed_mime(&begun.mime, &config.attachment_mime_types)
+                {
+                    Some("mime_not_allowed")
+                } else {
+                    None
+                };
+
+                match error {
+                    Some(error) => reject_upload(&clients, &token, &connection_id, begun, error),
+                    None => upload = Some(begun),
+                }
+            }
+            Ok(SocketRequest::Control(SocketControl::AttachEnd)) => {
+                let Some(finished) = upload.take() else {
+                    tracing::debug!("attach_end without a file being sent");
+                    continue;
+                };
+
+                if finished.data.len() != finished.size {
+                    reject_upload(&clients, &token, &connection_id, finished, "size_mismatch");
+                    continue;
+                }
+
+                let filename = finished.filename.clone();
+                let info = attachments.insert(attachments::File {
+                    filename: finished.filename,
+                    mime: finished.mime,
+                    data: finished.data.into(),
+                    owner: token.clone(),
+                });
+
+                let Ok(info) = info else {
+                    send_to_connection(
+                        &clients,
+                        &token,
+                        &connection_id,
+                        ServerEvent::AttachFailed {
+                            filename,
+                            error: "too_large",
+                        },
+                    );
+                    continue;
+                };
+
+                let id = info.id.clone();
+
+                // nobody can see the file if the message didn't go out
+                if deliver_message(
+                    &clients,
+                    &token,
+                    finished.to,
+                    finished.body,
+                    Some(Box::new(info)),
+                    &config,
+                    &metrics,
+                    &store,
+                    &attachments,
+                    &backplane,
+                )
+                .await
+                .is_err()
+                {
+                    attachments.remove(&id);
+                    send_to_connection(
+                        &clients,
+                        &token,
+                        &connection_id,
+                        ServerEvent::AttachFailed {
+                            filename,
+                            error: "undeliverable",
+                        },
+                    );
+                }
+            }
+            Err(err) => tracing::warn!(%err, "skipping malformed socket message"),
+        }
+    }
+
+    // disconnected, clean up. dropping the outbox sender and arming the
+    // disconnect timer happen under the same lock, so nothing is queued for
+    // this connection once the timer runs
+
+    tracing::info!(left_cleanly, "client disconnected");
+
+    detach_connection(
+        &clients,
+        &token,
+        &connection_id,
+        left_cleanly,
+        config.clone(),
+        &store,
+        &rooms,
+        &dead_letters,
+        &backplane,
+    );
+
+    // a client that said goodbye on its last socket is done with its name.
+    // an account keeps it for the next login, it just went offline
+    let leaving = left_cleanly
+        .then(|| client_for_token(&clients, &token))
+        .flatten()
+        .filter(|client| !client.is_connected() && client.password_hash.is_none())
+        .map(|client| client.key().clone());
+
+    if let Some(key) = leaving {
+        tracing::info!(name = %key, "client left");
+
+        remove_client(
+            &key,
+            "left",
+            &clients,
+            &rooms,
+            &store,
+            &dead_letters,
+            &backplane,
+        )
+        .await;
+    }
+
+    // with the outbox closed the forwarding task sends the close frame and
+    // ends on its own. one stuck writing to a dead socket is cut short, so it
+    // never outlives the connection
+    if !forwarding_done
+        && tokio::time::timeout(config.pong_timeout, &mut forwarding_task)
+            .await
+            .is_err()
+    {
+        tracing::debug!("forwarding task did not finish, aborting it");
+        forwarding_task.abort();
+        let _ = forwarding_task.await;
+    }
+
+    metrics.active_connections.dec();
+}
+
+/// sends an event to one of the client's connections rather than all of them
+fn send_to_connection(clients: &ClientMap, token: &str, connection_id: &str, event: ServerEvent) {
+    if let Some(client) = client_for_token(clients, token) {
+        if let Some(client_tx) = client.connections.get(connection_id) {
+            let _ = client_tx.send(event);
+        }
+    }
+}
+
+/// lets the connection sending a file know it was turned down
+fn reject_upload(
+    clients: &ClientMap,
+    token: &str,
+    connection_id: &str,
+    upload: Upload,
+    error: &'static str,
+) {
+    tracing::debug!(filename = %upload.filename, error, "attachment rejected");
+
+    send_to_connection(
+        clients,
+        token,
+        connection_id,
+        ServerEvent::AttachFailed {
+            filename: upload.filename,
+            error,
+        },
+    );
+}
+
+/// hands a freshly connected socket or event stream the client's outbox,
+/// flushing whatever was queued while it was away, and brings it online.
+/// given the last sequence number the client saw, everything newer in the
+/// history is replayed instead. one opened with a session is counted as one
+/// of its connections, and isn't attached if the session ended meanwhile
+#[allow(clippy::too_many_arguments)]
+fn attach_connection(
+    clients: &ClientMap,
+    token: &str,
+    session: Option<&str>,
+    connection_id: &str,
+    client_tx: outbox::Sender<ServerEvent>,
+    last_seq: Option<u64>,
+    config: &Config,
+    store: &store::Store,
+) {
+    // the guard locks a whole shard of clients, it's only held for the
+    // in-memory bookkeeping. the store write and the presence broadcast,
+    // which looks up other clients, come after it's dropped. returning
+    // drops the sender, which closes the connection right away
+    let Ok(mut client) = authenticate(clients, token, config) else {
+        tracing::info!("token lapsed before the connection was attached");
+        return;
+    };
+
+    if let Some(session) = session {
+        let Some(session) = client.sessions.get_mut(session) else {
+            tracing::info!("session ended before the connection was attached");
+            return;
+        };
+
+        session.connections.insert(connection_id.to_string());
+        session.connected_at = Some(unix_millis());
+        session.last_active = unix_millis();
+    }
+
+    tracing::Span::current().record("name", client.name.as_str());
+    tracing::info!(
+        pending = client.pending.len(),
+        devices = client.connections.len() + 1,
+        "client connected"
+    );
+
+    if let Some(disconnect_timer) = client.disconnect_timer.take() {
+        disconnect_timer.abort();
+    }
+
+    let missed = match last_seq {
+        Some(last_seq) => client.missed_since(last_seq),
+        None => client.pending.drain(..).collect(),
+    };
+
+    // sending never blocks, a backlog larger than the outbox follows the
+    // overflow policy
+    for event in missed {
+        let _ = client_tx.send(event);
+    }
+
+    client.unread = 0;
+    client.idle = false;
+
+    // the client owns the only sender of this connection, so removing it
+    // closes the connection
+    client
+        .connections
+        .insert(connection_id.to_string(), client_tx);
+    client.last_seen = unix_millis();
+
+    let last_seen = client.last_seen;
+    drop(client);
+
+    store.touch_client(token, last_seen);
+    set_presence(clients, token, Presence::Online);
+}
+
+/// lets the sender of a message know it reached the client holding `token`.
+/// both are looked up by token as either may have been renamed
+fn acknowledge(clients: &ClientMap, token: &str, event: ServerEvent) {
+    let ServerEvent::Message(message) = event else {
+        return;
+    };
+
+    let recipient = client_for_token(clients, token).map(|mut recipient| {
+        recipient.advance_status(&message.id, DeliveryStatus::Delivered);
+        recipient.name.clone()
+    });
+
+    if !message.acknowledge {
+        return;
+    }
+
+    if let (Some(recipient), Some(sender)) = (recipient, client_for_token(clients, &message.sender))
+    {
+        let _ = sender.send(&ServerEvent::Ack {
+            message_id: message.id,
+            to: recipient,
+        });
+    }
+}
+
+/// forgets a connection that went away. once the client has none left it
+/// goes offline, and once the grace period is over it's removed. a client
+/// that left on purpose skips the grace period, the caller removes it
+#[allow(clippy::too_many_arguments)]
+fn detach_connection(
+    clients: &Clients,
+    token: &str,
+    connection_id: &str,
+    left_cleanly: bool,
+    config: Arc<Config>,
+    store: &Arc<store::Store>,
+    rooms: &Rooms,
+    dead_letters: &Arc<deadletters::DeadLetters>,
+    backplane: &Arc<backplane::Backplane>,
+) {
+    if let Some(mut client) = client_for_token(clients, token) {
+        client.connections.remove(connection_id);
+
+        for session in client.sessions.values_mut() {
+            if session.connections.remove(connection_id) {
+                session.last_active = unix_millis();
+            }
+        }
+
+        // the client is still around on its other devices
+        if client.is_connected() {
+            return;
+        }
+
+        // queue messages from now on instead of sending them to a dead socket,
+        // the session window starts sliding again from here
+        client.last_seen = unix_millis();
+        client.expires_at = Some(tokio::time::Instant::now() + config.token_ttl);
+
+        if left_cleanly {
+            let last_seen = client.last_seen;
+            drop(client);
+
+            store.touch_client(token, last_seen);
+            set_presence(clients, token, Presence::Offline);
+            return;
+        }
+
+        let grace = config.disconnect_grace;
+
+        // a quick reconnect aborts the timer, so the client never shows as
+        // offline in between
+        let evict = {
+            let clients = clients.clone();
+            let token = token.to_string();
+            let store = store.clone();
+            let rooms = rooms.clone();
+            let dead_letters = dead_letters.clone();
+            let backplane = backplane.clone();
+
+            async move {
+                tokio::time::sleep(grace).await;
+
+                let key = match client_for_token(&clients, &token) {
+                    Some(mut client) => {
+                        client.disconnect_timer = None;
+
+                        // an account keeps its name for the next login
+                        client.password_hash.is_none().then(|| client.key().clone())
+                    }
+                    None => return,
+                };
+
+                let Some(key) = key else {
+                    set_presence(&clients, &token, Presence::Offline);
+                    return;
+                };
+
+                tracing::info!(name = %key, "grace period over, client removed");
+
+                remove_client(
+                    &key,
+                    "disconnected",
+                    &clients,
+                    &rooms,
+                    &store,
+                    &dead_letters,
+                    &backplane,
+                )
+                .await;
+            }
+        };
+
+        client.disconnect_timer = Some(tokio::spawn(evict));
+
+        let last_seen = client.last_seen;
+        drop(client);
+
+        store.touch_client(token, last_seen);
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct SocketQuery {
+    /// `json` or `msgpack`, wins over the subprotocol
+    pub format: Option<wire::WireFormat>,
+
+    /// sequence number of the last message the client saw before it
+    /// reconnected, also accepted as an `X-Last-Seq` header
+    pub last_seq: Option<u64>,
+}
+
+/// prefix marking the subprotocol entry that carries the token, browsers
+/// can't set headers on a websocket so this is how they authenticate
+const BEARER_PROTOCOL_PREFIX: &str = "bearer.";
+
+/// resolves the token of a socket, preferring an `Authorization: Bearer`
+/// header, then a `bearer.<token>` subprotocol, then the legacy path segment
+/// which leaks the token into access logs. also returns the subprotocol to
+/// accept during the handshake when the client offered any.
+fn socket_credentials(
+    path_token: Option<String>,
+    authorization: Option<String>,
+    protocols: Option<String>,
+) -> Option<(String, Option<String>)> {
+    if let Some(token) = authorization
+        .as_deref()
+        .and_then(|value| value.strip_prefix("Bearer "))
+    {
+        return Some((token.trim().to_string(), None));
+    }
+
+    if let Some(protocols) = protocols.as_deref() {
+        let protocols: Vec<&str> = protocols.split(',').map(str::trim).collect();
+
+        if let Some(bearer) = protocols
+            .iter()
+            .find(|protocol| protocol.starts_with(BEARER_PROTOCOL_PREFIX))
+        {
+            // echo a real protocol if there is one rather than the token
+            let accepted = protocols
+                .iter()
+                .find(|protocol| !protocol.starts_with(BEARER_PROTOCOL_PREFIX))
+                .unwrap_or(bearer);
+
+            return Some((
+                bearer[BEARER_PROTOCOL_PREFIX.len()..].to_string(),
+                Some(accepted.to_string()),
+            ));
+        }
+    }
+
+    path_token.map(|token| (token, None))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn ws_handler(
+    credentials: Option<(String, Option<String>)>,
+    query: SocketQuery,
+    protocols: Option<String>,
+    remote: Option<std::net::SocketAddr>,
+    ws: Ws,
+    clients: Clients,
+    config: Arc<Config>,
+    metrics: Arc<metrics::Metrics>,
+    shutting_down: Arc<AtomicBool>,
+    maintenance: Arc<AtomicBool>,
+    store: Arc<store::Store>,
+    attachments: Arc<attachments::Attachments>,
+    connection_limiter: Arc<connlimit::ConnectionLimiter>,
+    rooms: Rooms,
+    dead_letters: Arc<deadletters::DeadLetters>,
+    backplane: Arc<backplane::Backplane>,
+) -> Result<impl Reply, warp::Rejection> {
+    if shutting_down.load(Ordering::SeqCst) {
+        return Err(warp::reject::custom(ShuttingDown));
+    }
+
+    if maintenance.load(Ordering::SeqCst) {
+        return Err(warp::reject::custom(Maintenance));
+    }
+
+    let (token, mut protocol) = credentials.ok_or_else(|| warp::reject::custom(InvalidToken))?;
+
+    // the socket goes by the client's own token even when given a session,
+    // it's closed along with the session though
+    let (token, session) = {
+        let client = authenticate(&clients, &token, &config)?;
+        let session = client.session_for(&token).map(|(id, _)| id.clone());
+        (client.token.clone(), session)
+    };
+
+    let format = wire::WireFormat::negotiate(query.format, protocols.as_deref());
+
+    let version = wire::ProtocolVersion::negotiate(protocols.as_deref())
+        .map_err(|()| warp::reject::custom(UnsupportedProtocol))?;
+
+    // a client that asked through the subprotocol expects to see it accepted.
+    // only one can be, the version matters more, the frames themselves show
+    // whether messagepack was picked up
+    if let Some(version) = version {
+        protocol = Some(version.protocol().to_string());
+    } else if protocols.as_deref().is_some_and(wire::offers_msgpack) {
+        protocol = Some(wire::MSGPACK_PROTOCOL.to_string());
+    }
+
+    // taken last, so a refused handshake doesn't hold on to one
+    let slot = match remote {
+        Some(remote) => Some(connection_limiter.acquire(remote.ip()).ok_or_else(|| {
+            tracing::info!(%remote, "too many connections from address");
+            warp::reject::custom(TooManyConnections)
+        })?),
+        None => None,
+    };
+
+    let mut response = ws
+        .max_message_size(config.max_message_request_bytes() as usize)
+        .on_upgrade(move |socket| async move {
+            client_connected(
+                token,
+                session,
+                remote,
+                format,
+                version.unwrap_or_default(),
+                query.last_seq,
+                socket,
+                clients,
+                config,
+                metrics,
+                store,
+                attachments,
+                rooms,
+                dead_letters,
+                backplane,
+            )
+            .await;
+
+            // cleaned up, the address may open another socket
+            drop(slot);
+        })
+        .into_response();
+
+    if let Some(protocol) = protocol
+        .as_deref()
+        .and_then(|protocol| warp::http::HeaderValue::from_str(protocol).ok())
+    {
+        response
+            .headers_mut()
+            .insert(warp::http::header::SEC_WEBSOCKET_PROTOCOL, protocol);
+    }
+
+    Ok(response)
+}
+
+/// a live event stream, detaching its connection once hyper drops it,
+/// whether the outbox closed or the client went away
+struct EventStream {
+    token: String,
+    connection_id: String,
+    clients: Clients,
+    config: Arc<Config>,
+    metrics: Arc<metrics::Metrics>,
+    store: Arc<store::Store>,
+    rooms: Rooms,
+    dead_letters: Arc<deadletters::DeadLetters>,
+    backplane: Arc<backplane::Backplane>,
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        tracing::info!(token = %token_prefix(&self.token), "event stream closed");
+        self.metrics.active_connections.dec();
+
+        // a stream can't tell a client leaving from a dropped network
+        detach_connection(
+            &self.clients,
+            &self.token,
+            &self.connection_id,
+            false,
+            self.config.clone(),
+            &self.store,
+            &self.rooms,
+            &self.dead_letters,
+            &self.backplane,
+        );
+    }
+}
+
+/// server-sent events for clients that can't hold a websocket open. the
+/// stream carries the same events as a socket would, sending goes through
+/// `/send_message`
+#[allow(clippy::too_many_arguments)]
+async fn sse_handler(
+    credentials: Option<(String, Option<String>)>,
+    clients: Clients,
+    config: Arc<Config>,
+    metrics: Arc<metrics::Metrics>,
+    shutting_down: Arc<AtomicBool>,
+    maintenance: Arc<AtomicBool>,
+    store: Arc<store::Store>,
+    rooms: Rooms,
+    dead_letters: Arc<deadletters::DeadLetters>,
+    backplane: Arc<backplane::Backplane>,
+) -> Result<impl Reply, warp::Rejection> {
+    if shutting_down.load(Ordering::SeqCst) {
+        return Err(warp::reject::custom(ShuttingDown));
+    }
+
+    if maintenance.load(Ordering::SeqCst) {
+        return Err(warp::reject::custom(Maintenance));
+    }
+
+    let (token, _) = credentials.ok_or_else(|| warp::reject::custom(InvalidToken))?;
+
+    let (token, session) = {
+        let client = authenticate(&clients, &token, &config)?;
+        let session = client.session_for(&token).map(|(id, _)| id.clone());
+        (client.token.clone(), session)
+    };
+
+    let (client_tx, client_rx) = outbox::channel(config.outbox_capacity, config.overflow_policy);
+    let connection_id = uuid::Uuid::new_v4().as_simple().to_string();
+
+    attach_connection(
+        &clients,
+        &token,
+        session.as_deref(),
+        &connection_id,
+        client_tx,
+        None,
+        &config,
+        &store,
+    );
+
+    metrics.connection_opened();
+
+    let stream = EventStream {
+        token,
+        connection_id,
+        clients,
+        config,
+        metrics,
+        store,
+        rooms,
+        dead_letters,
+        backplane,
+    };
+
+    // there's no telling when an event was written, so messages count as
+    // delivered once hyper takes them
+    let events = futures::stream::unfold((client_rx, stream), |(mut client_rx, stream)| async {
+        let event = client_rx.recv().await?;
+        let data = warp::sse::Event::default().json_data(&event);
+
+        acknowledge(&stream.clients, &stream.token, event);
+
+        Some((data, (client_rx, stream)))
+    });
+
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)))
+}
+
+/// runs a message body through the content filter, then escapes it if
+/// configured to
+fn filter_body(config: &Config, body: String) -> Result<String, ChatError> {
+    let body = config
+        .content_filter
+        .apply(body)
+        .map_err(|filter::Blocked| {
+            tracing::debug!("message blocked by the content filter");
+            ChatError::BlockedContent
+        })?;
+
+    if config.escape_html {
+        return Ok(filter::escape_html(&body));
+    }
+
+    Ok(body)
+}
+
+/// delivers a message to every resolvable recipient, queueing it for the ones
+/// that are offline and relaying it to the ones another instance holds.
+/// fails only if none of the recipients could be resolved.
+#[allow(clippy::too_many_arguments)]
+async fn deliver_message(
+    clients: &Clients,
+    token: &str,
+    to: Vec<String>,
+    body: String,
+    attachment: Option<Box<attachments::AttachmentInfo>>,
+    config: &Config,
+    metrics: &metrics::Metrics,
+    store: &Arc<store::Store>,
+    attachments: &Arc<attachments::Attachments>,
+    backplane: &backplane::Backplane,
+) -> Result<MessageResponse, ChatError> {
+    let outgoing = Outgoing {
+        message: prepare_message(clients, token, body, attachment, config, store)?,
+        to,
+        ttl: None,
+    };
+
+    if backplane.is_enabled() {
+        relay_outgoing(
+            outgoing,
+            clients,
+            config,
+            metrics,
+            store,
+            attachments,
+            backplane,
+        )
+        .await
+    } else {
+        send_outgoing(outgoing, clients, config, metrics, store, attachments)
+    }
+}
+
+/// checks a message a client is sending and stamps it, the sender counts as
+/// active from here on
+fn prepare_message(
+    clients: &ClientMap,
+    token: &str,
+    body: String,
+    attachment: Option<Box<attachments::AttachmentInfo>>,
+    config: &Config,
+    store: &store::Store,
+) -> Result<Message, ChatError> {
+    if body.len() > config.max_message_bytes {
+        return Err(ChatError::MessageTooLong);
+    }
+
+    let body = filter_body(config, body)?;
+
+    let mut sender = authenticate(clients, token, config)?;
+    let token = sender.token.clone();
+    sender.throttle_send(config)?;
+    let back_from_idle = sender.touch();
+
+    store.touch_client(&token, sender.last_seen);
+
+    let sender_name = sender.name.clone();
+    drop(sender);
+
+    if back_from_idle {
+        set_presence(clients, &token, Presence::Online);
+    }
+
+    Ok(Message {
+        id: uuid::Uuid::new_v4().as_simple().to_string(),
+        sender: token,
+        from: sender_name,
+        body,
+        timestamp: unix_millis(),
+        room: None,
+        attachment,
+        acknowledge: true,
+        ..Default::default()
+    })
+}
+
+/// hands a prepared message to each of `to`
+fn fan_out(
+    clients: &ClientMap,
+    message: &Message,
+    to: Vec<String>,
+    config: &Config,
+    metrics: &metrics::Metrics,
+    store: &store::Store,
+) -> Result<MessageResponse, ChatError> {
+    let sender_name = &message.from;
+    let mut seen = HashSet::new();
+    let mut response = MessageResponse {
+        message_id: message.id.clone(),
+        ..Default::default()
+    };
+
+    for name in to {
+        let key = name_key(&name);
+
+        if !seen.insert(key.clone()) {
+            continue;
+        }
+
+        let Some(mut client) = clients.get_mut(&key) else {
+            tracing::debug!(to = %name, "unknown recipient");
+            metrics.delivery_failures.inc();
+            response.unknown.push(name);
+            continue;
+        };
+
+        // reported as delivered so the sender can't tell it was blocked
+        if client.blocked.contains(&message.sender) {
+            tracing::debug!(from = %sender_name, to = %name, "sender is blocked, message dropped");
+            response.delivered.push(name);
+            continue;
+        }
+
+        // most likely a retry without an idempotency key, the recipient
+        // would find the same message twice once back
+        if !client.is_connected() && client.is_queued_duplicate(message, config.dedup_window) {
+            tracing::debug!(from = %sender_name, to = %name, "same message already queued, dropped");
+            response.queued.push(name);
+            continue;
+        }
+
+        let message = client.sequenced(message);
+
+        client.push_history(message.clone(), &config.history_caps);
+        clients.index_message(&message.id, &client.token);
+        store.insert_message(&client.token, &message);
+
+        match client.send_or_queue(ServerEvent::Message(message.clone())) {
+            Delivery::Sent => {
+                tracing::debug!(from = %sender_name, to = %name, "message delivered");
+                response.delivered.push(name);
+            }
+            Delivery::Queued => {
+                tracing::debug!(from = %sender_name, to = %name, "recipient offline, message queued");
+                client.unread += 1;
+                response.queued.push(name);
+            }
+            // dropped by the overflow policy, it's still in the history
+            Delivery::Dropped => {
+                tracing::warn!(to = %name, "outbox full, message dropped");
+                metrics.delivery_failures.inc();
+                response.delivered.push(name);
+            }
+        }
+
+        metrics.record_sent(1);
+    }
+
+    if response.delivered.is_empty() && response.queued.is_empty() {
+        return Err(ChatError::UnknownRecipient);
+    }
+
+    notify_mentions(clients, message, |_| true);
+
+    Ok(response)
+}
+
+async fn handle_send_message(
+    request: MessageRequest,
+    state: State,
+) -> Result<impl Reply, warp::Rejection> {
+    Ok(warp::reply::json(&send_message(&state, request).await?))
+}
+
+/// sends a message right away or schedules it, and answers a retry carrying
+/// the same idempotency key with the response to the first try
+async fn send_message(
+    state: &State,
+    mut request: MessageRequest,
+) -> Result<MessageResponse, ChatError> {
+    let State {
+        clients, config, ..
+    } = state;
+
+    let token = authenticate(clients, &request.token, config)?.token.clone();
+
+    let Some(key) = request.idempotency_key.take() else {
+        return send_claimed(state, &token, request).await;
+    };
+
+    match authenticate(clients, &token, config)?.claim_key(&key) {
+        Claim::Claimed => {}
+        Claim::Sent(response) => {
+            tracing::debug!(message_id = %response.message_id, "repeated send, not delivering again");
+            return Ok(response);
+        }
+        Claim::InFlight => return Err(ChatError::SendInProgress),
+    }
+
+    let result = send_claimed(state, &token, request).await;
+
+    if let Some(mut sender) = client_for_token(clients, &token) {
+        sender.settle_key(&key, result.as_ref().ok().cloned());
+    }
+
+    result
+}
+
+/// the part of `send_message` that runs once any idempotency key is claimed
+async fn send_claimed(
+    state: &State,
+    token: &str,
+    request: MessageRequest,
+) -> Result<MessageResponse, ChatError> {
+    let State {
+        clients,
+        config,
+        metrics,
+        store,
+        attachments,
+        scheduled,
+        backplane,
+        ..
+    } = state;
+
+    // someone else's file is as unknown as one that doesn't exist
+    let attachment = request
+        .attachment
+        .as_deref()
+        .map(|id| {
+            attachments
+                .info_for(id, token)
+                .map(Box::new)
+                .ok_or(ChatError::UnknownAttachment)
+        })
+        .transpose()?;
+
+    let ttl = match request.ttl_secs {
+        Some(0) => return Err(ChatError::InvalidTtl),
+        Some(secs) if secs > MAX_MESSAGE_TTL.as_secs() => return Err(ChatError::InvalidTtl),
+        secs => secs.map(tokio::time::Duration::from_secs),
+    };
+
+    let outgoing = Outgoing {
+        message: prepare_message(clients, token, request.body, attachment, config, store)?,
+        to: request.to.into_vec(),
+        ttl,
+    };
+
+    let response = match request.deliver_at {
+        Some(deliver_at) if deliver_at > unix_millis() => {
+            let response = MessageResponse {
+                message_id: outgoing.message.id.clone(),
+                deliver_at: Some(deliver_at),
+                ..Default::default()
+            };
+
+            tracing::debug!(message_id = %response.message_id, deliver_at, "message scheduled");
+            scheduled.insert(deliver_at, outgoing);
+
+            response
+        }
+        _ if backplane.is_enabled() => {
+            relay_outgoing(
+                outgoing,
+                clients,
+                config,
+                metrics,
+                store,
+                attachments,
+                backplane,
+            )
+            .await?
+        }
+        _ => send_outgoing(outgoing, clients, config, metrics, store, attachments)?,
+    };
+
+    Ok(response)
+}
+
+/// a prepared message on its way to its recipients, waiting in the schedule
+/// when it was sent for later
+#[derive(Debug)]
+struct Outgoing {
+    message: Message,
+    to: Vec<String>,
+
+    /// counted from the moment the message is delivered
+    ttl: Option<tokio::time::Duration>,
+}
+
+type Scheduled = Arc<schedule::Schedule<Outgoing>>;
+
+fn send_outgoing(
+    outgoing: Outgoing,
+    clients: &Clients,
+    config: &Config,
+    metrics: &metrics::Metrics,
+    store: &Arc<store::Store>,
+    attachments: &Arc<attachments::Attachments>,
+) -> Result<MessageResponse, ChatError> {
+    let Outgoing {
+        mut message,
+        to,
+        ttl,
+    } = outgoing;
+
+    message.expires_at = ttl.map(|ttl| unix_millis() + ttl.as_millis() as i64);
+
+    let response = fan_out(clients, &message, to, config, metrics, store)?;
+
+    if let Some(ttl) = ttl {
+        tokio::spawn(expire_message(
+            clients.clone(),
+            store.clone(),
+            message.id,
+            message.sender,
+            message.attachment.map(|attachment| attachment.id),
+            attachments.clone(),
+            ttl,
+        ));
+    }
+
+    Ok(response)
+}
+
+/// sends `outgoing` to the recipients this instance holds and relays it to
+/// the ones another instance holds. a time to live only runs out on this
+/// instance, relayed copies just carry the time they expire at
+async fn relay_outgoing(
+    mut outgoing: Outgoing,
+    clients: &Clients,
+    config: &Config,
+    metrics: &metrics::Metrics,
+    store: &Arc<store::Store>,
+    attachments: &Arc<attachments::Attachments>,
+    backplane: &backplane::Backplane,
+) -> Result<MessageResponse, ChatError> {
+    let (local, mut elsewhere): (Vec<_>, Vec<_>) = std::mem::take(&mut outgoing.to)
+        .into_iter()
+        .partition(|name| clients.get(&name_key(name)).is_some());
+
+    let mut seen = HashSet::new();
+    elsewhere.retain(|name| seen.insert(name_key(name)));
+
+    let mut message = outgoing.message.clone();
+    message.expires_at = outgoing
+        .ttl
+        .map(|ttl| unix_millis() + ttl.as_millis() as i64);
+
+    let relayed = backplane.relay(&message, elsewhere.clone()).await;
+    let unknown: Vec<String> = elsewhere
+        .into_iter()
+        .filter(|name| !relayed.contains(name))
+        .collect();
+
+    metrics.delivery_failures.inc_by(unknown.len() as u64);
+
+    let mut response = if local.is_empty() {
+        if relayed.is_empty() {
+            return Err(ChatError::UnknownRecipient);
+        }
+
+        MessageResponse {
+            message_id: message.id,
+            ..Default::default()
+        }
+    } else {
+        outgoing.to = local;
+        send_outgoing(outgoing, clients, config, metrics, store, attachments)?
+    };
+
+    response.unknown.extend(unknown);
+    response.relayed = relayed;
+
+    Ok(response)
+}
+
+/// delivers a message another instance relayed for the client under name
+/// key `key`, if this instance holds it. every instance hears every relayed
+/// message, most are for someone else
+fn deliver_relayed(
+    clients: &ClientMap,
+    key: &str,
+    relayed: backplane::Relayed,
+    config: &Config,
+    metrics: &metrics::Metrics,
+    store: &store::Store,
+) {
+    let Some(name) = clients.get(key).map(|client| client.name.clone()) else {
+        return;
+    };
+
+    let mut message = relayed.message;
+    message.sender = relayed.sender;
+
+    if fan_out(clients, &message, vec![name], config, metrics, store).is_err() {
+        tracing::debug!(message_id = %message.id, "relayed message not delivered");
+    }
+}
+
+/// sends scheduled messages as they fall due. recipients that went offline
+/// in the meantime get them queued like any other message, a sender that's
+/// gone by then takes its scheduled messages along
+async fn deliver_scheduled(
+    scheduled: Scheduled,
+    clients: Clients,
+    config: Arc<Config>,
+    metrics: Arc<metrics::Metrics>,
+    store: Arc<store::Store>,
+    attachments: Arc<attachments::Attachments>,
+) {
+    loop {
+        let outgoing = scheduled.next_due().await;
+        let message_id = outgoing.message.id.clone();
+
+        if client_for_token(&clients, &outgoing.message.sender).is_none() {
+            tracing::debug!(%message_id, "sender is gone, dropping scheduled message");
+            continue;
+        }
+
+        match send_outgoing(outgoing, &clients, &config, &metrics, &store, &attachments) {
+            Ok(_) => tracing::debug!(%message_id, "scheduled message delivered"),
+            Err(_) => tracing::debug!(%message_id, "no recipient of a scheduled message is left"),
+        }
+    }
+}
+
+/// longest time to live a message may ask for
+const MAX_MESSAGE_TTL: tokio::time::Duration = tokio::time::Duration::from_secs(7 * 24 * 60 * 60);
+
+/// waits out the time to live of a message, then removes it from every
+/// history and queue it's in, the store included, along with its attachment.
+/// the sender and the connected recipients are told, offline ones simply
+/// never see it
+async fn expire_message(
+    clients: Clients,
+    store: Arc<store::Store>,
+    message_id: String,
+    sender: String,
+    attachment_id: Option<String>,
+    attachments: Arc<attachments::Attachments>,
+    ttl: tokio::time::Duration,
+) {
+    tokio::time::sleep(ttl).await;
+
+    let event = ServerEvent::Expire {
+        message_id: message_id.clone(),
+    };
+
+    store.remove_message(&message_id);
+
+    for mut client in clients.iter_mut() {
+        if client.remove_message(&message_id) || client.token == sender {
+            let _ = client.send(&event);
+        }
+    }
+
+    if let Some(id) = attachment_id {
+        attachments.remove(&id);
+    }
+
+    tracing::debug!(%message_id, "message expired");
+}
+
+/// delivers a message to every connected client, the sender included, and
+/// answers with how many it reached
+async fn handle_broadcast(
+    request: BroadcastRequest,
+    clients: Clients,
+    config: Arc<Config>,
+    metrics: Arc<metrics::Metrics>,
+) -> Result<impl Reply, warp::Rejection> {
+    if request.body.len() > config.max_message_bytes {
+        return Err(warp::reject::custom(MessageTooLong));
+    }
+
+    let body = filter_body(&config, request.body)?;
+
+    let mut sender = authenticate(&clients, &request.token, &config)?;
+    let token = sender.token.clone();
+    sender.throttle_send(&config)?;
+    let back_from_idle = sender.touch();
+
+    let sender_name = sender.name.clone();
+    drop(sender);
+
+    if back_from_idle {
+        set_presence(&clients, &token, Presence::Online);
+    }
+
+    let message = Message {
+        id: uuid::Uuid::new_v4().as_simple().to_string(),
+        sender: token.clone(),
+        from: sender_name.clone(),
+        body,
+        timestamp: unix_millis(),
+        room: None,
+        acknowledge: false,
+        ..Default::default()
+    };
+
+    // clients without a live socket are skipped instead of failing the
+    // request
+    let mut delivered = 0;
+
+    for mut client in clients.iter_mut() {
+        if !client.is_connected() {
+            continue;
+        }
+
+        let event = ServerEvent::Message(client.sequenced(&message));
+
+        if client.send(&event).is_ok() {
+            delivered += 1;
+        }
+    }
+
+    notify_mentions(&clients, &message, |_| true);
+
+    tracing::debug!(from = %sender_name, delivered, "broadcast delivered");
+    metrics.record_sent(delivered as u64);
+
+    Ok(warp::reply::json(&BroadcastResponse { delivered }))
+}
+
+/// longest accepted report reason, in characters
+const MAX_REPORT_REASON_LENGTH: usize = 500;
+
+/// flags a message the client received for the moderators, along with a copy
+/// of it as it reads now
+async fn handle_report(
+    request: ReportRequest,
+    clients: Clients,
+    reports: Arc<reports::Reports>,
+    config: Arc<Config>,
+) -> Result<impl Reply, warp::Rejection> {
+    let reason = request.reason.trim();
+
+    if reason.is_empty() || reason.chars().count() > MAX_REPORT_REASON_LENGTH {
+        return Err(warp::reject::custom(InvalidReason));
+    }
+
+    let reporter = authenticate(&clients, &request.token, &config)?;
+
+    let message = reporter
+        .history
+        .iter()
+        .find(|message| message.id == request.message_id)
+        .ok_or_else(|| warp::reject::custom(UnknownMessage))?;
+
+    let report_id = reports.insert(reports::Report {
+        id: uuid::Uuid::new_v4().as_simple().to_string(),
+        reporter: reporter.name.clone(),
+        message_id: message.id.clone(),
+        sender: message.from.clone(),
+        body: message.body.clone(),
+        reason: reason.to_string(),
+        timestamp: unix_millis(),
+        resolved: false,
+        reporter_token: reporter.token.clone(),
+    });
+
+    tracing::info!(%report_id, reporter = %reporter.name, "message reported");
+
+    Ok(warp::reply::json(&ReportResponse { report_id }))
+}
+
+/// the token of the client called `name`, for blocking and unblocking it.
+/// looked up before the requesting client is locked, both may live in the
+/// same shard
+fn blocked_token(clients: &ClientMap, name: &str) -> Result<String, warp::Rejection> {
+    clients
+        .get(&name_key(name))
+        .map(|client| client.token.clone())
+        .ok_or_else(|| warp::reject::custom(UnknownClient))
+}
+
+/// stops direct messages, typing notices and mentions from another client.
+/// its sends keep succeeding as far as it can tell
+async fn handle_block(
+    request: BlockRequest,
+    clients: Clients,
+    config: Arc<Config>,
+    store: Arc<store::Store>,
+) -> Result<impl Reply, warp::Rejection> {
+    let blocked = blocked_token(&clients, &request.name)?;
+    let mut client = authenticate(&clients, &request.token, &config)?;
+
+    if client.blocked.insert(blocked.clone()) {
+        store.insert_block(&client.token, &blocked);
+        tracing::debug!(name = %client.name, blocked = %request.name, "client blocked");
+    }
+
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+async fn handle_unblock(
+    request: BlockRequest,
+    clients: Clients,
+    config: Arc<Config>,
+    store: Arc<store::Store>,
+) -> Result<impl Reply, warp::Rejection> {
+    let blocked = blocked_token(&clients, &request.name)?;
+    let mut client = authenticate(&clients, &request.token, &config)?;
+
+    if client.blocked.remove(&blocked) {
+        store.remove_block(&client.token, &blocked);
+        tracing::debug!(name = %client.name, unblocked = %request.name, "client unblocked");
+    }
+
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+/// moves a client to a new name, keeping its token, history and queues
+async fn handle_rename(
+    request: RenameRequest,
+    clients: Clients,
+    config: Arc<Config>,
+    bans: BanList,
+    store: Arc<store::Store>,
+    backplane: Arc<backplane::Backplane>,
+) -> Result<impl Reply, warp::Rejection> {
+    let name = normalize_name(&request.name).map_err(|error| {
+        tracing::debug!(error, "invalid name");
+        warp::reject::custom(InvalidName)
+    })?;
+
+    let key = name_key(&name);
+
+    if config.reserved_names.contains(&key) {
+        return Err(warp::reject::custom(ReservedName));
+    }
+
+    if bans.lock().await.names.contains(&key) {
+        return Err(warp::reject::custom(BannedName));
+    }
+
+    let (token, old_name) = {
+        let client = authenticate(&clients, &request.token, &config)?;
+        (client.token.clone(), client.name.clone())
+    };
+    let old_key = name_key(&old_name);
+
+    if key == old_key {
+        // changing only the case keeps the key
+        let mut client = clients
+            .get_mut(&key)
+            .ok_or_else(|| warp::reject::custom(InvalidToken))?;
+        client.name = name.clone();
+    } else {
+        let claimed = backplane
+            .claim_name(&key)
+            .await
+            .map_err(|backplane::Unavailable| warp::reject::custom(BackplaneUnavailable))?;
+
+        if !claimed {
+            return Err(warp::reject::custom(NameTaken));
+        }
+
+        let Some((_, mut client)) = clients.remove(&old_key) else {
+            backplane.release_name(&key);
+            return Err(warp::reject::custom(InvalidToken));
+        };
+
+        client.name = name.clone();
+
+        // put it back under its old name, the new one must be free
+        if let Err(mut client) = clients.try_insert(key, client).map(drop) {
+            client.name = old_name;
+            clients.insert(old_key, *client);
+            return Err(warp::reject::custom(NameTaken));
+        }
+
+        backplane.release_name(&old_key);
+    }
+
+    store.rename_client(&token, &name);
+
+    for mut client in clients.iter_mut() {
+        client.rename_reactor(&token, &name);
+    }
+
+    tracing::info!(from = %old_name, to = %name, "client renamed");
+
+    for client in clients.iter().filter(|client| client.token != token) {
+        let _ = client.send(&ServerEvent::Rename {
+            from: old_name.clone(),
+            to: name.clone(),
+        });
+    }
+
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+async fn handle_logout(
+    request: LogoutRequest,
+    clients: Clients,
+    rooms: Rooms,
+    store: Arc<store::Store>,
+    dead_letters: Arc<deadletters::DeadLetters>,
+    backplane: Arc<backplane::Backplane>,
+    config: Arc<Config>,
+) -> Result<impl Reply, warp::Rejection> {
+    let client = authenticate(&clients, &request.token, &config)?;
+    let name = client.key().clone();
+    let own_token = client.token == request.token;
+    drop(client);
+
+    // an account with a password stays for the next login, only the session
+    // ends
+    if !own_token {
+        clients.end_session(&request.token);
+        tracing::info!(%name, "session ended");
+
+        return Ok(warp::reply::with_status(
+            warp::reply(),
+            warp::http::StatusCode::NO_CONTENT,
+        ));
+    }
+
+    tracing::info!(%name, "client logged out");
+
+    remove_client(
+        &name,
+        "logged_out",
+        &clients,
+        &rooms,
+        &store,
+        &dead_letters,
+        &backplane,
+    )
+    .await;
+
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+/// deletes the account for good, along with everything kept for it: the
+/// history and pending queue, in memory and in the store, and its rooms
+async fn handle_delete_account(
+    request: LogoutRequest,
+    clients: Clients,
+    rooms: Rooms,
+    config: Arc<Config>,
+    store: Arc<store::Store>,
+    dead_letters: Arc<deadletters::DeadLetters>,
+    backplane: Arc<backplane::Backplane>,
+) -> Result<impl Reply, warp::Rejection> {
+    let (name, token) = {
+        let client = authenticate(&clients, &request.token, &config)?;
+        (client.key().clone(), client.token.clone())
+    };
+
+    tracing::info!(%name, "account deleted");
+
+    remove_client(
+        &name,
+        "account_deleted",
+        &clients,
+        &rooms,
+        &store,
+        &dead_letters,
+        &backplane,
+    )
+    .await;
+
+    // any other way of going away leaves the stored history be
+    store.remove_history(&token);
+
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+/// forgets the client under name key `key` along with its room memberships.
+/// dropping the client drops its senders, which closes its sockets. direct
+/// messages it never received become dead letters, for `reason`
+async fn remove_client(
+    key: &str,
+    reason: &'static str,
+    clients: &ClientMap,
+    rooms: &Rooms,
+    store: &store::Store,
+    dead_letters: &deadletters::DeadLetters,
+    backplane: &backplane::Backplane,
+) -> bool {
+    let Some((_, mut client)) = clients.remove(key) else {
+        return false;
+    };
+
+    store.remove_client(&client.token);
+    backplane.release_name(key);
+    bury_pending(
+        clients,
+        &client.name,
+        std::mem::take(&mut client.pending),
+        reason,
+        dead_letters,
+    );
+
+    if let Some(disconnect_timer) = client.disconnect_timer {
+        disconnect_timer.abort();
+    }
+
+    if client.status != Presence::Offline {
+        broadcast_presence(
+            clients,
+            &client.name,
+            Presence::Offline,
+            client.status_message.as_deref(),
+        );
+    }
+
+    rooms.lock().await.retain(|_, room| {
+        room.remove_member(&client.token);
+        !room.members.is_empty()
+    });
+
+    true
+}
+
+/// records the direct messages still queued for `to`, a client that's going
+/// away, as dead letters and tells their senders, those that are connected,
+/// that they won't arrive. the client must already be out of the map
+fn bury_pending(
+    clients: &ClientMap,
+    to: &str,
+    pending: VecDeque<ServerEvent>,
+    reason: &'static str,
+    dead_letters: &deadletters::DeadLetters,
+) {
+    for event in pending {
+        let ServerEvent::Message(message) = event else {
+            continue;
+        };
+
+        // rooms keep their own history, and a deleted message was never
+        // going to be read anyway
+        if message.room.is_some() || message.deleted {
+            continue;
+        }
+
+        tracing::debug!(message_id = %message.id, to, reason, "dead letter");
+
+        if let Some(sender) = client_for_token(clients, &message.sender) {
+            let _ = sender.send(&ServerEvent::Undeliverable {
+                message_id: message.id.clone(),
+                reason,
+            });
+        }
+
+        dead_letters.insert(deadletters::DeadLetter {
+            message_id: message.id,
+            from: message.from,
+            to: to.to_string(),
+            body: message.body,
+            reason,
+            timestamp: unix_millis(),
+        });
+    }
+}
+
+/// makes sure `token` is the configured admin token. compared in constant
+/// time, so the time a guess takes says nothing about how close it was
+fn check_admin(config: &Config, token: &str) -> Result<(), warp::Rejection> {
+    match config.admin_token.as_deref() {
+        Some(admin_token)
+            if ring::constant_time::verify_slices_are_equal(
+                admin_token.as_bytes(),
+                token.as_bytes(),
+            )
+            .is_ok() =>
+        {
+            Ok(())
+        }
+        _ => Err(warp::reject::custom(InvalidToken)),
+    }
+}
+
+/// disconnects a client and removes it for good, it has to register again
+#[allow(clippy::too_many_arguments)]
+async fn handle_kick(
+    request: KickRequest,
+    remote: Option<std::net::SocketAddr>,
+    clients: Clients,
+    rooms: Rooms,
+    config: Arc<Config>,
+    store: Arc<store::Store>,
+    audit: Arc<audit::AuditLog>,
+    dead_letters: Arc<deadletters::DeadLetters>,
+    backplane: Arc<backplane::Backplane>,
+) -> Result<impl Reply, warp::Rejection> {
+    check_admin(&config, &request.admin_token)?;
+
+    let key = name_key(&request.name);
+
+    // tell the client why before its sockets close, they flush first
+    if let Some(client) = clients.get(&key) {
+        let _ = client.send(&ServerEvent::System {
+            body: "You were kicked by a moderator".to_string(),
+        });
+    }
+
+    let removed = remove_client(
+        &key,
+        "kicked",
+        &clients,
+        &rooms,
+        &store,
+        &dead_letters,
+        &backplane,
+    )
+    .await;
+
+    if !removed {
+        return Err(warp::reject::custom(UnknownClient));
+    }
+
+    tracing::info!(name = %key, "client kicked");
+    audit.record(audit::Action::Kick, remote, &key);
+
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+/// turns maintenance mode on or off. while it's on nobody new gets in, but
+/// clients that are already connected carry on as usual
+async fn handle_maintenance(
+    request: MaintenanceRequest,
+    remote: Option<std::net::SocketAddr>,
+    maintenance: Arc<AtomicBool>,
+    config: Arc<Config>,
+    audit: Arc<audit::AuditLog>,
+) -> Result<impl Reply, warp::Rejection> {
+    check_admin(&config, &request.admin_token)?;
+
+    maintenance.store(request.enabled, Ordering::SeqCst);
+
+    tracing::info!(enabled = request.enabled, "maintenance mode toggled");
+    audit.record(
+        audit::Action::Maintenance,
+        remote,
+        if request.enabled { "on" } else { "off" },
+    );
+
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+/// sends a notice from the server to every connected client
+async fn handle_announce(
+    request: AnnounceRequest,
+    remote: Option<std::net::SocketAddr>,
+    clients: Clients,
+    config: Arc<Config>,
+    audit: Arc<audit::AuditLog>,
+) -> Result<impl Reply, warp::Rejection> {
+    check_admin(&config, &request.admin_token)?;
+
+    if request.body.len() > config.max_message_bytes {
+        return Err(warp::reject::custom(MessageTooLong));
+    }
+
+    let event = ServerEvent::System { body: request.body };
+
+    let delivered = clients
+        .iter()
+        .filter(|client| client.send(&event).is_ok())
+        .count();
+
+    tracing::info!(delivered, "announcement sent");
+    audit.record(audit::Action::Announce, remote, "everyone");
+
+    Ok(warp::reply::json(&BroadcastResponse { delivered }))
+}
+
+/// makes sure an `Authorization` header carries the admin token as a bearer
+/// token, for admin requests without a body
+fn check_admin_header(config: &Config, authorization: Option<&str>) -> Result<(), warp::Rejection> {
+    let token = authorization
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    check_admin(config, token.trim())
+}
+
+/// a snapshot of the server for admins, without scraping the metrics. the
+/// admin token goes in an `Authorization: Bearer` header
+async fn handle_stats(
+    authorization: Option<String>,
+    started_at: tokio::time::Instant,
+    clients: Clients,
+    metrics: Arc<metrics::Metrics>,
+    config: Arc<Config>,
+) -> Result<impl Reply, warp::Rejection> {
+    check_admin_header(&config, authorization.as_deref())?;
+
+    let mut stats = StatsResponse {
+        registered: 0,
+        connected: 0,
+        in_grace: 0,
+        messages_delivered: metrics.messages_sent.get(),
+        uptime_secs: started_at.elapsed().as_secs(),
+        history_messages: 0,
+        history_bytes: 0,
+    };
+
+    for client in clients.iter() {
+        stats.registered += 1;
+        stats.history_messages += client.history.len();
+        stats.history_bytes += client.history_bytes();
+
+        if client.is_connected() {
+            stats.connected += 1;
+        } else if client.disconnect_timer.is_some() {
+            stats.in_grace += 1;
+        }
+    }
+
+    Ok(warp::reply::json(&stats))
+}
+
+async fn handle_list_reports(
+    authorization: Option<String>,
+    reports: Arc<reports::Reports>,
+    config: Arc<Config>,
+) -> Result<impl Reply, warp::Rejection> {
+    check_admin_header(&config, authorization.as_deref())?;
+
+    Ok(warp::reply::json(&reports.list()))
+}
+
+async fn handle_dead_letters(
+    authorization: Option<String>,
+    dead_letters: Arc<deadletters::DeadLetters>,
+    config: Arc<Config>,
+) -> Result<impl Reply, warp::Rejection> {
+    check_admin_header(&config, authorization.as_deref())?;
+
+    Ok(warp::reply::json(&dead_letters.list()))
+}
+
+async fn handle_audit(
+    authorization: Option<String>,
+    audit: Arc<audit::AuditLog>,
+    config: Arc<Config>,
+) -> Result<impl Reply, warp::Rejection> {
+    check_admin_header(&config, authorization.as_deref())?;
+
+    Ok(warp::reply::json(&audit.list()))
+}
+
+async fn handle_resolve_report(
+    id: String,
+    request: ResolveReportRequest,
+    remote: Option<std::net::SocketAddr>,
+    reports: Arc<reports::Reports>,
+    config: Arc<Config>,
+    audit: Arc<audit::AuditLog>,
+) -> Result<impl Reply, warp::Rejection> {
+    check_admin(&config, &request.admin_token)?;
+
+    if !reports.resolve(&id) {
+        return Err(warp::reject::custom(UnknownReport));
+    }
+
+    tracing::info!(report_id = %id, "report resolved");
+    audit.record(audit::Action::ResolveReport, remote, &id);
+
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+/// keeps a name or address from registering again. clients already holding
+/// a banned name stay until they're kicked.
+async fn handle_ban(
+    request: BanRequest,
+    remote: Option<std::net::SocketAddr>,
+    bans: BanList,
+    config: Arc<Config>,
+    store: Arc<store::Store>,
+    audit: Arc<audit::AuditLog>,
+) -> Result<impl Reply, warp::Rejection> {
+    check_admin(&config, &request.admin_token)?;
+
+    if request.name.is_none() && request.ip.is_none() {
+        return Err(warp::reject::custom(InvalidBan));
+    }
+
+    let mut bans = bans.lock().await;
+
+    if let Some(name) = request.name.as_deref() {
+        let key = name_key(name);
+
+        store.insert_ban(store::BAN_NAME, &key);
+        bans.names.insert(key);
+    }
+
+    if let Some(ip) = request.ip {
+        store.insert_ban(store::BAN_ADDR, &ip.to_string());
+        bans.addrs.insert(ip);
+    }
+
+    tracing::info!(name = ?request.name, ip = ?request.ip, "banned");
+    audit.record(audit::Action::Ban, remote, &request.target());
+
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+async fn handle_unban(
+    request: BanRequest,
+    remote: Option<std::net::SocketAddr>,
+    bans: BanList,
+    config: Arc<Config>,
+    store: Arc<store::Store>,
+    audit: Arc<audit::AuditLog>,
+) -> Result<impl Reply, warp::Rejection> {
+    check_admin(&config, &request.admin_token)?;
+
+    if request.name.is_none() && request.ip.is_none() {
+        return Err(warp::reject::custom(InvalidBan));
+    }
+
+    let mut bans = bans.lock().await;
+
+    if let Some(name) = request.name.as_deref() {
+        let key = name_key(name);
+
+        store.remove_ban(store::BAN_NAME, &key);
+        bans.names.remove(&key);
+    }
+
+    if let Some(ip) = request.ip {
+        store.remove_ban(store::BAN_ADDR, &ip.to_string());
+        bans.addrs.remove(&ip);
+    }
+
+    tracing::info!(name = ?request.name, ip = ?request.ip, "unbanned");
+    audit.record(audit::Action::Unban, remote, &request.target());
+
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+async fn handle_presence(
+    request: PresenceRequest,
+    clients: Clients,
+    config: Arc<Config>,
+) -> Result<impl Reply, warp::Rejection> {
+    // control characters are dropped rather than refused, they're most
+    // likely a pasted newline, which becomes a space
+    let status_message = request
+        .status_message
+        .map(|status_message| {
+            let status_message: String = status_message
+                .chars()
+                .filter_map(|c| match c {
+                    c if c.is_control() && c.is_whitespace() => Some(' '),
+                    c if c.is_control() => None,
+                    c => Some(c),
+                })
+                .collect::<String>()
+                .trim()
+                .to_string();
+
+            if status_message.chars().count() > MAX_STATUS_MESSAGE_LENGTH {
+                return Err(warp::reject::custom(StatusMessageTooLong));
+            }
+
+            filter_body(&config, status_message).map_err(warp::Rejection::from)
+        })
+        .transpose()?;
+
+    let mut client = authenticate(&clients, &request.token, &config)?;
+
+    // whatever the client says wins over it having gone idle
+    client.idle = false;
+
+    let mut changed = client.status != request.status;
+    client.status = request.status;
+
+    if let Some(status_message) = status_message {
+        let status_message = Some(status_message).filter(|message| !message.is_empty());
+
+        changed |= client.status_message != status_message;
+        client.status_message = status_message;
+    }
+
+    let name = client.name.clone();
+    let status_message = client.status_message.clone();
+    drop(client);
+
+    if changed {
+        broadcast_presence(&clients, &name, request.status, status_message.as_deref());
+    }
+
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+/// passes a typing notice to the recipient's socket. it isn't kept anywhere,
+/// a recipient that's offline simply never sees it
+async fn handle_typing(
+    request: TypingRequest,
+    clients: Clients,
+    config: Arc<Config>,
+) -> Result<impl Reply, warp::Rejection> {
+    let (token, from) = {
+        let client = authenticate(&clients, &request.token, &config)?;
+        (client.token.clone(), client.name.clone())
+    };
+
+    let recipient = clients
+        .get(&name_key(&request.to))
+        .ok_or_else(|| warp::reject::custom(UnknownRecipient))?;
+
+    if !recipient.blocked.contains(&token) {
+        let _ = recipient.send(&ServerEvent::Typing { from });
+    }
+
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+/// sends a read receipt to the sender of a message in the reader's history
+async fn handle_read(
+    request: ReadRequest,
+    clients: Clients,
+    config: Arc<Config>,
+) -> Result<impl Reply, warp::Rejection> {
+    let mut reader = authenticate(&clients, &request.token, &config)?;
+
+    let sender = reader
+        .history
+        .iter()
+        .find(|message| message.id == request.message_id)
+        .map(|message| message.sender.clone())
+        .ok_or_else(|| warp::reject::custom(UnknownMessage))?;
+
+    reader.advance_status(&request.message_id, DeliveryStatus::Read);
+
+    let by = reader.name.clone();
+    drop(reader);
+
+    // the sender may have logged out since, then there's nobody to tell
+    if let Some(mut sender) = client_for_token(&clients, &sender) {
+        let delivery = sender.send_or_queue(ServerEvent::Read {
+            message_id: request.message_id,
+            by,
+        });
+
+        tracing::debug!(to = %sender.name, ?delivery, "read receipt sent");
+    }
+
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+/// where a direct message stands with each of its recipients, for its
+/// sender only. the token goes in an `Authorization: Bearer` header
+async fn handle_message_status(
+    id: String,
+    authorization: Option<String>,
+    clients: Clients,
+    config: Arc<Config>,
+) -> Result<impl Reply, warp::Rejection> {
+    let token = authorization
+        .as_deref()
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        .ok_or_else(|| warp::reject::custom(InvalidToken))?;
+
+    let token = authenticate(&clients, token, &config)?.token.clone();
+
+    let mut sender = None;
+    let mut recipients = std::collections::BTreeMap::new();
+
+    // only the clients the index says hold a copy are looked at
+    for holder in clients.message_holders(&id) {
+        let Some(client) = client_for_token(&clients, &holder) else {
+            continue;
+        };
+
+        if let Some(message) = client.history.iter().find(|message| message.id == id) {
+            sender = Some(message.sender.clone());
+            recipients.insert(client.name.clone(), message.status);
+        }
+    }
+
+    match sender {
+        None => return Err(warp::reject::custom(UnknownMessage)),
+        Some(sender) if sender != token => {
+            return Err(warp::reject::custom(NotMessageSender));
+        }
+        Some(_) => {}
+    }
+
+    Ok(warp::reply::json(&MessageStatusResponse {
+        message_id: id,
+        status: recipients.values().copied().min().unwrap_or_default(),
+        recipients,
+    }))
+}
+
+/// makes sure message `id` exists and was sent by the owner of `token`,
+/// deleted messages are treated as gone
+fn check_message_sender(clients: &ClientMap, id: &str, token: &str) -> Result<(), warp::Rejection> {
+    let sender = clients
+        .message_holders(id)
+        .iter()
+        .find_map(|holder| {
+            client_for_token(clients, holder)?
+                .history
+                .iter()
+                .find(|message| message.id == id && !message.deleted)
+                .map(|message| message.sender.clone())
+        })
+        .ok_or_else(|| warp::reject::custom(UnknownMessage))?;
+
+    if sender != token {
+        return Err(warp::reject::custom(NotMessageSender));
+    }
+
+    Ok(())
+}
+
+async fn handle_edit_message(
+    id: String,
+    request: EditMessageRequest,
+    clients: Clients,
+    config: Arc<Config>,
+    store: Arc<store::Store>,
+) -> Result<impl Reply, warp::Rejection> {
+    if request.body.len() > config.max_message_bytes {
+        return Err(warp::reject::custom(MessageTooLong));
+    }
+
+    let body = filter_body(&config, request.body)?;
+
+    let token = authenticate(&clients, &request.token, &config)?
+        .token
+        .clone();
+    check_message_sender(&clients, &id, &token)?;
+
+    for holder in clients.message_holders(&id) {
+        let Some(mut client) = client_for_token(&clients, &holder) else {
+            continue;
+        };
+
+        let mut copies = 0;
+
+        for message in client.message_copies_mut(&id) {
+            message.body = body.clone();
+            copies += 1;
+        }
+
+        // a recipient that's offline gets the edited body with the message
+        if copies > 0 {
+            store.update_message_body(&client.token, &id, &body);
+
+            let _ = client.send(&ServerEvent::Edit {
+                message_id: id.clone(),
+                body: body.clone(),
+            });
+        }
+    }
+
+    tracing::debug!(message_id = %id, "message edited");
+
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+async fn handle_delete_message(
+    id: String,
+    request: DeleteMessageRequest,
+    clients: Clients,
+    config: Arc<Config>,
+    store: Arc<store::Store>,
+    attachments: Arc<attachments::Attachments>,
+) -> Result<impl Reply, warp::Rejection> {
+    let token = authenticate(&clients, &request.token, &config)?
+        .token
+        .clone();
+    check_message_sender(&clients, &id, &token)?;
+
+    for holder in clients.message_holders(&id) {
+        let Some(mut client) = client_for_token(&clients, &holder) else {
+            continue;
+        };
+
+        let mut copies = 0;
+
+        for message in client.message_copies_mut(&id) {
+            message.body.clear();
+            message.deleted = true;
+            copies += 1;
+
+            if let Some(attachment) = message.attachment.take() {
+                attachments.remove(&attachment.id);
+            }
+        }
+
+        if copies > 0 {
+            store.tombstone_message(&client.token, &id);
+
+            let _ = client.send(&ServerEvent::Delete {
+                message_id: id.clone(),
+            });
+        }
+    }
+
+    tracing::debug!(message_id = %id, "message deleted");
+
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+/// toggles a reaction on a message, for its sender or any of its recipients
+async fn handle_react(
+    id: String,
+    request: ReactionRequest,
+    clients: Clients,
+    config: Arc<Config>,
+) -> Result<impl Reply, warp::Rejection> {
+    let emoji = request.emoji;
+    let length = emoji.chars().count();
+
+    if length == 0
+        || length > MAX_REACTION_LENGTH
+        || emoji.chars().any(|c| c.is_whitespace() || c.is_control())
+    {
+        return Err(warp::reject::custom(InvalidReaction));
+    }
+
+    let (token, by) = {
+        let client = authenticate(&clients, &request.token, &config)?;
+        (client.token.clone(), client.name.clone())
+    };
+    let token = token.as_str();
+
+    // clients can't react to messages they're not part of, that'd tell them
+    // the message exists
+    let (sender, added) = clients
+        .iter()
+        .find_map(|client| {
+            client
+                .history
+                .iter()
+                .filter(|message| client.token == token || message.sender == token)
+                .find(|message| message.id == id && !message.deleted)
+                .map(|message| {
+                    let reacted = message
+                        .reactions
+                        .get(&emoji)
+                        .is_some_and(|reactors| reactors.contains_key(token));
+
+                    (message.sender.clone(), !reacted)
+                })
+        })
+        .ok_or_else(|| warp::reject::custom(UnknownMessage))?;
+
+    let event = ServerEvent::Reaction {
+        message_id: id.clone(),
+        emoji: emoji.clone(),
+        by: by.clone(),
+        added,
+    };
+
+    for mut client in clients.iter_mut() {
+        let mut copies = 0;
+
+        for message in client.message_copies_mut(&id) {
+            if added {
+                message
+                    .reactions
+                    .entry(emoji.clone())
+                    .or_default()
+                    .insert(token.to_string(), by.clone());
+            } else if let Some(reactors) = message.reactions.get_mut(&emoji) {
+                reactors.remove(token);
+
+                if reactors.is_empty() {
+                    message.reactions.remove(&emoji);
+                }
+            }
+
+            copies += 1;
+        }
+
+        if copies > 0 || client.token == sender {
+            let _ = client.send(&event);
+        }
+    }
+
+    tracing::debug!(message_id = %id, %emoji, added, "reaction toggled");
+
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+/// maximum number of messages pinned in one conversation
+const PIN_LIMIT: usize = 50;
+
+/// the direct conversation between two clients, the same whichever of them
+/// asks
+fn conversation(a: &str, b: &str) -> (String, String) {
+    if a <= b {
+        (a.to_string(), b.to_string())
+    } else {
+        (b.to_string(), a.to_string())
+    }
+}
+
+/// the conversations direct message `id` is part of for the client owning
+/// `token`: the one with the sender for a recipient, one per recipient still
+/// holding it for the sender. empty when the client isn't a participant
+fn message_conversations(clients: &ClientMap, id: &str, token: &str) -> Vec<(String, String)> {
+    clients
+        .iter()
+        .filter_map(|client| {
+            client
+                .history
+                .iter()
+                .filter(|message| client.token == token || message.sender == token)
+                .find(|message| message.id == id && !message.deleted && message.room.is_none())
+                .map(|message| conversation(&client.token, &message.sender))
+        })
+        .collect()
+}
+
+async fn handle_pin(
+    id: String,
+    request: PinRequest,
+    clients: Clients,
+    pins: Pins,
+    config: Arc<Config>,
+) -> Result<impl Reply, warp::Rejection> {
+    set_pinned(id, request, true, clients, pins, config).await
+}
+
+async fn handle_unpin(
+    id: String,
+    request: PinRequest,
+    clients: Clients,
+    pins: Pins,
+    config: Arc<Config>,
+) -> Result<impl Reply, warp::Rejection> {
+    set_pinned(id, request, false, clients, pins, config).await
+}
+
+/// pins or unpins a message in every conversation it's part of, telling the
+/// participants that are connected
+async fn set_pinned(
+    id: String,
+    request: PinRequest,
+    pinned: bool,
+    clients: Clients,
+    pins: Pins,
+    config: Arc<Config>,
+) -> Result<impl Reply, warp::Rejection> {
+    let (token, by) = {
+        let client = authenticate(&clients, &request.token, &config)?;
+        (client.token.clone(), client.name.clone())
+    };
+
+    // like reactions, outsiders can't tell the message exists
+    let conversations = message_conversations(&clients, &id, &token);
+
+    if conversations.is_empty() {
+        return Err(warp::reject::custom(UnknownMessage));
+    }
+
+    let mut pins = pins.lock().await;
+
+    if pinned
+        && conversations.iter().any(|conversation| {
+            pins.get(conversation)
+                .is_some_and(|pinned| pinned.len() >= PIN_LIMIT && !pinned.contains(&id))
+        })
+    {
+        return Err(warp::reject::custom(TooManyPins));
+    }
+
+    let mut participants = HashSet::new();
+
+    for conversation in conversations {
+        let pinned_ids = pins.entry(conversation.clone()).or_default();
+
+        if pinned && !pinned_ids.contains(&id) {
+            pinned_ids.push(id.clone());
+        } else if !pinned {
+            pinned_ids.retain(|pinned| *pinned != id);
+
+            if pinned_ids.is_empty() {
+                pins.remove(&conversation);
+            }
+        }
+
+        participants.insert(conversation.0);
+        participants.insert(conversation.1);
+    }
+
+    drop(pins);
+
+    let event = ServerEvent::Pin {
+        message_id: id.clone(),
+        by,
+        pinned,
+    };
+
+    for token in participants {
+        if let Some(client) = client_for_token(&clients, &token) {
+            let _ = client.send(&event);
+        }
+    }
+
+    tracing::debug!(message_id = %id, pinned, "pin toggled");
+
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+/// the messages pinned in the conversation with `peer` that are still
+/// around, oldest pin first
+async fn handle_pins(
+    token: String,
+    peer: String,
+    clients: Clients,
+    pins: Pins,
+    config: Arc<Config>,
+) -> Result<impl Reply, warp::Rejection> {
+    // looked up before the client is locked, both may live in the same shard
+    let peer_token = clients
+        .get(&name_key(&peer))
+        .map(|peer| peer.token.clone())
+        .ok_or_else(|| warp::reject::custom(UnknownClient))?;
+
+    let token = authenticate(&clients, &token, &config)?.token.clone();
+
+    let pinned_ids = pins
+        .lock()
+        .await
+        .get(&conversation(&token, &peer_token))
+        .cloned()
+        .unwrap_or_default();
+
+    // received messages are in the client's history, sent ones in the peer's
+    let mut found: HashMap<String, Message> = HashMap::new();
+
+    for (holder, sender) in [(&token, &peer_token), (&peer_token, &token)] {
+        let Some(holder) = client_for_token(&clients, holder) else {
+            continue;
+        };
+
+        for message in &holder.history {
+            if message.sender == *sender && !message.deleted && pinned_ids.contains(&message.id) {
+                found.insert(message.id.clone(), message.clone());
+            }
+        }
+    }
+
+    let messages: Vec<Message> = pinned_ids
+        .iter()
+        .filter_map(|id| found.remove(id))
+        .collect();
+
+    Ok(warp::reply::json(&messages))
+}
+
+/// creates a room with its creator as the only member. rooms can also come
+/// into being by joining them, but only this way can they be private
+/// the name a room goes by, with surrounding whitespace dropped. creating
+/// and joining check it the same way, so joining can't bring a room into
+/// being that creating would refuse
+fn validate_room_name(name: &str) -> Result<&str, warp::Rejection> {
+    let name = name.trim();
+
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return Err(warp::reject::custom(InvalidName));
+    }
+
+    Ok(name)
+}
+
+async fn handle_create_room(
+    request: CreateRoomRequest,
+    clients: Clients,
+    rooms: Rooms,
+    config: Arc<Config>,
+) -> Result<impl Reply, warp::Rejection> {
+    let name = validate_room_name(&request.name)?;
+
+    let default = config.room_retention;
+
+    let max_messages = match request.max_messages {
+        Some(max) if max == 0 || max > default.max_messages => {
+            return Err(warp::reject::custom(InvalidRetention))
+        }
+        max => max.unwrap_or(default.max_messages),
+    };
+
+    let max_age = match request.max_age_secs.map(tokio::time::Duration::from_secs) {
+        Some(age) if age.is_zero() || default.max_age.is_some_and(|max| age > max) => {
+            return Err(warp::reject::custom(InvalidRetention))
+        }
+        age => age.or(default.max_age),
+    };
+
+    let token = authenticate(&clients, &request.token, &config)?
+        .token
+        .clone();
+
+    let mut rooms = rooms.lock().await;
+
+    if rooms.contains_key(name) {
+        return Err(warp::reject::custom(RoomExists));
+    }
+
+    rooms.insert(
+        name.to_string(),
+        Room {
+            members: HashSet::from([token.clone()]),
+            private: request.private,
+            read_only: request.read_only,
+            roles: HashMap::from([(token, RoomRole::Owner)]),
+            retention: Retention {
+                max_messages,
+                max_age,
+            },
+            ..Default::default()
+        },
+    );
+
+    tracing::debug!(
+        room = %name,
+        private = request.private,
+        read_only = request.read_only,
+        "room created"
+    );
+
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::CREATED,
+    ))
+}
+
+/// lets another client into a private room, any member may invite. the
+/// invitee is told, or finds out once it's back
+async fn handle_invite(
+    room: String,
+    request: RoomMemberRequest,
+    clients: Clients,
+    rooms: Rooms,
+    config: Arc<Config>,
+) -> Result<impl Reply, warp::Rejection> {
+    // looked up before the inviter is locked, both may live in the same shard
+    let invitee = clients
+        .get(&name_key(&request.name))
+        .map(|invitee| invitee.token.clone())
+        .ok_or_else(|| warp::reject::custom(UnknownClient))?;
+
+    let (token, by) = {
+        let client = authenticate(&clients, &request.token, &config)?;
+        (client.token.clone(), client.name.clone())
+    };
+
+    let newly_invited = {
+        let mut rooms = rooms.lock().await;
+
+        let room = rooms
+            .get_mut(&room)
+            .filter(|room| room.members.contains(&token))
+            .ok_or_else(|| warp::reject::custom(NotRoomMember))?;
+
+        !room.members.contains(&invitee) && room.invited.insert(invitee.clone())
+    };
+
+    if newly_invited {
+        if let Some(mut invitee) = client_for_token(&clients, &invitee) {
+            invitee.send_or_queue(ServerEvent::Invite {
+                room: room.clone(),
+                by,
+            });
+        }
+
+        tracing::debug!(%room, "client invited");
+    }
+
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+/// every public room, plus the private ones the client is in when it sends
+/// its token as a bearer token
+async fn handle_list_rooms(
+    authorization: Option<String>,
+    clients: Clients,
+    rooms: Rooms,
+    config: Arc<Config>,
+) -> Result<impl Reply, warp::Rejection> {
+    let token = match authorization
+        .as_deref()
+        .and_then(|value| value.strip_prefix("Bearer "))
+    {
+        Some(token) => {
+            let token = authenticate(&clients, token.trim(), &config)?.token.clone();
+            Some(token)
+        }
+        None => None,
+    };
+
+    let mut listed: Vec<RoomInfo> = rooms
+        .lock()
+        .await
+        .iter()
+        .filter(|(_, room)| {
+            !room.private
+                || token
+                    .as_ref()
+                    .is_some_and(|token| room.members.contains(token))
+        })
+        .map(|(name, room)| RoomInfo {
+            name: name.clone(),
+            members: room.members.len(),
+            private: room.private,
+            read_only: room.read_only,
+        })
+        .collect();
+
+    listed.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(warp::reply::json(&listed))
+}
+
+async fn handle_join_room(
+    room: String,
+    request: RoomMembershipRequest,
+    clients: Clients,
+    rooms: Rooms,
+    config: Arc<Config>,
+) -> Result<impl Reply, warp::Rejection> {
+    let room = validate_room_name(&room)?.to_string();
+
+    let token = authenticate(&clients, &request.token, &config)?
+        .token
+        .clone();
+
+    let mut rooms = rooms.lock().await;
+    let room = rooms.entry(room).or_default();
+
+    if room.private && !room.members.contains(&token) && !room.invited.remove(&token) {
+        return Err(warp::reject::custom(NotInvited));
+    }
+
+    // whoever brings a room into being by joining it owns it
+    if room.members.is_empty() {
+        room.roles.insert(token.clone(), RoomRole::Owner);
+        room.retention = config.room_retention;
+    }
+
+    room.members.insert(token);
+
+    Ok(warp::reply())
+}
+
+async fn handle_leave_room(
+    room: String,
+    request: RoomMembershipRequest,
+    clients: Clients,
+    rooms: Rooms,
+    config: Arc<Config>,
+) -> Result<impl Reply, warp::Rejection> {
+    let token = authenticate(&clients, &request.token, &config)?
+        .token
+        .clone();
+
+    let mut rooms = rooms.lock().await;
+
+    let left = rooms
+        .get_mut(&room)
+        .ok_or_else(|| warp::reject::custom(NotRoomMember))?;
+
+    if !left.remove_member(&token) {
+        return Err(warp::reject::custom(NotRoomMember));
+    }
+
+    if left.members.is_empty() {
+        rooms.remove(&room);
+    }
+
+    Ok(warp::reply())
+}
+
+async fn handle_room_message(
+    room: String,
+    request: RoomMessageRequest,
+    clients: Clients,
+    rooms: Rooms,
+    config: Arc<Config>,
+    metrics: Arc<metrics::Metrics>,
+) -> Result<impl Reply, warp::Rejection> {
+    if request.body.len() > config.max_message_bytes {
+        return Err(warp::reject::custom(MessageTooLong));
+    }
+
+    let body = filter_body(&config, request.body)?;
+
+    let token = authenticate(&clients, &request.token, &config)?
+        .token
+        .clone();
+
+    let members = {
+        let rooms = rooms.lock().await;
+
+        let joined = rooms
+            .get(&room)
+            .filter(|joined| joined.members.contains(&token))
+            .ok_or_else(|| warp::reject::custom(NotRoomMember))?;
+
+        if joined.read_only && joined.role(&token) < RoomRole::Moderator {
+            return Err(warp::reject::custom(ReadOnlyRoom));
+        }
+
+        joined.members.clone()
+    };
+
+    let mut sender = authenticate(&clients, &token, &config)?;
+    sender.throttle_send(&config)?;
+    let back_from_idle = sender.touch();
+
+    let sender_name = sender.name.clone();
+    drop(sender);
+
+    if back_from_idle {
+        set_presence(&clients, &token, Presence::Online);
+    }
+
+    let message = Message {
+        id: uuid::Uuid::new_v4().as_simple().to_string(),
+        sender: token.clone(),
+        from: sender_name.clone(),
+        body,
+        timestamp: unix_millis(),
+        room: Some(room.clone()),
+        acknowledge: false,
+        ..Default::default()
+    };
+
+    if let Some(joined) = rooms.lock().await.get_mut(&room) {
+        joined.push_history(message.clone());
+    }
+
+    // members that were evicted since joining no longer resolve and are skipped
+    let mut delivered = 0;
+
+    for mut client in clients.iter_mut() {
+        if client.token == token || !members.contains(&client.token) || !client.is_connected() {
+            continue;
+        }
+
+        let event = ServerEvent::Message(client.sequenced(&message));
+
+        if client.send(&event).is_ok() {
+            delivered += 1;
+        }
+    }
+
+    notify_mentions(&clients, &message, |client| members.contains(&client.token));
+
+    tracing::debug!(from = %sender_name, %room, delivered, "room message delivered");
+    metrics.record_sent(delivered as u64);
+
+    Ok(warp::reply::json(&RoomMessageResponse {
+        message_id: message.id,
+        delivered,
+    }))
+}
+
+/// what the room still keeps of its history, for its members
+async fn handle_room_history(
+    room: String,
+    token: String,
+    clients: Clients,
+    rooms: Rooms,
+    config: Arc<Config>,
+) -> Result<impl Reply, warp::Rejection> {
+    let token = authenticate(&clients, &token, &config)?.token.clone();
+
+    let mut rooms = rooms.lock().await;
+
+    let joined = rooms
+        .get_mut(&room)
+        .filter(|joined| joined.members.contains(&token))
+        .ok_or_else(|| warp::reject::custom(NotRoomMember))?;
+
+    joined.prune_history();
+
+    Ok(warp::reply::json(&joined.history))
+}
+
+/// sends an event to the members of a room that are connected
+fn send_to_members(clients: &ClientMap, members: &HashSet<String>, event: &ServerEvent) {
+    for client in clients.iter() {
+        if members.contains(&client.token) {
+            let _ = client.send(event);
+        }
+    }
+}
+
+/// the token and current name of the client called `name`
+fn client_by_name(clients: &ClientMap, name: &str) -> Result<(String, String), warp::Rejection> {
+    clients
+        .get(&name_key(name))
+        .map(|client| (client.token.clone(), client.name.clone()))
+        .ok_or_else(|| warp::reject::custom(UnknownClient))
+}
+
+/// gives a member a role, owner only. handing out `owner` passes the room
+/// on, the previous owner stays as a moderator
+async fn handle_room_role(
+    room: String,
+    request: RoomRoleRequest,
+    clients: Clients,
+    rooms: Rooms,
+    config: Arc<Config>,
+) -> Result<impl Reply, warp::Rejection> {
+    // looked up before the owner is locked, both may live in the same shard
+    let (target, name) = client_by_name(&clients, &request.name)?;
+    let token = authenticate(&clients, &request.token, &config)?
+        .token
+        .clone();
+
+    if target == token {
+        return Err(warp::reject::custom(InvalidRole));
+    }
+
+    let members = {
+        let mut rooms = rooms.lock().await;
+
+        let joined = rooms
+            .get_mut(&room)
+            .filter(|joined| joined.members.contains(&token))
+            .ok_or_else(|| warp::reject::custom(NotRoomMember))?;
+
+        if joined.role(&token) != RoomRole::Owner {
+            return Err(warp::reject::custom(InsufficientRole));
+        }
+
+        if !joined.members.contains(&target) {
+            return Err(warp::reject::custom(UnknownClient));
+        }
+
+        match request.role {
+            RoomRole::Owner => {
+                joined.roles.insert(token.clone(), RoomRole::Moderator);
+                joined.roles.insert(target, RoomRole::Owner);
+            }
+            RoomRole::Moderator => {
+                joined.roles.insert(target, RoomRole::Moderator);
+            }
+            RoomRole::Member => {
+                joined.roles.remove(&target);
+            }
+        }
+
+        joined.members.clone()
+    };
+
+    tracing::debug!(%room, %name, role = ?request.role, "room role changed");
+
+    send_to_members(
+        &clients,
+        &members,
+        &ServerEvent::Role {
+            room,
+            name,
+            role: request.role,
+        },
+    );
+
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+/// removes a member from a room. moderators may kick plain members, the
+/// owner anyone but itself
+async fn handle_room_kick(
+    room: String,
+    request: RoomMemberRequest,
+    clients: Clients,
+    rooms: Rooms,
+    config: Arc<Config>,
+) -> Result<impl Reply, warp::Rejection> {
+    let (target, name) = client_by_name(&clients, &request.name)?;
+    let token = authenticate(&clients, &request.token, &config)?
+        .token
+        .clone();
+
+    {
+        let mut rooms = rooms.lock().await;
+
+        let joined = rooms
+            .get_mut(&room)
+            .filter(|joined| joined.members.contains(&token))
+            .ok_or_else(|| warp::reject::custom(NotRoomMember))?;
+
+        if !joined.members.contains(&target) {
+            return Err(warp::reject::custom(UnknownClient));
+        }
+
+        let role = joined.role(&token);
+
+        if role < RoomRole::Moderator || joined.role(&target) >= role {
+            return Err(warp::reject::custom(InsufficientRole));
+        }
+
+        joined.remove_member(&target);
+    }
+
+    if let Some(kicked) = client_for_token(&clients, &target) {
+        let _ = kicked.send(&ServerEvent::System {
+            body: format!("You were removed from {}", room),
+        });
+    }
+
+    tracing::debug!(%room, %name, "client kicked from room");
+
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+/// deletes a message posted to a room, for its sender or a moderator
+async fn handle_delete_room_message(
+    room: String,
+    id: String,
+    request: RoomMembershipRequest,
+    clients: Clients,
+    rooms: Rooms,
+    config: Arc<Config>,
+) -> Result<impl Reply, warp::Rejection> {
+    let token = authenticate(&clients, &request.token, &config)?
+        .token
+        .clone();
+
+    let members = {
+        let mut rooms = rooms.lock().await;
+
+        let joined = rooms
+            .get_mut(&room)
+            .filter(|joined| joined.members.contains(&token))
+            .ok_or_else(|| warp::reject::custom(NotRoomMember))?;
+
+        let role = joined.role(&token);
+
+        let message = joined
+            .history
+            .iter_mut()
+            .find(|message| message.id == id && !message.deleted)
+            .ok_or_else(|| warp::reject::custom(UnknownMessage))?;
+
+        if message.sender != token && role < RoomRole::Moderator {
+            return Err(warp::reject::custom(InsufficientRole));
+        }
+
+        message.body.clear();
+        message.deleted = true;
+
+        joined.members.clone()
+    };
+
+    tracing::debug!(%room, message_id = %id, "room message deleted");
+
+    send_to_members(&clients, &members, &ServerEvent::Delete { message_id: id });
+
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+async fn handle_status(token: String, state: State) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&client_status(&state, &token)?))
+}
+
+/// the client owning `token` as it sees itself
+fn client_status(state: &State, token: &str) -> Result<StatusResponse, ChatError> {
+    let client = authenticate(&state.clients, token, &state.config)?;
+
+    Ok(StatusResponse {
+        name: client.name.clone(),
+        status: client.status,
+        status_message: client.status_message.clone(),
+        last_seen: client.last_seen,
+        unread: client.unread,
+    })
+}
+
+async fn handle_history(
+    token: String,
+    clients: Clients,
+    config: Arc<Config>,
+) -> Result<impl Reply, warp::Rejection> {
+    let client = authenticate(&clients, &token, &config)?;
+
+    Ok(warp::reply::json(&client.history))
+}
+
+/// whether a message body matches a search, for now a case-insensitive
+/// substring match. `query` is already lowercase
+fn matches_search(body: &str, query: &str) -> bool {
+    body.to_lowercase().contains(query)
+}
+
+/// the messages in the client's history whose body contains the query,
+/// oldest first
+async fn handle_search(
+    token: String,
+    query: SearchQuery,
+    clients: Clients,
+    config: Arc<Config>,
+) -> Result<impl Reply, warp::Rejection> {
+    let q = query.q.trim().to_lowercase();
+
+    if q.is_empty() {
+        return Err(warp::reject::custom(EmptyQuery));
+    }
+
+    let from = query.from.as_deref().map(name_key);
+    let client = authenticate(&clients, &token, &config)?;
+
+    let found: Vec<&Message> = client
+        .history
+        .iter()
+        .filter(|message| !message.deleted)
+        .filter(|message| {
+            from.as_ref()
+                .is_none_or(|from| name_key(&message.from) == *from)
+        })
+        .filter(|message| matches_search(&message.body, &q))
+        .collect();
+
+    Ok(warp::reply::json(&found))
+}
+
+#[derive(serde::Serialize)]
+struct UploadResponse {
+    pub file_id: String,
+    pub url: String,
+}
+
+/// takes a file ahead of the message it's sent with, as multipart form data
+/// with a `token` field and a `file` field. the message then refers to it
+/// by `file_id`
+async fn handle_upload(
+    form: warp::multipart::FormData,
+    clients: Clients,
+    config: Arc<Config>,
+    attachments: Arc<attachments::Attachments>,
+) -> Result<impl Reply, warp::Rejection> {
+    use futures::TryStreamExt;
+    use warp::hyper::body::Buf;
+
+    if config.max_attachment_bytes == 0 {
+        return Err(warp::reject::not_found());
+    }
+
+    let mut token = None;
+    let mut file = None;
+
+    let mut parts = form;
+
+    while let Some(part) = parts.try_next().await.map_err(|err| {
+        tracing::debug!(%err, "could not read upload");
+        warp::reject::custom(wire::MalformedBody)
+    })? {
+        let name = part.name().to_string();
+        let filename = part.filename().map(str::to_string);
+        let mime = part.content_type().map(str::to_string);
+
+        let mut data = Vec::new();
+        let mut chunks = part.stream();
+
+        while let Some(chunk) = chunks
+            .try_next()
+            .await
+            .map_err(|_| warp::reject::custom(wire::MalformedBody))?
+        {
+            data.extend_from_slice(chunk.chunk());
+
+            if data.len() > config.max_attachment_bytes {
+                return Err(warp::reject::custom(AttachmentTooLarge));
+            }
+        }
+
+        match name.as_str() {
+            "token" => token = String::from_utf8(data).ok(),
+            "file" => file = Some((filename, mime, data)),
+            _ => {}
+        }
+    }
+
+    let (Some(token), Some((filename, mime, data))) = (token, file) else {
+        return Err(warp::reject::custom(wire::MalformedBody));
+    };
+
+    let token = authenticate(&clients, &token, &config)?.token.clone();
+
+    let filename = filename.unwrap_or_default();
+    let mime = mime.unwrap_or_else(|| "application/octet-stream".to_string());
+
+    if !attachments::is_valid_filename(&filename) || !attachments::is_valid_mime(&mime) {
+        return Err(warp::reject::custom(InvalidAttachment));
+    }
+
+    if !attachments::is_allowed_mime(&mime, &config.attachment_mime_types) {
+        return Err(warp::reject::custom(MimeNotAllowed));
+    }
+
+    let info = attachments
+        .insert(attachments::File {
+            filename,
+            mime,
+            data: data.into(),
+            owner: token,
+        })
+        .map_err(|attachments::TooLarge| warp::reject::custom(AttachmentTooLarge))?;
+
+    tracing::debug!(file_id = %info.id, size = info.size, "file uploaded");
+
+    Ok(warp::reply::json(&UploadResponse {
+        file_id: info.id,
+        url: info.url,
+    }))
+}
+
+/// the file attached to a message. always offered as a download so a file
+/// claiming to be html can't run in the page
+async fn handle_attachment(
+    id: String,
+    attachments: Arc<attachments::Attachments>,
+) -> Result<impl Reply, warp::Rejection> {
+    let file = attachments
+        .get(&id)
+        .ok_or_else(|| warp::reject::custom(UnknownAttachment))?;
+
+    let content_disposition = attachments::content_disposition(&file.filename);
+
+    let mut response = warp::reply::Response::new(file.data.into());
+    let headers = response.headers_mut();
+
+    // both were validated when the file came in
+    if let Ok(mime) = warp::http::HeaderValue::from_str(&file.mime) {
+        headers.insert(warp::http::header::CONTENT_TYPE, mime);
+    }
+
+    if let Ok(content_disposition) = warp::http::HeaderValue::from_str(&content_disposition) {
+        headers.insert(warp::http::header::CONTENT_DISPOSITION, content_disposition);
+    }
+
+    headers.insert(
+        warp::http::header::X_CONTENT_TYPE_OPTIONS,
+        warp::http::HeaderValue::from_static("nosniff"),
+    );
+
+    Ok(response)
+}
+
+/// every stored message addressed to the client as a download, one json
+/// object per line. the history is copied out so the client isn't held while
+/// it's written
+async fn handle_export(
+    token: String,
+    clients: Clients,
+    config: Arc<Config>,
+) -> Result<impl Reply, warp::Rejection> {
+    let history = authenticate(&clients, &token, &config)?.history.clone();
+
+    let lines = futures::stream::iter(history).map(|message| {
+        let mut line = serde_json::to_vec(&message)?;
+        line.push(b'\n');
+
+        Ok::<_, serde_json::Error>(line)
+    });
+
+    let mut response = warp::reply::Response::new(warp::hyper::Body::wrap_stream(lines));
+    let headers = response.headers_mut();
+
+    headers.insert(
+        warp::http::header::CONTENT_TYPE,
+        warp::http::HeaderValue::from_static("application/x-ndjson"),
+    );
+    headers.insert(
+        warp::http::header::CONTENT_DISPOSITION,
+        warp::http::HeaderValue::from_static("attachment; filename=\"history.ndjson\""),
+    );
+
+    Ok(response)
+}
+
+/// periodically drops clients whose session lapsed, the lapsed sessions of
+/// accounts with a password and the message index entries of copies that
+/// are gone
+async fn sweep_expired_clients(
+    clients: Clients,
+    rooms: Rooms,
+    config: Arc<Config>,
+    store: Arc<store::Store>,
+    dead_letters: Arc<deadletters::DeadLetters>,
+    backplane: Arc<backplane::Backplane>,
+) {
+    let mut sweep_timer = tokio::time::interval(config.session_sweep_interval);
+
+    loop {
+        sweep_timer.tick().await;
+
+        let now = tokio::time::Instant::now();
+
+        // removed once the map is no longer locked, like any other client
+        // that goes away, so their rooms and everyone watching hear of it
+        let expired = clients
+            .iter()
+            .filter(|client| client.is_expired(now))
+            .map(|client| client.key().clone())
+            .collect::<Vec<_>>();
+
+        for key in expired {
+            // it may have come back since
+            if !clients
+                .get(&key)
+                .is_some_and(|client| client.is_expired(now))
+            {
+                continue;
+            }
+
+            tracing::debug!(name = %key, "session expired");
+
+            remove_client(
+                &key,
+                "session_expired",
+                &clients,
+                &rooms,
+                &store,
+                &dead_letters,
+                &backplane,
+            )
+            .await;
+        }
+
+        let ended = clients.end_lapsed_sessions(now);
+
+        if ended > 0 {
+            tracing::debug!(ended, "lapsed sessions ended");
+        }
+
+        let pruned = clients.prune_message_index();
+
+        if pruned > 0 {
+            tracing::debug!(pruned, "stale message index entries dropped");
+        }
+    }
+}
+
+/// marks connected clients that have gone quiet as away. they're checked
+/// twice per idle period, so one shows as away somewhere between one and one
+/// and a half periods after its last message
+async fn mark_idle_clients(clients: Clients, config: Arc<Config>) {
+    let mut idle_timer =
+        tokio::time::interval((config.idle_away / 2).max(tokio::time::Duration::from_secs(1)));
+
+    loop {
+        idle_timer.tick().await;
+
+        let cutoff = unix_millis() - config.idle_away.as_millis() as i64;
+
+        let idle: Vec<(String, Option<String>)> = clients
+            .iter_mut()
+            .filter_map(|mut client| {
+                if client.status != Presence::Online
+                    || !client.is_connected()
+                    || client.last_seen > cutoff
+                {
+                    return None;
+                }
+
+                client.status = Presence::Away;
+                client.idle = true;
+
+                Some((client.name.clone(), client.status_message.clone()))
+            })
+            .collect();
+
+        // guards are gone by now, broadcasting looks at every client
+        for (name, status_message) in idle {
+            tracing::debug!(%name, "client went idle");
+            broadcast_presence(&clients, &name, Presence::Away, status_message.as_deref());
+        }
+    }
+}
+
+/// a page of clients, ordered by name so pages stay consistent between calls.
+/// a prefix narrows the listing down before it's paged.
+async fn handle_list_clients(
+    query: ListClientsQuery,
+    clients: Clients,
+) -> Result<impl Reply, warp::Rejection> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_LIMIT)
+        .min(MAX_PAGE_LIMIT);
+
+    let prefix = query.prefix.as_deref().map(name_key).unwrap_or_default();
+
+    let mut keys: Vec<String> = clients
+        .iter()
+        .map(|client| client.key().clone())
+        .filter(|key| key.starts_with(&prefix))
+        .collect();
+    keys.sort_unstable();
+
+    // a client that went away since the keys were taken is left out of the page
+    let page = keys
+        .iter()
+        .skip(query.offset.unwrap_or(0))
+        .take(limit)
+        .filter_map(|key| clients.get(key))
+        .filter_map(|client| serde_json::value::to_raw_value(&*client).ok())
+        .collect();
+
+    Ok(warp::reply::json(&ClientsPage {
+        total: keys.len(),
+        clients: page,
+    }))
+}
+
+/// which build is running, everything is baked in at compile time
+async fn handle_version() -> Result<impl Reply, warp::Rejection> {
+    Ok(warp::reply::json(&VersionResponse {
+        version: env!("CARGO_PKG_VERSION"),
+        commit: env!("CHAT_GIT_COMMIT"),
+        build_timestamp: env!("CHAT_BUILD_TIMESTAMP").parse().unwrap_or_default(),
+    }))
+}
+
+/// liveness, answers as long as the process is able to serve requests
+async fn handle_healthz(started_at: tokio::time::Instant) -> Result<impl Reply, warp::Rejection> {
+    Ok(warp::reply::json(&HealthResponse {
+        status: "ok",
+        uptime_secs: started_at.elapsed().as_secs(),
+    }))
+}
+
+/// readiness, only once the listener is up and until shutdown begins so load
+/// balancers stop routing to an instance that is draining. an instance whose
+/// database stopped answering, or that lost its backplane subscription, isn't
+/// ready either
+async fn handle_readyz(
+    started_at: tokio::time::Instant,
+    ready: Arc<AtomicBool>,
+    shutting_down: Arc<AtomicBool>,
+    store: Arc<store::Store>,
+    backplane: Arc<backplane::Backplane>,
+) -> Result<impl Reply, warp::Rejection> {
+    let (status, code) = if !ready.load(Ordering::SeqCst) {
+        ("starting", warp::http::StatusCode::SERVICE_UNAVAILABLE)
+    } else if shutting_down.load(Ordering::SeqCst) {
+        ("shutting_down", warp::http::StatusCode::SERVICE_UNAVAILABLE)
+    } else if !store.is_healthy() {
+        (
+            "store_unavailable",
+            warp::http::StatusCode::SERVICE_UNAVAILABLE,
+        )
+    } else if !backplane.is_healthy() {
+        (
+            "backplane_unavailable",
+            warp::http::StatusCode::SERVICE_UNAVAILABLE,
+        )
+    } else {
+        ("ok", warp::http::StatusCode::OK)
+    };
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&HealthResponse {
+            status,
+            uptime_secs: started_at.elapsed().as_secs(),
+        }),
+        code,
+    ))
+}
+
+async fn handle_metrics(
+    clients: Clients,
+    metrics: Arc<metrics::Metrics>,
+) -> Result<impl Reply, warp::Rejection> {
+    metrics.registered_clients.set(clients.len() as i64);
+
+    Ok(warp::reply::with_header(
+        metrics.render(),
+        warp::http::header::CONTENT_TYPE,
+        "text/plain; version=0.0.4",
+    ))
+}
+
+/// the counters as json, for dashboards that don't scrape prometheus. the
+/// admin token goes in an `Authorization: Bearer` header
+async fn handle_metrics_json(
+    authorization: Option<String>,
+    clients: Clients,
+    metrics: Arc<metrics::Metrics>,
+    config: Arc<Config>,
+) -> Result<impl Reply, warp::Rejection> {
+    check_admin_header(&config, authorization.as_deref())?;
+
+    Ok(warp::reply::json(&metrics.snapshot(clients.len())))
+}
+
+async fn handle_rejection(
+    rejection: warp::Rejection,
+    metrics: Arc<metrics::Metrics>,
+) -> Result<impl Reply, Infallible> {
+    use warp::http::StatusCode;
+
+    metrics.rejections.inc();
+
+    let mut retry_after = None;
+
+    let (status, error) = if let Some(limited) = rejection.find::<RateLimited>() {
+        retry_after = Some(limited.retry_after);
+        (StatusCode::TOO_MANY_REQUESTS, "rate_limited")
+    } else if rejection.find::<TooManyConnections>().is_some() {
+        (StatusCode::TOO_MANY_REQUESTS, "too_many_connections")
+    } else if rejection.find::<UnknownRecipient>().is_some() {
+        (StatusCode::NOT_FOUND, "unknown_recipient")
+    } else if rejection.find::<UnknownMessage>().is_some() {
+        (StatusCode::NOT_FOUND, "unknown_message")
+    } else if rejection.find::<TooManyPins>().is_some() {
+        (StatusCode::CONFLICT, "too_many_pins")
+    } else if rejection.find::<SendInProgress>().is_some() {
+        (StatusCode::CONFLICT, "send_in_progress")
+    } else if rejection.find::<EmptyQuery>().is_some() {
+        (StatusCode::BAD_REQUEST, "empty_query")
+    } else if rejection.find::<UnknownSession>().is_some() {
+        (StatusCode::NOT_FOUND, "unknown_session")
+    } else if rejection.find::<UnknownReport>().is_some() {
+        (StatusCode::NOT_FOUND, "unknown_report")
+    } else if rejection.find::<InvalidReason>().is_some() {
+        (StatusCode::BAD_REQUEST, "invalid_reason")
+    } else if rejection.find::<NotMessageSender>().is_some() {
+        (StatusCode::FORBIDDEN, "not_message_sender")
+    } else if rejection.find::<InvalidReaction>().is_some() {
+        (StatusCode::BAD_REQUEST, "invalid_reaction")
+    } else if rejection.find::<InvalidName>().is_some() {
+        (StatusCode::BAD_REQUEST, "invalid_name")
+    } else if rejection.find::<ReservedName>().is_some() {
+        (StatusCode::BAD_REQUEST, "name_reserved")
+    } else if rejection.find::<NameTaken>().is_some() {
+        (StatusCode::NOT_ACCEPTABLE, "name_taken")
+    } else if rejection.find::<InvalidToken>().is_some() {
+        (StatusCode::UNAUTHORIZED, "invalid_token")
+    } else if rejection.find::<InvalidCredentials>().is_some() {
+        (StatusCode::UNAUTHORIZED, "invalid_credentials")
+    } else if rejection.find::<BannedName>().is_some() {
+        (StatusCode::FORBIDDEN, "name_banned")
+    } else if rejection.find::<InvalidBan>().is_some() {
+        (StatusCode::BAD_REQUEST, "invalid_ban")
+    } else if rejection.find::<UnknownClient>().is_some() {
+        (StatusCode::NOT_FOUND, "unknown_client")
+    } else if rejection.find::<UnknownAttachment>().is_some() {
+        (StatusCode::NOT_FOUND, "unknown_attachment")
+    } else if rejection.find::<InvalidAttachment>().is_some() {
+        (StatusCode::BAD_REQUEST, "invalid_attachment")
+    } else if rejection.find::<AttachmentTooLarge>().is_some() {
+        (StatusCode::PAYLOAD_TOO_LARGE, "attachment_too_large")
+    } else if rejection.find::<MimeNotAllowed>().is_some() {
+        (StatusCode::UNSUPPORTED_MEDIA_TYPE, "mime_not_allowed")
+    } else if rejection.find::<ShuttingDown>().is_some() {
+        (StatusCode::SERVICE_UNAVAILABLE, "shutting_down")
+    } else if rejection.find::<Maintenance>().is_some() {
+        (StatusCode::SERVICE_UNAVAILABLE, "maintenance")
+    } else if rejection.find::<BackplaneUnavailable>().is_some() {
+        (StatusCode::SERVICE_UNAVAILABLE, "backplane_unavailable")
+    } else if rejection.find::<NotRoomMember>().is_some() {
+        (StatusCode::FORBIDDEN, "not_room_member")
+    } else if rejection.find::<NotInvited>().is_some() {
+        (StatusCode::FORBIDDEN, "not_invited")
+    } else if rejection.find::<RoomExists>().is_some() {
+        (StatusCode::CONFLICT, "room_exists")
+    } else if rejection.find::<InsufficientRole>().is_some() {
+        (StatusCode::FORBIDDEN, "insufficient_role")
+    } else if rejection.find::<InvalidRole>().is_some() {
+        (StatusCode::BAD_REQUEST, "invalid_role")
+    } else if rejection.find::<UnsupportedProtocol>().is_some() {
+        (StatusCode::BAD_REQUEST, "unsupported_protocol")
+    } else if rejection.find::<ReadOnlyRoom>().is_some() {
+        (StatusCode::FORBIDDEN, "read_only_room")
+    } else if rejection.find::<InvalidRetention>().is_some() {
+        (StatusCode::BAD_REQUEST, "invalid_retention")
+    } else if rejection.find::<MessageTooLong>().is_some() {
+        (StatusCode::BAD_REQUEST, "message_too_long")
+    } else if rejection.find::<InvalidTtl>().is_some() {
+        (StatusCode::BAD_REQUEST, "invalid_ttl")
+    } else if rejection.find::<StatusMessageTooLong>().is_some() {
+        (StatusCode::BAD_REQUEST, "status_message_too_long")
+    } else if rejection.find::<BlockedContent>().is_some() {
+        (StatusCode::BAD_REQUEST, "blocked_content")
+    } else if rejection.find::<wire::MalformedBody>().is_some() {
+        (StatusCode::BAD_REQUEST, "malformed_body")
+    } else if rejection.find::<wire::RequestTimeout>().is_some() {
+        (StatusCode::REQUEST_TIMEOUT, "request_timeout")
+    } else if rejection.find::<warp::reject::InvalidQuery>().is_some() {
+        (StatusCode::BAD_REQUEST, "invalid_query")
+    } else if rejection.find::<warp::reject::PayloadTooLarge>().is_some() {
+        (StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large")
+    } else if rejection.find::<warp::reject::LengthRequired>().is_some() {
+        (StatusCode::LENGTH_REQUIRED, "length_required")
+    } else if rejection.is_not_found() {
+        (StatusCode::NOT_FOUND, "not_found")
+    } else if rejection.find::<warp::reject::MethodNotAllowed>().is_some() {
+        (StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed")
+    } else {
+        (StatusCode::INTERNAL_SERVER_ERROR, "internal_error")
+    };
+
+    if status.is_server_error() {
+        tracing::warn!(?rejection, %status, "request failed");
+    } else {
+        tracing::debug!(error, %status, "request rejected");
+    }
+
+    let mut response =
+        warp::reply::with_status(warp::reply::json(&ErrorResponse { error }), status)
+            .into_response();
+
+    if let Some(retry_after) = retry_after {
+        // whole seconds, rounded up so clients don't retry a moment too soon
+        let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+
+        response
+            .headers_mut()
+            .insert(warp::http::header::RETRY_AFTER, secs.into());
+    }
+
+    Ok(response)
+}
+
+/// resolves on ctrl-c, or on SIGTERM where available
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+/// waits for `signal`, then turns away new clients and closes every open
+/// socket, giving them the drain period to go away cleanly
+async fn shutdown(
+    signal: impl std::future::Future<Output = ()>,
+    clients: Clients,
+    config: Arc<Config>,
+    shutting_down: Arc<AtomicBool>,
+) {
+    signal.await;
+
+    tracing::info!("shutting down");
+    shutting_down.store(true, Ordering::SeqCst);
+
+    // dropping the senders flushes what's queued and then sends a close frame
+    for mut client in clients.iter_mut() {
+        let _ = client.send(&ServerEvent::System {
+            body: "The server is shutting down".to_string(),
+        });
+
+        client.connections.clear();
+    }
+
+    tokio::time::sleep(config.shutdown_drain).await;
+}
+
+/// everything the routes and the background tasks share. `main` builds it
+/// from the config and whatever the store restored, tests build their own
+#[derive(Clone)]
+struct State {
+    clients: Clients,
+    rooms: Rooms,
+    pins: Pins,
+    bans: BanList,
+    config: Arc<Config>,
+    store: Arc<store::Store>,
+    metrics: Arc<metrics::Metrics>,
+    attachments: Arc<attachments::Attachments>,
+    scheduled: Scheduled,
+    reports: Arc<reports::Reports>,
+    audit: Arc<audit::AuditLog>,
+    dead_letters: Arc<deadletters::DeadLetters>,
+    backplane: Arc<backplane::Backplane>,
+    registration_limiter: AddressLimiter,
+    login_limiter: AddressLimiter,
+    connection_limiter: Arc<connlimit::ConnectionLimiter>,
+    started_at: tokio::time::Instant,
+
+    /// set once the server is listening
+    ready: Arc<AtomicBool>,
+    shutting_down: Arc<AtomicBool>,
+    maintenance: Arc<AtomicBool>,
+}
+
+impl State {
+    fn new(config: Arc<Config>, store: Arc<store::Store>, clients: Clients, bans: BanList) -> Self {
+        State {
+            clients,
+            rooms: Arc::new(Mutex::new(HashMap::new())),
+            pins: Arc::new(Mutex::new(HashMap::new())),
+            bans,
+            metrics: Arc::new(metrics::Metrics::new()),
+            attachments: Arc::new(attachments::Attachments::new(
+                config.attachment_storage_bytes,
+            )),
+            scheduled: Arc::new(schedule::Schedule::new()),
+            reports: Arc::new(reports::Reports::default()),
+            audit: Arc::new(audit::AuditLog::default()),
+            dead_letters: Arc::new(deadletters::DeadLetters::default()),
+            backplane: Arc::new(backplane::Backplane::new(config.redis_url.as_deref())),
+            registration_limiter: Arc::new(Mutex::new(ratelimit::RateLimiter::new(
+                config.register_rate_limit,
+                config.register_rate_window,
+            ))),
+            login_limiter: Arc::new(Mutex::new(ratelimit::RateLimiter::new(
+                config.login_rate_limit,
+                config.login_rate_window,
+            ))),
+            connection_limiter: Arc::new(connlimit::ConnectionLimiter::new(
+                config.max_connections_per_ip,
+            )),
+            started_at: tokio::time::Instant::now(),
+            ready: Arc::new(AtomicBool::new(false)),
+            shutting_down: Arc::new(AtomicBool::new(false)),
+            maintenance: Arc::new(AtomicBool::new(false)),
+            config,
+            store,
+        }
+    }
+}
+
+/// starts what runs next to the routes: the session sweep, idle detection,
+/// the delivery of scheduled messages and, with a backplane, of messages
+/// other instances relay
+fn spawn_background_tasks(state: &State) {
+    tokio::spawn(sweep_expired_clients(
+        state.clients.clone(),
+        state.rooms.clone(),
+        state.config.clone(),
+        state.store.clone(),
+        state.dead_letters.clone(),
+        state.backplane.clone(),
+    ));
+
+    if !state.config.idle_away.is_zero() {
+        tokio::spawn(mark_idle_clients(
+            state.clients.clone(),
+            state.config.clone(),
+        ));
+    }
+
+    // restored messages that expire pick their countdown up where it was,
+    // the store already dropped the ones that ran out while it was down
+    let now = unix_millis();
+    let mut expiring = HashMap::new();
+
+    for client in state.clients.iter() {
+        for message in &client.history {
+            if let Some(expires_at) = message.expires_at {
+                expiring.insert(message.id.clone(), (message.sender.clone(), expires_at));
+            }
+        }
+    }
+
+    for (message_id, (sender, expires_at)) in expiring {
+        tokio::spawn(expire_message(
+            state.clients.clone(),
+            state.store.clone(),
+            message_id,
+            sender,
+            None,
+            state.attachments.clone(),
+            tokio::time::Duration::from_millis(expires_at.saturating_sub(now).max(0) as u64),
+        ));
+    }
+
+    tokio::spawn(deliver_scheduled(
+        state.scheduled.clone(),
+        state.clients.clone(),
+        state.config.clone(),
+        state.metrics.clone(),
+        state.store.clone(),
+        state.attachments.clone(),
+    ));
+
+    if state.backplane.is_enabled() {
+        // clients restored from the store hold their names here
+        state
+            .backplane
+            .claim_names(state.clients.iter().map(|client| client.key().clone()));
+
+        let State {
+            backplane,
+            clients,
+            config,
+            metrics,
+            store,
+            ..
+        } = state.clone();
+
+        tokio::spawn(async move {
+            backplane
+                .listen(|key, relayed| {
+                    deliver_relayed(&clients, key, relayed, &config, &metrics, &store)
+                })
+                .await
+        });
+    }
+}
+
+/// every route the server answers, with errors turned into responses and
+/// request ids, tracing and cors applied
+fn build_routes(state: State) -> warp::filters::BoxedFilter<(warp::reply::Response,)> {
+    let State {
+        clients,
+        rooms,
+        pins,
+        bans,
+        config,
+        store,
+        metrics,
+        attachments,
+        reports,
+        audit,
+        dead_letters,
+        backplane,
+        connection_limiter,
+        started_at,
+        ready,
+        shutting_down,
+        maintenance,
+        ..
+    } = state.clone();
+
+    // handlers with their core split out, like registration, take the state
+    // as a whole. the others get the parts they use
+    let state = warp::any().map(move || state.clone());
+
+    let recover_metrics = metrics.clone();
+
+    let cors = config.cors.as_ref().map(CorsConfig::filter);
+
+    let max_message_request_bytes = config.max_message_request_bytes();
+    let max_request_bytes = config.max_request_bytes;
+    let request_timeout = config.request_timeout;
+    let max_attachment_bytes = config.max_attachment_bytes as u64;
+
+    let shutting_down = warp::any().map(move || shutting_down.clone());
+    let maintenance = warp::any().map(move || maintenance.clone());
+    let started_at = warp::any().map(move || started_at);
+    let attachments = warp::any().map(move || attachments.clone());
+    let bans = warp::any().map(move || bans.clone());
+    let config = warp::any().map(move || config.clone());
+    let clients = warp::any().map(move || clients.clone());
+    let metrics = warp::any().map(move || metrics.clone());
+    let store = warp::any().map(move || store.clone());
+    let rooms = warp::any().map(move || rooms.clone());
+    let pins = warp::any().map(move || pins.clone());
+    let reports = warp::any().map(move || reports.clone());
+    let audit = warp::any().map(move || audit.clone());
+    let dead_letters = warp::any().map(move || dead_letters.clone());
+    let backplane = warp::any().map(move || backplane.clone());
+    let connection_limiter = warp::any().map(move || connection_limiter.clone());
+
+    let serve_static = warp::get().and(warp::fs::dir("static/"));
+
+    let registration_handler = warp::path("register")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(warp::addr::remote())
+        .and(state.clone())
+        .and_then(handle_registration);
+
+    let login_handler = warp::path("login")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(warp::addr::remote())
+        .and(state.clone())
+        .and_then(handle_login);
+
+    let refresh_handler = warp::path("refresh")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(state.clone())
+        .and_then(handle_refresh);
+
+    let list_sessions_handler = warp::path("sessions")
+        .and(warp::get())
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(clients.clone())
+        .and(config.clone())
+        .and_then(handle_list_sessions);
+
+    let revoke_session_handler = warp::path("sessions")
+        .and(warp::path::param())
+        .and(warp::path("revoke"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(clients.clone())
+        .and(config.clone())
+        .and_then(handle_revoke_session);
+
+    let send_message_handler = warp::path("send_message")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_message_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(state.clone())
+        .and_then(handle_send_message);
+
+    let broadcast_handler = warp::path("broadcast")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_message_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(clients.clone())
+        .and(config.clone())
+        .and(metrics.clone())
+        .and_then(handle_broadcast);
+
+    let logout_handler = warp::path("logout")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(clients.clone())
+        .and(rooms.clone())
+        .and(store.clone())
+        .and(dead_letters.clone())
+        .and(backplane.clone())
+        .and(config.clone())
+        .and_then(handle_logout);
+
+    let delete_account_handler = warp::path("account")
+        .and(warp::delete())
+        .and(warp::body::content_length_limit(max_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(clients.clone())
+        .and(rooms.clone())
+        .and(config.clone())
+        .and(store.clone())
+        .and(dead_letters.clone())
+        .and(backplane.clone())
+        .and_then(handle_delete_account);
+
+    let kick_handler = warp::path("admin")
+        .and(warp::path("kick"))
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(warp::addr::remote())
+        .and(clients.clone())
+        .and(rooms.clone())
+        .and(config.clone())
+        .and(store.clone())
+        .and(audit.clone())
+        .and(dead_letters.clone())
+        .and(backplane.clone())
+        .and_then(handle_kick);
+
+    let maintenance_handler = warp::path("admin")
+        .and(warp::path("maintenance"))
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(warp::addr::remote())
+        .and(maintenance.clone())
+        .and(config.clone())
+        .and(audit.clone())
+        .and_then(handle_maintenance);
+
+    let stats_handler = warp::path("admin")
+        .and(warp::path("stats"))
+        .and(warp::get())
+        .and(warp::header::optional("authorization"))
+        .and(started_at)
+        .and(clients.clone())
+        .and(metrics.clone())
+        .and(config.clone())
+        .and_then(handle_stats);
+
+    let metrics_json_handler = warp::path("admin")
+        .and(warp::path("metrics.json"))
+        .and(warp::get())
+        .and(warp::header::optional("authorization"))
+        .and(clients.clone())
+        .and(metrics.clone())
+        .and(config.clone())
+        .and_then(handle_metrics_json);
+
+    let list_reports_handler = warp::path("admin")
+        .and(warp::path("reports"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::header::optional("authorization"))
+        .and(reports.clone())
+        .and(config.clone())
+        .and_then(handle_list_reports);
+
+    let resolve_report_handler = warp::path("admin")
+        .and(warp::path("reports"))
+        .and(warp::path::param())
+        .and(warp::path("resolve"))
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(warp::addr::remote())
+        .and(reports.clone())
+        .and(config.clone())
+        .and(audit.clone())
+        .and_then(handle_resolve_report);
+
+    let dead_letters_handler = warp::path("admin")
+        .and(warp::path("dead-letters"))
+        .and(warp::get())
+        .and(warp::header::optional("authorization"))
+        .and(dead_letters.clone())
+        .and(config.clone())
+        .and_then(handle_dead_letters);
+
+    let audit_handler = warp::path("admin")
+        .and(warp::path("audit"))
+        .and(warp::get())
+        .and(warp::header::optional("authorization"))
+        .and(audit.clone())
+        .and(config.clone())
+        .and_then(handle_audit);
+
+    let announce_handler = warp::path("admin")
+        .and(warp::path("announce"))
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_message_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(warp::addr::remote())
+        .and(clients.clone())
+        .and(config.clone())
+        .and(audit.clone())
+        .and_then(handle_announce);
+
+    let ban_handler = warp::path("admin")
+        .and(warp::path("ban"))
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(warp::addr::remote())
+        .and(bans.clone())
+        .and(config.clone())
+        .and(store.clone())
+        .and(audit.clone())
+        .and_then(handle_ban);
+
+    let unban_handler = warp::path("admin")
+        .and(warp::path("unban"))
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(warp::addr::remote())
+        .and(bans.clone())
+        .and(config.clone())
+        .and(store.clone())
+        .and(audit.clone())
+        .and_then(handle_unban);
+
+    let rename_handler = warp::path("rename")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(clients.clone())
+        .and(config.clone())
+        .and(bans.clone())
+        .and(store.clone())
+        .and(backplane.clone())
+        .and_then(handle_rename);
+
+    let report_handler = warp::path("report")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(clients.clone())
+        .and(reports)
+        .and(config.clone())
+        .and_then(handle_report);
+
+    let block_handler = warp::path("block")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(clients.clone())
+        .and(config.clone())
+        .and(store.clone())
+        .and_then(handle_block);
+
+    let unblock_handler = warp::path("unblock")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(clients.clone())
+        .and(config.clone())
+        .and(store.clone())
+        .and_then(handle_unblock);
+
+    let presence_handler = warp::path("presence")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(clients.clone())
+        .and(config.clone())
+        .and_then(handle_presence);
+
+    let typing_handler = warp::path("typing")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(clients.clone())
+        .and(config.clone())
+        .and_then(handle_typing);
+
+    let read_handler = warp::path("read")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(clients.clone())
+        .and(config.clone())
+        .and_then(handle_read);
+
+    let create_room_handler = warp::path("rooms")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(clients.clone())
+        .and(rooms.clone())
+        .and(config.clone())
+        .and_then(handle_create_room);
+
+    let list_rooms_handler = warp::path("rooms")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::header::optional("authorization"))
+        .and(clients.clone())
+        .and(rooms.clone())
+        .and(config.clone())
+        .and_then(handle_list_rooms);
+
+    let invite_handler = warp::path("rooms")
+        .and(warp::path::param())
+        .and(warp::path("invite"))
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(clients.clone())
+        .and(rooms.clone())
+        .and(config.clone())
+        .and_then(handle_invite);
+
+    let room_role_handler = warp::path("rooms")
+        .and(warp::path::param())
+        .and(warp::path("role"))
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(clients.clone())
+        .and(rooms.clone())
+        .and(config.clone())
+        .and_then(handle_room_role);
+
+    let room_kick_handler = warp::path("rooms")
+        .and(warp::path::param())
+        .and(warp::path("kick"))
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(clients.clone())
+        .and(rooms.clone())
+        .and(config.clone())
+        .and_then(handle_room_kick);
+
+    let delete_room_message_handler = warp::path("rooms")
+        .and(warp::path::param())
+        .and(warp::path("messages"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(warp::body::content_length_limit(max_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(clients.clone())
+        .and(rooms.clone())
+        .and(config.clone())
+        .and_then(handle_delete_room_message);
+
+    let room_history_handler = warp::path("rooms")
+        .and(warp::path::param())
+        .and(warp::path("history"))
+        .and(warp::get())
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(clients.clone())
+        .and(rooms.clone())
+        .and(config.clone())
+        .and_then(handle_room_history);
+
+    let join_room_handler = warp::path("rooms")
+        .and(warp::path::param())
+        .and(warp::path("join"))
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(clients.clone())
+        .and(rooms.clone())
+        .and(config.clone())
+        .and_then(handle_join_room);
+
+    let leave_room_handler = warp::path("rooms")
+        .and(warp::path::param())
+        .and(warp::path("leave"))
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(clients.clone())
+        .and(rooms.clone())
+        .and(config.clone())
+        .and_then(handle_leave_room);
+
+    let room_message_handler = warp::path("rooms")
+        .and(warp::path::param())
+        .and(warp::path("message"))
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_message_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(clients.clone())
+        .and(rooms.clone())
+        .and(config.clone())
+        .and(metrics.clone())
+        .and_then(handle_room_message);
+
+    let edit_message_handler = warp::path("messages")
+        .and(warp::path::param())
+        .and(warp::path("edit"))
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_message_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(clients.clone())
+        .and(config.clone())
+        .and(store.clone())
+        .and_then(handle_edit_message);
+
+    let delete_message_handler = warp::path("messages")
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(warp::body::content_length_limit(max_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(clients.clone())
+        .and(config.clone())
+        .and(store.clone())
+        .and(attachments.clone())
+        .and_then(handle_delete_message);
+
+    let react_handler = warp::path("messages")
+        .and(warp::path::param())
+        .and(warp::path("react"))
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(clients.clone())
+        .and(config.clone())
+        .and_then(handle_react);
+
+    let message_status_handler = warp::path("messages")
+        .and(warp::path::param())
+        .and(warp::path("status"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::header::optional("authorization"))
+        .and(clients.clone())
+        .and(config.clone())
+        .and_then(handle_message_status);
+
+    let pin_handler = warp::path("messages")
+        .and(warp::path::param())
+        .and(warp::path("pin"))
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(clients.clone())
+        .and(pins.clone())
+        .and(config.clone())
+        .and_then(handle_pin);
+
+    let unpin_handler = warp::path("messages")
+        .and(warp::path::param())
+        .and(warp::path("unpin"))
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_request_bytes))
+        .and(wire::body(request_timeout))
+        .and(clients.clone())
+        .and(pins.clone())
+        .and(config.clone())
+        .and_then(handle_unpin);
+
+    let pins_handler = warp::path("pins")
+        .and(warp::get())
+        .and(warp::path::param())
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(clients.clone())
+        .and(pins)
+        .and(config.clone())
+        .and_then(handle_pins);
+
+    let status_handler = warp::path("status")
+        .and(warp::get())
+        .and(warp::path::param())
+        .and(state.clone())
+        .and_then(handle_status);
+
+    let history_handler = warp::path("history")
+        .and(warp::get())
+        .and(warp::path::param())
+        .and(clients.clone())
+        .and(config.clone())
+        .and_then(handle_history);
+
+    let search_handler = warp::path("search")
+        .and(warp::get())
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(clients.clone())
+        .and(config.clone())
+        .and_then(handle_search);
+
+    let upload_handler = warp::path("upload")
+        .and(warp::post())
+        // room for the multipart framing and the token
+        .and(warp::multipart::form().max_length(max_attachment_bytes + 16 * 1024))
+        .and(clients.clone())
+        .and(config.clone())
+        .and(attachments.clone())
+        .and_then(handle_upload);
+
+    let attachment_handler = warp::path("attachments")
+        .and(warp::get())
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(attachments.clone())
+        .and_then(handle_attachment);
+
+    let export_handler = warp::path("export")
+        .and(warp::get())
+        .and(warp::path::param())
+        .and(clients.clone())
+        .and(config.clone())
+        .and_then(handle_export);
+
+    let messages_handler = warp::path("messages")
+        .and(
+            warp::path::param()
+                .map(Some)
+                .or(warp::path::end().map(|| None))
+                .unify(),
+        )
+        .and(warp::header::optional("authorization"))
+        .and(warp::header::optional("sec-websocket-protocol"))
+        .map(socket_credentials)
+        .and(
+            warp::query::<SocketQuery>()
+                .and(warp::header::optional("x-last-seq"))
+                .map(|query: SocketQuery, last_seq: Option<u64>| SocketQuery {
+                    last_seq: query.last_seq.or(last_seq),
+                    ..query
+                }),
+        )
+        .and(warp::header::optional("sec-websocket-protocol"))
+        .and(warp::addr::remote())
+        .and(warp::ws())
+        .and(clients.clone())
+        .and(config.clone())
+        .and(metrics.clone())
+        .and(shutting_down.clone())
+        .and(maintenance.clone())
+        .and(store.clone())
+        .and(attachments.clone())
+        .and(connection_limiter)
+        .and(rooms.clone())
+        .and(dead_letters.clone())
+        .and(backplane.clone())
+        .and_then(ws_handler)
+        // boxed for the same reason as the admin routes below
+        .boxed();
+
+    let events_handler = warp::path("events")
+        .and(
+            warp::path::param()
+                .map(Some)
+                .or(warp::path::end().map(|| None))
+                .unify(),
+        )
+        .and(warp::get())
+        .and(warp::header::optional("authorization"))
+        .map(|token, authorization| socket_credentials(token, authorization, None))
+        .and(clients.clone())
+        .and(config.clone())
+        .and(metrics.clone())
+        .and(shutting_down.clone())
+        .and(maintenance.clone())
+        .and(store.clone())
+        .and(rooms.clone())
+        .and(dead_letters.clone())
+        .and(backplane.clone())
+        .and_then(sse_handler)
+        .boxed();
+
+    let healthz_handler = warp::path("healthz")
+        .and(warp::get())
+        .and(started_at)
+        .and_then(handle_healthz);
+
+    let version_handler = warp::path("version")
+        .and(warp::get())
+        .and_then(handle_version);
+
+    let readyz_handler = warp::path("readyz")
+        .and(warp::get())
+        .and(started_at)
+        .and({
+            let ready = ready.clone();
+            warp::any().map(move || ready.clone())
+        })
+        .and(shutting_down.clone())
+        .and(store.clone())
+        .and(backplane.clone())
+        .and_then(handle_readyz);
+
+    let metrics_handler = warp::path("metrics")
+        .and(warp::get())
+        .and(clients.clone())
+        .and(metrics.clone())
+        .and_then(handle_metrics);
+
+    let list_clients_handler = warp::path("clients")
+        .and(warp::get())
+        .and(warp::query())
+        .and(clients.clone())
+        .and_then(handle_list_clients);
+
+    let attachment_handlers = upload_handler.or(attachment_handler).boxed();
+
+    // the reads that can grow large, compressed for clients that take it
+    let read_handlers = status_handler
+        .or(history_handler)
+        .or(export_handler)
+        .or(search_handler)
+        .or(list_clients_handler);
+
+    let read_handlers = gzip_accepted(true)
+        .and(read_handlers.clone())
+        .with(warp::compression::gzip())
+        .or(gzip_accepted(false).and(read_handlers))
+        .map(|reply| warp::reply::with_header(reply, warp::http::header::VARY, "accept-encoding"))
+        .boxed();
+
+    // boxed to keep the type of the whole route tree from nesting too deep
+    let admin_handlers = kick_handler
+        .or(maintenance_handler)
+        .or(announce_handler)
+        .or(ban_handler)
+        .or(unban_handler)
+        .or(stats_handler)
+        .or(metrics_json_handler)
+        .or(list_reports_handler)
+        .or(resolve_report_handler)
+        .or(audit_handler)
+        .or(dead_letters_handler)
+        .boxed();
+
+    let room_handlers = create_room_handler
+        .or(list_rooms_handler)
+        .or(invite_handler)
+        .or(room_role_handler)
+        .or(room_kick_handler)
+        .or(delete_room_message_handler)
+        .or(room_history_handler)
+        .or(join_room_handler)
+        .or(leave_room_handler)
+        .or(room_message_handler)
+        .boxed();
+
+    let ops_handlers = metrics_handler
+        .or(healthz_handler)
+        .or(readyz_handler)
+        .or(version_handler)
+        .boxed();
+
+    let pin_handlers = pin_handler
+        .or(unpin_handler)
+        .or(pins_handler)
+        .or(message_status_handler)
+        .boxed();
+
+    let session_handlers = list_sessions_handler.or(revoke_session_handler).boxed();
+
+    let client_handlers = rename_handler
+        .or(presence_handler)
+        .or(typing_handler)
+        .or(block_handler)
+        .or(unblock_handler)
+        .or(report_handler)
+        .boxed();
+
+    let routes = registration_handler
+        .or(login_handler)
+        .or(refresh_handler)
+        .or(session_handlers)
+        .or(messages_handler)
+        .or(events_handler)
+        .or(attachment_handlers)
         .or(send_message_handler)
-        .or(status_handler)
-        .or(list_clients_handler)
+        .or(broadcast_handler)
+        .or(logout_handler)
+        .or(delete_account_handler)
+        .or(admin_handlers)
+        .or(client_handlers)
+        .or(read_handler)
+        .or(room_handlers)
+        .or(edit_message_handler)
+        .or(delete_message_handler)
+        .or(react_handler)
+        .or(pin_handlers)
+        .or(read_handlers)
+        .or(ops_handlers)
         .or(serve_static)
-        .with(
-            warp::cors()
-                .allow_any_origin()
-                .allow_header(warp::http::header::CONTENT_TYPE)
-                .allow_methods(vec![warp::http::Method::GET, warp::http::Method::POST]),
-        );
+        .recover(move |rejection| handle_rejection(rejection, recover_metrics.clone()));
+
+    // every response, errors included, carries the id its log lines are tagged with
+    let routes = warp::header::optional::<String>("x-request-id")
+        .map(request_id)
+        .and(routes)
+        .map(|id: String, reply| warp::reply::with_header(reply, "x-request-id", id))
+        .with(warp::trace(|info| {
+            tracing::info_span!(
+                "request",
+                method = %info.method(),
+                path = info.path(),
+                request_id = tracing::field::Empty,
+            )
+        }));
+
+    // without cors headers browsers keep pages from other origins out
+    match cors {
+        Some(cors) => routes.with(cors).map(Reply::into_response).boxed(),
+        None => routes.map(Reply::into_response).boxed(),
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
+        )
+        .init();
+
+    let config = Arc::new(Config::load(&Args::parse()).unwrap_or_else(|err| {
+        tracing::error!("{}", err);
+        std::process::exit(1);
+    }));
+    let store = Arc::new(
+        store::Store::open(&config.database_path).unwrap_or_else(|err| {
+            tracing::error!("{}", err);
+            std::process::exit(1);
+        }),
+    );
+
+    // restored sessions start a fresh window, nobody could use them while
+    // the server was down
+    let restored = store.load().unwrap_or_else(|err| {
+        tracing::error!("{}", err);
+        std::process::exit(1);
+    });
+
+    if !restored.is_empty() {
+        tracing::info!(clients = restored.len(), "restored clients");
+    }
+
+    let bans = store.load_bans().unwrap_or_else(|err| {
+        tracing::error!("{}", err);
+        std::process::exit(1);
+    });
+
+    let clients: Clients = Arc::new(ClientMap::default());
 
-    println!("starting http server");
-    warp::serve(routes).run(([0, 0, 0, 0], 8080)).await;
+    for mut client in restored {
+        client.prune_history(&config.history_caps);
+
+        let client = Client {
+            expires_at: Some(tokio::time::Instant::now() + config.token_ttl),
+            last_seq: client
+                .history
+                .iter()
+                .map(|message| message.seq)
+                .max()
+                .unwrap_or(0),
+            ..client
+        };
+
+        clients.insert(name_key(&client.name), client);
+    }
+
+    let bans: BanList = Arc::new(Mutex::new(bans));
+    let state = State::new(config.clone(), store, clients, bans);
+
+    spawn_background_tasks(&state);
+
+    let bind_addr = config.bind_addr;
+
+    // read the certificate up front so a bad path fails with a clear message
+    // instead of a panic inside warp
+    let tls = config.tls.as_ref().map(|tls| {
+        let read = |path: &std::path::Path| {
+            std::fs::read(path).unwrap_or_else(|err| {
+                tracing::error!("could not read {}: {}", path.display(), err);
+                std::process::exit(1);
+            })
+        };
+
+        (read(&tls.cert_path), read(&tls.key_path))
+    });
+
+    let shutdown = shutdown(
+        shutdown_signal(),
+        state.clients.clone(),
+        config.clone(),
+        state.shutting_down.clone(),
+    );
+    let ready = state.ready.clone();
+    let routes = build_routes(state);
+
+    match tls {
+        Some((cert, key)) => {
+            let (addr, server) = warp::serve(routes)
+                .tls()
+                .cert(cert)
+                .key(key)
+                .bind_with_graceful_shutdown(bind_addr, shutdown);
+
+            tracing::info!(%addr, "starting https server");
+            ready.store(true, Ordering::SeqCst);
+            server.await;
+        }
+        None => {
+            let (addr, server) = warp::serve(routes)
+                .try_bind_with_graceful_shutdown(bind_addr, shutdown)
+                .unwrap_or_else(|err| {
+                    tracing::error!("could not listen on {}: {}", bind_addr, err);
+                    std::process::exit(1);
+                });
+
+            tracing::info!(%addr, "starting http server");
+            ready.store(true, Ordering::SeqCst);
+            server.await;
+        }
+    }
 }
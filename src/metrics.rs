@@ -0,0 +1,207 @@
+//! prometheus counters and gauges describing the server's activity, and the
+//! same numbers as json for dashboards that don't speak prometheus
+
+use std::sync::{
+    atomic::{AtomicI64, Ordering},
+    Mutex,
+};
+
+use prometheus::{Encoder, IntCounter, IntGauge, Registry, TextEncoder};
+use tokio::time::Instant;
+
+/// number of one second buckets the message rate is counted over
+const RATE_WINDOW_SECS: usize = 60;
+
+/// counts over the last `RATE_WINDOW_SECS` seconds, one bucket per second.
+/// a bucket is reused once its second has gone out of the window
+#[derive(Debug)]
+struct RollingCount {
+    started_at: Instant,
+
+    /// second since `started_at` each bucket counts, and its count
+    buckets: [(u64, u64); RATE_WINDOW_SECS],
+}
+
+impl RollingCount {
+    fn new() -> Self {
+        RollingCount {
+            started_at: Instant::now(),
+            buckets: [(0, 0); RATE_WINDOW_SECS],
+        }
+    }
+
+    fn add(&mut self, count: u64, now: Instant) {
+        let second = now.duration_since(self.started_at).as_secs();
+        let bucket = &mut self.buckets[second as usize % RATE_WINDOW_SECS];
+
+        if bucket.0 != second {
+            *bucket = (second, 0);
+        }
+
+        bucket.1 += count;
+    }
+
+    fn total(&self, now: Instant) -> u64 {
+        let second = now.duration_since(self.started_at).as_secs();
+
+        self.buckets
+            .iter()
+            .filter(|(at, _)| second - at < RATE_WINDOW_SECS as u64)
+            .map(|(_, count)| count)
+            .sum()
+    }
+}
+
+/// what `/admin/metrics.json` answers with
+#[derive(Debug, serde::Serialize)]
+pub struct Snapshot {
+    pub registered_clients: i64,
+    pub active_connections: i64,
+
+    /// most sockets connected at once since the server started
+    pub peak_connections: i64,
+
+    pub messages_sent: u64,
+
+    /// messages handed to a recipient within the last minute
+    pub messages_last_minute: u64,
+
+    pub registrations: u64,
+    pub registration_failures: u64,
+    pub delivery_failures: u64,
+
+    /// requests answered with an error
+    pub rejections: u64,
+}
+
+pub struct Metrics {
+    registry: Registry,
+
+    /// clients currently holding a name, refreshed on every scrape
+    pub registered_clients: IntGauge,
+
+    /// sockets currently connected
+    pub active_connections: IntGauge,
+
+    /// messages handed to a recipient, one per recipient
+    pub messages_sent: IntCounter,
+
+    /// registrations that were turned down
+    pub registration_failures: IntCounter,
+
+    /// messages that could not be delivered, unknown recipients included
+    pub delivery_failures: IntCounter,
+
+    /// registrations that handed out a token
+    pub registrations: IntCounter,
+
+    /// requests answered with an error
+    pub rejections: IntCounter,
+
+    peak_connections: AtomicI64,
+    recent_messages: Mutex<RollingCount>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("chat".to_string()), None).unwrap();
+
+        let registered_clients =
+            IntGauge::new("registered_clients", "Clients currently holding a name").unwrap();
+        let active_connections =
+            IntGauge::new("active_connections", "Websockets currently connected").unwrap();
+        let messages_sent =
+            IntCounter::new("messages_sent_total", "Messages handed to a recipient").unwrap();
+        let registration_failures = IntCounter::new(
+            "registration_failures_total",
+            "Registrations that were turned down",
+        )
+        .unwrap();
+        let delivery_failures = IntCounter::new(
+            "delivery_failures_total",
+            "Messages that could not be delivered",
+        )
+        .unwrap();
+        let registrations = IntCounter::new(
+            "registrations_total",
+            "Registrations that handed out a token",
+        )
+        .unwrap();
+        let rejections =
+            IntCounter::new("rejections_total", "Requests answered with an error").unwrap();
+
+        registry
+            .register(Box::new(registered_clients.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(active_connections.clone()))
+            .unwrap();
+        registry.register(Box::new(messages_sent.clone())).unwrap();
+        registry
+            .register(Box::new(registration_failures.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(delivery_failures.clone()))
+            .unwrap();
+        registry.register(Box::new(registrations.clone())).unwrap();
+        registry.register(Box::new(rejections.clone())).unwrap();
+
+        Metrics {
+            registry,
+            registered_clients,
+            active_connections,
+            messages_sent,
+            registration_failures,
+            delivery_failures,
+            registrations,
+            rejections,
+            peak_connections: AtomicI64::new(0),
+            recent_messages: Mutex::new(RollingCount::new()),
+        }
+    }
+
+    /// counts a socket that just connected, raising the peak if it's a new one
+    pub fn connection_opened(&self) {
+        self.active_connections.inc();
+        self.peak_connections
+            .fetch_max(self.active_connections.get(), Ordering::Relaxed);
+    }
+
+    /// counts messages handed to `count` recipients
+    pub fn record_sent(&self, count: u64) {
+        self.messages_sent.inc_by(count);
+        self.recent_messages
+            .lock()
+            .unwrap()
+            .add(count, Instant::now());
+    }
+
+    /// every number for the json endpoint, `registered_clients` refreshed
+    /// from `registered`
+    pub fn snapshot(&self, registered: usize) -> Snapshot {
+        self.registered_clients.set(registered as i64);
+
+        Snapshot {
+            registered_clients: self.registered_clients.get(),
+            active_connections: self.active_connections.get(),
+            peak_connections: self.peak_connections.load(Ordering::Relaxed),
+            messages_sent: self.messages_sent.get(),
+            messages_last_minute: self.recent_messages.lock().unwrap().total(Instant::now()),
+            registrations: self.registrations.get(),
+            registration_failures: self.registration_failures.get(),
+            delivery_failures: self.delivery_failures.get(),
+            rejections: self.rejections.get(),
+        }
+    }
+
+    /// renders every metric in the prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+
+        String::from_utf8(buffer).unwrap()
+    }
+}
@@ -0,0 +1,306 @@
+//! bounded, non-blocking channel used to hand messages to a socket's
+//! forwarding task. unlike `tokio::sync::mpsc`, a full outbox is resolved by
+//! an [`OverflowPolicy`] instead of making the sender wait, so delivering to
+//! a slow client never stalls whoever holds the clients lock.
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::sync::Notify;
+
+/// what to do when a message is sent to a full outbox
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// discard the oldest queued message to make room
+    DropOldest,
+
+    /// discard the message being sent
+    DropNewest,
+
+    /// close the outbox, which disconnects the slow client
+    Disconnect,
+}
+
+impl std::str::FromStr for OverflowPolicy {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "drop_oldest" => Ok(OverflowPolicy::DropOldest),
+            "drop_newest" => Ok(OverflowPolicy::DropNewest),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SendError<T> {
+    /// the receiving side is gone, or was closed by the overflow policy
+    Closed(T),
+
+    /// the outbox is full and the message was dropped
+    Full(T),
+}
+
+#[derive(Debug)]
+struct State<T> {
+    queue: VecDeque<T>,
+    closed: bool,
+}
+
+#[derive(Debug)]
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    notify: Notify,
+    closed_notify: Notify,
+    senders: AtomicUsize,
+    capacity: usize,
+    policy: OverflowPolicy,
+}
+
+impl<T> Shared<T> {
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.notify.notify_one();
+        self.closed_notify.notify_waiters();
+    }
+}
+
+#[derive(Debug)]
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+#[derive(Debug)]
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub fn channel<T>(capacity: usize, policy: OverflowPolicy) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            queue: VecDeque::with_capacity(capacity),
+            closed: false,
+        }),
+        notify: Notify::new(),
+        closed_notify: Notify::new(),
+        senders: AtomicUsize::new(1),
+        capacity,
+        policy,
+    });
+
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+impl<T> Sender<T> {
+    /// queues a value without waiting, applying the overflow policy when full
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let mut state = self.shared.state.lock().unwrap();
+
+        if state.closed {
+            return Err(SendError::Closed(value));
+        }
+
+        if state.queue.len() >= self.shared.capacity {
+            match self.shared.policy {
+                OverflowPolicy::DropOldest => {
+                    state.queue.pop_front();
+                }
+                OverflowPolicy::DropNewest => return Err(SendError::Full(value)),
+                OverflowPolicy::Disconnect => {
+                    state.queue.clear();
+                    drop(state);
+
+                    self.shared.close();
+                    return Err(SendError::Closed(value));
+                }
+            }
+        }
+
+        state.queue.push_back(value);
+        drop(state);
+
+        self.shared.notify.notify_one();
+        Ok(())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        // like mpsc, the outbox closes once the last sender is gone
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.close();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// waits for the next value, returns `None` once the outbox is closed
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+
+                if let Some(value) = state.queue.pop_front() {
+                    return Some(value);
+                }
+
+                if state.closed {
+                    return None;
+                }
+            }
+
+            self.shared.notify.notified().await;
+        }
+    }
+
+    /// resolves once the outbox is closed, even if values are still queued
+    pub async fn closed(&self) {
+        loop {
+            let notified = self.shared.closed_notify.notified();
+
+            if self.shared.state.lock().unwrap().closed {
+                return;
+            }
+
+            notified.await;
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// everything queued right now, without waiting for more
+    fn drain(receiver: &mut Receiver<u32>) -> Vec<u32> {
+        receiver
+            .shared
+            .state
+            .lock()
+            .unwrap()
+            .queue
+            .drain(..)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn values_arrive_in_order() {
+        let (sender, mut receiver) = channel(4, OverflowPolicy::DropOldest);
+
+        sender.send(1).unwrap();
+        sender.send(2).unwrap();
+
+        assert_eq!(receiver.recv().await, Some(1));
+        assert_eq!(receiver.recv().await, Some(2));
+    }
+
+    #[test]
+    fn full_outbox_drops_the_oldest() {
+        let (sender, mut receiver) = channel(2, OverflowPolicy::DropOldest);
+
+        for value in 1..=3 {
+            sender.send(value).unwrap();
+        }
+
+        assert_eq!(drain(&mut receiver), [2, 3]);
+    }
+
+    #[test]
+    fn full_outbox_drops_the_newest() {
+        let (sender, mut receiver) = channel(2, OverflowPolicy::DropNewest);
+
+        sender.send(1).unwrap();
+        sender.send(2).unwrap();
+
+        assert!(matches!(sender.send(3), Err(SendError::Full(3))));
+        assert_eq!(drain(&mut receiver), [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn full_outbox_disconnects() {
+        let (sender, mut receiver) = channel(1, OverflowPolicy::Disconnect);
+
+        sender.send(1).unwrap();
+
+        assert!(matches!(sender.send(2), Err(SendError::Closed(2))));
+        assert!(matches!(sender.send(3), Err(SendError::Closed(3))));
+
+        // what was queued goes too, the client is cut off at once
+        receiver.closed().await;
+        assert_eq!(receiver.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn outbox_closes_with_its_last_sender() {
+        let (sender, mut receiver) = channel(4, OverflowPolicy::DropOldest);
+        let clone = sender.clone();
+
+        sender.send(1).unwrap();
+        drop(sender);
+
+        clone.send(2).unwrap();
+        drop(clone);
+
+        // queued values are still handed out before the end
+        assert_eq!(receiver.recv().await, Some(1));
+        assert_eq!(receiver.recv().await, Some(2));
+        assert_eq!(receiver.recv().await, None);
+    }
+
+    #[test]
+    fn sending_to_a_dropped_receiver_fails() {
+        let (sender, receiver) = channel(4, OverflowPolicy::DropOldest);
+
+        drop(receiver);
+
+        assert!(matches!(sender.send(1), Err(SendError::Closed(1))));
+    }
+
+    #[tokio::test]
+    async fn recv_waits_for_a_value() {
+        let (sender, mut receiver) = channel(4, OverflowPolicy::DropOldest);
+
+        let received = tokio::spawn(async move { receiver.recv().await });
+
+        tokio::task::yield_now().await;
+        sender.send(7).unwrap();
+
+        assert_eq!(received.await.unwrap(), Some(7));
+    }
+
+    #[test]
+    fn policies_parse_from_their_names() {
+        assert_eq!("drop_oldest".parse(), Ok(OverflowPolicy::DropOldest));
+        assert_eq!("drop_newest".parse(), Ok(OverflowPolicy::DropNewest));
+        assert_eq!("disconnect".parse(), Ok(OverflowPolicy::Disconnect));
+        assert_eq!("block".parse::<OverflowPolicy>(), Err(()));
+    }
+}
@@ -0,0 +1,81 @@
+//! password hashes for accounts registered with a password. hashes are
+//! pbkdf2 with hmac-sha256 and a random salt, stored as
+//! `pbkdf2-sha256$<iterations>$<salt>$<hash>` with salt and hash in hex, so
+//! raising the iterations later keeps the existing hashes verifiable.
+//!
+//! hashing is slow on purpose, call these through `spawn_blocking` rather
+//! than on the runtime.
+
+use std::num::NonZeroU32;
+
+use ring::{pbkdf2, rand::SecureRandom};
+
+/// what a stored hash starts with
+const SCHEME: &str = "pbkdf2-sha256";
+
+const ALGORITHM: pbkdf2::Algorithm = pbkdf2::PBKDF2_HMAC_SHA256;
+
+const SALT_BYTES: usize = 16;
+
+const HASH_BYTES: usize = 32;
+
+/// shortest accepted password, in characters
+pub const MIN_LENGTH: usize = 8;
+
+/// longest accepted password, in bytes, so hashing one stays cheap enough
+pub const MAX_LENGTH: usize = 1024;
+
+/// hashes `password` with a fresh salt and `iterations` rounds
+pub fn hash(password: &str, iterations: NonZeroU32) -> String {
+    let mut salt = [0; SALT_BYTES];
+
+    ring::rand::SystemRandom::new()
+        .fill(&mut salt)
+        .expect("the system has no source of randomness");
+
+    let mut hash = [0; HASH_BYTES];
+    pbkdf2::derive(ALGORITHM, iterations, &salt, password.as_bytes(), &mut hash);
+
+    format!(
+        "{}${}${}${}",
+        SCHEME,
+        iterations,
+        to_hex(&salt),
+        to_hex(&hash)
+    )
+}
+
+/// whether `password` is the one `stored` was made from, false for anything
+/// that isn't a hash this module made
+pub fn verify(password: &str, stored: &str) -> bool {
+    let parts = stored.split('$').collect::<Vec<_>>();
+
+    let [SCHEME, iterations, salt, hash] = parts[..] else {
+        return false;
+    };
+
+    let (Some(iterations), Some(salt), Some(hash)) = (
+        iterations.parse().ok().and_then(NonZeroU32::new),
+        from_hex(salt),
+        from_hex(hash),
+    ) else {
+        return false;
+    };
+
+    pbkdf2::verify(ALGORITHM, iterations, &salt, password.as_bytes(), &hash).is_ok()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|at| u8::from_str_radix(hex.get(at..at + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,122 @@
+//! fixed-window rate limiter, counting hits per key and turning away
+//! whatever goes over the limit until the key's window runs out
+
+use std::{collections::HashMap, hash::Hash};
+
+use tokio::time::{Duration, Instant};
+
+#[derive(Debug)]
+struct Window {
+    started_at: Instant,
+    hits: u32,
+}
+
+#[derive(Debug)]
+pub struct RateLimiter<K> {
+    windows: HashMap<K, Window>,
+
+    /// hits allowed per key within one window, 0 turns the limiter off
+    limit: u32,
+    window: Duration,
+
+    /// when stale windows are dropped next, so unused keys don't pile up
+    next_prune: Instant,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(limit: u32, window: Duration) -> Self {
+        RateLimiter {
+            windows: HashMap::new(),
+            limit,
+            window,
+            next_prune: Instant::now() + window,
+        }
+    }
+
+    /// records a hit for `key`, or returns how long until it's allowed again
+    pub fn check(&mut self, key: K, now: Instant) -> Result<(), Duration> {
+        if self.limit == 0 {
+            return Ok(());
+        }
+
+        if now >= self.next_prune {
+            let window = self.window;
+
+            self.windows
+                .retain(|_, entry| now.duration_since(entry.started_at) < window);
+            self.next_prune = now + window;
+        }
+
+        let entry = self.windows.entry(key).or_insert(Window {
+            started_at: now,
+            hits: 0,
+        });
+
+        if now.duration_since(entry.started_at) >= self.window {
+            entry.started_at = now;
+            entry.hits = 0;
+        }
+
+        if entry.hits >= self.limit {
+            return Err(self.window - now.duration_since(entry.started_at));
+        }
+
+        entry.hits += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn hits_over_the_limit_wait_out_the_window() {
+        let mut limiter = RateLimiter::new(2, WINDOW);
+        let start = Instant::now();
+
+        assert!(limiter.check("a", start).is_ok());
+        assert!(limiter.check("a", start + Duration::from_secs(10)).is_ok());
+        assert_eq!(
+            limiter.check("a", start + Duration::from_secs(20)),
+            Err(Duration::from_secs(40))
+        );
+
+        // a new window starts once the old one ran out
+        assert!(limiter.check("a", start + WINDOW).is_ok());
+    }
+
+    #[test]
+    fn keys_are_counted_apart() {
+        let mut limiter = RateLimiter::new(1, WINDOW);
+        let now = Instant::now();
+
+        assert!(limiter.check("a", now).is_ok());
+        assert!(limiter.check("b", now).is_ok());
+        assert!(limiter.check("a", now).is_err());
+    }
+
+    #[test]
+    fn zero_limit_lets_everything_through() {
+        let mut limiter = RateLimiter::new(0, WINDOW);
+        let now = Instant::now();
+
+        for _ in 0..100 {
+            assert!(limiter.check("a", now).is_ok());
+        }
+    }
+
+    #[test]
+    fn stale_windows_are_pruned() {
+        let mut limiter = RateLimiter::new(1, WINDOW);
+        let start = Instant::now();
+
+        limiter.check("a", start).unwrap();
+        limiter.check("b", start + WINDOW * 2).unwrap();
+
+        assert_eq!(limiter.windows.len(), 1);
+        assert!(limiter.windows.contains_key("b"));
+    }
+}
@@ -0,0 +1,86 @@
+//! messages flagged by their recipients for a moderator to look at. like the
+//! history the queue only lives in memory, and only so many reports are kept:
+//! past that resolved ones make room first, then the oldest.
+
+use std::{collections::VecDeque, sync::Mutex};
+
+/// maximum number of reports kept
+const REPORT_LIMIT: usize = 1000;
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Report {
+    pub id: String,
+
+    /// name of the client who filed the report, as it was then
+    pub reporter: String,
+
+    pub message_id: String,
+
+    /// name of the client who sent the message, as it was then
+    pub sender: String,
+
+    /// the message body at the time of the report, later edits or a delete
+    /// don't change what the moderator sees
+    pub body: String,
+
+    pub reason: String,
+
+    /// unix timestamp in milliseconds of the report
+    pub timestamp: i64,
+
+    pub resolved: bool,
+
+    /// token of the reporter, so the same message isn't queued twice
+    #[serde(skip)]
+    pub reporter_token: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Reports {
+    reports: Mutex<VecDeque<Report>>,
+}
+
+impl Reports {
+    /// queues a report and returns its id. a reporter flagging the same
+    /// message again gets the id of the first report
+    pub fn insert(&self, report: Report) -> String {
+        let mut reports = self.reports.lock().unwrap();
+
+        if let Some(existing) = reports.iter().find(|existing| {
+            existing.reporter_token == report.reporter_token
+                && existing.message_id == report.message_id
+        }) {
+            return existing.id.clone();
+        }
+
+        if reports.len() == REPORT_LIMIT {
+            match reports.iter().position(|report| report.resolved) {
+                Some(index) => reports.remove(index),
+                None => reports.pop_front(),
+            };
+        }
+
+        let id = report.id.clone();
+        reports.push_back(report);
+
+        id
+    }
+
+    /// every report kept, oldest first
+    pub fn list(&self) -> Vec<Report> {
+        self.reports.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// marks a report as dealt with, false if there's no such report
+    pub fn resolve(&self, id: &str) -> bool {
+        let mut reports = self.reports.lock().unwrap();
+
+        match reports.iter_mut().find(|report| report.id == id) {
+            Some(report) => {
+                report.resolved = true;
+                true
+            }
+            None => false,
+        }
+    }
+}
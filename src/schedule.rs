@@ -0,0 +1,79 @@
+//! messages held back until the time their sender asked for. like the rest of
+//! the in-memory state the queue doesn't survive a restart, anything still
+//! waiting is lost.
+
+use std::{collections::BTreeMap, sync::Mutex};
+
+use tokio::sync::Notify;
+
+use super::unix_millis;
+
+#[derive(Debug, Default)]
+struct Queue<T> {
+    /// (due time in unix milliseconds, insertion counter) -> item, so items
+    /// due at the same millisecond come out in the order they went in
+    items: BTreeMap<(i64, u64), T>,
+    inserted: u64,
+}
+
+#[derive(Debug)]
+pub struct Schedule<T> {
+    queue: Mutex<Queue<T>>,
+
+    /// woken on every insert, the new item may be due before the one being
+    /// waited for
+    changed: Notify,
+}
+
+impl<T> Schedule<T> {
+    pub fn new() -> Self {
+        Schedule {
+            queue: Mutex::new(Queue {
+                items: BTreeMap::new(),
+                inserted: 0,
+            }),
+            changed: Notify::new(),
+        }
+    }
+
+    /// queues `item` until `due`, a unix timestamp in milliseconds
+    pub fn insert(&self, due: i64, item: T) {
+        let mut queue = self.queue.lock().unwrap();
+
+        queue.inserted += 1;
+        let key = (due, queue.inserted);
+        queue.items.insert(key, item);
+        drop(queue);
+
+        self.changed.notify_one();
+    }
+
+    /// waits for the earliest item to fall due and takes it out of the queue
+    pub async fn next_due(&self) -> T {
+        loop {
+            let due = {
+                let mut queue = self.queue.lock().unwrap();
+
+                match queue.items.keys().next() {
+                    Some(&key) if key.0 <= unix_millis() => {
+                        return queue.items.remove(&key).unwrap();
+                    }
+                    next => next.map(|&(due, _)| due),
+                }
+            };
+
+            match due {
+                Some(due) => {
+                    let wait =
+                        tokio::time::Duration::from_millis((due - unix_millis()).max(0) as u64);
+
+                    tokio::select! {
+                        _ = tokio::time::sleep(wait) => {}
+                        _ = self.changed.notified() => {}
+                    }
+                }
+                None => self.changed.notified().await,
+            }
+        }
+    }
+}
@@ -0,0 +1,415 @@
+//! optional write-through persistence of clients, their message history and
+//! bans, so a restart doesn't lose accounts. built with the `sqlite` feature it
+//! keeps everything in a sqlite database, without it every call is a no-op
+//! and state only lives in memory.
+//!
+//! accounts with a password keep their hash, their sessions only live in
+//! memory and they log in again after a restart.
+
+use super::{Bans, Client, Message};
+
+/// kinds of ban, as stored
+pub const BAN_NAME: &str = "name";
+pub const BAN_ADDR: &str = "addr";
+
+#[cfg(feature = "sqlite")]
+use super::{unix_millis, HISTORY_LIMIT};
+
+pub struct Store {
+    #[cfg(feature = "sqlite")]
+    connection: std::sync::Mutex<rusqlite::Connection>,
+}
+
+#[cfg(feature = "sqlite")]
+impl Store {
+    pub fn open(path: &std::path::Path) -> Result<Self, String> {
+        let connection = rusqlite::Connection::open(path)
+            .map_err(|err| format!("could not open {}: {}", path.display(), err))?;
+
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS clients (
+                    token TEXT PRIMARY KEY,
+                    name TEXT NOT NULL,
+                    last_seen INTEGER NOT NULL,
+                    password_hash TEXT
+                );
+                CREATE TABLE IF NOT EXISTS messages (
+                    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                    recipient TEXT NOT NULL,
+                    id TEXT NOT NULL,
+                    sender TEXT NOT NULL,
+                    sender_name TEXT NOT NULL,
+                    body TEXT NOT NULL,
+                    timestamp INTEGER NOT NULL,
+                    recipient_seq INTEGER NOT NULL DEFAULT 0,
+                    deleted INTEGER NOT NULL DEFAULT 0,
+                    expires_at INTEGER
+                );
+                CREATE INDEX IF NOT EXISTS messages_by_recipient
+                    ON messages (recipient, seq);
+                CREATE TABLE IF NOT EXISTS blocks (
+                    token TEXT NOT NULL,
+                    blocked TEXT NOT NULL,
+                    PRIMARY KEY (token, blocked)
+                );
+                CREATE TABLE IF NOT EXISTS bans (
+                    kind TEXT NOT NULL,
+                    value TEXT NOT NULL,
+                    PRIMARY KEY (kind, value)
+                );",
+            )
+            .map_err(|err| format!("could not set up {}: {}", path.display(), err))?;
+
+        // databases from before sequence numbers lack the column
+        let has_seq: bool = connection
+            .query_row(
+                "SELECT COUNT(*) > 0 FROM pragma_table_info('messages')
+                    WHERE name = 'recipient_seq'",
+                [],
+                |row| row.get(0),
+            )
+            .map_err(|err| format!("could not set up {}: {}", path.display(), err))?;
+
+        if !has_seq {
+            connection
+                .execute(
+                    "ALTER TABLE messages ADD COLUMN recipient_seq INTEGER NOT NULL DEFAULT 0",
+                    [],
+                )
+                .map_err(|err| format!("could not migrate {}: {}", path.display(), err))?;
+        }
+
+        // as do databases from before passwords
+        let has_password: bool = connection
+            .query_row(
+                "SELECT COUNT(*) > 0 FROM pragma_table_info('clients')
+                    WHERE name = 'password_hash'",
+                [],
+                |row| row.get(0),
+            )
+            .map_err(|err| format!("could not set up {}: {}", path.display(), err))?;
+
+        if !has_password {
+            connection
+                .execute("ALTER TABLE clients ADD COLUMN password_hash TEXT", [])
+                .map_err(|err| format!("could not migrate {}: {}", path.display(), err))?;
+        }
+
+        // and databases from before messages could be deleted
+        let has_deleted: bool = connection
+            .query_row(
+                "SELECT COUNT(*) > 0 FROM pragma_table_info('messages')
+                    WHERE name = 'deleted'",
+                [],
+                |row| row.get(0),
+            )
+            .map_err(|err| format!("could not set up {}: {}", path.display(), err))?;
+
+        if !has_deleted {
+            connection
+                .execute(
+                    "ALTER TABLE messages ADD COLUMN deleted INTEGER NOT NULL DEFAULT 0",
+                    [],
+                )
+                .map_err(|err| format!("could not migrate {}: {}", path.display(), err))?;
+        }
+
+        // and databases from before messages could expire
+        let has_expiry: bool = connection
+            .query_row(
+                "SELECT COUNT(*) > 0 FROM pragma_table_info('messages')
+                    WHERE name = 'expires_at'",
+                [],
+                |row| row.get(0),
+            )
+            .map_err(|err| format!("could not set up {}: {}", path.display(), err))?;
+
+        if !has_expiry {
+            connection
+                .execute("ALTER TABLE messages ADD COLUMN expires_at INTEGER", [])
+                .map_err(|err| format!("could not migrate {}: {}", path.display(), err))?;
+        }
+
+        Ok(Store {
+            connection: std::sync::Mutex::new(connection),
+        })
+    }
+
+    /// every persisted client with its history, oldest message first.
+    /// messages that expired while nobody was around to remove them are
+    /// dropped on the way
+    pub fn load(&self) -> Result<Vec<Client>, String> {
+        let connection = self.connection.lock().unwrap();
+
+        let load = || -> rusqlite::Result<Vec<Client>> {
+            connection.execute(
+                "DELETE FROM messages WHERE expires_at <= ?1",
+                [unix_millis()],
+            )?;
+
+            let mut clients = connection
+                .prepare("SELECT token, name, last_seen, password_hash FROM clients")?
+                .query_map([], |row| {
+                    Ok(Client {
+                        token: row.get(0)?,
+                        name: row.get(1)?,
+                        last_seen: row.get(2)?,
+                        password_hash: row.get(3)?,
+                        ..Default::default()
+                    })
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+
+            let mut history = connection.prepare(
+                "SELECT id, sender, sender_name, body, timestamp, recipient_seq, deleted,
+                    expires_at FROM messages WHERE recipient = ?1 ORDER BY seq",
+            )?;
+
+            let mut blocks = connection.prepare("SELECT blocked FROM blocks WHERE token = ?1")?;
+
+            for client in &mut clients {
+                client.blocked = blocks
+                    .query_map([&client.token], |row| row.get(0))?
+                    .collect::<rusqlite::Result<_>>()?;
+
+                client.history = history
+                    .query_map([&client.token], |row| {
+                        Ok(Message {
+                            id: row.get(0)?,
+                            sender: row.get(1)?,
+                            from: row.get(2)?,
+                            body: row.get(3)?,
+                            timestamp: row.get(4)?,
+                            seq: row.get(5)?,
+                            deleted: row.get(6)?,
+                            expires_at: row.get(7)?,
+                            acknowledge: true,
+                            ..Default::default()
+                        })
+                    })?
+                    .collect::<rusqlite::Result<_>>()?;
+            }
+
+            Ok(clients)
+        };
+
+        load().map_err(|err| format!("could not load clients: {}", err))
+    }
+
+    pub fn load_bans(&self) -> Result<Bans, String> {
+        let connection = self.connection.lock().unwrap();
+
+        let load = || -> rusqlite::Result<Vec<(String, String)>> {
+            connection
+                .prepare("SELECT kind, value FROM bans")?
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect()
+        };
+
+        let mut bans = Bans::default();
+
+        for (kind, value) in load().map_err(|err| format!("could not load bans: {}", err))? {
+            match kind.as_str() {
+                BAN_NAME => {
+                    bans.names.insert(value);
+                }
+                BAN_ADDR => {
+                    let addr = value
+                        .parse()
+                        .map_err(|_| format!("could not load bans: invalid address {}", value))?;
+
+                    bans.addrs.insert(addr);
+                }
+                _ => tracing::warn!(%kind, "skipping unknown kind of ban"),
+            }
+        }
+
+        Ok(bans)
+    }
+
+    pub fn insert_client(&self, client: &Client) {
+        self.execute(
+            "INSERT OR REPLACE INTO clients (token, name, last_seen, password_hash)
+                VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![
+                client.token,
+                client.name,
+                client.last_seen,
+                client.password_hash
+            ],
+        );
+    }
+
+    pub fn rename_client(&self, token: &str, name: &str) {
+        self.execute(
+            "UPDATE clients SET name = ?2 WHERE token = ?1",
+            rusqlite::params![token, name],
+        );
+    }
+
+    pub fn touch_client(&self, token: &str, last_seen: i64) {
+        self.execute(
+            "UPDATE clients SET last_seen = ?2 WHERE token = ?1",
+            rusqlite::params![token, last_seen],
+        );
+    }
+
+    /// forgets a client and whom it blocked. the history addressed to it
+    /// stays, `remove_history` purges that
+    pub fn remove_client(&self, token: &str) {
+        self.execute(
+            "DELETE FROM blocks WHERE token = ?1",
+            rusqlite::params![token],
+        );
+        self.execute(
+            "DELETE FROM clients WHERE token = ?1",
+            rusqlite::params![token],
+        );
+    }
+
+    /// deletes the history addressed to a client
+    pub fn remove_history(&self, token: &str) {
+        self.execute(
+            "DELETE FROM messages WHERE recipient = ?1",
+            rusqlite::params![token],
+        );
+    }
+
+    /// adds a message to the history of `recipient`, keeping only as many as
+    /// the in-memory history does
+    pub fn insert_message(&self, recipient: &str, message: &Message) {
+        self.execute(
+            "INSERT INTO messages
+                (recipient, id, sender, sender_name, body, timestamp, recipient_seq, expires_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            rusqlite::params![
+                recipient,
+                message.id,
+                message.sender,
+                message.from,
+                message.body,
+                message.timestamp,
+                message.seq,
+                message.expires_at
+            ],
+        );
+        self.execute(
+            "DELETE FROM messages WHERE recipient = ?1 AND seq NOT IN (
+                SELECT seq FROM messages WHERE recipient = ?1 ORDER BY seq DESC LIMIT ?2
+            )",
+            rusqlite::params![recipient, HISTORY_LIMIT],
+        );
+    }
+
+    /// replaces the body of message `id` in the history of `recipient`
+    pub fn update_message_body(&self, recipient: &str, id: &str, body: &str) {
+        self.execute(
+            "UPDATE messages SET body = ?3 WHERE recipient = ?1 AND id = ?2",
+            rusqlite::params![recipient, id, body],
+        );
+    }
+
+    /// drops message `id` from every history it's in
+    pub fn remove_message(&self, id: &str) {
+        self.execute("DELETE FROM messages WHERE id = ?1", rusqlite::params![id]);
+    }
+
+    /// leaves message `id` in the history of `recipient` as a tombstone, in
+    /// its place but without a body
+    pub fn tombstone_message(&self, recipient: &str, id: &str) {
+        self.execute(
+            "UPDATE messages SET body = '', deleted = 1 WHERE recipient = ?1 AND id = ?2",
+            rusqlite::params![recipient, id],
+        );
+    }
+
+    pub fn insert_block(&self, token: &str, blocked: &str) {
+        self.execute(
+            "INSERT OR IGNORE INTO blocks (token, blocked) VALUES (?1, ?2)",
+            rusqlite::params![token, blocked],
+        );
+    }
+
+    pub fn remove_block(&self, token: &str, blocked: &str) {
+        self.execute(
+            "DELETE FROM blocks WHERE token = ?1 AND blocked = ?2",
+            rusqlite::params![token, blocked],
+        );
+    }
+
+    pub fn insert_ban(&self, kind: &str, value: &str) {
+        self.execute(
+            "INSERT OR IGNORE INTO bans (kind, value) VALUES (?1, ?2)",
+            rusqlite::params![kind, value],
+        );
+    }
+
+    pub fn remove_ban(&self, kind: &str, value: &str) {
+        self.execute(
+            "DELETE FROM bans WHERE kind = ?1 AND value = ?2",
+            rusqlite::params![kind, value],
+        );
+    }
+
+    /// whether the database still answers
+    pub fn is_healthy(&self) -> bool {
+        self.connection.lock().is_ok_and(|connection| {
+            connection
+                .query_row("SELECT 1", [], |row| row.get::<_, i64>(0))
+                .is_ok()
+        })
+    }
+
+    /// writes are best effort, a failing disk shouldn't take the chat down
+    fn execute(&self, sql: &str, params: impl rusqlite::Params) {
+        if let Err(err) = self.connection.lock().unwrap().execute(sql, params) {
+            tracing::error!(%err, "could not persist state");
+        }
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+impl Store {
+    pub fn open(_path: &std::path::Path) -> Result<Self, String> {
+        Ok(Store {})
+    }
+
+    pub fn load(&self) -> Result<Vec<Client>, String> {
+        Ok(Vec::new())
+    }
+
+    pub fn load_bans(&self) -> Result<Bans, String> {
+        Ok(Bans::default())
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        true
+    }
+
+    pub fn insert_client(&self, _client: &Client) {}
+
+    pub fn rename_client(&self, _token: &str, _name: &str) {}
+
+    pub fn touch_client(&self, _token: &str, _last_seen: i64) {}
+
+    pub fn remove_client(&self, _token: &str) {}
+
+    pub fn remove_history(&self, _token: &str) {}
+
+    pub fn insert_message(&self, _recipient: &str, _message: &Message) {}
+
+    pub fn update_message_body(&self, _recipient: &str, _id: &str, _body: &str) {}
+
+    pub fn remove_message(&self, _id: &str) {}
+
+    pub fn tombstone_message(&self, _recipient: &str, _id: &str) {}
+
+    pub fn insert_block(&self, _token: &str, _blocked: &str) {}
+
+    pub fn remove_block(&self, _token: &str, _blocked: &str) {}
+
+    pub fn insert_ban(&self, _kind: &str, _value: &str) {}
+
+    pub fn remove_ban(&self, _kind: &str, _value: &str) {}
+}
@@ -0,0 +1,165 @@
+//! how the config is put together from the command line, env vars and the
+//! config file, without touching the real env
+
+use std::collections::HashMap;
+
+use crate::{Args, Config, Settings};
+
+/// settings with `overrides` as if they came from the command line
+fn settings(overrides: &[(&str, &str)]) -> Settings {
+    Settings {
+        overrides: overrides
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect::<HashMap<_, _>>(),
+        ..Settings::empty()
+    }
+}
+
+#[test]
+fn bind_address_falls_back_to_every_interface_on_8080() {
+    let config = Config::from_settings(settings(&[])).unwrap();
+
+    assert_eq!(config.bind_addr, "0.0.0.0:8080".parse().unwrap());
+}
+
+#[test]
+fn bind_address_and_port_are_read_separately() {
+    let config = Config::from_settings(settings(&[
+        ("CHAT_BIND_ADDR", "127.0.0.1"),
+        ("CHAT_PORT", "9000"),
+    ]))
+    .unwrap();
+
+    assert_eq!(config.bind_addr, "127.0.0.1:9000".parse().unwrap());
+
+    let config = Config::from_settings(settings(&[("CHAT_PORT", "9000")])).unwrap();
+
+    assert_eq!(config.bind_addr, "0.0.0.0:9000".parse().unwrap());
+}
+
+#[test]
+fn invalid_bind_address_or_port_is_an_error() {
+    for (key, value) in [
+        ("CHAT_BIND_ADDR", "localhost"),
+        ("CHAT_PORT", "70000"),
+        ("CHAT_PORT", "http"),
+    ] {
+        let err = Config::from_settings(settings(&[(key, value)])).unwrap_err();
+
+        assert!(err.contains(key), "{}", err);
+    }
+}
+
+/// a config file in the temp dir, removed once dropped
+struct TempConfig {
+    path: std::path::PathBuf,
+}
+
+impl TempConfig {
+    fn new(contents: &str) -> Self {
+        let name = format!("chat-rs-{}.toml", uuid::Uuid::new_v4().as_simple());
+        let path = std::env::temp_dir().join(name);
+
+        std::fs::write(&path, contents).unwrap();
+
+        TempConfig { path }
+    }
+
+    /// the file's settings on their own, env vars left out
+    fn settings(&self) -> Result<Settings, String> {
+        Settings::load(&Some(self.path.clone())).map(|settings| Settings {
+            read_env: false,
+            ..settings
+        })
+    }
+}
+
+impl Drop for TempConfig {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[test]
+fn config_file_sets_what_it_names() {
+    let file = TempConfig::new(
+        r#"
+        bind_addr = "127.0.0.1"
+        port = 9000
+        disconnect_grace_secs = 5
+        max_message_bytes = 2048
+        reserved_names = ["Admin", "root"]
+        "#,
+    );
+
+    let config = Config::from_settings(file.settings().unwrap()).unwrap();
+
+    assert_eq!(config.bind_addr, "127.0.0.1:9000".parse().unwrap());
+    assert_eq!(config.disconnect_grace, tokio::time::Duration::from_secs(5));
+    assert_eq!(config.max_message_bytes, 2048);
+    assert_eq!(
+        config.reserved_names,
+        ["admin".to_string(), "root".to_string()].into()
+    );
+
+    // anything it leaves out keeps its default
+    assert_eq!(config.outbox_capacity, Config::defaults().outbox_capacity);
+}
+
+#[test]
+fn config_file_with_an_unknown_setting_is_an_error() {
+    let file = TempConfig::new("prot = 9000");
+
+    let err = Config::from_settings(file.settings().unwrap()).unwrap_err();
+
+    assert!(err.contains("prot"), "{}", err);
+}
+
+#[test]
+fn command_line_wins_over_the_config_file() {
+    let file = TempConfig::new("port = 9000");
+
+    let config = Config::from_settings(Settings {
+        overrides: [("CHAT_PORT".to_string(), "9001".to_string())].into(),
+        ..file.settings().unwrap()
+    })
+    .unwrap();
+
+    assert_eq!(config.bind_addr.port(), 9001);
+}
+
+#[test]
+fn arguments_become_overrides() {
+    use clap::Parser;
+
+    let args = Args::try_parse_from([
+        "chat-rs",
+        "--bind",
+        "127.0.0.1",
+        "--port",
+        "9000",
+        "--grace-secs",
+        "0",
+        "--config",
+        "other.toml",
+    ])
+    .unwrap();
+
+    assert_eq!(args.config_path(), Some("other.toml".into()));
+
+    let config = Config::from_settings(Settings {
+        overrides: args.overrides(),
+        ..Settings::empty()
+    })
+    .unwrap();
+
+    assert_eq!(config.bind_addr, "127.0.0.1:9000".parse().unwrap());
+    assert!(config.disconnect_grace.is_zero());
+    assert_eq!(
+        config.max_message_bytes,
+        Config::defaults().max_message_bytes
+    );
+
+    assert!(Args::try_parse_from(["chat-rs", "--port", "http"]).is_err());
+}
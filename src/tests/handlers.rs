@@ -0,0 +1,709 @@
+//! the core of registration, sending and status, called without a server
+
+use std::sync::atomic::Ordering;
+
+use serde_json::json;
+use warp::Reply;
+
+use super::test_state;
+use crate::{
+    attach_connection, check_admin, client_status, handle_logout, handle_registration, outbox,
+    refresh_session, register_client, send_message, ChatError, LogoutRequest, MessageRequest,
+    RegistrationOutcome, SessionTokens, State,
+};
+
+async fn register(state: &State, name: &str) -> String {
+    match register_client(state, name, None, None, None).await {
+        Ok(RegistrationOutcome::Registered { token, .. }) => token,
+        outcome => panic!("registering {}: {:?}", name, outcome),
+    }
+}
+
+/// registers an account for `name`, hashed cheaply, and returns the tokens
+/// of its first session
+async fn register_account(state: &State, name: &str) -> SessionTokens {
+    match register_client(state, name, Some("correct horse"), None, None).await {
+        Ok(RegistrationOutcome::Registered {
+            token,
+            refresh_token: Some(refresh_token),
+        }) => SessionTokens {
+            token,
+            refresh_token,
+        },
+        outcome => panic!("registering {}: {:?}", name, outcome),
+    }
+}
+
+/// logs out with `token`, true if the logout went through
+async fn logout(state: &State, token: &str) -> bool {
+    handle_logout(
+        LogoutRequest {
+            token: token.to_string(),
+        },
+        state.clients.clone(),
+        state.rooms.clone(),
+        state.store.clone(),
+        state.dead_letters.clone(),
+        state.backplane.clone(),
+        state.config.clone(),
+    )
+    .await
+    .is_ok()
+}
+
+fn message(token: &str, to: &str, body: &str) -> MessageRequest {
+    serde_json::from_value(json!({ "token": token, "to": to, "body": body })).unwrap()
+}
+
+#[tokio::test]
+async fn registration_hands_out_a_token() {
+    let state = test_state(|_| {});
+
+    let token = register(&state, "alice").await;
+
+    assert_eq!(client_status(&state, &token).unwrap().name, "alice");
+}
+
+#[tokio::test]
+async fn instrumented_handler_still_replies_like_a_handler() {
+    let state = test_state(|_| {});
+
+    let request = serde_json::from_value(json!({ "name": "alice" })).unwrap();
+
+    // the span around it is invisible to warp, what comes back is a reply
+    let response = handle_registration(request, None, state)
+        .await
+        .unwrap()
+        .into_response();
+
+    assert_eq!(response.status(), warp::http::StatusCode::OK);
+}
+
+#[tokio::test]
+async fn registration_refuses_a_taken_name() {
+    let state = test_state(|_| {});
+
+    register(&state, "alice").await;
+
+    // names are compared case-insensitively
+    let outcome = register_client(&state, "ALICE", None, None, None).await;
+
+    assert_eq!(
+        outcome,
+        Ok(RegistrationOutcome::Refused {
+            error: "The name is already taken",
+            status: warp::http::StatusCode::NOT_ACCEPTABLE,
+        })
+    );
+    assert_eq!(state.metrics.registration_failures.get(), 1);
+}
+
+#[tokio::test]
+async fn registration_refuses_an_invalid_name() {
+    let state = test_state(|_| {});
+
+    let outcome = register_client(&state, "  ", None, None, None).await;
+
+    assert!(matches!(
+        outcome,
+        Ok(RegistrationOutcome::Refused {
+            status: warp::http::StatusCode::BAD_REQUEST,
+            ..
+        })
+    ));
+}
+
+#[tokio::test]
+async fn registration_says_what_is_wrong_with_a_name() {
+    let state = test_state(|_| {});
+
+    let too_long = "a".repeat(crate::MAX_NAME_LENGTH + 1);
+
+    for (name, error) in [
+        (" \t ", "The name can't be empty"),
+        (too_long.as_str(), "The name is too long"),
+        ("bob\u{7}", "The name can't contain control characters"),
+    ] {
+        assert_eq!(
+            register_client(&state, name, None, None, None).await,
+            Ok(RegistrationOutcome::Refused {
+                error,
+                status: warp::http::StatusCode::BAD_REQUEST,
+            }),
+            "{:?}",
+            name
+        );
+    }
+}
+
+#[tokio::test]
+async fn registration_refuses_a_name_composed_differently() {
+    let state = test_state(|_| {});
+
+    // é as one code point, then as e with a combining accent
+    register(&state, "jos\u{e9}").await;
+
+    assert_eq!(
+        register_client(&state, "jose\u{301}", None, None, None).await,
+        Ok(RegistrationOutcome::Refused {
+            error: "The name is already taken",
+            status: warp::http::StatusCode::NOT_ACCEPTABLE,
+        })
+    );
+
+    // names are kept composed
+    let token = register(&state, "rene\u{301}").await;
+
+    assert_eq!(client_status(&state, &token).unwrap().name, "ren\u{e9}");
+}
+
+#[tokio::test]
+async fn registration_refuses_a_reserved_name() {
+    let state = test_state(|config| config.reserved_names = ["admin".to_string()].into());
+
+    let outcome = register_client(&state, "Admin", None, None, None).await;
+
+    assert_eq!(
+        outcome,
+        Ok(RegistrationOutcome::Refused {
+            error: "The name is reserved",
+            status: warp::http::StatusCode::BAD_REQUEST,
+        })
+    );
+}
+
+#[tokio::test]
+async fn registration_allows_a_name_close_to_a_reserved_one() {
+    let state = test_state(|config| config.reserved_names = ["admin".to_string()].into());
+
+    for name in ["admin2", "administrator", "badmin"] {
+        register(&state, name).await;
+    }
+}
+
+#[tokio::test]
+async fn registration_is_refused_once_the_server_is_full() {
+    let state = test_state(|config| config.max_clients = 2);
+
+    let alice = register(&state, "alice").await;
+    register(&state, "bob").await;
+
+    assert_eq!(
+        register_client(&state, "carol", None, None, None).await,
+        Ok(RegistrationOutcome::Refused {
+            error: "The server is full",
+            status: warp::http::StatusCode::SERVICE_UNAVAILABLE,
+        })
+    );
+
+    // a seat opens up when someone leaves
+    assert!(logout(&state, &alice).await);
+
+    register(&state, "carol").await;
+}
+
+#[tokio::test]
+async fn registration_fails_during_maintenance() {
+    let state = test_state(|_| {});
+    state.maintenance.store(true, Ordering::SeqCst);
+
+    let outcome = register_client(&state, "alice", None, None, None).await;
+
+    assert_eq!(outcome, Err(ChatError::Maintenance));
+}
+
+#[tokio::test]
+async fn registration_is_rate_limited_per_address() {
+    let state = test_state(|config| config.register_rate_limit = 1);
+    let remote = Some(([10, 0, 0, 1], 4000).into());
+
+    assert!(matches!(
+        register_client(&state, "alice", None, None, remote).await,
+        Ok(RegistrationOutcome::Registered { .. })
+    ));
+    assert!(matches!(
+        register_client(&state, "bob", None, None, remote).await,
+        Err(ChatError::RateLimited { .. })
+    ));
+}
+
+#[tokio::test]
+async fn message_for_offline_client_is_queued_and_counted_unread() {
+    let state = test_state(|_| {});
+
+    let alice = register(&state, "alice").await;
+    let bob = register(&state, "bob").await;
+
+    let response = send_message(&state, message(&alice, "bob", "hi"))
+        .await
+        .unwrap();
+
+    assert_eq!(response.queued, ["bob"]);
+    assert!(response.delivered.is_empty());
+    assert_eq!(client_status(&state, &bob).unwrap().unread, 1);
+}
+
+#[tokio::test]
+async fn message_to_some_unknown_recipients_reaches_the_rest() {
+    let state = test_state(|_| {});
+
+    let alice = register(&state, "alice").await;
+    register(&state, "bob").await;
+    register(&state, "carol").await;
+
+    let request = serde_json::from_value(
+        json!({ "token": alice, "to": ["bob", "nobody", "carol"], "body": "hi" }),
+    )
+    .unwrap();
+
+    let response = send_message(&state, request).await.unwrap();
+
+    assert_eq!(response.queued, ["bob", "carol"]);
+    assert_eq!(response.unknown, ["nobody"]);
+}
+
+#[tokio::test]
+async fn message_to_unknown_recipient_fails() {
+    let state = test_state(|_| {});
+
+    let alice = register(&state, "alice").await;
+
+    let result = send_message(&state, message(&alice, "nobody", "hi")).await;
+
+    assert_eq!(result.unwrap_err(), ChatError::UnknownRecipient);
+}
+
+#[tokio::test]
+async fn message_at_the_size_limit_is_sent() {
+    let state = test_state(|config| config.max_message_bytes = 4);
+
+    let alice = register(&state, "alice").await;
+    register(&state, "bob").await;
+
+    // the limit is in bytes, not characters
+    let response = send_message(&state, message(&alice, "bob", "é!!"))
+        .await
+        .unwrap();
+
+    assert_eq!(response.queued, ["bob"]);
+}
+
+#[tokio::test]
+async fn message_over_the_size_limit_fails() {
+    let state = test_state(|config| config.max_message_bytes = 4);
+
+    let alice = register(&state, "alice").await;
+    register(&state, "bob").await;
+
+    let result = send_message(&state, message(&alice, "bob", "too long")).await;
+
+    assert_eq!(result.unwrap_err(), ChatError::MessageTooLong);
+}
+
+#[tokio::test]
+async fn message_with_invalid_ttl_fails() {
+    let state = test_state(|_| {});
+
+    let alice = register(&state, "alice").await;
+    register(&state, "bob").await;
+
+    let request =
+        serde_json::from_value(json!({ "token": alice, "to": "bob", "body": "hi", "ttl_secs": 0 }))
+            .unwrap();
+
+    assert_eq!(
+        send_message(&state, request).await.unwrap_err(),
+        ChatError::InvalidTtl
+    );
+}
+
+#[tokio::test]
+async fn message_retried_with_same_key_is_sent_once() {
+    let state = test_state(|_| {});
+
+    let alice = register(&state, "alice").await;
+    let bob = register(&state, "bob").await;
+
+    let request = || {
+        serde_json::from_value(
+            json!({ "token": alice, "to": "bob", "body": "hi", "idempotency_key": "k1" }),
+        )
+        .unwrap()
+    };
+
+    let first = send_message(&state, request()).await.unwrap();
+    let second = send_message(&state, request()).await.unwrap();
+
+    assert_eq!(first.message_id, second.message_id);
+    assert_eq!(client_status(&state, &bob).unwrap().unread, 1);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_retries_with_same_key_are_sent_once() {
+    // identical queued messages aren't collapsed, a second copy would show
+    let state = test_state(|config| config.dedup_window = tokio::time::Duration::ZERO);
+
+    let alice = register(&state, "alice").await;
+    let bob = register(&state, "bob").await;
+
+    let tries = (0..8).map(|_| {
+        let state = state.clone();
+        let request = serde_json::from_value(
+            json!({ "token": alice, "to": "bob", "body": "hi", "idempotency_key": "k1" }),
+        )
+        .unwrap();
+
+        tokio::spawn(async move { send_message(&state, request).await })
+    });
+
+    let mut message_ids = std::collections::HashSet::new();
+
+    for result in futures::future::join_all(tries).await {
+        match result.unwrap() {
+            Ok(response) => {
+                message_ids.insert(response.message_id);
+            }
+            // lost the race to a try that hadn't finished yet
+            Err(err) => assert_eq!(err, ChatError::SendInProgress),
+        }
+    }
+
+    assert_eq!(message_ids.len(), 1);
+    assert_eq!(client_status(&state, &bob).unwrap().unread, 1);
+}
+
+#[tokio::test]
+async fn messages_above_the_send_rate_are_throttled_for_a_while() {
+    let state = test_state(|config| {
+        config.send_burst = 2;
+        config.send_rate = 10.0;
+    });
+
+    let alice = register(&state, "alice").await;
+    register(&state, "bob").await;
+
+    for _ in 0..2 {
+        send_message(&state, message(&alice, "bob", "hi"))
+            .await
+            .unwrap();
+    }
+
+    let Err(ChatError::RateLimited { retry_after }) =
+        send_message(&state, message(&alice, "bob", "hi")).await
+    else {
+        panic!("the third message went through");
+    };
+
+    tokio::time::sleep(retry_after).await;
+
+    send_message(&state, message(&alice, "bob", "hi"))
+        .await
+        .unwrap();
+}
+
+#[test]
+fn events_are_tagged_with_their_type() {
+    let event = crate::ServerEvent::Message(crate::Message {
+        id: "1".to_string(),
+        from: "alice".to_string(),
+        body: "hi".to_string(),
+        ..Default::default()
+    });
+
+    let frame = serde_json::to_string(&event).unwrap();
+
+    assert!(frame.contains(r#""type":"message""#), "{}", frame);
+}
+
+#[tokio::test]
+async fn message_with_invalid_token_fails() {
+    let state = test_state(|_| {});
+
+    register(&state, "bob").await;
+
+    let result = send_message(&state, message("not a token", "bob", "hi")).await;
+
+    assert_eq!(result.unwrap_err(), ChatError::InvalidToken);
+}
+
+#[tokio::test]
+async fn sending_a_message_advances_last_seen() {
+    let state = test_state(|_| {});
+
+    let alice = register(&state, "alice").await;
+    register(&state, "bob").await;
+
+    let before = client_status(&state, &alice).unwrap().last_seen;
+
+    tokio::time::sleep(tokio::time::Duration::from_millis(20)).await;
+    send_message(&state, message(&alice, "bob", "hi"))
+        .await
+        .unwrap();
+
+    assert!(client_status(&state, &alice).unwrap().last_seen > before);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn clients_are_served_concurrently() {
+    let state = test_state(|_| {});
+
+    // every client registers, messages its neighbour and checks its status
+    // at once, stepping on each other only where the map shards overlap
+    let tasks = (0..64).map(|i| {
+        let state = state.clone();
+
+        tokio::spawn(async move {
+            let token = register(&state, &format!("client{}", i)).await;
+
+            for _ in 0..10 {
+                let _ = send_message(
+                    &state,
+                    message(&token, &format!("client{}", (i + 1) % 64), "hi"),
+                )
+                .await;
+
+                client_status(&state, &token).unwrap();
+            }
+        })
+    });
+
+    for task in futures::future::join_all(tasks).await {
+        task.unwrap();
+    }
+
+    assert_eq!(state.clients.len(), 64);
+}
+
+#[tokio::test]
+async fn status_with_invalid_token_fails() {
+    let state = test_state(|_| {});
+
+    assert_eq!(
+        client_status(&state, "not a token").unwrap_err(),
+        ChatError::InvalidToken
+    );
+}
+
+#[tokio::test]
+async fn refresh_hands_out_new_tokens_and_retires_the_old() {
+    let state = test_state(|config| config.password_hash_iterations = 1_000.try_into().unwrap());
+
+    let first = register_account(&state, "alice").await;
+    let second = refresh_session(&state, &first.refresh_token).unwrap();
+
+    assert_ne!(second.token, first.token);
+    assert_ne!(second.refresh_token, first.refresh_token);
+    assert_eq!(client_status(&state, &second.token).unwrap().name, "alice");
+    assert_eq!(
+        client_status(&state, &first.token).unwrap_err(),
+        ChatError::InvalidToken
+    );
+
+    // a refresh token is no access token
+    assert_eq!(
+        client_status(&state, &second.refresh_token).unwrap_err(),
+        ChatError::InvalidToken
+    );
+}
+
+#[tokio::test]
+async fn refresh_with_an_expired_refresh_token_fails() {
+    let state = test_state(|config| config.password_hash_iterations = 1_000.try_into().unwrap());
+
+    let tokens = register_account(&state, "alice").await;
+
+    for mut client in state.clients.iter_mut() {
+        for session in client.sessions.values_mut() {
+            session.refresh_expires_at = tokio::time::Instant::now();
+        }
+    }
+
+    assert_eq!(
+        refresh_session(&state, &tokens.refresh_token).unwrap_err(),
+        ChatError::InvalidToken
+    );
+}
+
+#[tokio::test]
+async fn refresh_token_is_only_good_once() {
+    let state = test_state(|config| config.password_hash_iterations = 1_000.try_into().unwrap());
+
+    let tokens = register_account(&state, "alice").await;
+
+    refresh_session(&state, &tokens.refresh_token).unwrap();
+
+    assert_eq!(
+        refresh_session(&state, &tokens.refresh_token).unwrap_err(),
+        ChatError::InvalidToken
+    );
+}
+
+#[tokio::test]
+async fn expired_access_token_is_refused() {
+    let state = test_state(|config| config.password_hash_iterations = 1_000.try_into().unwrap());
+
+    let tokens = register_account(&state, "alice").await;
+
+    for mut client in state.clients.iter_mut() {
+        for session in client.sessions.values_mut() {
+            session.access_expires_at = tokio::time::Instant::now();
+        }
+    }
+
+    assert_eq!(
+        client_status(&state, &tokens.token).unwrap_err(),
+        ChatError::InvalidToken
+    );
+
+    // until it's refreshed
+    let refreshed = refresh_session(&state, &tokens.refresh_token).unwrap();
+
+    assert_eq!(
+        client_status(&state, &refreshed.token).unwrap().name,
+        "alice"
+    );
+}
+
+#[tokio::test]
+async fn logout_ends_the_session_but_keeps_the_account() {
+    let state = test_state(|config| config.password_hash_iterations = 1_000.try_into().unwrap());
+
+    let tokens = register_account(&state, "alice").await;
+
+    assert!(logout(&state, &tokens.token).await);
+
+    assert_eq!(
+        client_status(&state, &tokens.token).unwrap_err(),
+        ChatError::InvalidToken
+    );
+    assert!(state.clients.get("alice").is_some());
+}
+
+#[tokio::test]
+async fn logout_with_a_refresh_token_is_refused() {
+    let state = test_state(|config| config.password_hash_iterations = 1_000.try_into().unwrap());
+
+    let tokens = register_account(&state, "alice").await;
+
+    assert!(!logout(&state, &tokens.refresh_token).await);
+
+    assert!(state.clients.get("alice").is_some());
+    assert_eq!(client_status(&state, &tokens.token).unwrap().name, "alice");
+}
+
+#[tokio::test]
+async fn logout_without_a_password_removes_the_client() {
+    let state = test_state(|_| {});
+
+    let token = register(&state, "alice").await;
+
+    assert!(logout(&state, &token).await);
+
+    assert!(state.clients.get("alice").is_none());
+}
+
+#[tokio::test]
+async fn logout_frees_the_name_and_retires_the_token() {
+    let state = test_state(|_| {});
+
+    let token = register(&state, "alice").await;
+
+    assert!(logout(&state, &token).await);
+
+    assert_eq!(
+        client_status(&state, &token).unwrap_err(),
+        ChatError::InvalidToken
+    );
+
+    let token = register(&state, "alice").await;
+
+    assert_eq!(client_status(&state, &token).unwrap().name, "alice");
+}
+
+#[tokio::test]
+async fn admin_token_has_to_match_exactly() {
+    let state = test_state(|config| config.admin_token = Some("secret".to_string()));
+
+    assert!(check_admin(&state.config, "secret").is_ok());
+    assert!(check_admin(&state.config, "secre").is_err());
+    assert!(check_admin(&state.config, "secrets").is_err());
+
+    // without an admin token there's no admin
+    let state = test_state(|config| config.admin_token = None);
+
+    assert!(check_admin(&state.config, "").is_err());
+}
+
+#[tokio::test]
+async fn message_index_forgets_copies_that_are_gone() {
+    let state = test_state(|_| {});
+
+    let alice = register(&state, "alice").await;
+    let bob = register(&state, "bob").await;
+    register(&state, "carol").await;
+
+    let sent = send_message(&state, message(&alice, "bob", "hi"))
+        .await
+        .unwrap();
+    send_message(&state, message(&alice, "carol", "hi"))
+        .await
+        .unwrap();
+
+    assert_eq!(state.clients.message_holders(&sent.message_id), [bob]);
+    assert_eq!(state.clients.prune_message_index(), 0);
+
+    state.clients.remove("bob");
+
+    assert_eq!(state.clients.prune_message_index(), 1);
+    assert!(state.clients.message_holders(&sent.message_id).is_empty());
+}
+
+#[tokio::test]
+async fn connection_whose_token_expired_is_not_announced() {
+    let state = test_state(|_| {});
+
+    let alice = register(&state, "alice").await;
+    let bob = register(&state, "bob").await;
+
+    let connection = |token: &str| {
+        let (tx, rx) = outbox::channel(16, state.config.overflow_policy);
+        let connection_id = uuid::Uuid::new_v4().as_simple().to_string();
+
+        attach_connection(
+            &state.clients,
+            token,
+            None,
+            &connection_id,
+            tx,
+            None,
+            &state.config,
+            &state.store,
+        );
+
+        rx
+    };
+
+    let mut alice_rx = connection(&alice);
+
+    // lapses between the handshake checking it and the connection attaching
+    state.clients.get_mut("bob").unwrap().expires_at = Some(tokio::time::Instant::now());
+
+    let mut bob_rx = connection(&bob);
+
+    assert!(bob_rx.recv().await.is_none());
+
+    let heard = tokio::time::timeout(tokio::time::Duration::from_millis(100), async {
+        while let Some(event) = alice_rx.recv().await {
+            let event = serde_json::to_value(&event).unwrap();
+
+            if event["type"] == "presence" && event["name"] == "bob" {
+                return event;
+            }
+        }
+
+        unreachable!("alice's outbox closed")
+    })
+    .await;
+
+    assert!(heard.is_err(), "bob announced as {:?}", heard);
+}
@@ -0,0 +1,296 @@
+//! tests, end to end against a real server in `server` and straight against
+//! the functions behind the handlers in `handlers`. either way every test
+//! gets fresh state of its own. `persistence` checks what sqlite keeps
+//! across a restart, `config` how settings are read.
+
+use futures::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio_tungstenite::tungstenite::{self, client::IntoClientRequest};
+
+use super::*;
+
+mod config;
+mod handlers;
+#[cfg(feature = "sqlite")]
+mod persistence;
+mod server;
+
+/// how long a test waits for an event before giving up on it
+const EVENT_TIMEOUT: tokio::time::Duration = tokio::time::Duration::from_secs(5);
+
+/// state on the built-in defaults as changed by `configure`, the env and any
+/// chat.toml are ignored. nothing is persisted, the store lives in memory
+pub fn test_state(configure: impl FnOnce(&mut Config)) -> State {
+    let mut config = Config::defaults();
+    config.database_path = ":memory:".into();
+    configure(&mut config);
+
+    let config = Arc::new(config);
+    let store = Arc::new(store::Store::open(&config.database_path).unwrap());
+
+    State::new(config, store, Arc::default(), Arc::default())
+}
+
+/// a server listening on an ephemeral port of 127.0.0.1, stopped once dropped
+pub struct TestServer {
+    pub addr: std::net::SocketAddr,
+
+    /// the state the routes run on, for looking behind the api
+    pub state: State,
+
+    shutdown: Option<tokio::sync::oneshot::Sender<()>>,
+}
+
+impl TestServer {
+    pub async fn start() -> Self {
+        Self::with_config(|_| {}).await
+    }
+
+    /// starts a server on the state `test_state` builds
+    pub async fn with_config(configure: impl FnOnce(&mut Config)) -> Self {
+        let state = test_state(configure);
+
+        spawn_background_tasks(&state);
+
+        let (shutdown, stopped) = tokio::sync::oneshot::channel::<()>();
+        let (addr, server) = warp::serve(build_routes(state.clone())).bind_with_graceful_shutdown(
+            ([127, 0, 0, 1], 0),
+            async {
+                let _ = stopped.await;
+            },
+        );
+
+        tokio::spawn(server);
+        state.ready.store(true, Ordering::SeqCst);
+
+        TestServer {
+            addr,
+            state,
+            shutdown: Some(shutdown),
+        }
+    }
+
+    fn url(&self, scheme: &str, path: &str) -> String {
+        format!("{}://{}{}", scheme, self.addr, path)
+    }
+
+    /// sends a request with an optional json body and returns the status
+    /// with the decoded body, `Value::Null` when there's none
+    pub async fn request(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<Value>,
+        headers: &[(&str, &str)],
+    ) -> (warp::http::StatusCode, Value) {
+        let mut request = warp::http::Request::builder()
+            .method(method)
+            .uri(self.url("http", path))
+            .header("content-type", "application/json");
+
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+
+        let body = body.map(|body| body.to_string()).unwrap_or_default();
+        let request = request.body(warp::hyper::Body::from(body)).unwrap();
+
+        let response = warp::hyper::Client::new().request(request).await.unwrap();
+        let status = response.status();
+        let bytes = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+
+        (
+            status,
+            serde_json::from_slice(&bytes).unwrap_or(Value::Null),
+        )
+    }
+
+    pub async fn post(&self, path: &str, body: Value) -> (warp::http::StatusCode, Value) {
+        self.request("POST", path, Some(body), &[]).await
+    }
+
+    /// registers `name` and returns its token
+    pub async fn register(&self, name: &str) -> String {
+        let (status, body) = self.post("/register", json!({ "name": name })).await;
+
+        assert_eq!(status, warp::http::StatusCode::OK, "registering {}", name);
+
+        body["token"].as_str().unwrap().to_string()
+    }
+
+    /// opens a socket for `token`, authenticated by header. returns once the
+    /// server attached it, so whatever is sent from then on goes to it
+    pub async fn connect(&self, token: &str) -> TestSocket {
+        self.try_connect(token).await.unwrap()
+    }
+
+    /// like `connect`, but hands back the status of a refused handshake
+    pub async fn try_connect(&self, token: &str) -> Result<TestSocket, warp::http::StatusCode> {
+        self.connect_to("/messages", token, None)
+            .await
+            .map(|(socket, _)| socket)
+    }
+
+    /// opens a socket for `token` at `path`, which may carry a query,
+    /// offering `protocols` as subprotocols. the token goes in a header
+    /// unless the protocols carry it as `bearer.<token>`. returns the socket
+    /// along with the subprotocol the server accepted
+    pub async fn connect_to(
+        &self,
+        path: &str,
+        token: &str,
+        protocols: Option<&str>,
+    ) -> Result<(TestSocket, Option<String>), warp::http::StatusCode> {
+        let mut request = self.url("ws", path).into_client_request().unwrap();
+
+        if !protocols.is_some_and(|protocols| protocols.contains(BEARER_PROTOCOL_PREFIX)) {
+            request.headers_mut().insert(
+                "authorization",
+                format!("Bearer {}", token).parse().unwrap(),
+            );
+        }
+
+        if let Some(protocols) = protocols {
+            request
+                .headers_mut()
+                .insert("sec-websocket-protocol", protocols.parse().unwrap());
+        }
+
+        let (stream, response) = match tokio_tungstenite::connect_async(request).await {
+            Ok(connected) => connected,
+            Err(tungstenite::Error::Http(response)) => return Err(response.status()),
+            Err(err) => panic!("connecting: {}", err),
+        };
+
+        let attached = async {
+            while !client_for_token(&self.state.clients, token)
+                .is_some_and(|client| client.is_connected())
+            {
+                tokio::time::sleep(tokio::time::Duration::from_millis(5)).await;
+            }
+        };
+
+        tokio::time::timeout(EVENT_TIMEOUT, attached)
+            .await
+            .expect("socket not attached in time");
+
+        let protocol = response
+            .headers()
+            .get("sec-websocket-protocol")
+            .and_then(|protocol| protocol.to_str().ok())
+            .map(str::to_string);
+
+        Ok((TestSocket { stream }, protocol))
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+pub struct TestSocket {
+    stream: tokio_tungstenite::WebSocketStream<
+        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+    >,
+}
+
+impl TestSocket {
+    /// the next event the server pushed, panics if none comes in time
+    pub async fn next_event(&mut self) -> Value {
+        loop {
+            let frame = tokio::time::timeout(EVENT_TIMEOUT, self.stream.next())
+                .await
+                .expect("no event in time")
+                .expect("socket closed")
+                .unwrap();
+
+            if let tungstenite::Message::Text(text) = frame {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    /// the next binary frame the server pushed, for sockets speaking
+    /// messagepack, decoded into json to compare against
+    pub async fn next_binary_event(&mut self) -> Value {
+        loop {
+            let frame = tokio::time::timeout(EVENT_TIMEOUT, self.stream.next())
+                .await
+                .expect("no event in time")
+                .expect("socket closed")
+                .unwrap();
+
+            if let tungstenite::Message::Binary(bytes) = frame {
+                return rmp_serde::from_slice(&bytes).unwrap();
+            }
+        }
+    }
+
+    /// the next event of type `kind`, skipping any other
+    pub async fn next_event_of(&mut self, kind: &str) -> Value {
+        loop {
+            let event = self.next_event().await;
+
+            if event["type"] == kind {
+                return event;
+            }
+        }
+    }
+
+    /// closes the socket, waiting for the server to close its end too
+    pub async fn close(mut self) {
+        self.stream.close(None).await.unwrap();
+
+        while let Some(Ok(_)) = self.stream.next().await {}
+    }
+
+    /// closes the socket with a normal close, saying the client is done
+    pub async fn leave(mut self) {
+        self.stream
+            .close(Some(tungstenite::protocol::CloseFrame {
+                code: tungstenite::protocol::frame::coding::CloseCode::Normal,
+                reason: "bye".into(),
+            }))
+            .await
+            .unwrap();
+
+        while let Some(Ok(_)) = self.stream.next().await {}
+    }
+
+    /// waits for the server to close the socket, panics if it stays open
+    pub async fn expect_closed(&mut self) {
+        let closed = async {
+            while let Some(Ok(frame)) = self.stream.next().await {
+                if let tungstenite::Message::Close(_) = frame {
+                    return;
+                }
+            }
+        };
+
+        tokio::time::timeout(EVENT_TIMEOUT, closed)
+            .await
+            .expect("socket not closed in time");
+    }
+
+    /// pushes a json frame to the server
+    pub async fn send(&mut self, payload: Value) {
+        self.stream
+            .send(tungstenite::Message::Text(payload.to_string()))
+            .await
+            .unwrap();
+    }
+
+    /// pushes a binary frame to the server, like a chunk of a file
+    pub async fn send_binary(&mut self, bytes: &[u8]) {
+        self.stream
+            .send(tungstenite::Message::Binary(bytes.to_vec()))
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,250 @@
+//! what survives a restart, against a sqlite database of its own per test
+
+use serde_json::json;
+
+use super::TestServer;
+use crate::{store::Store, Client, Message};
+
+/// a database file in the temp dir, removed once dropped
+struct TempDatabase {
+    path: std::path::PathBuf,
+}
+
+impl TempDatabase {
+    fn new() -> Self {
+        let name = format!("chat-rs-{}.db", uuid::Uuid::new_v4().as_simple());
+
+        TempDatabase {
+            path: std::env::temp_dir().join(name),
+        }
+    }
+}
+
+impl Drop for TempDatabase {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn message(id: &str, seq: u64, body: &str) -> Message {
+    Message {
+        id: id.to_string(),
+        seq,
+        from: "alice".to_string(),
+        sender: "alice-token".to_string(),
+        body: body.to_string(),
+        timestamp: 1_000 + seq as i64,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn state_reloads_after_reopening() {
+    let database = TempDatabase::new();
+
+    let store = Store::open(&database.path).unwrap();
+
+    store.insert_client(&Client {
+        token: "bob-token".to_string(),
+        name: "bob".to_string(),
+        last_seen: 42,
+        ..Default::default()
+    });
+    store.insert_message("bob-token", &message("first", 1, "hi"));
+    store.insert_message("bob-token", &message("second", 2, "typo"));
+    store.insert_message("bob-token", &message("third", 3, "oops"));
+    store.update_message_body("bob-token", "second", "fixed");
+    store.tombstone_message("bob-token", "third");
+    store.insert_block("bob-token", "carol-token");
+    store.insert_ban(crate::store::BAN_NAME, "mallory");
+
+    drop(store);
+
+    let store = Store::open(&database.path).unwrap();
+    let clients = store.load().unwrap();
+
+    assert_eq!(clients.len(), 1);
+
+    let bob = &clients[0];
+
+    assert_eq!(bob.name, "bob");
+    assert_eq!(bob.token, "bob-token");
+    assert_eq!(bob.last_seen, 42);
+    assert!(bob.blocked.contains("carol-token"));
+
+    let history = bob
+        .history
+        .iter()
+        .map(|message| (message.id.as_str(), message.seq, message.body.as_str()))
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        history,
+        [("first", 1, "hi"), ("second", 2, "fixed"), ("third", 3, "")]
+    );
+    assert!(!bob.history[1].deleted);
+    assert!(bob.history[2].deleted);
+
+    assert!(store.load_bans().unwrap().names.contains("mallory"));
+}
+
+#[tokio::test]
+async fn edits_and_deletes_are_persisted() {
+    let database = TempDatabase::new();
+    let path = database.path.clone();
+
+    let server = TestServer::with_config(|config| config.database_path = path).await;
+
+    let alice = server.register("alice").await;
+    server.register("bob").await;
+
+    let mut ids = Vec::new();
+
+    for body in ["helo", "never mind"] {
+        let (_, sent) = server
+            .post(
+                "/send_message",
+                json!({ "token": alice, "to": "bob", "body": body }),
+            )
+            .await;
+
+        ids.push(sent["message_id"].as_str().unwrap().to_string());
+    }
+
+    server
+        .post(
+            &format!("/messages/{}/edit", ids[0]),
+            json!({ "token": alice, "body": "hello" }),
+        )
+        .await;
+    server
+        .request(
+            "DELETE",
+            &format!("/messages/{}", ids[1]),
+            Some(json!({ "token": alice })),
+            &[],
+        )
+        .await;
+
+    drop(server);
+
+    let clients = Store::open(&database.path).unwrap().load().unwrap();
+    let bob = clients.iter().find(|client| client.name == "bob").unwrap();
+
+    assert_eq!(bob.history[0].body, "hello");
+    assert_eq!(bob.history[1].body, "");
+    assert!(bob.history[1].deleted);
+}
+
+#[test]
+fn expired_messages_are_dropped_on_load() {
+    let database = TempDatabase::new();
+
+    let store = Store::open(&database.path).unwrap();
+    let now = crate::unix_millis();
+
+    store.insert_client(&Client {
+        token: "bob-token".to_string(),
+        name: "bob".to_string(),
+        ..Default::default()
+    });
+
+    for (id, seq, expires_at) in [
+        ("stays", 1, None),
+        ("expired", 2, Some(now - 1_000)),
+        ("expiring", 3, Some(now + 60_000)),
+    ] {
+        store.insert_message(
+            "bob-token",
+            &Message {
+                expires_at,
+                ..message(id, seq, "hi")
+            },
+        );
+    }
+
+    drop(store);
+
+    let clients = Store::open(&database.path).unwrap().load().unwrap();
+    let history = &clients[0].history;
+
+    assert_eq!(
+        history
+            .iter()
+            .map(|message| message.id.as_str())
+            .collect::<Vec<_>>(),
+        ["stays", "expiring"]
+    );
+    assert_eq!(history[1].expires_at, Some(now + 60_000));
+}
+
+#[tokio::test]
+async fn expired_message_leaves_the_store() {
+    let database = TempDatabase::new();
+    let path = database.path.clone();
+
+    let server = TestServer::with_config(|config| config.database_path = path).await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let mut bob_socket = server.connect(&bob).await;
+
+    server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": "bob", "body": "gone soon", "ttl_secs": 1 }),
+        )
+        .await;
+
+    bob_socket.next_event_of("expire").await;
+
+    // lapsed while the server was still up, not merely filtered on load
+    let connection = rusqlite::Connection::open(&database.path).unwrap();
+    let rows: i64 = connection
+        .query_row("SELECT COUNT(*) FROM messages", [], |row| row.get(0))
+        .unwrap();
+
+    assert_eq!(rows, 0);
+}
+
+#[tokio::test]
+async fn logout_keeps_the_stored_history_deleting_the_account_does_not() {
+    let database = TempDatabase::new();
+    let path = database.path.clone();
+
+    let server = TestServer::with_config(|config| config.database_path = path).await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let carol = server.register("carol").await;
+    let _bob_socket = server.connect(&bob).await;
+    let _carol_socket = server.connect(&carol).await;
+
+    for to in ["bob", "carol"] {
+        server
+            .post(
+                "/send_message",
+                json!({ "token": alice, "to": to, "body": "keep this" }),
+            )
+            .await;
+    }
+
+    server.post("/logout", json!({ "token": bob })).await;
+    server
+        .request("DELETE", "/account", Some(json!({ "token": carol })), &[])
+        .await;
+
+    let connection = rusqlite::Connection::open(&database.path).unwrap();
+    let rows = |recipient: &str| -> i64 {
+        connection
+            .query_row(
+                "SELECT COUNT(*) FROM messages WHERE recipient = ?1",
+                [recipient],
+                |row| row.get(0),
+            )
+            .unwrap()
+    };
+
+    assert_eq!(rows(&bob), 1);
+    assert_eq!(rows(&carol), 0);
+}
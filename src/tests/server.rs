@@ -0,0 +1,3380 @@
+//! the server as clients see it, over http and websockets
+
+use serde_json::json;
+
+use super::TestServer;
+
+#[tokio::test]
+async fn message_sent_over_http_reaches_socket() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let mut bob_socket = server.connect(&bob).await;
+
+    let (status, response) = server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": "bob", "body": "hello" }),
+        )
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::OK);
+    assert_eq!(response["delivered"], json!(["bob"]));
+
+    let message = bob_socket.next_event_of("message").await;
+
+    assert_eq!(message["from"], "alice");
+    assert_eq!(message["body"], "hello");
+    assert_eq!(message["id"], response["message_id"]);
+}
+
+#[tokio::test]
+async fn message_is_stamped_by_the_server() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let mut bob_socket = server.connect(&bob).await;
+
+    let before = crate::unix_millis();
+
+    server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": "bob", "body": "what time is it" }),
+        )
+        .await;
+
+    let message = bob_socket.next_event_of("message").await;
+    let timestamp = message["timestamp"].as_i64().unwrap();
+
+    assert!((before..=crate::unix_millis()).contains(&timestamp));
+}
+
+#[tokio::test]
+async fn message_sent_over_socket_reaches_socket() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+
+    let mut alice_socket = server.connect(&alice).await;
+    let mut bob_socket = server.connect(&bob).await;
+
+    alice_socket
+        .send(json!({ "to": "bob", "body": "over the socket" }))
+        .await;
+
+    let message = bob_socket.next_event_of("message").await;
+
+    assert_eq!(message["from"], "alice");
+    assert_eq!(message["body"], "over the socket");
+
+    // alice hears the message made it
+    let ack = alice_socket.next_event_of("ack").await;
+
+    assert_eq!(ack["to"], "bob");
+    assert_eq!(ack["message_id"], message["id"]);
+}
+
+#[tokio::test]
+async fn message_for_offline_client_is_queued_until_it_connects() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+
+    let (_, response) = server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": "bob", "body": "while you were out" }),
+        )
+        .await;
+
+    assert_eq!(response["queued"], json!(["bob"]));
+
+    let mut bob_socket = server.connect(&bob).await;
+    let message = bob_socket.next_event_of("message").await;
+
+    assert_eq!(message["body"], "while you were out");
+}
+
+#[tokio::test]
+async fn broadcast_reaches_every_connected_client() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let carol = server.register("carol").await;
+    server.register("dave").await;
+
+    let mut alice_socket = server.connect(&alice).await;
+    let mut bob_socket = server.connect(&bob).await;
+    let mut carol_socket = server.connect(&carol).await;
+
+    let (status, response) = server
+        .post(
+            "/broadcast",
+            json!({ "token": alice, "body": "hello everyone" }),
+        )
+        .await;
+
+    // dave has no socket and is skipped, alice's own socket gets it too
+    assert_eq!(status, warp::http::StatusCode::OK);
+    assert_eq!(response["delivered"], 3);
+
+    for socket in [&mut alice_socket, &mut bob_socket, &mut carol_socket] {
+        let message = socket.next_event_of("message").await;
+
+        assert_eq!(message["from"], "alice");
+        assert_eq!(message["body"], "hello everyone");
+    }
+}
+
+#[tokio::test]
+async fn edited_message_changes_for_every_recipient() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let carol = server.register("carol").await;
+    let mut bob_socket = server.connect(&bob).await;
+
+    let (_, sent) = server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": ["bob", "carol"], "body": "helo" }),
+        )
+        .await;
+    let id = sent["message_id"].as_str().unwrap();
+
+    bob_socket.next_event_of("message").await;
+
+    let (status, _) = server
+        .post(
+            &format!("/messages/{}/edit", id),
+            json!({ "token": alice, "body": "hello" }),
+        )
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::NO_CONTENT);
+
+    let edit = bob_socket.next_event_of("edit").await;
+
+    assert_eq!(edit["message_id"], id);
+    assert_eq!(edit["body"], "hello");
+
+    // carol is offline, her history has the new body waiting
+    for token in [&bob, &carol] {
+        let (_, history) = server
+            .request("GET", &format!("/history/{}", token), None, &[])
+            .await;
+
+        assert_eq!(history[0]["body"], "hello");
+    }
+}
+
+#[tokio::test]
+async fn only_the_sender_edits_a_message() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+
+    let (_, sent) = server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": "bob", "body": "mine" }),
+        )
+        .await;
+
+    let (status, response) = server
+        .post(
+            &format!("/messages/{}/edit", sent["message_id"].as_str().unwrap()),
+            json!({ "token": bob, "body": "yours" }),
+        )
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::FORBIDDEN);
+    assert_eq!(response["error"], "not_message_sender");
+
+    let (status, response) = server
+        .post(
+            "/messages/nonexistent/edit",
+            json!({ "token": alice, "body": "nothing" }),
+        )
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::NOT_FOUND);
+    assert_eq!(response["error"], "unknown_message");
+}
+
+#[tokio::test]
+async fn deleted_message_leaves_a_tombstone() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let mut bob_socket = server.connect(&bob).await;
+
+    let (_, sent) = server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": "bob", "body": "oops" }),
+        )
+        .await;
+    let id = sent["message_id"].as_str().unwrap();
+    let path = format!("/messages/{}", id);
+
+    bob_socket.next_event_of("message").await;
+
+    // not bob's to delete
+    let (status, _) = server
+        .request("DELETE", &path, Some(json!({ "token": bob })), &[])
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::FORBIDDEN);
+
+    let (status, _) = server
+        .request("DELETE", &path, Some(json!({ "token": alice })), &[])
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::NO_CONTENT);
+    assert_eq!(bob_socket.next_event_of("delete").await["message_id"], id);
+
+    let (_, history) = server
+        .request("GET", &format!("/history/{}", bob), None, &[])
+        .await;
+
+    assert_eq!(history[0]["id"], id);
+    assert_eq!(history[0]["deleted"], true);
+    assert_eq!(history[0]["body"], "");
+
+    // gone for good, it can't be deleted twice
+    let (status, _) = server
+        .request("DELETE", &path, Some(json!({ "token": alice })), &[])
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn message_with_a_ttl_expires_from_the_history() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let carol = server.register("carol").await;
+    let mut bob_socket = server.connect(&bob).await;
+
+    let (status, sent) = server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": ["bob", "carol"], "body": "gone soon", "ttl_secs": 1 }),
+        )
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::OK);
+
+    let message = bob_socket.next_event_of("message").await;
+
+    assert_eq!(message["body"], "gone soon");
+    assert!(message["expires_at"].is_i64());
+
+    let expired = bob_socket.next_event_of("expire").await;
+
+    assert_eq!(expired["message_id"], sent["message_id"]);
+
+    // carol never connected, it's gone from her history all the same
+    for token in [&bob, &carol] {
+        let (_, history) = server
+            .request("GET", &format!("/history/{}", token), None, &[])
+            .await;
+
+        assert_eq!(history, json!([]));
+    }
+}
+
+#[tokio::test]
+async fn expired_client_is_removed_like_any_other() {
+    let server = TestServer::with_config(|config| {
+        config.token_ttl = tokio::time::Duration::from_millis(300);
+        config.session_sweep_interval = tokio::time::Duration::from_millis(50);
+    })
+    .await;
+
+    // a connected client never expires
+    let alice = server.register("alice").await;
+    let mut alice_socket = server.connect(&alice).await;
+
+    let bob = server.register("bob").await;
+
+    server
+        .post("/rooms", json!({ "token": bob, "name": "lobby" }))
+        .await;
+    let (status, _) = server
+        .post("/rooms/lobby/join", json!({ "token": alice }))
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::OK);
+
+    let (_, sent) = server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": "bob", "body": "you there?" }),
+        )
+        .await;
+
+    let undeliverable = alice_socket.next_event_of("undeliverable").await;
+
+    assert_eq!(undeliverable["message_id"], sent["message_id"]);
+    assert_eq!(undeliverable["reason"], "session_expired");
+    assert!(server.state.clients.get("bob").is_none());
+
+    // the room lost bob and is alice's alone
+    let rooms = server.state.rooms.lock().await;
+
+    assert_eq!(rooms["lobby"].members.len(), 1);
+    assert!(rooms["lobby"].members.contains(&alice));
+}
+
+#[tokio::test]
+async fn active_client_keeps_sliding_its_session_window() {
+    let server = TestServer::with_config(|config| {
+        config.token_ttl = tokio::time::Duration::from_millis(300);
+        config.session_sweep_interval = tokio::time::Duration::from_millis(50);
+    })
+    .await;
+
+    let bob = server.register("bob").await;
+
+    // twice the ttl, with every request starting the window over
+    for _ in 0..6 {
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        let (status, _) = server
+            .request("GET", &format!("/status/{}", bob), None, &[])
+            .await;
+
+        assert_eq!(status, warp::http::StatusCode::OK);
+    }
+
+    // and once it stops it lapses
+    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+
+    assert!(server.state.clients.get("bob").is_none());
+}
+
+#[tokio::test]
+async fn clean_close_removes_the_client_right_away() {
+    // a grace period no test outlasts, leaving cleanly mustn't wait for it
+    let server = TestServer::with_config(|config| {
+        config.disconnect_grace = tokio::time::Duration::from_secs(60)
+    })
+    .await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let mut alice_socket = server.connect(&alice).await;
+
+    let bob_socket = server.connect(&bob).await;
+
+    assert_eq!(
+        alice_socket.next_event_of("presence").await["status"],
+        "online"
+    );
+
+    bob_socket.leave().await;
+
+    let presence = alice_socket.next_event_of("presence").await;
+
+    assert_eq!(presence["name"], "bob");
+    assert_eq!(presence["status"], "offline");
+
+    // gone right after it's seen going offline, no grace period in between
+    let removed = async {
+        while server.state.clients.get("bob").is_some() {
+            tokio::time::sleep(tokio::time::Duration::from_millis(5)).await;
+        }
+    };
+
+    tokio::time::timeout(tokio::time::Duration::from_secs(5), removed)
+        .await
+        .expect("client not removed in time");
+
+    // the name is free again
+    server.register("bob").await;
+}
+
+#[tokio::test]
+async fn dropped_socket_keeps_the_client_through_the_grace_period() {
+    let server = TestServer::with_config(|config| {
+        config.disconnect_grace = tokio::time::Duration::from_secs(60)
+    })
+    .await;
+
+    let bob = server.register("bob").await;
+
+    server.connect(&bob).await.close().await;
+
+    let (status, body) = server
+        .request("GET", &format!("/status/{}", bob), None, &[])
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::OK);
+    assert_eq!(body["status"], "online");
+}
+
+#[tokio::test]
+async fn dropped_socket_is_removed_once_the_grace_period_is_over() {
+    let server = TestServer::with_config(|config| {
+        config.disconnect_grace = tokio::time::Duration::from_millis(200)
+    })
+    .await;
+
+    let bob = server.register("bob").await;
+
+    server.connect(&bob).await.close().await;
+    tokio::time::sleep(tokio::time::Duration::from_millis(400)).await;
+
+    assert!(server.state.clients.get("bob").is_none());
+
+    let (status, _) = server
+        .request("GET", &format!("/status/{}", bob), None, &[])
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::UNAUTHORIZED);
+
+    // the name is free again
+    server.register("bob").await;
+}
+
+#[tokio::test]
+async fn dropped_socket_is_removed_at_once_without_a_grace_period() {
+    let server =
+        TestServer::with_config(|config| config.disconnect_grace = tokio::time::Duration::ZERO)
+            .await;
+
+    let bob = server.register("bob").await;
+
+    server.connect(&bob).await.close().await;
+
+    let removed = async {
+        while server.state.clients.get("bob").is_some() {
+            tokio::time::sleep(tokio::time::Duration::from_millis(5)).await;
+        }
+    };
+
+    tokio::time::timeout(tokio::time::Duration::from_secs(1), removed)
+        .await
+        .expect("client not removed in time");
+}
+
+#[tokio::test]
+async fn reconnect_within_the_grace_period_stays_online() {
+    let server = TestServer::with_config(|config| {
+        config.disconnect_grace = tokio::time::Duration::from_millis(200)
+    })
+    .await;
+
+    let bob = server.register("bob").await;
+
+    server.connect(&bob).await.close().await;
+    let _bob_socket = server.connect(&bob).await;
+    tokio::time::sleep(tokio::time::Duration::from_millis(400)).await;
+
+    let (status, body) = server
+        .request("GET", &format!("/status/{}", bob), None, &[])
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::OK);
+    assert_eq!(body["status"], "online");
+}
+
+#[tokio::test]
+async fn presence_follows_the_socket_and_the_grace_period() {
+    let server = TestServer::with_config(|config| {
+        config.disconnect_grace = tokio::time::Duration::from_millis(200)
+    })
+    .await;
+
+    let bob = server.register("bob").await;
+    let status = || async {
+        let (_, body) = server
+            .request("GET", &format!("/status/{}", bob), None, &[])
+            .await;
+
+        body["status"].as_str().unwrap().to_string()
+    };
+
+    assert_eq!(status().await, "offline");
+
+    let bob_socket = server.connect(&bob).await;
+
+    assert_eq!(status().await, "online");
+
+    bob_socket.close().await;
+
+    assert_eq!(status().await, "online");
+
+    tokio::time::sleep(tokio::time::Duration::from_millis(400)).await;
+
+    // past the grace period there's no client left to show
+    let (status, _) = server
+        .request("GET", &format!("/status/{}", bob), None, &[])
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn others_hear_when_a_client_goes_offline() {
+    let server =
+        TestServer::with_config(|config| config.disconnect_grace = tokio::time::Duration::ZERO)
+            .await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+
+    let alice_socket = server.connect(&alice).await;
+    let mut bob_socket = server.connect(&bob).await;
+
+    alice_socket.close().await;
+
+    let presence = bob_socket.next_event_of("presence").await;
+
+    assert_eq!(presence["name"], "alice");
+    assert_eq!(presence["status"], "offline");
+}
+
+#[tokio::test]
+async fn health_checks_pass_without_a_token() {
+    let server = TestServer::start().await;
+
+    for path in ["/healthz", "/readyz"] {
+        let (status, body) = server.request("GET", path, None, &[]).await;
+
+        assert_eq!(status, warp::http::StatusCode::OK, "{}", path);
+        assert_eq!(body["status"], "ok", "{}", path);
+        assert!(body["uptime_secs"].is_u64(), "{}", path);
+    }
+}
+
+#[tokio::test]
+async fn draining_instance_is_not_ready() {
+    let server = TestServer::start().await;
+
+    server
+        .state
+        .shutting_down
+        .store(true, std::sync::atomic::Ordering::SeqCst);
+
+    let (status, body) = server.request("GET", "/readyz", None, &[]).await;
+
+    assert_eq!(status, warp::http::StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body["status"], "shutting_down");
+
+    // still alive though
+    let (status, _) = server.request("GET", "/healthz", None, &[]).await;
+
+    assert_eq!(status, warp::http::StatusCode::OK);
+}
+
+/// nothing listens on port 1, so the backplane never subscribes
+#[cfg(feature = "redis")]
+#[tokio::test]
+async fn instance_without_its_backplane_is_not_ready() {
+    let server = TestServer::with_config(|config| {
+        config.redis_url = Some("redis://127.0.0.1:1".to_string())
+    })
+    .await;
+
+    let (status, body) = server.request("GET", "/readyz", None, &[]).await;
+
+    assert_eq!(status, warp::http::StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body["status"], "backplane_unavailable");
+}
+
+#[tokio::test]
+async fn message_with_a_blocked_word_is_rejected() {
+    let server = TestServer::with_config(|config| {
+        config.content_filter =
+            crate::filter::ContentFilter::new(["darn"], crate::filter::FilterMode::Reject)
+    })
+    .await;
+
+    let alice = server.register("alice").await;
+    server.register("bob").await;
+
+    let (status, response) = server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": "bob", "body": "Darn it" }),
+        )
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::BAD_REQUEST);
+    assert_eq!(response["error"], "blocked_content");
+}
+
+#[tokio::test]
+async fn socket_asking_for_msgpack_gets_binary_frames() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+
+    let (mut bob_socket, _) = server
+        .connect_to("/messages?format=msgpack", &bob, None)
+        .await
+        .unwrap();
+
+    server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": "bob", "body": "packed" }),
+        )
+        .await;
+
+    let message = loop {
+        let event = bob_socket.next_binary_event().await;
+
+        if event["type"] == "message" {
+            break event;
+        }
+    };
+
+    assert_eq!(message["from"], "alice");
+    assert_eq!(message["body"], "packed");
+}
+
+#[tokio::test]
+async fn socket_gets_the_version_it_asked_for() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+
+    let (mut bob_socket, protocol) = server
+        .connect_to("/messages", &bob, Some("chat.v2"))
+        .await
+        .unwrap();
+
+    assert_eq!(protocol.as_deref(), Some("chat.v2"));
+
+    server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": "bob", "body": "enveloped" }),
+        )
+        .await;
+
+    let message = bob_socket.next_event_of("message").await;
+
+    assert_eq!(message["data"]["body"], "enveloped");
+    assert!(message.get("body").is_none());
+}
+
+#[tokio::test]
+async fn socket_asking_only_for_unknown_versions_is_refused() {
+    let server = TestServer::start().await;
+
+    let bob = server.register("bob").await;
+
+    let refused = server
+        .connect_to("/messages", &bob, Some("chat.v9"))
+        .await
+        .err();
+
+    assert_eq!(refused, Some(warp::http::StatusCode::BAD_REQUEST));
+}
+
+#[tokio::test]
+async fn metrics_json_reflects_activity() {
+    let server = TestServer::with_config(|config| config.admin_token = Some("admin".into())).await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let _bob_socket = server.connect(&bob).await;
+
+    for body in ["one", "two", "three"] {
+        server
+            .post(
+                "/send_message",
+                json!({ "token": alice, "to": "bob", "body": body }),
+            )
+            .await;
+    }
+
+    let (status, _) = server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": "nobody", "body": "lost" }),
+        )
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::NOT_FOUND);
+
+    let (status, metrics) = server
+        .request(
+            "GET",
+            "/admin/metrics.json",
+            None,
+            &[("authorization", "Bearer admin")],
+        )
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::OK);
+    assert_eq!(metrics["registered_clients"], 2);
+    assert_eq!(metrics["registrations"], 2);
+    assert_eq!(metrics["active_connections"], 1);
+    assert_eq!(metrics["peak_connections"], 1);
+    assert_eq!(metrics["messages_sent"], 3);
+    assert_eq!(metrics["rejections"], 1);
+
+    // only a very slow run would see the first messages age out of the window
+    let last_minute = metrics["messages_last_minute"].as_u64().unwrap();
+
+    assert!(
+        (1..=3).contains(&last_minute),
+        "{} in the last minute",
+        last_minute
+    );
+}
+
+#[tokio::test]
+async fn metrics_json_needs_the_admin_token() {
+    let server = TestServer::with_config(|config| config.admin_token = Some("admin".into())).await;
+
+    let (status, _) = server
+        .request("GET", "/admin/metrics.json", None, &[])
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::UNAUTHORIZED);
+}
+
+/// two instances sharing the redis at `CHAT_TEST_REDIS_URL`, by default one
+/// on localhost. names stay claimed in it after the test, so they're made
+/// unique per run
+#[cfg(feature = "redis")]
+#[tokio::test]
+#[ignore = "needs a running redis"]
+async fn message_crosses_instances_over_the_backplane() {
+    let url = std::env::var("CHAT_TEST_REDIS_URL")
+        .unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+    let run = &uuid::Uuid::new_v4().as_simple().to_string()[..8];
+
+    let first = TestServer::with_config(|config| config.redis_url = Some(url.clone())).await;
+    let second = TestServer::with_config(|config| config.redis_url = Some(url.clone())).await;
+
+    let alice_name = format!("alice-{}", run);
+    let bob_name = format!("bob-{}", run);
+
+    let alice = first.register(&alice_name).await;
+    let bob = second.register(&bob_name).await;
+
+    // the name is taken, even on the instance that doesn't hold it
+    let (status, _) = first.post("/register", json!({ "name": bob_name })).await;
+
+    assert_eq!(status, warp::http::StatusCode::NOT_ACCEPTABLE);
+
+    let mut bob_socket = second.connect(&bob).await;
+
+    // give the second instance a moment to subscribe
+    tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+
+    let (status, response) = first
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": bob_name, "body": "from the other side" }),
+        )
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::OK);
+    assert_eq!(response["relayed"], json!([bob_name]));
+
+    let message = bob_socket.next_event_of("message").await;
+
+    assert_eq!(message["from"], alice_name);
+    assert_eq!(message["body"], "from the other side");
+    assert_eq!(message["id"], response["message_id"]);
+
+    // a message sent over a socket crosses too
+    let mut alice_socket = first.connect(&alice).await;
+
+    alice_socket
+        .send(json!({ "to": bob_name, "body": "over the socket" }))
+        .await;
+
+    let message = bob_socket.next_event_of("message").await;
+
+    assert_eq!(message["from"], alice_name);
+    assert_eq!(message["body"], "over the socket");
+}
+
+#[tokio::test]
+async fn request_with_a_trickling_body_times_out() {
+    let server = TestServer::with_config(|config| {
+        config.request_timeout = Some(tokio::time::Duration::from_millis(200))
+    })
+    .await;
+
+    // half the body it announces, the rest never comes while the sender is held
+    let (mut sender, body) = warp::hyper::Body::channel();
+    sender
+        .send_data(warp::hyper::body::Bytes::from_static(b"{\"name\":"))
+        .await
+        .unwrap();
+
+    let request = warp::hyper::Request::post(server.url("http", "/register"))
+        .header("content-type", "application/json")
+        .header("content-length", "16")
+        .body(body)
+        .unwrap();
+
+    let response = tokio::time::timeout(
+        tokio::time::Duration::from_secs(5),
+        warp::hyper::Client::new().request(request),
+    )
+    .await
+    .expect("request didn't time out")
+    .unwrap();
+
+    assert_eq!(response.status(), warp::http::StatusCode::REQUEST_TIMEOUT);
+
+    drop(sender);
+}
+
+#[tokio::test]
+async fn sockets_per_address_are_capped() {
+    let server = TestServer::with_config(|config| config.max_connections_per_ip = 2).await;
+
+    let alice = server.register("alice").await;
+
+    let first = server.connect(&alice).await;
+    let _second = server.connect(&alice).await;
+
+    assert_eq!(
+        server.try_connect(&alice).await.err(),
+        Some(warp::http::StatusCode::TOO_MANY_REQUESTS)
+    );
+
+    first.close().await;
+
+    // the slot is handed back once the server finished cleaning up
+    let reconnected = async {
+        while server.try_connect(&alice).await.is_err() {
+            tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+        }
+    };
+
+    tokio::time::timeout(tokio::time::Duration::from_secs(5), reconnected)
+        .await
+        .expect("no slot handed back in time");
+}
+
+/// cheap hashes, the default number of rounds takes a while in a debug build
+const TEST_HASH_ITERATIONS: u32 = 1_000;
+
+#[tokio::test]
+async fn login_with_the_password_starts_a_session() {
+    let server = TestServer::with_config(|config| {
+        config.password_hash_iterations = TEST_HASH_ITERATIONS.try_into().unwrap()
+    })
+    .await;
+
+    let (status, registered) = server
+        .post(
+            "/register",
+            json!({ "name": "alice", "password": "correct horse" }),
+        )
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::OK);
+
+    let bob = server.register("bob").await;
+
+    let (status, logged_in) = server
+        .post(
+            "/login",
+            json!({ "name": "Alice", "password": "correct horse" }),
+        )
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::OK);
+
+    let session = logged_in["token"].as_str().unwrap();
+    assert_ne!(logged_in["token"], registered["token"]);
+
+    // the new session is as good as the one from registering
+    let mut alice_socket = server.connect(session).await;
+
+    server
+        .post(
+            "/send_message",
+            json!({ "token": bob, "to": "alice", "body": "welcome back" }),
+        )
+        .await;
+
+    assert_eq!(
+        alice_socket.next_event_of("message").await["body"],
+        "welcome back"
+    );
+
+    let (status, _) = server
+        .post(
+            "/send_message",
+            json!({ "token": registered["token"], "to": "bob", "body": "hi" }),
+        )
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::OK);
+}
+
+#[tokio::test]
+async fn leaving_a_room_takes_a_live_token() {
+    let server = TestServer::with_config(|config| {
+        config.password_hash_iterations = TEST_HASH_ITERATIONS.try_into().unwrap();
+        config.access_token_ttl = tokio::time::Duration::from_millis(200);
+    })
+    .await;
+
+    let (_, registered) = server
+        .post(
+            "/register",
+            json!({ "name": "alice", "password": "correct horse" }),
+        )
+        .await;
+    let alice = registered["token"].as_str().unwrap();
+
+    server
+        .post("/rooms/lobby/join", json!({ "token": alice }))
+        .await;
+
+    let (status, response) = server
+        .post("/rooms/lobby/leave", json!({ "token": "nope" }))
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::UNAUTHORIZED);
+    assert_eq!(response["error"], "invalid_token");
+
+    // an access token that ran out is as good as none
+    tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+
+    let (status, _) = server
+        .post("/rooms/lobby/leave", json!({ "token": alice }))
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::UNAUTHORIZED);
+    assert_eq!(server.state.rooms.lock().await["lobby"].members.len(), 1);
+}
+
+#[tokio::test]
+async fn login_with_a_wrong_password_is_unauthorized() {
+    let server = TestServer::with_config(|config| {
+        config.password_hash_iterations = TEST_HASH_ITERATIONS.try_into().unwrap()
+    })
+    .await;
+
+    server
+        .post(
+            "/register",
+            json!({ "name": "alice", "password": "correct horse" }),
+        )
+        .await;
+    server.register("bob").await;
+
+    // a wrong password, a name without a password and an unknown name can't
+    // be told apart
+    for (name, password) in [
+        ("alice", "battery staple"),
+        ("bob", "correct horse"),
+        ("carol", "correct horse"),
+    ] {
+        let (status, response) = server
+            .post("/login", json!({ "name": name, "password": password }))
+            .await;
+
+        assert_eq!(status, warp::http::StatusCode::UNAUTHORIZED, "{}", name);
+        assert_eq!(response["error"], "invalid_credentials");
+    }
+}
+
+#[tokio::test]
+async fn password_hash_is_never_served() {
+    let server = TestServer::with_config(|config| {
+        config.password_hash_iterations = TEST_HASH_ITERATIONS.try_into().unwrap()
+    })
+    .await;
+
+    let (_, registered) = server
+        .post(
+            "/register",
+            json!({ "name": "alice", "password": "correct horse" }),
+        )
+        .await;
+    let (_, logged_in) = server
+        .post(
+            "/login",
+            json!({ "name": "alice", "password": "correct horse" }),
+        )
+        .await;
+    let (_, listed) = server.request("GET", "/clients", None, &[]).await;
+
+    let hash = server
+        .state
+        .clients
+        .get("alice")
+        .and_then(|client| client.password_hash.clone())
+        .unwrap();
+
+    assert!(!hash.contains("correct horse"));
+
+    for served in [&registered, &logged_in, &listed] {
+        let served = served.to_string();
+
+        assert!(!served.contains(&hash), "{}", served);
+        assert!(!served.contains("pbkdf2"), "{}", served);
+    }
+
+    assert_eq!(listed["clients"][0]["name"], "alice");
+}
+
+#[tokio::test]
+async fn revoking_a_session_closes_only_its_socket() {
+    let server = TestServer::with_config(|config| {
+        config.password_hash_iterations = TEST_HASH_ITERATIONS.try_into().unwrap()
+    })
+    .await;
+
+    let (_, laptop) = server
+        .post(
+            "/register",
+            json!({ "name": "alice", "password": "correct horse", "device": "laptop" }),
+        )
+        .await;
+    let (_, phone) = server
+        .post(
+            "/login",
+            json!({ "name": "alice", "password": "correct horse", "device": "phone" }),
+        )
+        .await;
+
+    let laptop = laptop["token"].as_str().unwrap();
+    let phone = phone["token"].as_str().unwrap();
+
+    let mut laptop_socket = server.connect(laptop).await;
+    let mut phone_socket = server.connect(phone).await;
+
+    let (status, sessions) = server
+        .request("GET", &format!("/sessions/{}", laptop), None, &[])
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::OK);
+    assert_eq!(sessions.as_array().unwrap().len(), 2);
+
+    let session = |device: &str| {
+        sessions
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|session| session["device"] == device)
+            .unwrap()
+            .clone()
+    };
+
+    assert_eq!(session("laptop")["current"], true);
+    assert_eq!(session("phone")["current"], false);
+    assert_eq!(session("phone")["connected"], true);
+    assert!(session("phone")["connected_at"].is_i64());
+
+    let (status, _) = server
+        .post(
+            &format!(
+                "/sessions/{}/revoke",
+                session("phone")["id"].as_str().unwrap()
+            ),
+            json!({ "token": laptop }),
+        )
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::NO_CONTENT);
+
+    phone_socket.expect_closed().await;
+
+    // the laptop is still connected and signed in
+    let bob = server.register("bob").await;
+
+    server
+        .post(
+            "/send_message",
+            json!({ "token": bob, "to": "alice", "body": "still there?" }),
+        )
+        .await;
+
+    assert_eq!(
+        laptop_socket.next_event_of("message").await["body"],
+        "still there?"
+    );
+
+    let (status, _) = server
+        .request("GET", &format!("/status/{}", phone), None, &[])
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::UNAUTHORIZED);
+
+    let (_, sessions) = server
+        .request("GET", &format!("/sessions/{}", laptop), None, &[])
+        .await;
+
+    assert_eq!(sessions.as_array().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn errors_come_back_as_json() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    server.register("bob").await;
+
+    let cases = [
+        (
+            "/rename",
+            json!({ "token": alice, "name": "bob" }),
+            warp::http::StatusCode::NOT_ACCEPTABLE,
+            "name_taken",
+        ),
+        (
+            "/rename",
+            json!({ "token": alice, "name": "" }),
+            warp::http::StatusCode::BAD_REQUEST,
+            "invalid_name",
+        ),
+        (
+            "/send_message",
+            json!({ "token": "not a token", "to": "alice", "body": "hi" }),
+            warp::http::StatusCode::UNAUTHORIZED,
+            "invalid_token",
+        ),
+        (
+            "/send_message",
+            json!({ "token": alice, "to": "nobody", "body": "hi" }),
+            warp::http::StatusCode::NOT_FOUND,
+            "unknown_recipient",
+        ),
+        (
+            "/send_message",
+            json!({ "token": alice }),
+            warp::http::StatusCode::BAD_REQUEST,
+            "malformed_body",
+        ),
+    ];
+
+    for (path, body, expected_status, expected_error) in cases {
+        let (status, body) = server.post(path, body).await;
+
+        assert_eq!(status, expected_status, "{}", expected_error);
+        assert_eq!(body, json!({ "error": expected_error }));
+    }
+}
+
+#[tokio::test]
+async fn bad_token_and_unknown_recipient_are_told_apart() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+
+    let (status, _) = server.request("GET", "/status/bogus", None, &[]).await;
+
+    assert_eq!(status, warp::http::StatusCode::UNAUTHORIZED);
+
+    let (status, _) = server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": "nobody", "body": "hi" }),
+        )
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn history_keeps_the_newest_messages_in_order() {
+    let server = TestServer::with_config(|config| config.history_caps.max_messages = 3).await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+
+    for body in ["one", "two", "three", "four", "five"] {
+        server
+            .post(
+                "/send_message",
+                json!({ "token": alice, "to": "bob", "body": body }),
+            )
+            .await;
+    }
+
+    let (status, history) = server
+        .request("GET", &format!("/history/{}", bob), None, &[])
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::OK);
+
+    let bodies: Vec<_> = history
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|message| message["body"].as_str().unwrap())
+        .collect();
+
+    assert_eq!(bodies, ["three", "four", "five"]);
+}
+
+#[tokio::test]
+async fn room_messages_reach_only_its_members() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let carol = server.register("carol").await;
+
+    let mut bob_socket = server.connect(&bob).await;
+    let mut carol_socket = server.connect(&carol).await;
+
+    for token in [&alice, &bob] {
+        let (status, _) = server
+            .post("/rooms/lobby/join", json!({ "token": token }))
+            .await;
+
+        assert_eq!(status, warp::http::StatusCode::OK);
+    }
+
+    let (status, response) = server
+        .post(
+            "/rooms/lobby/message",
+            json!({ "token": alice, "body": "welcome" }),
+        )
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::OK);
+    assert_eq!(response["delivered"], 1);
+
+    let message = bob_socket.next_event_of("message").await;
+
+    assert_eq!(message["room"], "lobby");
+    assert_eq!(message["body"], "welcome");
+
+    // once bob left nobody else is there to hear it
+    let (status, _) = server
+        .post("/rooms/lobby/leave", json!({ "token": bob }))
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::OK);
+
+    let (_, response) = server
+        .post(
+            "/rooms/lobby/message",
+            json!({ "token": alice, "body": "anyone?" }),
+        )
+        .await;
+
+    assert_eq!(response["delivered"], 0);
+
+    // carol never joined, the first message she sees is the one sent to her
+    server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": "carol", "body": "just you" }),
+        )
+        .await;
+
+    assert_eq!(
+        carol_socket.next_event_of("message").await["body"],
+        "just you"
+    );
+
+    let (status, response) = server
+        .post(
+            "/rooms/lobby/message",
+            json!({ "token": carol, "body": "let me in" }),
+        )
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::FORBIDDEN);
+    assert_eq!(response["error"], "not_room_member");
+}
+
+#[tokio::test]
+async fn socket_that_stops_answering_pings_is_dropped() {
+    let server = TestServer::with_config(|config| {
+        config.ping_interval = tokio::time::Duration::from_millis(100);
+        config.pong_timeout = tokio::time::Duration::from_millis(100);
+    })
+    .await;
+
+    let bob = server.register("bob").await;
+
+    // a socket that's never read from never answers a ping
+    let _bob_socket = server.connect(&bob).await;
+
+    let dropped = async {
+        while server.state.clients.get("bob").unwrap().is_connected() {
+            tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+        }
+    };
+
+    tokio::time::timeout(tokio::time::Duration::from_secs(5), dropped)
+        .await
+        .expect("dead socket not dropped in time");
+}
+
+#[tokio::test]
+async fn socket_whose_writes_stall_is_dropped() {
+    let server = TestServer::with_config(|config| {
+        config.outbox_capacity = 1;
+        config.overflow_policy = crate::outbox::OverflowPolicy::Disconnect;
+        config.pong_timeout = tokio::time::Duration::from_millis(100);
+    })
+    .await;
+
+    let bob = server.register("bob").await;
+
+    // never read, so the socket backs up once the kernel buffers are full
+    let _bob_socket = server.connect(&bob).await;
+
+    let event = crate::ServerEvent::System {
+        body: "x".repeat(64 * 1024),
+    };
+
+    let overflowed = async {
+        while server
+            .state
+            .clients
+            .get("bob")
+            .unwrap()
+            .send(&event)
+            .is_ok()
+        {
+            tokio::task::yield_now().await;
+        }
+    };
+
+    tokio::time::timeout(tokio::time::Duration::from_secs(5), overflowed)
+        .await
+        .expect("outbox never overflowed");
+
+    let dropped = async {
+        while server.state.clients.get("bob").unwrap().is_connected() {
+            tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+        }
+    };
+
+    tokio::time::timeout(tokio::time::Duration::from_secs(5), dropped)
+        .await
+        .expect("stalled socket not dropped in time");
+}
+
+#[tokio::test]
+async fn shutdown_closes_sockets_and_turns_new_ones_away() {
+    let server = TestServer::with_config(|config| {
+        config.shutdown_drain = tokio::time::Duration::from_millis(50)
+    })
+    .await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let mut alice_socket = server.connect(&alice).await;
+
+    let (signal, signalled) = tokio::sync::oneshot::channel::<()>();
+    let shutdown = tokio::spawn(crate::shutdown(
+        async {
+            let _ = signalled.await;
+        },
+        server.state.clients.clone(),
+        server.state.config.clone(),
+        server.state.shutting_down.clone(),
+    ));
+
+    signal.send(()).unwrap();
+
+    assert_eq!(
+        alice_socket.next_event_of("system").await["body"],
+        "The server is shutting down"
+    );
+    alice_socket.expect_closed().await;
+
+    tokio::time::timeout(tokio::time::Duration::from_secs(5), shutdown)
+        .await
+        .expect("shutdown not done after the drain period")
+        .unwrap();
+
+    assert_eq!(
+        server.try_connect(&bob).await.err(),
+        Some(warp::http::StatusCode::SERVICE_UNAVAILABLE)
+    );
+}
+
+#[tokio::test]
+async fn registration_works_over_tls() {
+    use tokio_rustls::{rustls, webpki};
+
+    let state = super::test_state(|_| {});
+
+    let (shutdown, stopped) = tokio::sync::oneshot::channel::<()>();
+    let (addr, server) = warp::serve(crate::build_routes(state))
+        .tls()
+        .cert(include_bytes!("tls/cert.pem"))
+        .key(include_bytes!("tls/key.pem"))
+        .bind_with_graceful_shutdown(([127, 0, 0, 1], 0), async {
+            let _ = stopped.await;
+        });
+
+    tokio::spawn(server);
+
+    // trusts only the test ca the certificate is signed with
+    let mut config = rustls::ClientConfig::new();
+    config
+        .root_store
+        .add_pem_file(&mut &include_bytes!("tls/ca.pem")[..])
+        .unwrap();
+
+    let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let tls = tokio_rustls::TlsConnector::from(std::sync::Arc::new(config))
+        .connect(
+            webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap(),
+            tcp,
+        )
+        .await
+        .unwrap();
+
+    let (mut sender, connection) = warp::hyper::client::conn::handshake(tls).await.unwrap();
+    tokio::spawn(connection);
+
+    let request = warp::http::Request::post("/register")
+        .header("host", "localhost")
+        .header("content-type", "application/json")
+        .body(warp::hyper::Body::from(
+            json!({ "name": "alice" }).to_string(),
+        ))
+        .unwrap();
+
+    let response = sender.send_request(request).await.unwrap();
+
+    assert_eq!(response.status(), warp::http::StatusCode::OK);
+
+    let body = warp::hyper::body::to_bytes(response.into_body())
+        .await
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+    assert!(body["token"].is_string());
+
+    let _ = shutdown.send(());
+}
+
+#[tokio::test]
+async fn socket_authenticates_by_header_or_subprotocol() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+
+    // `connect` puts the token in an authorization header
+    server.connect(&alice).await;
+
+    // browsers can only offer it as a subprotocol
+    let (_, protocol) = server
+        .connect_to("/messages", &bob, Some(&format!("bearer.{}", bob)))
+        .await
+        .unwrap();
+
+    assert_eq!(protocol, Some(format!("bearer.{}", bob)));
+
+    assert_eq!(
+        server.try_connect("not a token").await.err(),
+        Some(warp::http::StatusCode::UNAUTHORIZED)
+    );
+}
+
+#[tokio::test]
+async fn metrics_scrape_reflects_activity() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let mut bob_socket = server.connect(&bob).await;
+
+    server.post("/register", json!({ "name": "alice" })).await;
+    server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": "bob", "body": "hi" }),
+        )
+        .await;
+    bob_socket.next_event_of("message").await;
+
+    let response = warp::hyper::Client::new()
+        .get(server.url("http", "/metrics").parse().unwrap())
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), warp::http::StatusCode::OK);
+
+    let body = warp::hyper::body::to_bytes(response.into_body())
+        .await
+        .unwrap();
+    let body = std::str::from_utf8(&body).unwrap();
+
+    for line in [
+        "chat_registered_clients 2",
+        "chat_active_connections 1",
+        "chat_registrations_total 2",
+        "chat_registration_failures_total 1",
+        "chat_messages_sent_total 1",
+    ] {
+        assert!(body.lines().any(|metric| metric == line), "{}", line);
+    }
+}
+
+#[tokio::test]
+async fn typing_reaches_the_recipient_and_is_not_kept() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let mut bob_socket = server.connect(&bob).await;
+
+    let (status, _) = server
+        .post("/typing", json!({ "token": alice, "to": "bob" }))
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::NO_CONTENT);
+    assert_eq!(bob_socket.next_event_of("typing").await["from"], "alice");
+
+    let (_, history) = server
+        .request("GET", &format!("/history/{}", bob), None, &[])
+        .await;
+
+    assert_eq!(history, json!([]));
+}
+
+#[tokio::test]
+async fn sender_is_acknowledged_once_the_message_is_delivered() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let mut alice_socket = server.connect(&alice).await;
+    let _bob_socket = server.connect(&bob).await;
+
+    let (_, sent) = server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": "bob", "body": "did you get this" }),
+        )
+        .await;
+
+    let ack = alice_socket.next_event_of("ack").await;
+
+    assert_eq!(ack["message_id"], sent["message_id"]);
+    assert_eq!(ack["to"], "bob");
+}
+
+#[tokio::test]
+async fn reading_a_message_sends_a_receipt_to_its_sender() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let mut alice_socket = server.connect(&alice).await;
+    let mut bob_socket = server.connect(&bob).await;
+
+    server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": "bob", "body": "read me" }),
+        )
+        .await;
+
+    let id = bob_socket.next_event_of("message").await["id"].clone();
+
+    let (status, _) = server
+        .post("/read", json!({ "token": bob, "message_id": id }))
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::NO_CONTENT);
+
+    let receipt = alice_socket.next_event_of("read").await;
+
+    assert_eq!(receipt["message_id"], id);
+    assert_eq!(receipt["by"], "bob");
+}
+
+#[tokio::test]
+async fn reactions_toggle_and_reach_everyone_in_the_conversation() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let carol = server.register("carol").await;
+
+    let mut sockets = [
+        server.connect(&alice).await,
+        server.connect(&bob).await,
+        server.connect(&carol).await,
+    ];
+
+    let (_, sent) = server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": ["bob", "carol"], "body": "lunch?" }),
+        )
+        .await;
+    let id = sent["message_id"].as_str().unwrap();
+    let path = format!("/messages/{}/react", id);
+
+    for added in [true, false] {
+        let (status, _) = server
+            .post(&path, json!({ "token": bob, "emoji": "👍" }))
+            .await;
+
+        assert_eq!(status, warp::http::StatusCode::NO_CONTENT);
+
+        for socket in &mut sockets {
+            let reaction = socket.next_event_of("reaction").await;
+
+            assert_eq!(reaction["message_id"], id);
+            assert_eq!(reaction["emoji"], "👍");
+            assert_eq!(reaction["by"], "bob");
+            assert_eq!(reaction["added"], added);
+        }
+
+        let (_, history) = server
+            .request("GET", &format!("/history/{}", carol), None, &[])
+            .await;
+
+        let reactors = &history[0]["reactions"]["👍"];
+
+        if added {
+            assert_eq!(*reactors, json!(["bob"]));
+        } else {
+            assert!(reactors.is_null());
+        }
+    }
+}
+
+#[tokio::test]
+async fn reactions_stay_with_whoever_made_them_across_renames() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let carol = server.register("carol").await;
+
+    let (_, sent) = server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": ["bob", "carol"], "body": "lunch?" }),
+        )
+        .await;
+    let path = format!("/messages/{}/react", sent["message_id"].as_str().unwrap());
+    let reactors = || async {
+        let (_, history) = server
+            .request("GET", &format!("/history/{}", carol), None, &[])
+            .await;
+
+        history[0]["reactions"]["👍"].clone()
+    };
+
+    server
+        .post(&path, json!({ "token": bob, "emoji": "👍" }))
+        .await;
+
+    // bob's reaction follows him to his new name, and carol taking his old
+    // one doesn't make it hers
+    for (token, name) in [(&bob, "robert"), (&carol, "bob")] {
+        server
+            .post("/rename", json!({ "token": token, "name": name }))
+            .await;
+    }
+
+    assert_eq!(reactors().await, json!(["robert"]));
+
+    server
+        .post(&path, json!({ "token": carol, "emoji": "👍" }))
+        .await;
+
+    assert_eq!(reactors().await, json!(["bob", "robert"]));
+
+    server
+        .post(&path, json!({ "token": bob, "emoji": "👍" }))
+        .await;
+
+    assert_eq!(reactors().await, json!(["bob"]));
+}
+
+#[tokio::test]
+async fn mentioned_client_is_notified_without_being_a_recipient() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    server.register("bob").await;
+    let carol = server.register("carol").await;
+    let mut carol_socket = server.connect(&carol).await;
+
+    let (_, sent) = server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": "bob", "body": "ask @Carol about it" }),
+        )
+        .await;
+
+    let mention = carol_socket.next_event_of("mention").await;
+
+    assert_eq!(mention["from"], "alice");
+    assert_eq!(mention["message_id"], sent["message_id"]);
+}
+
+#[tokio::test]
+async fn rename_frees_the_old_name_and_keeps_the_token() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+
+    let (status, _) = server
+        .post("/rename", json!({ "token": alice, "name": "alicia" }))
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::NO_CONTENT);
+
+    let (status, body) = server
+        .request("GET", &format!("/status/{}", alice), None, &[])
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::OK);
+    assert_eq!(body["name"], "alicia");
+
+    // the new name is who messages go to
+    let (_, response) = server
+        .post(
+            "/send_message",
+            json!({ "token": bob, "to": "alicia", "body": "nice name" }),
+        )
+        .await;
+
+    assert_eq!(response["queued"], json!(["alicia"]));
+
+    server.register("alice").await;
+}
+
+#[tokio::test]
+async fn client_listing_pages_add_up_to_everyone() {
+    let server = TestServer::start().await;
+
+    let names = ["alice", "bob", "carol", "dave", "erin", "frank", "grace"];
+
+    for name in names {
+        server.register(name).await;
+    }
+
+    let mut seen = Vec::new();
+    let mut offset = 0;
+
+    loop {
+        let (status, page) = server
+            .request(
+                "GET",
+                &format!("/clients?offset={}&limit=3", offset),
+                None,
+                &[],
+            )
+            .await;
+
+        assert_eq!(status, warp::http::StatusCode::OK);
+        assert_eq!(page["total"], names.len());
+
+        let clients = page["clients"].as_array().unwrap();
+
+        if clients.is_empty() {
+            break;
+        }
+
+        assert!(clients.len() <= 3);
+
+        seen.extend(
+            clients
+                .iter()
+                .map(|client| client["name"].as_str().unwrap().to_string()),
+        );
+        offset += clients.len();
+    }
+
+    assert_eq!(seen, names);
+}
+
+#[tokio::test]
+async fn client_listing_filters_by_prefix() {
+    let server = TestServer::start().await;
+
+    for name in ["alice", "alan", "bob"] {
+        server.register(name).await;
+    }
+
+    let (_, page) = server.request("GET", "/clients?prefix=Al", None, &[]).await;
+
+    let names: Vec<_> = page["clients"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|client| client["name"].as_str().unwrap())
+        .collect();
+
+    assert_eq!(names, ["alan", "alice"]);
+    assert_eq!(page["total"], 2);
+}
+
+#[tokio::test]
+async fn token_index_follows_register_rename_and_logout() {
+    let server = TestServer::start().await;
+    let clients = &server.state.clients;
+
+    let alice = server.register("alice").await;
+
+    assert_eq!(clients.key_for_token(&alice).as_deref(), Some("alice"));
+
+    server
+        .post("/rename", json!({ "token": alice, "name": "Alicia" }))
+        .await;
+
+    assert_eq!(clients.key_for_token(&alice).as_deref(), Some("alicia"));
+
+    server.post("/logout", json!({ "token": alice })).await;
+
+    assert_eq!(clients.key_for_token(&alice), None);
+    assert_eq!(clients.len(), 0);
+}
+
+#[tokio::test]
+async fn every_device_of_a_client_gets_its_messages() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+
+    let mut phone = server.connect(&bob).await;
+    let mut laptop = server.connect(&bob).await;
+
+    let (_, response) = server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": "bob", "body": "on both" }),
+        )
+        .await;
+
+    assert_eq!(response["delivered"], json!(["bob"]));
+
+    for socket in [&mut phone, &mut laptop] {
+        assert_eq!(socket.next_event_of("message").await["body"], "on both");
+    }
+}
+
+#[tokio::test]
+async fn kicked_client_is_disconnected_and_unlisted() {
+    let server =
+        TestServer::with_config(|config| config.admin_token = Some("secret".to_string())).await;
+
+    server.register("alice").await;
+    let bob = server.register("bob").await;
+    let mut bob_socket = server.connect(&bob).await;
+
+    let (status, _) = server
+        .post(
+            "/admin/kick",
+            json!({ "admin_token": "secret", "name": "bob" }),
+        )
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::NO_CONTENT);
+    assert_eq!(
+        bob_socket.next_event_of("system").await["body"],
+        "You were kicked by a moderator"
+    );
+    bob_socket.expect_closed().await;
+
+    let (_, page) = server.request("GET", "/clients", None, &[]).await;
+
+    assert_eq!(page["total"], 1);
+    assert_eq!(page["clients"][0]["name"], "alice");
+
+    // the admin token is checked before anything happens
+    let (status, _) = server
+        .post(
+            "/admin/kick",
+            json!({ "admin_token": "guess", "name": "alice" }),
+        )
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn banned_name_registers_again_once_unbanned() {
+    let server =
+        TestServer::with_config(|config| config.admin_token = Some("secret".to_string())).await;
+
+    let ban = json!({ "admin_token": "secret", "name": "Mallory" });
+
+    let (status, _) = server.post("/admin/ban", ban.clone()).await;
+
+    assert!(status.is_success());
+
+    let (status, body) = server.post("/register", json!({ "name": "mallory" })).await;
+
+    assert_eq!(status, warp::http::StatusCode::FORBIDDEN);
+    assert!(body["token"].is_null());
+
+    let (status, _) = server.post("/admin/unban", ban).await;
+
+    assert!(status.is_success());
+
+    server.register("mallory").await;
+}
+
+#[tokio::test]
+async fn markup_in_a_message_is_escaped() {
+    let server = TestServer::with_config(|config| config.escape_html = true).await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let mut bob_socket = server.connect(&bob).await;
+
+    server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": "bob", "body": "<script>alert('hi')</script>" }),
+        )
+        .await;
+
+    assert_eq!(
+        bob_socket.next_event_of("message").await["body"],
+        "&lt;script&gt;alert(&#39;hi&#39;)&lt;/script&gt;"
+    );
+}
+
+#[tokio::test]
+async fn oversized_body_is_refused() {
+    let server = TestServer::with_config(|config| config.max_request_bytes = 64).await;
+
+    let (status, body) = server
+        .post("/register", json!({ "name": "a".repeat(100) }))
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(body["error"], "payload_too_large");
+}
+
+#[tokio::test]
+async fn announcement_reaches_everyone_as_a_system_message() {
+    let server =
+        TestServer::with_config(|config| config.admin_token = Some("secret".to_string())).await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+
+    let mut sockets = [server.connect(&alice).await, server.connect(&bob).await];
+
+    let (status, _) = server
+        .post(
+            "/admin/announce",
+            json!({ "admin_token": "secret", "body": "back in five" }),
+        )
+        .await;
+
+    assert!(status.is_success());
+
+    for socket in &mut sockets {
+        assert_eq!(socket.next_event_of("system").await["body"], "back in five");
+    }
+}
+
+#[tokio::test]
+async fn maintenance_mode_holds_off_registration_until_lifted() {
+    let server =
+        TestServer::with_config(|config| config.admin_token = Some("secret".to_string())).await;
+
+    let maintenance = |enabled| {
+        server.post(
+            "/admin/maintenance",
+            json!({ "admin_token": "secret", "enabled": enabled }),
+        )
+    };
+
+    assert!(maintenance(true).await.0.is_success());
+
+    let (status, body) = server.post("/register", json!({ "name": "alice" })).await;
+
+    assert_eq!(status, warp::http::StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body["error"], "maintenance");
+
+    assert!(maintenance(false).await.0.is_success());
+
+    server.register("alice").await;
+}
+
+#[tokio::test]
+async fn sequence_numbers_count_up_per_recipient() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let carol = server.register("carol").await;
+    let mut bob_socket = server.connect(&bob).await;
+    let mut carol_socket = server.connect(&carol).await;
+
+    // bob hears from two senders, carol only from one
+    for (from, to) in [
+        (&alice, "bob"),
+        (&carol, "bob"),
+        (&alice, "carol"),
+        (&alice, "bob"),
+    ] {
+        server
+            .post(
+                "/send_message",
+                json!({ "token": from, "to": to, "body": "hi" }),
+            )
+            .await;
+    }
+
+    for (socket, expected) in [(&mut bob_socket, 1..=3), (&mut carol_socket, 1..=1)] {
+        for seq in expected {
+            assert_eq!(socket.next_event_of("message").await["seq"], seq);
+        }
+    }
+}
+
+#[tokio::test]
+async fn unread_count_resets_once_the_client_connects() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+
+    // distinct bodies, identical ones would only be queued once
+    for body in ["one", "two", "three"] {
+        server
+            .post(
+                "/send_message",
+                json!({ "token": alice, "to": "bob", "body": body }),
+            )
+            .await;
+    }
+
+    let status = || async {
+        server
+            .request("GET", &format!("/status/{}", bob), None, &[])
+            .await
+            .1
+    };
+
+    assert_eq!(status().await["unread"], 3);
+
+    let mut bob_socket = server.connect(&bob).await;
+
+    for _ in 0..3 {
+        bob_socket.next_event_of("message").await;
+    }
+
+    assert_eq!(status().await["unread"], 0);
+}
+
+#[tokio::test]
+async fn responses_carry_the_request_id() {
+    let server = TestServer::start().await;
+
+    let request_id = |id: Option<&str>| {
+        let mut request = warp::http::Request::get(server.url("http", "/healthz"));
+
+        if let Some(id) = id {
+            request = request.header("x-request-id", id);
+        }
+
+        async {
+            let response = warp::hyper::Client::new()
+                .request(request.body(warp::hyper::Body::empty()).unwrap())
+                .await
+                .unwrap();
+
+            response.headers()["x-request-id"]
+                .to_str()
+                .unwrap()
+                .to_string()
+        }
+    };
+
+    assert_eq!(request_id(Some("abc-123")).await, "abc-123");
+
+    // without one, or with one that can't be logged, the server makes one up
+    let generated = request_id(None).await;
+
+    assert!(!generated.is_empty());
+    assert_ne!(generated, request_id(None).await);
+    assert_ne!(request_id(Some("has spaces")).await, "has spaces");
+}
+
+#[tokio::test]
+async fn stats_count_clients_and_messages() {
+    let server = TestServer::with_config(|config| {
+        config.admin_token = Some("secret".to_string());
+        config.disconnect_grace = tokio::time::Duration::from_secs(60);
+    })
+    .await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let carol = server.register("carol").await;
+
+    let _alice_socket = server.connect(&alice).await;
+    let mut bob_socket = server.connect(&bob).await;
+    server.connect(&carol).await.close().await;
+
+    server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": "bob", "body": "hello" }),
+        )
+        .await;
+    bob_socket.next_event_of("message").await;
+
+    let (status, stats) = server
+        .request(
+            "GET",
+            "/admin/stats",
+            None,
+            &[("authorization", "Bearer secret")],
+        )
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::OK);
+    assert_eq!(stats["registered"], 3);
+    assert_eq!(stats["connected"], 2);
+    assert_eq!(stats["in_grace"], 1);
+    assert_eq!(stats["messages_delivered"], 1);
+    assert_eq!(stats["history_messages"], 1);
+    assert_eq!(stats["history_bytes"], 5);
+}
+
+#[tokio::test]
+async fn message_reaches_an_event_stream() {
+    use futures::StreamExt;
+
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+
+    let request = warp::http::Request::get(server.url("http", "/events"))
+        .header("authorization", format!("Bearer {}", bob))
+        .body(warp::hyper::Body::empty())
+        .unwrap();
+    let response = warp::hyper::Client::new().request(request).await.unwrap();
+
+    assert_eq!(response.status(), warp::http::StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "text/event-stream");
+
+    // the stream is attached by the time the response starts
+    server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": "bob", "body": "streamed" }),
+        )
+        .await;
+
+    let mut body = response.into_body();
+    let mut received = String::new();
+
+    let message = tokio::time::timeout(tokio::time::Duration::from_secs(5), async {
+        loop {
+            let chunk = body.next().await.unwrap().unwrap();
+            received.push_str(std::str::from_utf8(&chunk).unwrap());
+
+            let event = received
+                .lines()
+                .filter_map(|line| line.strip_prefix("data:"))
+                .filter_map(|data| serde_json::from_str::<serde_json::Value>(data).ok())
+                .find(|event| event["type"] == "message");
+
+            if let Some(event) = event {
+                return event;
+            }
+        }
+    })
+    .await
+    .expect("no message on the stream in time");
+
+    assert_eq!(message["from"], "alice");
+    assert_eq!(message["body"], "streamed");
+}
+
+#[tokio::test]
+async fn client_listing_is_gzipped_when_accepted() {
+    use std::io::Read;
+
+    let server = TestServer::start().await;
+
+    server.register("alice").await;
+
+    let fetch = |accept_encoding: Option<&str>| {
+        let mut request = warp::http::Request::get(server.url("http", "/clients"));
+
+        if let Some(accept_encoding) = accept_encoding {
+            request = request.header("accept-encoding", accept_encoding);
+        }
+
+        async {
+            let response = warp::hyper::Client::new()
+                .request(request.body(warp::hyper::Body::empty()).unwrap())
+                .await
+                .unwrap();
+
+            let encoding = response
+                .headers()
+                .get("content-encoding")
+                .map(|encoding| encoding.to_str().unwrap().to_string());
+            let body = warp::hyper::body::to_bytes(response.into_body())
+                .await
+                .unwrap();
+
+            (encoding, body)
+        }
+    };
+
+    let (encoding, plain) = fetch(None).await;
+
+    assert_eq!(encoding, None);
+
+    let (encoding, compressed) = fetch(Some("gzip, deflate")).await;
+
+    assert_eq!(encoding.as_deref(), Some("gzip"));
+
+    let mut decoded = Vec::new();
+    flate2::read::GzDecoder::new(&compressed[..])
+        .read_to_end(&mut decoded)
+        .unwrap();
+
+    assert_eq!(decoded, plain);
+}
+
+#[tokio::test]
+async fn preflight_passes_only_for_allowed_origins() {
+    let server = TestServer::with_config(|config| {
+        config.cors = Some(crate::CorsConfig {
+            origins: Some(vec!["https://chat.example.com".to_string()]),
+            methods: vec![warp::http::Method::GET, warp::http::Method::POST],
+            headers: vec![warp::http::header::CONTENT_TYPE],
+        })
+    })
+    .await;
+
+    let preflight = |origin: &str| {
+        let request = warp::http::Request::builder()
+            .method("OPTIONS")
+            .uri(server.url("http", "/send_message"))
+            .header("origin", origin)
+            .header("access-control-request-method", "POST")
+            .header("access-control-request-headers", "content-type")
+            .body(warp::hyper::Body::empty())
+            .unwrap();
+
+        warp::hyper::Client::new().request(request)
+    };
+
+    let allowed = preflight("https://chat.example.com").await.unwrap();
+
+    assert_eq!(allowed.status(), warp::http::StatusCode::OK);
+    assert_eq!(
+        allowed.headers()["access-control-allow-origin"],
+        "https://chat.example.com"
+    );
+
+    let refused = preflight("https://elsewhere.example.com").await.unwrap();
+
+    assert_eq!(refused.status(), warp::http::StatusCode::FORBIDDEN);
+    assert!(!refused
+        .headers()
+        .contains_key("access-control-allow-origin"));
+}
+
+#[tokio::test]
+async fn quiet_client_goes_away_and_comes_back_when_active() {
+    let server = TestServer::with_config(|config| {
+        config.idle_away = tokio::time::Duration::from_millis(200)
+    })
+    .await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let mut alice_socket = server.connect(&alice).await;
+    let mut bob_socket = server.connect(&bob).await;
+
+    async fn bob_presence(socket: &mut super::TestSocket) -> String {
+        loop {
+            let presence = socket.next_event_of("presence").await;
+
+            if presence["name"] == "bob" {
+                return presence["status"].as_str().unwrap().to_string();
+            }
+        }
+    }
+
+    assert_eq!(bob_presence(&mut alice_socket).await, "online");
+    assert_eq!(bob_presence(&mut alice_socket).await, "away");
+
+    bob_socket
+        .send(json!({ "to": "alice", "body": "back" }))
+        .await;
+
+    assert_eq!(bob_presence(&mut alice_socket).await, "online");
+}
+
+#[tokio::test]
+async fn status_message_shows_in_the_status_and_is_capped() {
+    let server = TestServer::start().await;
+
+    let bob = server.register("bob").await;
+
+    let (status, _) = server
+        .post(
+            "/presence",
+            json!({ "token": bob, "status": "away", "status_message": "at lunch" }),
+        )
+        .await;
+
+    assert!(status.is_success());
+
+    let (_, body) = server
+        .request("GET", &format!("/status/{}", bob), None, &[])
+        .await;
+
+    assert_eq!(body["status"], "away");
+    assert_eq!(body["status_message"], "at lunch");
+
+    let (status, body) = server
+        .post(
+            "/presence",
+            json!({
+                "token": bob,
+                "status": "away",
+                "status_message": "z".repeat(crate::MAX_STATUS_MESSAGE_LENGTH + 1),
+            }),
+        )
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"], "status_message_too_long");
+}
+
+#[tokio::test]
+async fn export_has_the_whole_history_one_message_per_line() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+
+    for body in ["first", "second"] {
+        server
+            .post(
+                "/send_message",
+                json!({ "token": alice, "to": "bob", "body": body }),
+            )
+            .await;
+    }
+
+    let response = warp::hyper::Client::new()
+        .get(
+            server
+                .url("http", &format!("/export/{}", bob))
+                .parse()
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), warp::http::StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "application/x-ndjson");
+
+    let body = warp::hyper::body::to_bytes(response.into_body())
+        .await
+        .unwrap();
+    let messages: Vec<serde_json::Value> = std::str::from_utf8(&body)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+
+    assert_eq!(messages.len(), 2);
+
+    for (message, body) in messages.iter().zip(["first", "second"]) {
+        assert_eq!(message["from"], "alice");
+        assert_eq!(message["body"], body);
+    }
+}
+
+#[tokio::test]
+async fn deleted_account_leaves_nothing_behind() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let _bob_socket = server.connect(&bob).await;
+
+    server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": "bob", "body": "remember this" }),
+        )
+        .await;
+
+    let (status, _) = server
+        .request("DELETE", "/account", Some(json!({ "token": bob })), &[])
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::NO_CONTENT);
+
+    let (status, _) = server
+        .request("GET", &format!("/history/{}", bob), None, &[])
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::UNAUTHORIZED);
+
+    // whoever takes the name next starts from scratch
+    let bob = server.register("bob").await;
+
+    let (_, history) = server
+        .request("GET", &format!("/history/{}", bob), None, &[])
+        .await;
+
+    assert_eq!(history, json!([]));
+}
+
+#[tokio::test]
+async fn file_sent_in_chunks_reaches_the_recipient() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let mut alice_socket = server.connect(&alice).await;
+    let mut bob_socket = server.connect(&bob).await;
+
+    alice_socket
+        .send(json!({
+            "type": "attach_begin",
+            "to": "bob",
+            "filename": "notes.txt",
+            "size": 11,
+            "mime": "text/plain",
+            "body": "my notes",
+        }))
+        .await;
+    alice_socket.send_binary(b"hello ").await;
+    alice_socket.send_binary(b"world").await;
+    alice_socket.send(json!({ "type": "attach_end" })).await;
+
+    let message = bob_socket.next_event_of("message").await;
+    let attachment = &message["attachment"];
+
+    assert_eq!(message["body"], "my notes");
+    assert_eq!(attachment["filename"], "notes.txt");
+    assert_eq!(attachment["mime"], "text/plain");
+    assert_eq!(attachment["size"], 11);
+
+    let response = warp::hyper::Client::new()
+        .get(
+            server
+                .url("http", attachment["url"].as_str().unwrap())
+                .parse()
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let body = warp::hyper::body::to_bytes(response.into_body())
+        .await
+        .unwrap();
+
+    assert_eq!(&body[..], b"hello world");
+}
+
+#[tokio::test]
+async fn uploaded_file_downloads_byte_for_byte() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let file = [0x89, b'P', b'N', b'G', 0x00, 0xff, 0x0d, 0x0a];
+
+    let mut body = format!(
+        "--boundary\r\n\
+         content-disposition: form-data; name=\"token\"\r\n\r\n\
+         {}\r\n\
+         --boundary\r\n\
+         content-disposition: form-data; name=\"file\"; filename=\"dot.png\"\r\n\
+         content-type: image/png\r\n\r\n",
+        alice
+    )
+    .into_bytes();
+    body.extend_from_slice(&file);
+    body.extend_from_slice(b"\r\n--boundary--\r\n");
+
+    let request = warp::http::Request::builder()
+        .method("POST")
+        .uri(server.url("http", "/upload"))
+        .header("content-type", "multipart/form-data; boundary=boundary")
+        .body(warp::hyper::Body::from(body))
+        .unwrap();
+
+    let response = warp::hyper::Client::new().request(request).await.unwrap();
+
+    assert_eq!(response.status(), warp::http::StatusCode::OK);
+
+    let bytes = warp::hyper::body::to_bytes(response.into_body())
+        .await
+        .unwrap();
+    let uploaded: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+
+    assert!(uploaded["file_id"].is_string());
+
+    let response = warp::hyper::Client::new()
+        .get(
+            server
+                .url("http", uploaded["url"].as_str().unwrap())
+                .parse()
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.headers()["content-type"], "image/png");
+
+    let downloaded = warp::hyper::body::to_bytes(response.into_body())
+        .await
+        .unwrap();
+
+    assert_eq!(&downloaded[..], &file[..]);
+}
+
+#[tokio::test]
+async fn scheduled_message_arrives_once_it_is_due() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let mut bob_socket = server.connect(&bob).await;
+
+    let deliver_at = crate::unix_millis() + 500;
+
+    let (status, _) = server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": "bob", "body": "later", "deliver_at": deliver_at }),
+        )
+        .await;
+
+    assert!(status.is_success());
+
+    // sent after the scheduled one, but it's the first bob sees
+    server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": "bob", "body": "now" }),
+        )
+        .await;
+
+    assert_eq!(bob_socket.next_event_of("message").await["body"], "now");
+    assert!(crate::unix_millis() < deliver_at);
+
+    assert_eq!(bob_socket.next_event_of("message").await["body"], "later");
+    assert!(crate::unix_millis() >= deliver_at);
+}
+
+#[tokio::test]
+async fn blocked_sender_is_told_its_message_went_through() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let carol = server.register("carol").await;
+    let mut bob_socket = server.connect(&bob).await;
+
+    let (status, _) = server
+        .post("/block", json!({ "token": bob, "name": "alice" }))
+        .await;
+
+    assert!(status.is_success());
+
+    let (status, body) = server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": "bob", "body": "hello?" }),
+        )
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::OK);
+    assert!(body["message_id"].is_string());
+
+    // carol's message comes after alice's, so alice's would have come first
+    server
+        .post(
+            "/send_message",
+            json!({ "token": carol, "to": "bob", "body": "hi bob" }),
+        )
+        .await;
+
+    let message = bob_socket.next_event_of("message").await;
+
+    assert_eq!(message["from"], "carol");
+}
+
+#[tokio::test]
+async fn reported_message_shows_in_the_admin_listing() {
+    let server =
+        TestServer::with_config(|config| config.admin_token = Some("secret".to_string())).await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+
+    let (_, sent) = server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": "bob", "body": "rude words" }),
+        )
+        .await;
+
+    let (status, report) = server
+        .post(
+            "/report",
+            json!({ "token": bob, "message_id": sent["message_id"], "reason": "rude" }),
+        )
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::OK);
+
+    let (status, reports) = server
+        .request(
+            "GET",
+            "/admin/reports",
+            None,
+            &[("authorization", "Bearer secret")],
+        )
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::OK);
+
+    let reports = reports.as_array().unwrap();
+
+    assert_eq!(reports.len(), 1);
+    assert_eq!(reports[0]["id"], report["report_id"]);
+    assert_eq!(reports[0]["reporter"], "bob");
+    assert_eq!(reports[0]["sender"], "alice");
+    assert_eq!(reports[0]["body"], "rude words");
+    assert_eq!(reports[0]["reason"], "rude");
+    assert_eq!(reports[0]["resolved"], false);
+}
+
+#[tokio::test]
+async fn pinned_message_shows_in_the_pins() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let mut bob_socket = server.connect(&bob).await;
+
+    let (_, sent) = server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": "bob", "body": "the address is 12 main st" }),
+        )
+        .await;
+    server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": "bob", "body": "see you there" }),
+        )
+        .await;
+
+    let id = sent["message_id"].as_str().unwrap();
+
+    let (status, _) = server
+        .post(&format!("/messages/{}/pin", id), json!({ "token": bob }))
+        .await;
+
+    assert!(status.is_success());
+
+    let pin = bob_socket.next_event_of("pin").await;
+
+    assert_eq!(pin["message_id"], id);
+    assert_eq!(pin["by"], "bob");
+
+    // the pins are the same from either side of the conversation
+    for (token, peer) in [(&alice, "bob"), (&bob, "alice")] {
+        let (status, pins) = server
+            .request("GET", &format!("/pins/{}/{}", token, peer), None, &[])
+            .await;
+
+        assert_eq!(status, warp::http::StatusCode::OK);
+
+        let pins = pins.as_array().unwrap();
+
+        assert_eq!(pins.len(), 1);
+        assert_eq!(pins[0]["id"], id);
+        assert_eq!(pins[0]["body"], "the address is 12 main st");
+    }
+}
+
+#[tokio::test]
+async fn search_finds_only_the_matching_messages() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let carol = server.register("carol").await;
+
+    for (token, body) in [
+        (&alice, "Lunch at noon?"),
+        (&alice, "running late"),
+        (&carol, "lunch is on me"),
+        (&carol, "never mind"),
+    ] {
+        server
+            .post(
+                "/send_message",
+                json!({ "token": token, "to": "bob", "body": body }),
+            )
+            .await;
+    }
+
+    let (status, found) = server
+        .request("GET", &format!("/search/{}?q=LUNCH", bob), None, &[])
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::OK);
+
+    let bodies: Vec<_> = found
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|message| message["body"].as_str().unwrap())
+        .collect();
+
+    assert_eq!(bodies, ["Lunch at noon?", "lunch is on me"]);
+    assert!(found[0]["id"].is_string());
+    assert!(found[0]["timestamp"].is_i64());
+
+    let (_, found) = server
+        .request(
+            "GET",
+            &format!("/search/{}?q=lunch&from=carol", bob),
+            None,
+            &[],
+        )
+        .await;
+
+    assert_eq!(found.as_array().unwrap().len(), 1);
+    assert_eq!(found[0]["from"], "carol");
+}
+
+#[tokio::test]
+async fn reconnect_with_a_cursor_replays_what_was_missed() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let send = |body: &str| {
+        server.post(
+            "/send_message",
+            json!({ "token": alice, "to": "bob", "body": body }),
+        )
+    };
+
+    let mut bob_socket = server.connect(&bob).await;
+
+    send("one").await;
+
+    let seen = bob_socket.next_event_of("message").await["seq"]
+        .as_u64()
+        .unwrap();
+
+    bob_socket.close().await;
+
+    send("two").await;
+    send("three").await;
+
+    let (mut bob_socket, _) = server
+        .connect_to(&format!("/messages?last_seq={}", seen), &bob, None)
+        .await
+        .unwrap();
+
+    send("four").await;
+
+    // the missed ones in order, nothing seen before and nothing twice
+    for body in ["two", "three", "four"] {
+        assert_eq!(bob_socket.next_event_of("message").await["body"], body);
+    }
+}
+
+#[tokio::test]
+async fn same_message_queued_twice_is_delivered_once() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+
+    for body in ["are you there?", "are you there?", "hello?"] {
+        server
+            .post(
+                "/send_message",
+                json!({ "token": alice, "to": "bob", "body": body }),
+            )
+            .await;
+    }
+
+    let mut bob_socket = server.connect(&bob).await;
+
+    assert_eq!(
+        bob_socket.next_event_of("message").await["body"],
+        "are you there?"
+    );
+    assert_eq!(bob_socket.next_event_of("message").await["body"], "hello?");
+}
+
+#[tokio::test]
+async fn version_is_the_crate_version() {
+    let server = TestServer::start().await;
+
+    let (status, version) = server.request("GET", "/version", None, &[]).await;
+
+    assert_eq!(status, warp::http::StatusCode::OK);
+    assert_eq!(version["version"], env!("CARGO_PKG_VERSION"));
+    assert!(version["commit"].is_string());
+}
+
+#[tokio::test]
+async fn private_room_takes_only_invited_members() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let carol = server.register("carol").await;
+
+    let (status, _) = server
+        .post(
+            "/rooms",
+            json!({ "token": bob, "name": "secret", "private": true }),
+        )
+        .await;
+
+    assert!(status.is_success());
+
+    let (status, response) = server
+        .post("/rooms/secret/join", json!({ "token": carol }))
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::FORBIDDEN);
+    assert_eq!(response["error"], "not_invited");
+
+    let (status, _) = server
+        .post(
+            "/rooms/secret/invite",
+            json!({ "token": bob, "name": "alice" }),
+        )
+        .await;
+
+    assert!(status.is_success());
+
+    let (status, _) = server
+        .post("/rooms/secret/join", json!({ "token": alice }))
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::OK);
+
+    // only its members see it listed
+    let listed = |rooms: serde_json::Value| {
+        rooms
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|room| room["name"] == "secret")
+    };
+
+    let (_, rooms) = server.request("GET", "/rooms", None, &[]).await;
+
+    assert!(!listed(rooms));
+
+    let (_, rooms) = server
+        .request(
+            "GET",
+            "/rooms",
+            None,
+            &[("authorization", &format!("Bearer {}", carol))],
+        )
+        .await;
+
+    assert!(!listed(rooms));
+
+    let (_, rooms) = server
+        .request(
+            "GET",
+            "/rooms",
+            None,
+            &[("authorization", &format!("Bearer {}", alice))],
+        )
+        .await;
+
+    assert!(listed(rooms));
+}
+
+#[tokio::test]
+async fn joining_refuses_a_room_name_creating_would() {
+    let server = TestServer::start().await;
+
+    let bob = server.register("bob").await;
+    let name = "x".repeat(64);
+
+    let (status, response) = server
+        .post("/rooms", json!({ "token": bob, "name": name }))
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::BAD_REQUEST);
+    assert_eq!(response["error"], "invalid_name");
+
+    let (status, response) = server
+        .post(&format!("/rooms/{}/join", name), json!({ "token": bob }))
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::BAD_REQUEST);
+    assert_eq!(response["error"], "invalid_name");
+    assert!(server.state.rooms.lock().await.is_empty());
+}
+
+#[tokio::test]
+async fn only_moderators_kick_and_only_owners_promote() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let carol = server.register("carol").await;
+
+    server
+        .post("/rooms", json!({ "token": bob, "name": "club" }))
+        .await;
+
+    for token in [&alice, &carol] {
+        server
+            .post("/rooms/club/join", json!({ "token": token }))
+            .await;
+    }
+
+    // a member can neither kick nor promote
+    let (status, response) = server
+        .post(
+            "/rooms/club/kick",
+            json!({ "token": carol, "name": "alice" }),
+        )
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::FORBIDDEN);
+    assert_eq!(response["error"], "insufficient_role");
+
+    let (status, _) = server
+        .post(
+            "/rooms/club/role",
+            json!({ "token": carol, "name": "alice", "role": "moderator" }),
+        )
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::FORBIDDEN);
+
+    let (status, _) = server
+        .post(
+            "/rooms/club/role",
+            json!({ "token": bob, "name": "alice", "role": "moderator" }),
+        )
+        .await;
+
+    assert!(status.is_success());
+
+    let (status, _) = server
+        .post(
+            "/rooms/club/kick",
+            json!({ "token": alice, "name": "carol" }),
+        )
+        .await;
+
+    assert!(status.is_success());
+
+    let (status, response) = server
+        .post(
+            "/rooms/club/message",
+            json!({ "token": carol, "body": "still here?" }),
+        )
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::FORBIDDEN);
+    assert_eq!(response["error"], "not_room_member");
+}
+
+#[tokio::test]
+async fn room_keeps_only_as_many_messages_as_it_retains() {
+    let server = TestServer::start().await;
+
+    let bob = server.register("bob").await;
+
+    server
+        .post(
+            "/rooms",
+            json!({ "token": bob, "name": "brief", "max_messages": 2 }),
+        )
+        .await;
+
+    for body in ["one", "two", "three"] {
+        let (status, _) = server
+            .post(
+                "/rooms/brief/message",
+                json!({ "token": bob, "body": body }),
+            )
+            .await;
+
+        assert_eq!(status, warp::http::StatusCode::OK);
+    }
+
+    let (status, history) = server
+        .request("GET", &format!("/rooms/brief/history/{}", bob), None, &[])
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::OK);
+
+    let bodies: Vec<_> = history
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|message| message["body"].as_str().unwrap())
+        .collect();
+
+    assert_eq!(bodies, ["two", "three"]);
+}
+
+#[tokio::test]
+async fn read_only_room_takes_posts_from_moderators_only() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let carol = server.register("carol").await;
+    let mut alice_socket = server.connect(&alice).await;
+    let mut bob_socket = server.connect(&bob).await;
+
+    server
+        .post(
+            "/rooms",
+            json!({ "token": bob, "name": "news", "read_only": true }),
+        )
+        .await;
+
+    for token in [&alice, &carol] {
+        server
+            .post("/rooms/news/join", json!({ "token": token }))
+            .await;
+    }
+
+    let (status, response) = server
+        .post(
+            "/rooms/news/message",
+            json!({ "token": alice, "body": "first!" }),
+        )
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::FORBIDDEN);
+    assert_eq!(response["error"], "read_only_room");
+
+    server
+        .post(
+            "/rooms/news/role",
+            json!({ "token": bob, "name": "carol", "role": "moderator" }),
+        )
+        .await;
+
+    let (status, _) = server
+        .post(
+            "/rooms/news/message",
+            json!({ "token": carol, "body": "extra, extra" }),
+        )
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::OK);
+
+    for socket in [&mut alice_socket, &mut bob_socket] {
+        assert_eq!(
+            socket.next_event_of("message").await["body"],
+            "extra, extra"
+        );
+    }
+}
+
+#[tokio::test]
+async fn rapid_reconnects_leave_one_consistent_client() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+
+    for _ in 0..10 {
+        let sockets = (0..5).map(|_| async {
+            server.connect(&bob).await.close().await;
+        });
+
+        futures::future::join_all(sockets).await;
+    }
+
+    let mut bob_socket = server.connect(&bob).await;
+
+    assert!(server.state.clients.get("bob").unwrap().is_connected());
+
+    server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": "bob", "body": "still there?" }),
+        )
+        .await;
+
+    assert_eq!(
+        bob_socket.next_event_of("message").await["body"],
+        "still there?"
+    );
+
+    bob_socket.close().await;
+
+    let gone = async {
+        while server.state.clients.get("bob").unwrap().is_connected() {
+            tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+        }
+    };
+
+    tokio::time::timeout(tokio::time::Duration::from_secs(5), gone)
+        .await
+        .expect("bob stayed connected");
+}
+
+#[tokio::test]
+async fn open_socket_does_not_hold_up_other_clients() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let _alice_socket = server.connect(&alice).await;
+
+    let others = async {
+        let bob = server.register("bob").await;
+
+        let (status, _) = server
+            .request("GET", &format!("/status/{}", bob), None, &[])
+            .await;
+
+        assert_eq!(status, warp::http::StatusCode::OK);
+    };
+
+    tokio::time::timeout(tokio::time::Duration::from_secs(1), others)
+        .await
+        .expect("requests were held up by the open socket");
+}
+
+#[tokio::test]
+async fn audit_log_records_admin_actions_in_order() {
+    let server =
+        TestServer::with_config(|config| config.admin_token = Some("secret".to_string())).await;
+
+    server.register("bob").await;
+
+    server
+        .post(
+            "/admin/kick",
+            json!({ "admin_token": "secret", "name": "bob" }),
+        )
+        .await;
+    server
+        .post(
+            "/admin/ban",
+            json!({ "admin_token": "secret", "name": "mallory" }),
+        )
+        .await;
+
+    let (status, audit) = server
+        .request(
+            "GET",
+            "/admin/audit",
+            None,
+            &[("authorization", "Bearer secret")],
+        )
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::OK);
+
+    let entries: Vec<_> = audit
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| {
+            (
+                entry["action"].as_str().unwrap(),
+                entry["target"].as_str().unwrap(),
+            )
+        })
+        .collect();
+
+    assert_eq!(entries, [("kick", "bob"), ("ban", "mallory")]);
+    assert!(audit[0]["seq"].as_u64() < audit[1]["seq"].as_u64());
+}
+
+#[tokio::test]
+async fn history_evicts_the_oldest_past_either_cap() {
+    let server = TestServer::with_config(|config| {
+        config.admin_token = Some("secret".to_string());
+        config.history_caps.max_messages = 3;
+        config.history_caps.max_bytes = 10;
+    })
+    .await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+
+    let history = || async {
+        let (_, history) = server
+            .request("GET", &format!("/history/{}", bob), None, &[])
+            .await;
+
+        history
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|message| message["body"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+    let send = |body: &str| {
+        server.post(
+            "/send_message",
+            json!({ "token": alice, "to": "bob", "body": body }),
+        )
+    };
+
+    // twelve bytes, past the byte cap before the count cap
+    for body in ["aaaa", "bbbb", "cccc"] {
+        send(body).await;
+    }
+
+    assert_eq!(history().await, ["bbbb", "cccc"]);
+
+    // four messages, past the count cap with bytes to spare
+    for body in ["d", "e"] {
+        send(body).await;
+    }
+
+    assert_eq!(history().await, ["cccc", "d", "e"]);
+
+    let (_, stats) = server
+        .request(
+            "GET",
+            "/admin/stats",
+            None,
+            &[("authorization", "Bearer secret")],
+        )
+        .await;
+
+    assert_eq!(stats["history_messages"], 3);
+    assert_eq!(stats["history_bytes"], 6);
+}
+
+#[tokio::test]
+async fn delivery_status_moves_from_sent_to_delivered_to_read() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let authorization = format!("Bearer {}", alice);
+
+    let (_, sent) = server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": "bob", "body": "tick tock" }),
+        )
+        .await;
+
+    let id = sent["message_id"].as_str().unwrap();
+    let path = format!("/messages/{}/status", id);
+
+    let status = || async {
+        let (_, status) = server
+            .request("GET", &path, None, &[("authorization", &authorization)])
+            .await;
+
+        status["status"].clone()
+    };
+
+    assert_eq!(status().await, "sent");
+
+    let mut bob_socket = server.connect(&bob).await;
+    bob_socket.next_event_of("message").await;
+
+    // the socket hears it once it's been written
+    let delivered = async {
+        while status().await != "delivered" {
+            tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+        }
+    };
+
+    tokio::time::timeout(tokio::time::Duration::from_secs(5), delivered)
+        .await
+        .expect("the message never showed as delivered");
+
+    server
+        .post("/read", json!({ "token": bob, "message_id": id }))
+        .await;
+
+    assert_eq!(status().await, "read");
+
+    // only the sender may ask
+    let (status, _) = server
+        .request(
+            "GET",
+            &path,
+            None,
+            &[("authorization", &format!("Bearer {}", bob))],
+        )
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn sender_hears_when_a_queued_message_cannot_be_delivered() {
+    let server = TestServer::start().await;
+
+    let alice = server.register("alice").await;
+    let bob = server.register("bob").await;
+    let mut alice_socket = server.connect(&alice).await;
+
+    let (_, sent) = server
+        .post(
+            "/send_message",
+            json!({ "token": alice, "to": "bob", "body": "you there?" }),
+        )
+        .await;
+
+    let (status, _) = server
+        .request("DELETE", "/account", Some(json!({ "token": bob })), &[])
+        .await;
+
+    assert_eq!(status, warp::http::StatusCode::NO_CONTENT);
+
+    let notice = alice_socket.next_event_of("undeliverable").await;
+
+    assert_eq!(notice["message_id"], sent["message_id"]);
+    assert_eq!(notice["reason"], "account_deleted");
+}
@@ -0,0 +1,329 @@
+//! how requests and events are encoded. json is the default everywhere,
+//! clients that would rather save the bytes can speak messagepack instead:
+//! sockets negotiate it at connect time and get binary frames, post bodies
+//! sent as `application/msgpack` are decoded as such.
+//!
+//! sockets also pick the version of the event envelope by offering a
+//! `chat.v<n>` subprotocol. clients that don't offer one get version 1, the
+//! flat shape every client spoke before versions existed.
+
+use futures::{Stream, TryStreamExt};
+use serde::{de::DeserializeOwned, Serialize};
+use warp::{hyper::body::Buf, Filter};
+
+/// subprotocol a socket offers to ask for messagepack frames
+pub const MSGPACK_PROTOCOL: &str = "msgpack";
+
+/// prefix of the subprotocols naming an envelope version
+const VERSION_PROTOCOL_PREFIX: &str = "chat.v";
+
+/// content type of messagepack post bodies
+const MSGPACK_CONTENT_TYPE: &str = "application/msgpack";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WireFormat {
+    #[default]
+    Json,
+    Msgpack,
+}
+
+impl WireFormat {
+    /// the format a socket asked for, either by `?format=` or by offering
+    /// the messagepack subprotocol
+    pub fn negotiate(query: Option<WireFormat>, protocols: Option<&str>) -> Self {
+        if let Some(format) = query {
+            return format;
+        }
+
+        match protocols {
+            Some(protocols) if offers_msgpack(protocols) => WireFormat::Msgpack,
+            _ => WireFormat::Json,
+        }
+    }
+
+    /// an event as a socket frame in the negotiated envelope, text for json
+    /// and binary for messagepack. maps keep their field names so both
+    /// formats carry the same shape
+    pub fn frame(self, version: ProtocolVersion, event: &impl Serialize) -> warp::ws::Message {
+        match version {
+            ProtocolVersion::V1 => self.encode(event),
+            ProtocolVersion::V2 => self.encode(&envelope(event)),
+        }
+    }
+
+    fn encode(self, event: &impl Serialize) -> warp::ws::Message {
+        match self {
+            WireFormat::Json => warp::ws::Message::text(serde_json::to_string(event).unwrap()),
+            WireFormat::Msgpack => {
+                warp::ws::Message::binary(rmp_serde::to_vec_named(event).unwrap())
+            }
+        }
+    }
+}
+
+/// versions of the event envelope a socket can negotiate
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ProtocolVersion {
+    /// `{"type": "message", "id": ..}`, the fields next to the type
+    #[default]
+    V1,
+
+    /// `{"type": "message", "data": {"id": ..}}`, the fields under `data` so
+    /// new ones can't clash with the envelope
+    V2,
+}
+
+impl ProtocolVersion {
+    const SUPPORTED: [ProtocolVersion; 2] = [ProtocolVersion::V1, ProtocolVersion::V2];
+
+    /// the subprotocol naming this version, echoed during the handshake
+    pub fn protocol(self) -> &'static str {
+        match self {
+            ProtocolVersion::V1 => "chat.v1",
+            ProtocolVersion::V2 => "chat.v2",
+        }
+    }
+
+    /// the newest version among those offered. `Ok(None)` when the client
+    /// offered no version at all, `Err` when it only offered unknown ones
+    pub fn negotiate(protocols: Option<&str>) -> Result<Option<Self>, ()> {
+        let offered: Vec<&str> = protocols
+            .into_iter()
+            .flat_map(|protocols| protocols.split(','))
+            .map(str::trim)
+            .filter(|protocol| protocol.starts_with(VERSION_PROTOCOL_PREFIX))
+            .collect();
+
+        if offered.is_empty() {
+            return Ok(None);
+        }
+
+        Self::SUPPORTED
+            .into_iter()
+            .rev()
+            .find(|version| offered.contains(&version.protocol()))
+            .map(Some)
+            .ok_or(())
+    }
+}
+
+/// moves everything but the `type` tag of an event under `data`
+fn envelope(event: &impl Serialize) -> serde_json::Value {
+    let mut data = match serde_json::to_value(event).unwrap() {
+        serde_json::Value::Object(data) => data,
+        other => return serde_json::json!({ "data": other }),
+    };
+
+    let mut envelope = serde_json::Map::new();
+
+    if let Some(kind) = data.remove("type") {
+        envelope.insert("type".to_string(), kind);
+    }
+
+    envelope.insert("data".to_string(), serde_json::Value::Object(data));
+
+    serde_json::Value::Object(envelope)
+}
+
+pub fn offers_msgpack(protocols: &str) -> bool {
+    protocols
+        .split(',')
+        .any(|protocol| protocol.trim() == MSGPACK_PROTOCOL)
+}
+
+/// decodes a frame a client pushed, text frames as json and binary ones as
+/// messagepack whatever the socket negotiated. `None` for anything else
+pub fn decode_frame<T: DeserializeOwned>(message: &warp::ws::Message) -> Option<Result<T, String>> {
+    if let Ok(text) = message.to_str() {
+        return Some(serde_json::from_str(text).map_err(|err| err.to_string()));
+    }
+
+    if message.is_binary() {
+        return Some(rmp_serde::from_slice(message.as_bytes()).map_err(|err| err.to_string()));
+    }
+
+    None
+}
+
+/// the post body could not be decoded in the format it was sent in
+#[derive(Debug)]
+pub struct MalformedBody;
+
+impl warp::reject::Reject for MalformedBody {}
+
+/// the request body didn't arrive within the request timeout
+#[derive(Debug)]
+pub struct RequestTimeout;
+
+impl warp::reject::Reject for RequestTimeout {}
+
+/// runs `read`, reading a request body, and gives up on it with
+/// `RequestTimeout` once `limit` passed. `None` waits as long as it takes
+async fn within<T>(
+    limit: Option<tokio::time::Duration>,
+    read: impl std::future::Future<Output = Result<T, warp::Rejection>>,
+) -> Result<T, warp::Rejection> {
+    let Some(limit) = limit else {
+        return read.await;
+    };
+
+    tokio::time::timeout(limit, read).await.unwrap_or_else(|_| {
+        tracing::debug!(?limit, "request body too slow, giving up");
+        Err(warp::reject::custom(RequestTimeout))
+    })
+}
+
+/// the whole body, as it comes in
+async fn read_body(
+    stream: impl Stream<Item = Result<impl Buf, warp::Error>>,
+) -> Result<Vec<u8>, warp::Rejection> {
+    let mut stream = Box::pin(stream);
+    let mut bytes = Vec::new();
+
+    while let Some(chunk) = stream.try_next().await.map_err(|err| {
+        tracing::debug!(%err, "could not read request body");
+        warp::reject::custom(MalformedBody)
+    })? {
+        bytes.extend_from_slice(chunk.chunk());
+    }
+
+    Ok(bytes)
+}
+
+/// like `warp::body::json`, but takes messagepack when the content type
+/// says so. a body that takes longer than `timeout` to arrive is given up on
+pub fn body<T: DeserializeOwned + Send>(
+    timeout: Option<tokio::time::Duration>,
+) -> impl Filter<Extract = (T,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("content-type")
+        .and(warp::body::stream())
+        .and_then(move |content_type: Option<String>, stream| async move {
+            let bytes = within(timeout, read_body(stream)).await?;
+
+            let is_msgpack = content_type.as_deref().is_some_and(|content_type| {
+                content_type.split(';').next().map(str::trim) == Some(MSGPACK_CONTENT_TYPE)
+            });
+
+            let decoded = if is_msgpack {
+                rmp_serde::from_slice(&bytes).map_err(|err| err.to_string())
+            } else {
+                serde_json::from_slice(&bytes).map_err(|err| err.to_string())
+            };
+
+            decoded.map_err(|err| {
+                tracing::debug!(%err, is_msgpack, "could not decode request body");
+                warp::reject::custom(MalformedBody)
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn format_comes_from_the_query_then_the_subprotocol() {
+        assert_eq!(WireFormat::negotiate(None, None), WireFormat::Json);
+        assert_eq!(
+            WireFormat::negotiate(None, Some("chat.v2, msgpack")),
+            WireFormat::Msgpack
+        );
+        assert_eq!(
+            WireFormat::negotiate(Some(WireFormat::Json), Some("msgpack")),
+            WireFormat::Json
+        );
+        assert_eq!(
+            WireFormat::negotiate(None, Some("msgpackish")),
+            WireFormat::Json
+        );
+    }
+
+    #[test]
+    fn frames_round_trip_in_either_format() {
+        let event = json!({ "type": "message", "body": "hi" });
+
+        let text = WireFormat::Json.frame(ProtocolVersion::V1, &event);
+        let binary = WireFormat::Msgpack.frame(ProtocolVersion::V1, &event);
+
+        assert!(text.is_text());
+        assert!(binary.is_binary());
+
+        for frame in [text, binary] {
+            let decoded: serde_json::Value = decode_frame(&frame).unwrap().unwrap();
+
+            assert_eq!(decoded, event);
+        }
+    }
+
+    #[test]
+    fn undecodable_frames_are_errors() {
+        let decoded = decode_frame::<serde_json::Value>(&warp::ws::Message::text("{not json"));
+
+        assert!(matches!(decoded, Some(Err(_))));
+        assert!(decode_frame::<serde_json::Value>(&warp::ws::Message::ping(Vec::new())).is_none());
+    }
+
+    #[test]
+    fn newest_offered_version_wins() {
+        assert_eq!(ProtocolVersion::negotiate(None), Ok(None));
+        assert_eq!(ProtocolVersion::negotiate(Some("msgpack")), Ok(None));
+        assert_eq!(
+            ProtocolVersion::negotiate(Some("chat.v1, chat.v2")),
+            Ok(Some(ProtocolVersion::V2))
+        );
+        assert_eq!(
+            ProtocolVersion::negotiate(Some("chat.v9, chat.v1")),
+            Ok(Some(ProtocolVersion::V1))
+        );
+        assert_eq!(ProtocolVersion::negotiate(Some("chat.v9")), Err(()));
+    }
+
+    #[test]
+    fn v2_moves_the_fields_under_data() {
+        let event = json!({ "type": "message", "body": "hi" });
+
+        let frame = WireFormat::Json.frame(ProtocolVersion::V2, &event);
+        let decoded: serde_json::Value = decode_frame(&frame).unwrap().unwrap();
+
+        assert_eq!(
+            decoded,
+            json!({ "type": "message", "data": { "body": "hi" } })
+        );
+    }
+
+    #[tokio::test]
+    async fn post_body_is_decoded_by_its_content_type() {
+        let request = json!({ "token": "t", "body": "hi" });
+
+        let decoded: serde_json::Value = warp::test::request()
+            .header("content-type", "application/msgpack; charset=binary")
+            .body(rmp_serde::to_vec_named(&request).unwrap())
+            .filter(&body(None))
+            .await
+            .unwrap();
+
+        assert_eq!(decoded, request);
+
+        let decoded: serde_json::Value = warp::test::request()
+            .header("content-type", "application/json")
+            .body(request.to_string())
+            .filter(&body(None))
+            .await
+            .unwrap();
+
+        assert_eq!(decoded, request);
+
+        // json sent as messagepack doesn't decode to a request
+        let rejected = warp::test::request()
+            .header("content-type", "application/msgpack")
+            .body(request.to_string())
+            .filter(&body::<std::collections::HashMap<String, String>>(None))
+            .await
+            .unwrap_err();
+
+        assert!(rejected.find::<MalformedBody>().is_some());
+    }
+}
@@ -5,7 +5,7 @@ export const getBaseUrl = (): string => {
 export const getWsUrl = (): string => {
   const url = new URL('/messages', location.href);
   url.port = '8080';
-  url.protocol = 'ws:';
+  url.protocol = location.protocol === 'https:' ? 'wss:' : 'ws:';
 
   return url.href;
 };